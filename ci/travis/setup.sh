@@ -51,6 +51,15 @@ else
 fi
 
 
+### Setup wasmtime ############################################################
+
+rustup target add wasm32-wasip1 wasm32-wasip1-threads
+if ! command -v wasmtime >/dev/null; then
+  echo "Installing latest 'wasmtime'"
+  curl -SsfL https://wasmtime.dev/install.sh | bash
+fi
+
+
 ### Setup xargo ################################################################
 
 echo -n "Fetching latest available 'xargo' version... "
@@ -0,0 +1,24 @@
+//! A `Deblockator` used as the global allocator of a bare-metal program.
+//!
+//! The example does not depend on `std`, and is built as a library so that
+//! it needs neither an entry point nor a panic handler: it builds as is for
+//! 16-bit microcontrollers (MSP430, AVR), where the default block size and
+//! thresholds of the allocator fit the small static heap, for instance with
+//! `cargo build --example bare_metal --target msp430-none-elf -Z build-std=core,alloc`.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use]
+extern crate deblockator;
+
+use alloc::vec::Vec;
+
+static_deblockator!(GLOBAL, 4096);
+
+/// Returns the sum of the first `n` integers, computed from a heap buffer.
+#[no_mangle]
+pub extern "C" fn sum_to(n: u16) -> u16 {
+    let values: Vec<u16> = (0..n).collect();
+    values.iter().fold(0, |sum, &value| sum.wrapping_add(value))
+}
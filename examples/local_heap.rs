@@ -0,0 +1,31 @@
+//! Collections of a subsystem allocated in a dedicated `Deblockator`.
+
+#![feature(allocator_api)]
+
+#[macro_use]
+extern crate deblockator;
+
+use std::alloc::System;
+
+use deblockator::Deblockator;
+
+fn main() {
+    // all the allocations of this subsystem are kept in its own blocks
+    let heap: Deblockator<System> = Deblockator::new(System);
+
+    let name = deblockator::boxed_in("parser", &heap);
+    let mut tokens = vec_in![&heap; 1, 2, 3];
+    tokens.extend(4..100);
+    let buffer = vec_in![&heap; 0u8; 4096];
+
+    let blocks = heap.blocks().count();
+    let used: usize = heap.blocks().map(|block| block.used).sum();
+    println!(
+        "{}: {} tokens and {} bytes of buffer in {} block(s), {} bytes used",
+        name,
+        tokens.len(),
+        buffer.len(),
+        blocks,
+        used
+    );
+}
@@ -0,0 +1,12 @@
+//! A `Deblockator` installed as the global allocator over a static heap.
+
+#[macro_use]
+extern crate deblockator;
+
+static_deblockator!(GLOBAL, 1 << 20);
+
+fn main() {
+    let v: Vec<usize> = (0..1000).collect();
+    let s = format!("{} items allocated in a static heap", v.len());
+    println!("{}", s);
+}
@@ -0,0 +1,15 @@
+//! A `Deblockator` installed as the global allocator over a static array.
+
+#[macro_use]
+extern crate deblockator;
+
+/// The memory used by the global allocator.
+static mut HEAP: [u8; 1 << 20] = [0; 1 << 20];
+
+global_deblockator!(GLOBAL, HEAP);
+
+fn main() {
+    let v: Vec<usize> = (0..1000).collect();
+    let s = format!("{} items allocated in a static region", v.len());
+    println!("{}", s);
+}
@@ -0,0 +1,91 @@
+//! A `Deblockator` configured for small memory devices.
+//!
+//! The block source is a fixed pool of `1kB` blocks taken from a static
+//! array, similar to what a 16-bit microcontroller (MSP430, AVR) would
+//! provide. On such targets, these parameters are also the defaults.
+
+#![feature(allocator_api)]
+
+extern crate deblockator;
+extern crate typenum;
+
+use std::alloc::AllocError;
+use std::alloc::Allocator;
+use std::alloc::GlobalAlloc;
+use std::alloc::Layout;
+use std::cell::Cell;
+use std::cell::UnsafeCell;
+use std::ptr::NonNull;
+
+use deblockator::Deblockator;
+use typenum::consts::U1024;
+use typenum::consts::U16;
+use typenum::consts::U256;
+
+/// The number of blocks in the pool.
+const BLOCKS: usize = 4;
+
+#[repr(C, align(16))]
+struct Block(UnsafeCell<[u8; 1024]>);
+
+/// A block source handing out `1kB` blocks from a static pool.
+struct BlockPool {
+    blocks: [Block; BLOCKS],
+    used: [Cell<bool>; BLOCKS],
+}
+
+impl BlockPool {
+    fn new() -> Self {
+        BlockPool {
+            blocks: [
+                Block(UnsafeCell::new([0; 1024])),
+                Block(UnsafeCell::new([0; 1024])),
+                Block(UnsafeCell::new([0; 1024])),
+                Block(UnsafeCell::new([0; 1024])),
+            ],
+            used: Default::default(),
+        }
+    }
+}
+
+unsafe impl Allocator for BlockPool {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() > 1024 || layout.align() > 16 {
+            return Err(AllocError);
+        }
+        for (block, used) in self.blocks.iter().zip(self.used.iter()) {
+            if !used.replace(true) {
+                let ptr = NonNull::new(block.0.get() as *mut u8).ok_or(AllocError)?;
+                return Ok(NonNull::slice_from_raw_parts(ptr, 1024));
+            }
+        }
+        Err(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout) {
+        for (block, used) in self.blocks.iter().zip(self.used.iter()) {
+            if block.0.get() as *mut u8 == ptr.as_ptr() {
+                used.set(false);
+            }
+        }
+    }
+}
+
+fn main() {
+    let heap: Deblockator<BlockPool, U1024, U16, U256, U16> = Deblockator::new(BlockPool::new());
+
+    unsafe {
+        let layout = Layout::from_size_align(24, 4).unwrap();
+        let ptrs: Vec<*mut u8> = (0..64).map(|_| heap.alloc(layout)).collect();
+        assert!(ptrs.iter().all(|ptr| !ptr.is_null()));
+        println!(
+            "allocated {} objects of {} bytes",
+            ptrs.len(),
+            layout.size()
+        );
+
+        for ptr in ptrs {
+            heap.dealloc(ptr, layout);
+        }
+    }
+}
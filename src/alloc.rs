@@ -1,22 +1,154 @@
+use core::alloc::AllocError;
 use core::alloc::Allocator;
 use core::alloc::GlobalAlloc;
 use core::alloc::Layout;
 use core::cell::UnsafeCell;
 use core::cmp::max;
+use core::cmp::min;
+use core::ffi::c_int;
+use core::fmt;
+#[cfg(feature = "watchdog")]
+use core::hint;
+use core::iter;
 use core::marker::PhantomData;
+use core::mem;
 use core::mem::align_of;
+use core::mem::size_of;
+use core::panic::Location;
+use core::ptr;
 use core::ptr::NonNull;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
 
 use spin::Mutex;
-use typenum::consts::U16384;
-use typenum::consts::U4096;
-use typenum::consts::U65536;
+use spin::MutexGuard;
 use typenum::PowerOfTwo;
 use typenum::Unsigned;
 
+use super::cache::LargeCache;
+use super::clock::Clock;
+use super::consts::DefaultBlockAlign;
+use super::consts::DefaultBlockSize;
+use super::consts::DefaultLargeAlign;
+use super::consts::DefaultLargeSize;
+use super::hole::BlockInfo;
 use super::hole::HeapBlock;
 use super::hole::Hole;
+use super::hole::GRANULE;
+use super::leaks::LeakTable;
+use super::leaks::PassthroughAllocation;
+use super::magazine;
+use super::policy::Criticality;
+use super::policy::DeallocError;
+use super::policy::DeallocErrorPolicy;
+use super::policy::DumpContent;
+use super::policy::ExponentialGrowth;
+use super::policy::Fit;
+use super::policy::GrowthPolicy;
+use super::policy::GrowthRequest;
+use super::policy::Health;
+use super::policy::HealthThresholds;
+use super::policy::Placement;
+use super::policy::RegionPolicy;
+use super::policy::RegionPriority;
+use super::policy::Retention;
+use super::policy::SizeRounding;
+use super::policy::Watermarks;
+use super::policy::ZeroFill;
+use super::reentrancy;
+use super::reentrancy::Hooks;
+use super::region::Region;
+use super::registry::BlockRegistry;
+use super::scope;
+use super::shared::SharedRegion;
+use super::sites::SiteTable;
+use super::snapshot::LiveAllocation;
+use super::snapshot::Snapshot;
+use super::stats::Overhead;
 use super::utils::align_up;
+use super::utils::Fnv1a;
+#[cfg(feature = "watchdog")]
+use super::watchdog::LockWatchdog;
+
+/// A reclamation hook, and the maximum number of retries.
+type Retry = (fn(usize) -> bool, usize);
+
+/// A failure hook, and the number of failures per call.
+type FailureHook = (fn(Layout, AllocFailure), usize);
+
+/// A corruption hook, and the number of operations per heap verification.
+type Verification = (fn(usize), usize);
+
+/// An out-of-memory report sink, and whether only the first failure is reported.
+type OomSink = (fn(&dyn fmt::Display), bool);
+
+/// The number of largest holes listed by out-of-memory reports.
+const OOM_REPORT_HOLES: usize = 4;
+
+/// The `errno` value of an invalid alignment given to `posix_memalign`.
+const EINVAL: c_int = 22;
+
+/// The `errno` value of an allocation failure.
+const ENOMEM: c_int = 12;
+
+/// The alignment of the allocations made by `realloc_with_header` from a
+/// null pointer, which `malloc` guarantees on common targets.
+const MALLOC_ALIGN: usize = 2 * size_of::<usize>();
+
+/// The header preceding an allocation made with `alloc_with_header`.
+#[repr(C)]
+struct AllocHeader {
+    size: usize,   // the size of the allocation.
+    align: usize,  // the alignment of the allocation.
+    block: usize,  // the address of the heap block holding the allocation, or `0`.
+    cookie: usize, // the cookie given by the user, or `0`.
+    thread: usize, // the thread that made the allocation, or `0` if unknown.
+    site: usize,   // the `Location` of the call site of the allocation.
+    serial: usize, // the number of header allocations made before this one.
+    prev: usize,   // the header of the next most recent live allocation, or `0`.
+    next: usize,   // the header of the next less recent live allocation, or `0`.
+    check: usize,  // `HEADER_MAGIC` xor'ed with the address of the allocation.
+}
+
+/// The magic number checking the header of an allocation made with `alloc_with_header`.
+const HEADER_MAGIC: usize = 0xDEB1_0C4A_7011_B0C5_u64 as usize;
+
+/// The number of allocations made by the self test.
+const SELF_TEST_ALLOCATIONS: usize = 8;
+
+/// An error detected by the allocator self test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTestError {
+    /// An allocation with the given layout failed.
+    AllocFailed(Layout),
+    /// An allocation was not aligned as requested by its layout.
+    Misaligned(Layout),
+    /// The contents of an allocation with the given layout were overwritten.
+    Corrupted(Layout),
+    /// The hole list of the heap block at the given address is inconsistent.
+    Inconsistent(usize),
+}
+
+/// The reason an allocation failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocFailure {
+    /// Growth was disabled or refused by the growth policy, and the existing
+    /// heap blocks were full.
+    NoGrowth,
+    /// Obtaining more memory would have exceeded the quota.
+    Quota,
+    /// The backing allocator could not provide more memory.
+    OutOfMemory,
+    /// The blocks obtained were not contiguous, so the layout could not fit.
+    NotContiguous,
+    /// The heap was frozen.
+    Frozen,
+    /// The allocation would exceed the budget of the current heap scope.
+    Budget,
+    /// The allocation was larger than the size ceiling of the current tag.
+    Ceiling,
+}
 
 #[cfg(not(test))]
 /// A global allocator using a linked heap made of smaller blocks.
@@ -37,14 +169,27 @@ use super::utils::align_up;
 ///
 /// * **`BS`** (block size): the size of a single heap block.
 /// * **`BA`** (block alignment): the alignment required for a heap block.
+///   It is passed to the backing allocator with every block request, and
+///   is independent from the block size: for instance, blocks aligned on
+///   `2MB` (`U2097152`) can be backed by huge pages by the OS.
 /// * **`LS`** (large block size): the size above which an individual block is
 ///   allocated instead of using heap blocks. A typical value is 1/4th of the
-///   block size. *Undefined behaviour if not lower than the block size !*
+///   block size, and it must be below the payload of a block (the block size
+///   minus the heap block header), so that every other layout fits a block.
 /// * **`LA`** (large block alignment): the alignment required for a large block.
 ///
+/// The defaults are `64kB` blocks aligned on `4kB`, with layouts of `16kB` or
+/// more allocated separately. On 16-bit targets, they are lowered to `1kB`
+/// blocks aligned on `16` bytes, with a large layout limit of `256` bytes.
+///
 /// [`linked-list-allocator`]: https://crates.io/crates/linked-list-allocator
-pub struct Deblockator<A, BS = U65536, BA = U4096, LS = U16384, LA = U4096>
-where
+pub struct Deblockator<
+    A,
+    BS = DefaultBlockSize,
+    BA = DefaultBlockAlign,
+    LS = DefaultLargeSize,
+    LA = DefaultLargeAlign,
+> where
     A: Allocator,
     BS: Unsigned + 'static,
     BA: Unsigned + PowerOfTwo,
@@ -56,14 +201,77 @@ where
     __large_size: PhantomData<LS>,
     __large_padding: PhantomData<LA>,
     mutex: Mutex<()>,
+    #[cfg(feature = "watchdog")]
+    watchdog: Option<LockWatchdog>,
     block_allocator: UnsafeCell<A>,
-    first_block: UnsafeCell<Option<&'static mut HeapBlock>>,
+    first_block: UnsafeCell<Option<&'static mut HeapBlock<BS>>>,
+    large_cache: UnsafeCell<LargeCache>,
+    large_live: UnsafeCell<(usize, usize, usize)>,
+    emergency: Option<&'static Region>,
+    registry: Option<&'static BlockRegistry>,
+    sites: Option<&'static SiteTable>,
+    leaks: Option<&'static LeakTable>,
+    rounding: SizeRounding,
+    fit: Fit,
+    small_objects: bool,
+    deferred_coalescing: bool,
+    quick_list: bool,
+    sweep_before_grow: bool,
+    magazines: bool,
+    idle_release: Option<(&'static dyn Clock, u64)>,
+    empty_limit: usize,
+    inline_maintenance: AtomicBool,
+    block_metadata: Option<fn() -> usize>,
+    search_stripe: Option<fn() -> usize>,
+    free_space_order: bool,
+    backing_policy: RegionPolicy,
+    shared_policies: AtomicBool,
+    retry: Option<Retry>,
+    growth: AtomicBool,
+    growth_cap: usize,
+    min_split: usize,
+    indexed_holes: bool,
+    aligned_blocks: bool,
+    growth_policy: Option<&'static dyn GrowthPolicy>,
+    passthrough: bool,
+    placements: &'static [(Layout, Placement)],
+    zero_fill: ZeroFill,
+    ceilings: &'static [(usize, usize)],
+    payload_align: usize,
+    reserve: UnsafeCell<Option<&'static mut HeapBlock<BS>>>,
+    reserve_threshold: usize,
+    reserve_tried: UnsafeCell<bool>,
+    acquired: UnsafeCell<usize>,
+    quota: usize,
+    low_limit: usize,
+    failure_hook: Option<FailureHook>,
+    oom_sink: Option<OomSink>,
+    failures: AtomicUsize,
+    verification: Option<Verification>,
+    operations: AtomicUsize,
+    user_bytes: AtomicUsize,
+    header_frees: AtomicUsize,
+    remote_frees: AtomicUsize,
+    live: Mutex<(usize, usize)>,
+    health: HealthThresholds,
+    assessed_failures: AtomicUsize,
+    dealloc_error: DeallocErrorPolicy,
+    watermarks: Option<Watermarks>,
+    watermark_level: UnsafeCell<usize>,
+    generations: UnsafeCell<usize>,
+    frozen: AtomicBool,
+    hooks: Hooks,
 }
 
 #[cfg(test)]
 /// Test definition with public variables.
-pub struct Deblockator<A, BS = U65536, BA = U4096, LS = U16384, LA = U4096>
-where
+pub struct Deblockator<
+    A,
+    BS = DefaultBlockSize,
+    BA = DefaultBlockAlign,
+    LS = DefaultLargeSize,
+    LA = DefaultLargeAlign,
+> where
     A: Allocator,
     BS: Unsigned + 'static,
     BA: Unsigned + PowerOfTwo,
@@ -75,8 +283,66 @@ where
     __large_size: PhantomData<LS>,
     __large_padding: PhantomData<LA>,
     pub mutex: Mutex<()>,
+    #[cfg(feature = "watchdog")]
+    pub watchdog: Option<LockWatchdog>,
     pub block_allocator: UnsafeCell<A>,
-    pub first_block: UnsafeCell<Option<&'static mut HeapBlock>>,
+    pub first_block: UnsafeCell<Option<&'static mut HeapBlock<BS>>>,
+    pub large_cache: UnsafeCell<LargeCache>,
+    pub large_live: UnsafeCell<(usize, usize, usize)>,
+    pub emergency: Option<&'static Region>,
+    pub registry: Option<&'static BlockRegistry>,
+    pub sites: Option<&'static SiteTable>,
+    pub leaks: Option<&'static LeakTable>,
+    pub rounding: SizeRounding,
+    pub fit: Fit,
+    pub small_objects: bool,
+    pub deferred_coalescing: bool,
+    pub quick_list: bool,
+    pub sweep_before_grow: bool,
+    pub magazines: bool,
+    pub idle_release: Option<(&'static dyn Clock, u64)>,
+    pub empty_limit: usize,
+    pub inline_maintenance: AtomicBool,
+    pub block_metadata: Option<fn() -> usize>,
+    pub search_stripe: Option<fn() -> usize>,
+    pub free_space_order: bool,
+    pub backing_policy: RegionPolicy,
+    pub shared_policies: AtomicBool,
+    pub retry: Option<Retry>,
+    pub growth: AtomicBool,
+    pub growth_cap: usize,
+    pub min_split: usize,
+    pub indexed_holes: bool,
+    pub aligned_blocks: bool,
+    pub growth_policy: Option<&'static dyn GrowthPolicy>,
+    pub passthrough: bool,
+    pub placements: &'static [(Layout, Placement)],
+    pub zero_fill: ZeroFill,
+    pub ceilings: &'static [(usize, usize)],
+    pub payload_align: usize,
+    pub reserve: UnsafeCell<Option<&'static mut HeapBlock<BS>>>,
+    pub reserve_threshold: usize,
+    pub reserve_tried: UnsafeCell<bool>,
+    pub acquired: UnsafeCell<usize>,
+    pub quota: usize,
+    pub low_limit: usize,
+    pub failure_hook: Option<FailureHook>,
+    pub oom_sink: Option<OomSink>,
+    pub failures: AtomicUsize,
+    pub verification: Option<Verification>,
+    pub operations: AtomicUsize,
+    pub user_bytes: AtomicUsize,
+    pub header_frees: AtomicUsize,
+    pub remote_frees: AtomicUsize,
+    pub live: Mutex<(usize, usize)>,
+    pub health: HealthThresholds,
+    pub assessed_failures: AtomicUsize,
+    pub dealloc_error: DeallocErrorPolicy,
+    pub watermarks: Option<Watermarks>,
+    pub watermark_level: UnsafeCell<usize>,
+    pub generations: UnsafeCell<usize>,
+    pub frozen: AtomicBool,
+    pub hooks: Hooks,
 }
 
 unsafe impl<A, BS, BA, LS, LA> Sync for Deblockator<A, BS, BA, LS, LA>
@@ -120,237 +386,5710 @@ where
     LS: Unsigned,
     LA: Unsigned + PowerOfTwo,
 {
+    /// Compile-time validation of the type parameters.
+    ///
+    /// Evaluated when `new` is instantiated, so that an invalid configuration
+    /// fails the build instead of corrupting the heap at runtime.
+    const VALID_CONFIGURATION: () = {
+        assert!(
+            BS::USIZE >= size_of::<HeapBlock<BS>>() + size_of::<Hole>(),
+            "the block size (BS) cannot fit the heap block header and a hole"
+        );
+        assert!(
+            BS::USIZE.is_multiple_of(align_of::<Hole>()),
+            "the block size (BS) must be a multiple of the hole alignment"
+        );
+        assert!(
+            LS::USIZE > 0,
+            "the large layout limit (LS) must not be zero"
+        );
+        assert!(
+            LS::USIZE < BS::USIZE - size_of::<HeapBlock<BS>>(),
+            "the large layout limit (LS) must be below the payload of a block (BS)"
+        );
+    };
+
     /// Create a new allocator instance, wrapping the given allocator.
     pub const fn new(alloc: A) -> Self {
+        let () = Self::VALID_CONFIGURATION;
         Deblockator {
             __block_size: PhantomData,
             __block_padding: PhantomData,
             __large_size: PhantomData,
             __large_padding: PhantomData,
             mutex: Mutex::new(()),
+            #[cfg(feature = "watchdog")]
+            watchdog: None,
             block_allocator: UnsafeCell::new(alloc),
             first_block: UnsafeCell::new(None),
+            large_cache: UnsafeCell::new(LargeCache::new(0)),
+            large_live: UnsafeCell::new((0, 0, 0)),
+            emergency: None,
+            registry: None,
+            sites: None,
+            leaks: None,
+            rounding: SizeRounding::Exact,
+            fit: Fit::First,
+            small_objects: false,
+            deferred_coalescing: false,
+            quick_list: false,
+            sweep_before_grow: false,
+            magazines: false,
+            idle_release: None,
+            empty_limit: usize::MAX,
+            inline_maintenance: AtomicBool::new(true),
+            block_metadata: None,
+            search_stripe: None,
+            free_space_order: false,
+            backing_policy: RegionPolicy::NORMAL,
+            shared_policies: AtomicBool::new(false),
+            retry: None,
+            growth: AtomicBool::new(true),
+            growth_cap: 0,
+            min_split: 0,
+            indexed_holes: false,
+            aligned_blocks: false,
+            growth_policy: None,
+            passthrough: true,
+            placements: &[],
+            zero_fill: ZeroFill::Never,
+            ceilings: &[],
+            payload_align: align_of::<Hole>(),
+            reserve: UnsafeCell::new(None),
+            reserve_threshold: 0,
+            reserve_tried: UnsafeCell::new(false),
+            acquired: UnsafeCell::new(0),
+            quota: usize::MAX,
+            low_limit: usize::MAX,
+            failure_hook: None,
+            oom_sink: None,
+            failures: AtomicUsize::new(0),
+            verification: None,
+            operations: AtomicUsize::new(0),
+            user_bytes: AtomicUsize::new(0),
+            header_frees: AtomicUsize::new(0),
+            remote_frees: AtomicUsize::new(0),
+            live: Mutex::new((0, 0)),
+            health: HealthThresholds::new(),
+            assessed_failures: AtomicUsize::new(0),
+            dealloc_error: DeallocErrorPolicy::Panic,
+            watermarks: None,
+            watermark_level: UnsafeCell::new(0),
+            generations: UnsafeCell::new(0),
+            frozen: AtomicBool::new(false),
+            hooks: Hooks::new(),
         }
     }
 
-    /// Create a kernel-compatible layout that can fit the requested layout
-    unsafe fn padded(&self, layout: Layout, align: usize) -> Layout {
-        let padding = layout.padding_needed_for(align);
-        Layout::from_size_align_unchecked(layout.size() + padding, align)
+    /// Release heap blocks once they have been empty for `idle` clock ticks.
+    ///
+    /// Empty heap blocks are otherwise kept forever. Releasing them only
+    /// after some time avoids returning a block to the backing allocator
+    /// just before it is needed again. Idle blocks are released during
+    /// deallocations, or when calling [`release_idle`](#method.release_idle).
+    pub const fn with_idle_release(mut self, clock: &'static dyn Clock, idle: u64) -> Self {
+        self.idle_release = Some((clock, idle));
+        self
     }
-}
 
-unsafe impl<A, BS, BA, LS, LA> GlobalAlloc for Deblockator<A, BS, BA, LS, LA>
-where
-    A: Allocator,
-    BS: Unsigned + 'static,
-    BA: Unsigned + PowerOfTwo,
-    LS: Unsigned,
-    LA: Unsigned + PowerOfTwo,
-{
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let lock = self.mutex.lock();
-        let allocator = &mut *self.block_allocator.get();
+    /// Report the lock of the allocator being held for too long to the given watchdog.
+    ///
+    /// Acquisitions of the lock record their call site, and acquisitions
+    /// blocked for longer than the bound of the watchdog report the call site
+    /// that holds the lock. Only available with the `watchdog` feature.
+    #[cfg(feature = "watchdog")]
+    pub const fn with_lock_watchdog(mut self, watchdog: LockWatchdog) -> Self {
+        self.watchdog = Some(watchdog);
+        self
+    }
 
-        // if the requested memory block is large, simply dedicate a single block
-        if layout.size() >= LS::to_usize() {
-            return match allocator.allocate(self.padded(layout, LA::to_usize())) {
-                Ok(ptr) => ptr.as_ptr() as *mut u8,
-                Err(_) => ::core::ptr::null_mut::<u8>(),
-            };
-        }
+    /// Serve the allocations made from the hooks of the allocator from the given region.
+    ///
+    /// Hooks such as the failure hook, the watermark callback or the block
+    /// metadata initializer may log, and therefore allocate. Allocations made
+    /// while a hook runs never reach the heap blocks, where they could
+    /// deadlock or recurse: they are served from the emergency region, or
+    /// fail if none is set.
+    ///
+    /// Some hooks run with the allocator locked, so they must not free heap
+    /// memory, nor call the other methods of the allocator. Frees made while
+    /// a hook runs are handled by the [deallocation error
+    /// policy](#method.with_dealloc_error_policy) as `DeallocError::Reentrant`,
+    /// unless they give back memory of the emergency region.
+    pub const fn with_emergency_region(mut self, region: &'static Region) -> Self {
+        self.emergency = Some(region);
+        self
+    }
 
-        // Pad the layout to the minimum legal size
-        let block_layout = {
-            let size = max(HeapBlock::<BS>::min_size(), layout.size());
-            Layout::from_size_align_unchecked(align_up(size, align_of::<Hole>()), layout.align())
-        };
+    /// Keep the addresses of the heap blocks sorted in the given registry.
+    ///
+    /// The heap block containing a freed pointer is then found with a binary
+    /// search, instead of walking the list of heap blocks. Heap blocks that
+    /// do not fit in the registry are still found by walking the list.
+    pub const fn with_block_registry(mut self, registry: &'static BlockRegistry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
 
-        // traverse the heap blocks to find an allocatable block
-        let mut next_block: *mut Option<&mut HeapBlock> = self.first_block.get();
-        while let Some(ref mut block) = *next_block {
-            if let Ok(ptr) = block.allocate_first_fit(block_layout) {
-                return ptr.as_ptr() as *mut u8;
-            };
-            next_block = &mut block.next;
-        }
+    /// Aggregate the allocations made with a header by call site in the given table.
+    ///
+    /// Allocations made with [`alloc_with_header`](#method.alloc_with_header)
+    /// or [`alloc_with_cookie`](#method.alloc_with_cookie) are then counted
+    /// for their call site, and the sites holding the most memory can be
+    /// listed at runtime with [`SiteTable::top`]. Allocations made without
+    /// a header have no call site, and are not counted.
+    ///
+    /// [`SiteTable::top`]: struct.SiteTable.html#method.top
+    pub const fn with_site_table(mut self, sites: &'static SiteTable) -> Self {
+        self.sites = Some(sites);
+        self
+    }
 
-        // No block can contain the requested layout: allocate a new one !
-        let new_heap_layout = Layout::from_size_align_unchecked(BS::to_usize(), BA::to_usize());
-        let new_heap_ptr = match allocator.allocate(new_heap_layout) {
-            Ok(ptr) => NonNull::new(ptr.as_ptr() as *mut HeapBlock).unwrap(),
-            Err(_) => return ::core::ptr::null_mut::<u8>(),
-            // Err(_) => return 0xDEADBEEF as usize as *mut _,
-        };
+    /// Record the live large allocations in the given table, for leak reports.
+    ///
+    /// Large allocations are forwarded to the backing allocator without a
+    /// header, so they are recorded apart with their layout and call site,
+    /// and listed by [`write_leak_report`](#method.write_leak_report) along
+    /// with the live header allocations. The call site is the caller of the
+    /// allocator, which is the standard library for a global allocator.
+    /// Large allocations made with a header are listed from their header.
+    pub const fn with_leak_table(mut self, leaks: &'static LeakTable) -> Self {
+        self.leaks = Some(leaks);
+        self
+    }
 
-        // Initialize the block and use it to allocate
-        let new_block = HeapBlock::<BS>::new(new_heap_ptr);
-        let new_block_ptr = match new_block.allocate_first_fit(block_layout) {
-            Ok(mem) => mem.as_ptr() as *mut _,
-            Err(_) => return ::core::ptr::null_mut::<u8>(),
-            // Err(_) => return 0xCAFEBABE as usize as *mut _,
+    /// Keep or release empty heap blocks according to the given policy.
+    ///
+    /// This replaces the policy set by [`with_idle_release`] or
+    /// [`with_empty_block_limit`], which are shorthands for some policies.
+    ///
+    /// [`with_idle_release`]: #method.with_idle_release
+    /// [`with_empty_block_limit`]: #method.with_empty_block_limit
+    pub const fn with_retention(mut self, retention: Retention) -> Self {
+        let (idle_release, empty_limit) = match retention {
+            Retention::KeepAll => (None, usize::MAX),
+            Retention::KeepSpare(limit) => (None, limit),
+            Retention::ReleaseIdle(clock, idle) => (Some((clock, idle)), usize::MAX),
         };
-        *next_block = Some(new_block);
+        self.idle_release = idle_release;
+        self.empty_limit = empty_limit;
+        self
+    }
 
-        drop(lock);
-        new_block_ptr
+    /// Keep at most `limit` empty heap blocks, releasing the other ones.
+    ///
+    /// Empty heap blocks beyond the limit are returned to the backing
+    /// allocator as soon as they are found during housekeeping, without
+    /// waiting for them to be idle. A limit of `0` releases heap blocks as
+    /// soon as they become empty, while a small limit keeps a few blocks
+    /// ready for allocation bursts.
+    pub const fn with_empty_block_limit(mut self, limit: usize) -> Self {
+        self.empty_limit = limit;
+        self
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let lock = self.mutex.lock();
-        if layout.size() >= LS::to_usize() {
-            let allocator = &mut *self.block_allocator.get();
-            allocator.deallocate(
-                NonNull::new(ptr).unwrap(),
-                self.padded(layout, LA::to_usize()),
+    /// Grow the heap with blocks doubling in size, up to `cap` bytes.
+    ///
+    /// Each new heap block is twice as large as the previous one, starting
+    /// from `BS` bytes, so that a steadily growing heap makes fewer requests
+    /// to the backing allocator. The size is computed from the number of
+    /// blocks in the heap, so it decreases again once blocks are released.
+    /// If a large block cannot be obtained, a block of `BS` bytes is
+    /// requested instead.
+    pub const fn with_exponential_growth(mut self, cap: usize) -> Self {
+        self.growth_cap = cap;
+        self
+    }
+
+    /// Never split a hole if it would leave a hole smaller than `bytes`.
+    ///
+    /// By default, holes are split as long as the rest can hold a free
+    /// hole, which can scatter many slivers too small to ever be reused.
+    /// With a larger threshold, such holes are skipped instead, unless the
+    /// allocation fills them exactly.
+    pub const fn with_min_split(mut self, bytes: usize) -> Self {
+        self.min_split = bytes;
+        self
+    }
+
+    /// Index the holes of each heap block by size, for best-fit searches.
+    ///
+    /// With [`Fit::Best`], the smallest hole large enough for a layout is
+    /// then found in `O(log n)` in a tree linked through the holes, instead
+    /// of by scanning the whole hole list, and over-aligned layouts only
+    /// look at the holes aligned enough or large enough to be padded. Each
+    /// hole holds a tree node, so
+    /// allocations and holes are at least five words large. The other fit
+    /// strategies still walk the hole list, and only keep the tree updated.
+    ///
+    /// [`Fit::Best`]: enum.Fit.html#variant.Best
+    pub const fn with_indexed_holes(mut self) -> Self {
+        self.indexed_holes = true;
+        self
+    }
+
+    /// Grow the heap according to the given policy.
+    ///
+    /// The policy chooses the size of the chunks requested from the backing
+    /// allocator, and when to stop growing. It replaces the exponential
+    /// growth, if any.
+    pub const fn with_growth_policy(mut self, policy: &'static dyn GrowthPolicy) -> Self {
+        self.growth_policy = Some(policy);
+        self
+    }
+
+    /// Acquire heap blocks aligned to their size, to find them in constant time.
+    ///
+    /// The heap block containing a pointer is then found by masking the
+    /// pointer, instead of walking the list of heap blocks, which speeds up
+    /// deallocations in a heap made of many blocks. Heap blocks are always
+    /// made of a single `BS`-byte chunk: the growth policy can still stop
+    /// the heap from growing, but contiguous chunks are no longer merged.
+    /// The block size must be a power of two.
+    ///
+    /// Pointers that were not allocated by the heap can no longer be reliably
+    /// reported to the deallocation error handler, since the memory below
+    /// them is read to check it is a heap block header. Lookups of any
+    /// pointer, such as [`block_metadata`](#method.block_metadata), do not
+    /// mask pointers.
+    pub const fn with_aligned_blocks(mut self) -> Self {
+        assert!(
+            BS::USIZE.is_power_of_two(),
+            "aligned blocks require the block size (BS) to be a power of two"
+        );
+        self.aligned_blocks = true;
+        self
+    }
+
+    /// Cache up to `limit` bytes of freed large allocations for reuse.
+    ///
+    /// Large allocations are then kept after being freed, and reused for
+    /// identical layouts, instead of always going through the backing
+    /// allocator. A limit of `0` (the default) disables the cache.
+    pub const fn with_large_cache(mut self, limit: usize) -> Self {
+        self.large_cache = UnsafeCell::new(LargeCache::new(limit));
+        self
+    }
+
+    /// Initialize the metadata of new heap blocks with the given function.
+    ///
+    /// Heap blocks have a word of user-defined metadata (`0` by default),
+    /// which can be used for instance to tag blocks with an arena or tenant
+    /// identifier, and be read from any pointer within the block with
+    /// [`block_metadata`](#method.block_metadata).
+    pub const fn with_block_metadata(mut self, init: fn() -> usize) -> Self {
+        self.block_metadata = Some(init);
+        self
+    }
+
+    /// Start the block search at an index given by the stripe function.
+    ///
+    /// By default, the search for a free spot always starts at the first heap
+    /// block. When several threads share the allocator, a stripe function
+    /// returning a thread or CPU identifier makes them start at different
+    /// blocks (the index wraps around the number of blocks), so that they
+    /// tend to fill different blocks and share fewer cache lines.
+    pub const fn with_search_stripe(mut self, stripe: fn() -> usize) -> Self {
+        self.search_stripe = Some(stripe);
+        self
+    }
+
+    /// Keep the heap blocks ordered by decreasing free space.
+    ///
+    /// Searches then start with the block most likely to fit the layout. The
+    /// order is only restored when an allocation had to probe more than one
+    /// block, so blocks gaining free space are moved forward lazily.
+    pub const fn with_free_space_order(mut self) -> Self {
+        self.free_space_order = true;
+        self
+    }
+
+    /// Apply the given policy to the heap blocks obtained from the backing allocator.
+    ///
+    /// Shared blocks follow the policy of their [`SharedRegion`] instead, so
+    /// that heap blocks from faster memory can be preferred, or kept for
+    /// small allocations. The heap does not grow for layouts larger than the
+    /// maximum size of the policy.
+    ///
+    /// [`SharedRegion`]: struct.SharedRegion.html
+    pub const fn with_backing_policy(mut self, policy: RegionPolicy) -> Self {
+        self.backing_policy = policy;
+        self
+    }
+
+    /// Retry failed allocations up to `retries` times after calling `reclaim`.
+    ///
+    /// When an allocation fails, the reclamation hook is called with the
+    /// attempt number (starting at `0`), and can free memory by any mean,
+    /// such as dropping application caches or calling
+    /// [`flush_large_cache`](#method.flush_large_cache) or
+    /// [`maintain`](#method.maintain), since the allocator is not locked
+    /// during the call. The hook returns `false` to give up immediately.
+    pub const fn with_retry(mut self, reclaim: fn(usize) -> bool, retries: usize) -> Self {
+        self.retry = Some((reclaim, retries));
+        self
+    }
+
+    /// Never request memory from the backing allocator.
+    ///
+    /// Allocations are then only served from existing heap blocks (and from
+    /// the large allocation cache), and fail once those are exhausted. Heap
+    /// blocks should be obtained beforehand, for instance with a first
+    /// allocation phase, and growth can be toggled at runtime with
+    /// [`set_growth`](#method.set_growth).
+    pub const fn without_growth(mut self) -> Self {
+        self.growth = AtomicBool::new(false);
+        self
+    }
+
+    /// Keep a reserve block for allocations of at most `threshold` bytes.
+    ///
+    /// The reserve block is acquired when the heap first grows, and only used
+    /// once the other heap blocks are full and the backing allocator fails,
+    /// so that error paths needing a few bytes (such as formatting a panic
+    /// message) can still make progress. High-criticality allocations may
+    /// use it whatever their size (see
+    /// [`alloc_with_criticality`](#method.alloc_with_criticality)). It is
+    /// never released, and is not listed by [`blocks`](#method.blocks). If
+    /// the backing allocator cannot provide it then, no reserve is kept.
+    pub const fn with_reserve(mut self, threshold: usize) -> Self {
+        self.reserve_threshold = threshold;
+        self
+    }
+
+    /// Limit the memory obtained from the backing allocator to `quota` bytes.
+    ///
+    /// Heap blocks and large allocations are both accounted for, so that
+    /// several allocators sharing the same backing memory (for instance,
+    /// one per plugin) cannot starve each other. Requests exceeding the
+    /// quota fail as if the backing allocator was out of memory.
+    pub const fn with_quota(mut self, quota: usize) -> Self {
+        self.quota = quota;
+        self
+    }
+
+    /// Report failed allocations to the given hook.
+    ///
+    /// The hook is called with the layout and the reason of the failure,
+    /// once all retries are exhausted, for one failure out of `every` (use
+    /// `1` to report all of them). It is called without the allocator being
+    /// locked, so that failures handled gracefully by the application can
+    /// still be recorded by telemetry.
+    pub const fn with_failure_hook(mut self, hook: fn(Layout, AllocFailure), every: usize) -> Self {
+        self.failure_hook = Some((hook, if every == 0 { 1 } else { every }));
+        self
+    }
+
+    /// Send a report of the state of the heap to the given sink when an allocation fails.
+    ///
+    /// The sink is called with the report (see
+    /// [`write_oom_report`](#method.write_oom_report)), which it can write to
+    /// a log or a serial port for instance. With `first_only`, only the first
+    /// failure is reported, so that a device running out of memory in the
+    /// field leaves a single report of the state that led to it. The
+    /// allocator is locked while the report is written.
+    pub const fn with_oom_report(mut self, sink: fn(&dyn fmt::Display), first_only: bool) -> Self {
+        self.oom_sink = Some((sink, first_only));
+        self
+    }
+
+    /// Verify the integrity of the heap blocks every `every` operations.
+    ///
+    /// One allocation or deallocation out of `every` first checks the hole
+    /// lists of all heap blocks (see [`check_integrity`]), and calls the hook
+    /// with the address of the first inconsistent heap block, if any. A small
+    /// interval detects corruption sooner, at the cost of scanning the whole
+    /// heap more often (use `1` to verify it on every operation).
+    ///
+    /// [`check_integrity`]: #method.check_integrity
+    pub const fn with_verification(mut self, hook: fn(usize), every: usize) -> Self {
+        self.verification = Some((hook, if every == 0 { 1 } else { every }));
+        self
+    }
+
+    /// Use the given thresholds to assess the [`health`](#method.health) of the heap.
+    pub const fn with_health_thresholds(mut self, thresholds: HealthThresholds) -> Self {
+        self.health = thresholds;
+        self
+    }
+
+    /// Use the given policy when an invalid deallocation is detected.
+    ///
+    /// Deallocations of pointers outside of any heap block, and some double
+    /// frees, are detected; by default, they cause a panic.
+    pub const fn with_dealloc_error_policy(mut self, policy: DeallocErrorPolicy) -> Self {
+        self.dealloc_error = policy;
+        self
+    }
+
+    /// Report when the memory obtained from the backing allocator crosses the given levels.
+    ///
+    /// The callback is called with the allocator locked, so it is meant for
+    /// cheap notifications, such as toggling a status LED or setting a flag.
+    /// Allocations it makes are served from the emergency region, if any
+    /// (see [`with_emergency_region`](#method.with_emergency_region)).
+    pub const fn with_watermarks(mut self, watermarks: Watermarks) -> Self {
+        self.watermarks = Some(watermarks);
+        self
+    }
+
+    /// Reject low-criticality allocations needing more than `limit` bytes in total.
+    ///
+    /// Allocations made with [`Criticality::Low`] fail instead of bringing the
+    /// memory obtained from the backing allocator above `limit` bytes, which
+    /// keeps the remaining memory (up to the quota) for more critical ones.
+    ///
+    /// [`Criticality::Low`]: enum.Criticality.html#variant.Low
+    pub const fn with_low_criticality_limit(mut self, limit: usize) -> Self {
+        self.low_limit = limit;
+        self
+    }
+
+    /// Serve all allocations from heap blocks, even large ones.
+    ///
+    /// By default, layouts of `LS` bytes or more are forwarded to the
+    /// backing allocator, which must then be a general-purpose allocator.
+    /// Without passthrough, the backing allocator only ever receives block
+    /// requests, so it can be a simple block source (such as a page frame
+    /// allocator); layouts larger than a block then only fit when the
+    /// blocks obtained are contiguous, and fail otherwise.
+    pub const fn without_passthrough(mut self) -> Self {
+        self.passthrough = false;
+        self
+    }
+
+    /// Override where the allocations of specific layouts are served from.
+    ///
+    /// Each given layout (size and alignment) is always forwarded to the
+    /// backing allocator or always served from heap blocks, whatever the
+    /// `LS` threshold, and even without passthrough. Other layouts keep
+    /// using the threshold.
+    pub const fn with_placements(mut self, placements: &'static [(Layout, Placement)]) -> Self {
+        self.placements = placements;
+        self
+    }
+
+    /// Zero the memory obtained from the backing allocator, or rely on it being zeroed.
+    ///
+    /// Zeroed allocations made in memory never used before then skip most
+    /// of their clearing, and shared blocks are zeroed when lent. By
+    /// default, memory is not zeroed, and zeroed allocations are cleared
+    /// entirely.
+    pub const fn with_zero_fill(mut self, zero_fill: ZeroFill) -> Self {
+        self.zero_fill = zero_fill;
+        self
+    }
+
+    /// Limit the size of the allocations made under the given tags.
+    ///
+    /// Each entry is a tag and the largest allocation size allowed while the
+    /// tag is the one of the current thread (see `TagScope`, with the `std`
+    /// feature). Larger allocations fail with [`AllocFailure::Ceiling`]
+    /// instead of eating the heap, and in-place growth stops at the ceiling.
+    ///
+    /// [`AllocFailure::Ceiling`]: enum.AllocFailure.html#variant.Ceiling
+    pub const fn with_size_ceilings(mut self, ceilings: &'static [(usize, usize)]) -> Self {
+        self.ceilings = ceilings;
+        self
+    }
+
+    /// Start the usable memory of new heap blocks on the given alignment.
+    ///
+    /// The memory between the block header and the first aligned address is
+    /// left unused, so that allocations can start on a page boundary, for
+    /// instance to be protected or mapped for DMA at page granularity. The
+    /// alignment must be a power of two not larger than the block alignment,
+    /// and must leave some usable memory in a block.
+    pub const fn with_payload_alignment(mut self, align: usize) -> Self {
+        assert!(
+            align.is_power_of_two(),
+            "the payload alignment must be a power of two"
+        );
+        assert!(
+            align <= BA::USIZE,
+            "the payload alignment cannot exceed the block alignment (BA)"
+        );
+        let payload = (size_of::<HeapBlock<BS>>() + align - 1) & !(align - 1);
+        assert!(
+            payload + size_of::<Hole>() <= BS::USIZE,
+            "the payload alignment leaves no usable memory in a block"
+        );
+        self.payload_align = align;
+        self
+    }
+
+    /// Use the given rounding policy for block-managed allocations.
+    ///
+    /// The policy is applied to the requested size before searching for a
+    /// hole, and must not be changed after the first allocation since the
+    /// same rounding is needed to deallocate memory. Sizes can only be
+    /// rounded to multiples of a power of two.
+    pub const fn with_size_rounding(mut self, rounding: SizeRounding) -> Self {
+        if let SizeRounding::Multiple(n) = rounding {
+            assert!(
+                n.is_power_of_two(),
+                "sizes can only be rounded to multiples of a power of two"
             );
-        } else {
-            let mut block: *mut Option<&mut HeapBlock> = self.first_block.get();
-            while let Some(ref mut b) = *block {
-                if b.contains(ptr as *const u8) {
-                    b.deallocate(NonNull::new_unchecked(ptr), layout);
-                    return;
-                }
-                block = &mut b.next;
-            }
-            panic!("double free !")
         }
-        drop(lock);
+        self.rounding = rounding;
+        self
     }
-}
 
-#[cfg(test)]
-mod test {
+    /// Use the given strategy to choose the hole of block-managed allocations.
+    ///
+    /// Blocks are still searched in order, but each block places an
+    /// allocation according to the strategy. Unlike the rounding policy,
+    /// the strategy does not affect deallocation.
+    pub const fn with_fit(mut self, fit: Fit) -> Self {
+        self.fit = fit;
+        self
+    }
 
-    use super::*;
+    /// Serve small allocations from bitmap-managed slots in each heap block.
+    ///
+    /// Allocations of up to 64 bytes, aligned to at most 16 bytes, then take
+    /// one or more 16-byte slots of a small-object region allocated in the
+    /// block on first use, instead of splitting holes. They fall back to the
+    /// hole list when the region of a block is full.
+    pub const fn with_small_objects(mut self) -> Self {
+        self.small_objects = true;
+        self
+    }
 
-    use core::alloc::AllocError;
-    use core::mem::size_of;
+    /// Defer the merging of freed memory with the neighbouring holes.
+    ///
+    /// Freed block-managed allocations are kept in per-block free lists by
+    /// size class, and reused as is by allocations of similar sizes, which
+    /// suits free-heavy phases reallocating the same sizes. The free lists
+    /// of a block are merged into its holes when an allocation fails to fit
+    /// otherwise, or by calling [`coalesce`](#method.coalesce). Allocations
+    /// too large for a size class are always merged immediately.
+    pub const fn with_deferred_coalescing(mut self) -> Self {
+        self.deferred_coalescing = true;
+        self
+    }
 
-    use typenum::consts::U2048;
+    /// Keep the last few chunks freed in each heap block out of the hole list.
+    ///
+    /// Allocations check the quick list of a block before walking its holes,
+    /// so that a program freeing and reallocating the same size takes the
+    /// chunk back directly. The oldest chunks are merged into the hole list
+    /// once the quick list is full, or when an allocation fails to fit
+    /// otherwise. Deferred coalescing takes precedence over the quick list.
+    pub const fn with_quick_list(mut self) -> Self {
+        self.quick_list = true;
+        self
+    }
 
-    struct MockAlloc {
-        pub allocated: [bool; 3],
-        pub blocks: [[u8; 4096]; 3],
+    /// Merge the memory freed in every heap block before growing the heap.
+    ///
+    /// When an allocation fits no heap block, the free lists and quick lists
+    /// of all blocks are merged into their holes, as with
+    /// [`coalesce`](#method.coalesce), and the allocation is retried before
+    /// a new block is acquired. This makes the growth path slower, but keeps
+    /// the heap from growing while freed memory could still be reused, which
+    /// suits deployments with a memory cap.
+    pub const fn with_sweep_before_grow(mut self) -> Self {
+        self.sweep_before_grow = true;
+        self
     }
 
-    impl MockAlloc {
-        pub fn new() -> Self {
-            Self {
-                allocated: [false; 3],
-                blocks: [[0; 4096], [0; 4096], [0; 4096]],
-            }
+    /// Serve small allocations from per-thread magazines.
+    ///
+    /// Allocations of up to 128 bytes, aligned on 16 bytes at most, are
+    /// rounded up to a power of two and served from a magazine of free
+    /// chunks kept by the current thread, without taking the lock. Freed
+    /// chunks go back to the magazine once the lock was briefly taken to
+    /// check they belong to a heap block. Empty magazines are refilled from
+    /// the heap blocks by batches, full ones are flushed back to them by
+    /// batches, and each magazine is flushed back
+    /// when its thread exits. A thread only keeps a magazine for the first
+    /// allocator with magazines it uses. Chunks held in magazines count as
+    /// allocated, and are not moved by [`migrate_to`](#method.migrate_to).
+    ///
+    /// # Safety
+    ///
+    /// The allocator must not be moved once used, and must outlive all the
+    /// threads using it, since their magazines are flushed back to it on
+    /// exit: this is the case of a `static` global allocator.
+    #[cfg(feature = "std")]
+    pub const unsafe fn with_magazines(mut self) -> Self {
+        self.magazines = true;
+        self
+    }
+
+    /// Return the chunks held in the magazine of the current thread to the heap blocks.
+    ///
+    /// See [`with_magazines`](#method.with_magazines).
+    #[cfg(feature = "std")]
+    pub fn flush_magazine(&self) {
+        if self.magazines {
+            magazine::flush(self.owner(), Self::flush_chunks);
         }
     }
 
-    unsafe impl Allocator for MockAlloc {
-        unsafe fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
-            for i in 0..self.blocks.len() {
-                if !self.allocated[i] {
-                    self.allocated[i] = true;
-                    return NonNull::new(self.blocks[i].as_mut().as_mut_ptr()).ok_or(AllocError);
-                }
+    /// Return all cached large allocations to the backing allocator.
+    ///
+    /// Returns the number of bytes released.
+    pub fn flush_large_cache(&self) -> usize {
+        let _lock = self.lock();
+        unsafe {
+            let cache = &mut *self.large_cache.get();
+            let released = cache.bytes();
+            for (ptr, layout) in cache.drain() {
+                self.release(ptr, layout);
             }
-            Err(AllocError)
+            released
         }
+    }
 
-        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
-            for i in 0..self.blocks.len() {
-                if ptr.as_ptr() == self.blocks[i].as_mut().as_mut_ptr() {
-                    if !self.allocated[i] {
-                        panic!("double free")
-                    } else {
-                        self.allocated[i] = false;
-                        return;
-                    }
-                }
+    /// Assess the health of the heap, with the thresholds set by
+    /// [`with_health_thresholds`](#method.with_health_thresholds).
+    ///
+    /// The heap is failing if enough allocations failed since the previous
+    /// assessment, near exhaustion if the memory obtained from the backing
+    /// allocator is close to the quota (if any), and fragmented if too much
+    /// of the free memory of the heap blocks lies outside of the largest
+    /// hole. Supervisory code can poll it to switch the application into
+    /// degraded modes.
+    pub fn health(&self) -> Health {
+        let thresholds = self.health;
+        let failures = self.failures.load(Ordering::Relaxed);
+        let previous = self.assessed_failures.swap(failures, Ordering::Relaxed);
+        if thresholds.failures > 0 && failures.wrapping_sub(previous) >= thresholds.failures {
+            return Health::Failing;
+        }
+
+        let _lock = self.lock();
+        let acquired = unsafe { *self.acquired.get() };
+        if self.quota != usize::MAX
+            && acquired.saturating_mul(100) >= self.quota.saturating_mul(thresholds.exhaustion)
+        {
+            return Health::NearExhaustion;
+        }
+
+        let mut free = 0;
+        let mut largest = 0;
+        let mut next_block = unsafe { &*self.first_block.get() };
+        while let Some(ref block) = *next_block {
+            for hole in block.holes() {
+                free += hole.size;
+                largest = max(largest, hole.size);
             }
-            panic!("no such block !")
+            next_block = &block.next;
+        }
+        if free > 0 && (free - largest) * 100 > free * thresholds.fragmentation {
+            return Health::Fragmented;
         }
+        Health::Healthy
     }
 
-    #[test]
-    /// Test the mock allocator works as expected.
-    fn mockalloc() {
-        unsafe {
-            let mut ma = MockAlloc::new();
-            let layout = Layout::from_size_align_unchecked(4096, 4096);
+    /// Returns the number of bytes currently obtained from the backing allocator.
+    pub fn acquired_bytes(&self) -> usize {
+        let _lock = self.lock();
+        unsafe { *self.acquired.get() }
+    }
 
-            let pt1 = ma.alloc(layout).expect("could not allocate block 1");
-            let pt2 = ma.alloc(layout).expect("could not allocate block 2");
-            let pt3 = ma.alloc(layout).expect("could not allocate block 3");
-            ma.alloc(layout).expect_err("all blocks were not allocated");
+    /// Returns the number of live large allocations, and the bytes they hold.
+    ///
+    /// Large allocations are forwarded to the backing allocator, each in its
+    /// own exact-size memory, so they are counted apart from the heap blocks.
+    /// Freed large allocations kept in the large cache are not live.
+    pub fn large_allocations(&self) -> (usize, usize) {
+        let _lock = self.lock();
+        let (count, bytes, _) = unsafe { *self.large_live.get() };
+        (count, bytes)
+    }
 
-            for i in 0..3 {
-                assert!(ma.allocated[i]);
+    /// Returns how the memory held by the allocator is split between user data and overhead.
+    ///
+    /// The padding is computed as the memory used in the heap blocks and
+    /// large allocations minus the bytes requested by live allocations, so
+    /// it also includes the chunks held in magazines, and the small-object
+    /// regions and free lists of the heap blocks. Leased blocks, and the
+    /// allocations served from the emergency region, are not counted.
+    pub fn overhead(&self) -> Overhead {
+        let _lock = self.lock();
+        let mut overhead = Overhead::default();
+        let mut used = unsafe { (*self.large_live.get()).1 };
+        unsafe {
+            let first = (*self.first_block.get()).as_deref();
+            let blocks = iter::successors(first, |block| block.next.as_deref());
+            for block in blocks.chain((*self.reserve.get()).as_deref()) {
+                let info = block.info();
+                overhead.headers += block.payload;
+                overhead.free += info.len - info.used;
+                used += info.used - block.payload;
             }
+            overhead.cached = (*self.large_cache.get()).bytes();
+        }
+        overhead.user = self.user_bytes.load(Ordering::Relaxed);
+        overhead.padding = used.saturating_sub(overhead.user);
+        overhead
+    }
 
-            ma.dealloc(pt1, layout);
-            assert!(!ma.allocated[0]);
+    /// Release the heap blocks that have been idle for long enough.
+    ///
+    /// Does nothing unless an idle release policy was configured with
+    /// [`with_idle_release`](#method.with_idle_release). Returns the number
+    /// of bytes returned to the backing allocator.
+    pub fn release_idle(&self) -> usize {
+        let _lock = self.lock();
+        unsafe { self.release_idle_blocks() }
+    }
 
-            ma.dealloc(pt3, layout);
-            assert!(!ma.allocated[2]);
+    /// Merge the memory freed with deferred coalescing into the holes of each heap block.
+    ///
+    /// This also flushes the free lists of the segregated fit strategy, and
+    /// the quick lists. See [`with_deferred_coalescing`] and [`with_quick_list`].
+    ///
+    /// [`with_deferred_coalescing`]: #method.with_deferred_coalescing
+    /// [`with_quick_list`]: #method.with_quick_list
+    pub fn coalesce(&self) {
+        let _lock = self.lock();
+        unsafe { self.coalesce_blocks() }
+    }
 
-            let pt4 = ma.alloc(layout).expect("could not allocate block 4");
-            assert!(ma.allocated[0]);
-            assert!(!ma.allocated[2]);
-            assert_eq!(pt4.as_ptr(), pt1.as_ptr());
+    /// Merge the free lists and quick lists of every heap block into their holes.
+    ///
+    /// Must be called with the lock held.
+    unsafe fn coalesce_blocks(&self) {
+        let mut next_block = (*self.first_block.get()).as_deref_mut();
+        while let Some(block) = next_block {
+            block.flush_free_lists();
+            block.flush_quick_list();
+            next_block = block.next.as_deref_mut();
+        }
+        if let Some(ref mut reserve) = *self.reserve.get() {
+            reserve.flush_free_lists();
+            reserve.flush_quick_list();
         }
     }
 
-    #[test]
-    /// Check the underlying blocks are allocated as expected.
-    fn deblockator_blocks() {
-        let ma = MockAlloc::new();
-        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma);
+    /// Release every empty heap block to the backing allocator.
+    ///
+    /// Empty blocks are released whether idle for long enough or not, so
+    /// long-running programs can reclaim memory at quiescent points. The
+    /// large allocation cache is kept (see [`flush_caches`](#method.flush_caches)).
+    /// Returns the number of bytes returned to the backing allocator.
+    pub fn trim(&self) -> usize {
+        let _lock = self.lock();
+        unsafe { self.release_blocks(|block| block.is_empty()) }
+    }
 
+    /// Obtain enough heap blocks up front for `bytes` more bytes of allocations.
+    ///
+    /// Heap blocks are acquired until their holes total at least `bytes`
+    /// bytes, so that later allocations fitting in them do not request
+    /// memory from the backing allocator, for instance before entering a
+    /// section where it is forbidden (see also [`set_growth`]). Alignment and
+    /// fragmentation can still prevent an allocation from fitting. The new
+    /// blocks are empty, so the retention policy should keep them.
+    ///
+    /// [`set_growth`]: #method.set_growth
+    pub fn reserve(&self, bytes: usize) -> Result<(), AllocFailure> {
+        let _lock = self.lock();
         unsafe {
-            // quick accessor to the allocated blocks
-            let allocated = || va.block_allocator.get().read().allocated;
-            let blocks = || va.block_allocator.get().read().blocks;
+            let mut attempt = 0;
+            let mut free = self.free_bytes();
+            while free < bytes {
+                let layout = Layout::from_size_align_unchecked(bytes - free, 1);
+                self.grow(Criticality::Normal, layout, attempt)?;
+                attempt += 1;
+                free = self.free_bytes();
+            }
+        }
+        Ok(())
+    }
 
-            // Allocate a single boxed u32
-            let layout = Layout::from_size_align(32, 8).expect("bad layout");
-            let ptr1 = NonNull::new(va.alloc(layout)).expect("could not allocate 1");
-            ::core::ptr::write(ptr1.as_ptr(), 255);
-            assert_eq!(allocated(), [true, false, false]);
+    /// Return all cached memory to the backing allocator.
+    ///
+    /// This flushes the large allocation cache and [trims](#method.trim)
+    /// the heap, leaving only the memory in use (and the reserve block, if
+    /// any). Returns the number of bytes returned to the backing allocator.
+    pub fn flush_caches(&self) -> usize {
+        self.flush_large_cache() + self.trim()
+    }
 
-            // Allocate a second boxed u32
-            let ptr2 = NonNull::new(va.alloc(layout)).expect("could not allocate 2");
-            ::core::ptr::write(ptr2.as_ptr(), 254);
-            assert_eq!(allocated(), [true, false, false]);
+    /// Perform the allocator housekeeping.
+    ///
+    /// This is done during deallocations by default, but can be scheduled
+    /// manually instead (see [`set_inline_maintenance`]), or be done in a
+    /// background thread with the `std` feature (see [`spawn_maintenance`]).
+    /// Currently, this releases idle heap blocks and the empty heap blocks
+    /// beyond the limit (see [`with_empty_block_limit`]), and returns the
+    /// number of bytes returned to the backing allocator.
+    ///
+    /// [`set_inline_maintenance`]: #method.set_inline_maintenance
+    /// [`spawn_maintenance`]: #method.spawn_maintenance
+    /// [`with_empty_block_limit`]: #method.with_empty_block_limit
+    pub fn maintain(&self) -> usize {
+        let _lock = self.lock();
+        unsafe { self.housekeeping() }
+    }
 
-            // Allocate a large object to the second block
-            let layout = Layout::from_size_align(3129, 4096).expect("bad layout");
-            let ptr3 = NonNull::new(va.alloc(layout)).expect("could not allocate 3");
-            assert_eq!(allocated(), [true, true, false]);
+    /// Choose whether housekeeping is done during deallocations.
+    ///
+    /// Disabling it keeps deallocations as short as possible, but then
+    /// [`maintain`](#method.maintain) must be called regularly.
+    pub fn set_inline_maintenance(&self, enabled: bool) {
+        self.inline_maintenance.store(enabled, Ordering::Relaxed);
+    }
 
-            // Deallocate the first u32
-            let layout = Layout::from_size_align(32, 8).expect("bad layout");
-            va.dealloc(ptr1.as_ptr(), layout);
+    /// Choose whether memory can be requested from the backing allocator.
+    ///
+    /// When disabled, allocations that do not fit in the existing heap
+    /// blocks fail instead of acquiring new blocks or large allocations.
+    pub fn set_growth(&self, enabled: bool) {
+        self.growth.store(enabled, Ordering::Relaxed);
+    }
 
-            // FIXME: Reallocate the first u32 (hopefully at the same place)
-            let ptr4 = NonNull::new(va.alloc(layout)).expect("could not allocate 4");
-            assert_eq!(ptr4.as_ptr(), ptr1.as_ptr());
+    /// Freeze the heap, rejecting all allocations and deallocations.
+    ///
+    /// This catches heap activity in phases where it indicates a bug, even
+    /// from dependencies. Allocations fail with `AllocFailure::Frozen`, and
+    /// deallocations are handled by the deallocation error policy with
+    /// `DeallocError::Frozen`, leaking the memory unless the policy panics.
+    pub fn freeze(&self) {
+        self.frozen.store(true, Ordering::SeqCst);
+    }
 
-            // Deallocate the large block
-            let layout = Layout::from_size_align(3129, 4096).expect("bad layout");
-            va.dealloc(ptr3.as_ptr(), layout);
-            assert_eq!(allocated(), [true, false, false]);
+    /// Thaw the heap after a call to [`freeze`](#method.freeze).
+    pub fn thaw(&self) {
+        self.frozen.store(false, Ordering::SeqCst);
+    }
+
+    /// Returns whether the heap is frozen.
+    pub fn is_frozen(&self) -> bool {
+        self.frozen.load(Ordering::SeqCst)
+    }
+
+    /// Spawn a thread calling [`maintain`](#method.maintain) at a regular interval.
+    ///
+    /// Housekeeping is no longer done during deallocations afterwards.
+    #[cfg(feature = "std")]
+    pub fn spawn_maintenance(
+        &'static self,
+        interval: std::time::Duration,
+    ) -> std::thread::JoinHandle<()> {
+        self.set_inline_maintenance(false);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            self.maintain();
+        })
+    }
+
+    /// Returns the metadata of the heap block containing the given pointer.
+    ///
+    /// Returns `None` if the pointer is not inside a heap block, for instance
+    /// if it was obtained from a large allocation.
+    pub fn block_metadata<T>(&self, ptr: *const T) -> Option<usize> {
+        let _lock = self.lock();
+        unsafe {
+            self.find_block(ptr as *const u8)
+                .map(|block| (*block).metadata)
         }
     }
 
-    #[test]
-    #[should_panic]
-    fn double_free() {
-        let ma = MockAlloc::new();
-        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma);
+    /// Add a heap block lent by another subsystem.
+    ///
+    /// The block takes a reference to the shared region, and drops it
+    /// instead of deallocating the memory through the backing allocator
+    /// once the block is released (as an idle or empty block). The memory is
+    /// not counted in the acquired bytes, and never extended.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the allocator has aligned blocks and the memory is not
+    /// aligned to `BS` bytes, since its allocations could not be found by
+    /// masking their address.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be valid for reads and writes of `BS` bytes, aligned
+    /// for a `HeapBlock`, and must not be used by anything else until the
+    /// reference is dropped.
+    pub unsafe fn add_shared_block(&self, ptr: NonNull<u8>, region: &'static SharedRegion) {
+        assert!(
+            !self.aligned_blocks || (ptr.as_ptr() as usize).is_multiple_of(BS::to_usize()),
+            "shared blocks must be aligned to the block size (BS) with aligned blocks"
+        );
+        let _lock = self.lock();
+        if self.zero_fill != ZeroFill::Never {
+            ptr.as_ptr().write_bytes(0, BS::to_usize());
+        }
+        let block = HeapBlock::<BS>::new_aligned(ptr.cast(), self.payload_align);
+        block.generation = self.next_generation();
+        block.min_split = max(self.min_split, HeapBlock::<BS>::min_size());
+        if self.indexed_holes {
+            block.index_holes();
+        }
+        if let Some(init) = self.block_metadata {
+            block.metadata = reentrancy::call_hook(&self.hooks, init);
+        }
+        block.shared = Some(region);
+        region.retain();
+        self.register(block);
+        if region.policy() != RegionPolicy::NORMAL {
+            self.shared_policies.store(true, Ordering::Relaxed);
+        }
 
-        let layout = Layout::from_size_align(32, 8).expect("bad layout");
+        let mut next_block: *mut Option<&mut HeapBlock<BS>> = self.first_block.get();
+        while let Some(ref mut block) = *next_block {
+            next_block = &mut block.next;
+        }
+        *next_block = Some(block);
+    }
+
+    /// Move all heap blocks to another allocator, without copying them.
+    ///
+    /// The allocations made in the blocks can then be freed or resized
+    /// through `other`, for instance to hand an early-boot heap over to an
+    /// allocator backed by the full memory manager. The blocks become shared
+    /// blocks of `other` (see [`add_shared_block`]): each of them takes a
+    /// reference to the given region, and drops it instead of giving its
+    /// memory back to a backing allocator. Blocks already lent by a region
+    /// keep theirs. The segregated free lists of the blocks are flushed, and
+    /// the reserve block is moved as a regular one.
+    ///
+    /// Large allocations are not made in heap blocks, so they stay with this
+    /// allocator and must still be freed through it, while the user bytes of
+    /// the other allocations move to `other`. Returns the number of heap
+    /// blocks moved.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the allocators do not lay out, place and free allocations
+    /// the same way, since the same layouts must be used to free them. In
+    /// particular, both must have aligned blocks or neither, since the
+    /// blocks of an allocator without them may not be aligned to `BS`.
+    ///
+    /// [`add_shared_block`]: #method.add_shared_block
+    pub fn migrate_to<B: Allocator>(
+        &self,
+        other: &Deblockator<B, BS, BA, LS, LA>,
+        region: &'static SharedRegion,
+    ) -> usize {
+        let same_fit = match (self.fit, other.fit) {
+            (Fit::Custom(policy), Fit::Custom(other_policy)) => ptr::addr_eq(policy, other_policy),
+            (fit, other_fit) => mem::discriminant(&fit) == mem::discriminant(&other_fit),
+        };
+        assert!(
+            self.rounding == other.rounding
+                && self.passthrough == other.passthrough
+                && self.placements == other.placements
+                && self.magazines == other.magazines
+                && self.aligned_blocks == other.aligned_blocks
+                && self.small_objects == other.small_objects
+                && self.deferred_coalescing == other.deferred_coalescing
+                && self.quick_list == other.quick_list
+                && self.indexed_holes == other.indexed_holes
+                && self.payload_align == other.payload_align
+                && same_fit,
+            "incompatible allocator configurations"
+        );
+        if ptr::addr_eq(self.owner(), other.owner()) {
+            return 0;
+        }
+
+        // lock in address order, so that concurrent migrations between the
+        // same allocators in opposite directions can not deadlock
+        let _locks = if (self.owner() as usize) < (other.owner() as usize) {
+            let lock = self.lock();
+            (lock, other.lock())
+        } else {
+            let other_lock = other.lock();
+            (self.lock(), other_lock)
+        };
         unsafe {
-            let ptr1 = va.alloc(layout);
-            va.dealloc(ptr1, layout);
-            va.dealloc(ptr1, layout);
+            let mut tail: *mut Option<&'static mut HeapBlock<BS>> = other.first_block.get();
+            while let Some(ref mut block) = *tail {
+                tail = &mut block.next;
+            }
+
+            let mut moved = 0;
+            let mut next = (*self.first_block.get()).take();
+            let mut reserve = (*self.reserve.get()).take();
+            *self.reserve_tried.get() = false;
+            if let Some(registry) = self.registry {
+                registry.clear();
+            }
+            while let Some(block) = next.take().or_else(|| reserve.take()) {
+                next = block.next.take();
+                block.flush_free_lists();
+                block.flush_quick_list();
+                if block.shared.is_none() {
+                    region.retain();
+                    block.shared = Some(region);
+                    *self.acquired.get() -= block.size;
+                }
+                if block
+                    .shared
+                    .is_some_and(|r| r.policy() != RegionPolicy::NORMAL)
+                {
+                    other.shared_policies.store(true, Ordering::Relaxed);
+                }
+                block.generation = other.next_generation();
+                other.register(block);
+                *tail = Some(block);
+                tail = &mut (*tail).as_mut().unwrap().next;
+                moved += 1;
+            }
+            let user = self.user_bytes.load(Ordering::Relaxed);
+            let large = (*self.large_live.get()).2;
+            let user = user.saturating_sub(large);
+            self.user_bytes.fetch_sub(user, Ordering::Relaxed);
+            other.user_bytes.fetch_add(user, Ordering::Relaxed);
+            self.update_watermarks();
+            moved
+        }
+    }
+
+    /// Returns the generation of the heap block containing the given pointer.
+    ///
+    /// Every heap block gets a new generation when it is acquired, and each
+    /// time its last allocation is freed. A handle storing a pointer along
+    /// with its generation can thus detect that the memory was freed and
+    /// reused since, even at the same address.
+    ///
+    /// Returns `None` if the pointer is not inside a heap block.
+    pub fn block_generation<T>(&self, ptr: *const T) -> Option<usize> {
+        let _lock = self.lock();
+        unsafe {
+            self.find_block(ptr as *const u8)
+                .map(|block| (*block).generation)
+        }
+    }
+
+    /// Set the metadata of the heap block containing the given pointer.
+    ///
+    /// Returns `false` if the pointer is not inside a heap block.
+    pub fn set_block_metadata<T>(&self, ptr: *const T, metadata: usize) -> bool {
+        let _lock = self.lock();
+        unsafe {
+            self.find_block(ptr as *const u8)
+                .map(|block| (*block).metadata = metadata)
+        }
+        .is_some()
+    }
+
+    /// Check the hole lists of all heap blocks are consistent.
+    ///
+    /// Returns the address of the first inconsistent heap block, if any.
+    pub fn check_integrity(&self) -> Result<(), usize> {
+        let _lock = self.lock();
+        let reserve = unsafe { &*self.reserve.get() };
+        let mut next_block = unsafe { &*self.first_block.get() };
+        while let Some(ref block) = *next_block {
+            if !block.check() {
+                return Err(&**block as *const HeapBlock<BS> as usize);
+            }
+            next_block = &block.next;
+        }
+        match *reserve {
+            Some(ref block) if !block.check() => Err(&**block as *const HeapBlock<BS> as usize),
+            _ => Ok(()),
+        }
+    }
+
+    /// Verify the heap blocks if this operation is sampled for verification.
+    fn sample_verification(&self) {
+        if let Some((hook, every)) = self.verification {
+            let operations = self.operations.fetch_add(1, Ordering::Relaxed) + 1;
+            if operations.is_multiple_of(every) {
+                if let Err(block) = self.check_integrity() {
+                    reentrancy::call_hook(&self.hooks, || hook(block));
+                }
+            }
+        }
+    }
+
+    /// Run a power-on self test of the allocator.
+    ///
+    /// Allocations of various sizes and alignments, including a large one,
+    /// are made and checked for alignment, filled with a pattern, checked
+    /// for corruption, and freed; the integrity of the heap blocks is then
+    /// verified. All allocations are freed before returning, but the heap
+    /// blocks obtained are kept.
+    pub fn self_test(&self) -> Result<(), SelfTestError> {
+        let large = LS::to_usize();
+        let sizes = [1, 8, 24, 64, 100, 256, large / 2, large];
+        let aligns = [1, 8, 8, 64, 4, 16, 8, align_of::<usize>()];
+        let mut allocations = [None; SELF_TEST_ALLOCATIONS];
+        let mut result = Ok(());
+
+        for (i, allocation) in allocations.iter_mut().enumerate() {
+            let layout = match Layout::from_size_align(max(sizes[i], 1), aligns[i]) {
+                Ok(layout) => layout,
+                Err(_) => continue,
+            };
+            let ptr = unsafe { GlobalAlloc::alloc(self, layout) };
+            if ptr.is_null() {
+                result = Err(SelfTestError::AllocFailed(layout));
+                break;
+            }
+            *allocation = Some((ptr, layout));
+            if !(ptr as usize).is_multiple_of(layout.align()) {
+                result = Err(SelfTestError::Misaligned(layout));
+                break;
+            }
+            unsafe { ptr.write_bytes(0xA5 ^ i as u8, layout.size()) };
+        }
+
+        // check no allocation overwrote another one
+        for (i, allocation) in allocations.iter().enumerate() {
+            if let (Ok(()), Some((ptr, layout))) = (result, *allocation) {
+                let bytes = unsafe { core::slice::from_raw_parts(ptr, layout.size()) };
+                if bytes.iter().any(|&byte| byte != 0xA5 ^ i as u8) {
+                    result = Err(SelfTestError::Corrupted(layout));
+                }
+            }
+        }
+
+        for (ptr, layout) in allocations.iter().flatten() {
+            unsafe { GlobalAlloc::dealloc(self, *ptr, *layout) };
+        }
+
+        result.and(self.check_integrity().map_err(SelfTestError::Inconsistent))
+    }
+
+    /// Returns a reference to the backing allocator.
+    pub fn backing_allocator(&self) -> &A {
+        unsafe { &*self.block_allocator.get() }
+    }
+
+    /// Compute a compact digest of the heap metadata.
+    ///
+    /// The digest covers the number of heap blocks, and the offset and size
+    /// of every hole within each block, of every chunk of its segregated free
+    /// lists and quick list, and of its small-object slots along with their
+    /// bitmap. Since offsets are relative to their block, digests do not
+    /// depend on where the backing allocator placed the blocks, and can be
+    /// compared across reproductions of a crash to check whether the heap
+    /// state diverged. Chunks cached in per-thread magazines and in the large
+    /// allocation cache are not covered.
+    pub fn fingerprint(&self) -> u64 {
+        let _lock = self.lock();
+        let mut hasher = Fnv1a::new();
+
+        let mut next_block = unsafe { &*self.first_block.get() };
+        while let Some(ref block) = *next_block {
+            let block_addr = &**block as *const HeapBlock<BS> as usize;
+            hasher.write_usize(usize::MAX);
+            for hole in block.holes() {
+                hasher.write_usize(hole.addr - block_addr);
+                hasher.write_usize(hole.size);
+            }
+            hasher.write_usize(usize::MAX);
+            for chunk in block.pending_chunks() {
+                hasher.write_usize(chunk.addr - block_addr);
+                hasher.write_usize(chunk.size);
+            }
+            hasher.write_usize(usize::MAX);
+            if let Some((slots, used)) = block.small_slots() {
+                hasher.write_usize(slots - block_addr);
+                hasher.write_usize(used as usize);
+            }
+            next_block = &block.next;
+        }
+
+        hasher.finish()
+    }
+
+    /// Write a map of the heap blocks to the given writer.
+    ///
+    /// Each heap block is listed with its occupancy and metadata, followed by
+    /// its holes and used spans in address order. A used span is made of one
+    /// or more adjacent allocations, along with heap block bookkeeping. The
+    /// bytes of used spans are only written with [`DumpContent::Full`], as
+    /// hexadecimal lines, so that maps can be attached to bug reports without
+    /// leaking user data by default.
+    ///
+    /// The allocator is locked while writing, so the writer must not allocate
+    /// from the same `Deblockator`.
+    ///
+    /// [`DumpContent::Full`]: enum.DumpContent.html#variant.Full
+    pub fn dump(&self, out: &mut dyn fmt::Write, content: DumpContent) -> fmt::Result {
+        let _lock = self.lock();
+
+        let mut next_block = unsafe { &*self.first_block.get() };
+        while let Some(ref block) = *next_block {
+            let info = block.info();
+            writeln!(
+                out,
+                "block {:#x} len {} used {} holes {} generation {} metadata {:#x}",
+                info.start, info.len, info.used, info.holes, info.generation, block.metadata
+            )?;
+            let mut start = info.start + block.payload;
+            let spans = block
+                .holes()
+                .map(Some)
+                .chain(Some(None))
+                .map(|hole| (hole.map_or(info.start + info.len, |hole| hole.addr), hole));
+            for (end, hole) in spans {
+                if end > start {
+                    writeln!(out, "  used {:#x} {}", start, end - start)?;
+                    if content == DumpContent::Full {
+                        unsafe { dump_bytes(out, start, end - start)? };
+                    }
+                }
+                if let Some(hole) = hole {
+                    writeln!(out, "  hole {:#x} {}", hole.addr, hole.size)?;
+                    start = hole.addr + hole.size;
+                }
+            }
+            next_block = &block.next;
+        }
+        Ok(())
+    }
+
+    /// Write a compact report of the state of the heap after a failed allocation.
+    ///
+    /// The report starts with the failed layout, the reason of the failure
+    /// and the tag of the current thread, followed by the failure count and
+    /// the memory obtained from the backing allocator, the [overhead] of the
+    /// heap and its large allocations. Each heap block is then listed with its
+    /// occupancy and largest hole, and the report ends with the largest holes
+    /// of the heap. Allocation call sites are not recorded by the allocator,
+    /// so tagging subsystems with a [`TagScope`] is the way to tell them
+    /// apart in reports.
+    ///
+    /// The allocator is locked while writing, so the writer must not allocate
+    /// from the same `Deblockator`.
+    ///
+    /// [overhead]: #method.overhead
+    /// [`TagScope`]: struct.TagScope.html
+    pub fn write_oom_report(
+        &self,
+        out: &mut dyn fmt::Write,
+        layout: Layout,
+        failure: AllocFailure,
+    ) -> fmt::Result {
+        let overhead = self.overhead();
+        let (large, large_bytes) = self.large_allocations();
+        writeln!(
+            out,
+            "oom size {} align {} reason {:?} tag {:?}",
+            layout.size(),
+            layout.align(),
+            failure,
+            scope::current_tag()
+        )?;
+        writeln!(
+            out,
+            "failures {} acquired {} large {} bytes {}",
+            self.failures.load(Ordering::Relaxed),
+            self.acquired_bytes(),
+            large,
+            large_bytes
+        )?;
+        writeln!(
+            out,
+            "user {} headers {} padding {} free {} cached {}",
+            overhead.user, overhead.headers, overhead.padding, overhead.free, overhead.cached
+        )?;
+
+        let _lock = self.lock();
+        let mut holes = [0; OOM_REPORT_HOLES];
+        let mut next_block = unsafe { &*self.first_block.get() };
+        while let Some(ref block) = *next_block {
+            let info = block.info();
+            let mut largest = 0;
+            for hole in block.holes() {
+                largest = max(largest, hole.size);
+                if let Some(i) = holes.iter().position(|&size| size < hole.size) {
+                    holes[i..].rotate_right(1);
+                    holes[i] = hole.size;
+                }
+            }
+            writeln!(
+                out,
+                "block {:#x} len {} used {} holes {} largest {}",
+                info.start, info.len, info.used, info.holes, largest
+            )?;
+            next_block = &block.next;
+        }
+        out.write_str("largest holes")?;
+        for size in holes.iter().take_while(|&&size| size > 0) {
+            write!(out, " {}", size)?;
+        }
+        out.write_char('\n')
+    }
+
+    /// Write a map of the occupancy of each heap block to the given writer.
+    ///
+    /// Each heap block is listed with its address and size, followed by
+    /// lines of 64 characters, one per granule of 256 bytes: `#` for used
+    /// granules and `.` for free ones. The map is computed from the hole
+    /// lists, so memory held in free lists or quick lists shows as used.
+    pub fn heat_map(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        let _lock = self.lock();
+
+        let mut next_block = unsafe { &*self.first_block.get() };
+        while let Some(ref block) = *next_block {
+            let info = block.info();
+            writeln!(out, "block {:#x} len {}", info.start, info.len)?;
+            for (i, used) in block.occupancy().enumerate() {
+                if i % 64 == 0 {
+                    out.write_str("  ")?;
+                }
+                out.write_char(if used { '#' } else { '.' })?;
+                if i % 64 == 63 || (i + 1) * GRANULE >= info.len {
+                    out.write_char('\n')?;
+                }
+            }
+            next_block = &block.next;
+        }
+        Ok(())
+    }
+
+    /// Returns an iterator over the heap blocks, in list order.
+    ///
+    /// The allocator is locked until the iterator is dropped, so allocating
+    /// from the same `Deblockator` while iterating will deadlock.
+    #[track_caller]
+    pub fn blocks(&self) -> Blocks<'_, BS> {
+        let lock = self.lock();
+        Blocks {
+            _lock: lock,
+            next: unsafe { (*self.first_block.get()).as_deref() },
+        }
+    }
+
+    /// Lease a heap block for the exclusive use of a subsystem.
+    ///
+    /// An empty heap block is unlinked from the heap, or a new one of `BS`
+    /// bytes is obtained from the backing allocator. The returned lease
+    /// serves allocations from that block only, and frees all of them at
+    /// once when dropped, returning the block to the heap. Leased blocks are
+    /// not listed by [`blocks`](#method.blocks).
+    pub fn lease_block(&self) -> Result<BlockLease<'_, A, BS, BA, LS, LA>, AllocFailure> {
+        let _lock = self.lock();
+        unsafe {
+            let mut link: *mut Option<&'static mut HeapBlock<BS>> = self.first_block.get();
+            while let Some(block) = (*link).take() {
+                if block.shared.is_none() && block.is_empty() {
+                    *link = block.next.take();
+                    self.unregister(block);
+                    block.idle_since = HeapBlock::<BS>::BUSY;
+                    return Ok(BlockLease::new(self, block));
+                }
+                link = &mut (*link).get_or_insert(block).next;
+            }
+
+            let layout = Layout::from_size_align_unchecked(BS::to_usize(), self.chunk_align());
+            let ptr = self.acquire(layout, Criticality::Normal)?;
+            let block = HeapBlock::<BS>::new_aligned(ptr.cast(), self.payload_align);
+            block.generation = self.next_generation();
+            block.min_split = max(self.min_split, HeapBlock::<BS>::min_size());
+            if self.indexed_holes {
+                block.index_holes();
+            }
+            if let Some(init) = self.block_metadata {
+                block.metadata = reentrancy::call_hook(&self.hooks, init);
+            }
+            Ok(BlockLease::new(self, block))
+        }
+    }
+
+    /// Free all the allocations of a leased block, and link it back to the heap.
+    unsafe fn return_leased(&self, block: &'static mut HeapBlock<BS>) {
+        let _lock = self.lock();
+        block.reset();
+        block.generation = self.next_generation();
+        if let Some((clock, _)) = self.idle_release {
+            block.idle_since = clock.now();
+        }
+
+        self.register(block);
+        let mut link: *mut Option<&'static mut HeapBlock<BS>> = self.first_block.get();
+        while let Some(ref mut b) = *link {
+            link = &mut b.next;
+        }
+        *link = Some(block);
+    }
+
+    /// Take the lock of the allocator.
+    #[cfg(not(feature = "watchdog"))]
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        self.mutex.lock()
+    }
+
+    /// Take the lock of the allocator, recording the call site for the watchdog.
+    #[cfg(feature = "watchdog")]
+    #[track_caller]
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        let watchdog = match self.watchdog {
+            Some(ref watchdog) => watchdog,
+            None => return self.mutex.lock(),
+        };
+        let caller = Location::caller();
+        let mut waiting_since = None;
+        let mut reported = false;
+        loop {
+            if let Some(guard) = self.mutex.try_lock() {
+                watchdog.acquired(caller);
+                return guard;
+            }
+            let start = *waiting_since.get_or_insert_with(|| watchdog.now());
+            if !reported {
+                reported = watchdog.check(start);
+            }
+            hint::spin_loop();
+        }
+    }
+
+    /// Allocate the given layout in one of the existing heap blocks.
+    ///
+    /// The search starts at the block chosen by a custom placement policy,
+    /// or at the block selected by the stripe function (or at the first
+    /// block), and wraps around to the head of the list.
+    unsafe fn allocate_in_blocks(&self, layout: Layout) -> Option<(NonNull<u8>, usize)> {
+        let next_of = |block: *mut HeapBlock<BS>| {
+            (*block)
+                .next
+                .as_deref_mut()
+                .map(|next| next as *mut HeapBlock<BS>)
+        };
+        let head = (*self.first_block.get())
+            .as_deref_mut()
+            .map(|block| block as *mut HeapBlock<BS>);
+
+        let mut start = head;
+        let chosen = match self.fit {
+            Fit::Custom(policy) => {
+                let mut blocks = core::iter::successors(head, |&b| next_of(b)).map(|b| (*b).info());
+                policy.choose_block(&mut blocks, layout)
+            }
+            _ => None,
+        };
+        if let Some(index) = chosen {
+            for _ in 0..index {
+                start = start.and_then(next_of);
+            }
+            // an out of range choice falls back to the head of the list
+            if start.is_none() {
+                start = head;
+            }
+        } else if let Some(stripe) = self.search_stripe {
+            let mut count = 0;
+            let mut block = head;
+            while let Some(b) = block {
+                count += 1;
+                block = next_of(b);
+            }
+            if count > 0 {
+                for _ in 0..stripe() % count {
+                    start = start.and_then(next_of);
+                }
+            }
+        }
+
+        // with region policies, the blocks of each priority are searched in turn
+        let priorities: &[Option<RegionPriority>] = if self.has_region_policies() {
+            &[
+                Some(RegionPriority::Preferred),
+                Some(RegionPriority::Normal),
+                Some(RegionPriority::Fallback),
+            ]
+        } else {
+            &[None]
+        };
+        let mut probed = 0;
+        for &priority in priorities {
+            let mut block = start;
+            let mut wrapped = false;
+            loop {
+                match block {
+                    Some(_) if wrapped && block == start => break,
+                    Some(b) => {
+                        let admitted = priority
+                            .is_none_or(|priority| self.block_policy(&*b).admits(priority, layout));
+                        if admitted {
+                            probed += 1;
+                            if let Ok(claimed) = self.allocate_in_block(&mut *b, layout) {
+                                (*b).idle_since = HeapBlock::<BS>::BUSY;
+                                if self.free_space_order && probed > 1 {
+                                    self.sort_blocks();
+                                }
+                                return Some(claimed);
+                            }
+                        }
+                        block = next_of(b);
+                    }
+                    None if !wrapped && start != head => {
+                        wrapped = true;
+                        block = head;
+                    }
+                    None => break,
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns the policy of the memory region of the given heap block.
+    fn block_policy(&self, block: &HeapBlock<BS>) -> RegionPolicy {
+        block
+            .shared
+            .map_or(self.backing_policy, |region| region.policy())
+    }
+
+    /// Check if any memory region of the heap has a policy other than the default one.
+    fn has_region_policies(&self) -> bool {
+        self.backing_policy != RegionPolicy::NORMAL || self.shared_policies.load(Ordering::Relaxed)
+    }
+
+    /// Sort the heap blocks by decreasing free space, keeping the order of
+    /// blocks with as much free space.
+    ///
+    /// Blocks are inserted one at a time, so that a list already sorted is
+    /// only walked once. Must be called with the lock held.
+    unsafe fn sort_blocks(&self) {
+        let mut sorted: Option<&'static mut HeapBlock<BS>> = None;
+        let mut tail: *mut Option<&'static mut HeapBlock<BS>> = &mut sorted;
+        let mut tail_free = usize::MAX;
+        let mut rest = (*self.first_block.get()).take();
+        while let Some(block) = rest {
+            rest = block.next.take();
+            let mut link: *mut Option<&'static mut HeapBlock<BS>> = &mut sorted;
+            if block.free <= tail_free {
+                link = tail;
+            } else {
+                while let Some(ref mut b) = *link {
+                    if b.free < block.free {
+                        break;
+                    }
+                    link = &mut b.next;
+                }
+            }
+            block.next = (*link).take();
+            let last = block.next.is_none();
+            let block = (*link).insert(block);
+            if last {
+                tail_free = block.free;
+                tail = &mut block.next;
+            }
+        }
+        *self.first_block.get() = sorted;
+    }
+
+    /// Allocate the given layout in the given heap block, using its small-object
+    /// slots if enabled and the layout is small enough.
+    ///
+    /// Returns the allocation, and the number of its leading bytes to clear
+    /// for a zeroed allocation: allocations are only cleared entirely if made
+    /// in memory used before, or not known to be zeroed.
+    unsafe fn allocate_in_block(
+        &self,
+        block: &mut HeapBlock<BS>,
+        layout: Layout,
+    ) -> Result<(NonNull<u8>, usize), AllocError> {
+        let mut ptr = Err(AllocError);
+        if self.small_objects && HeapBlock::<BS>::is_small(layout) {
+            ptr = block.allocate_small(layout);
+        }
+        let ptr = ptr.or_else(|_| {
+            if self.deferred_coalescing {
+                block.allocate_deferred(layout, self.fit)
+            } else {
+                block.allocate(layout, self.fit)
+            }
+        })?;
+        let clean = block.touch(ptr, layout.size()) && self.zero_fill != ZeroFill::Never;
+        let dirty = if clean {
+            self.min_hole()
+        } else {
+            layout.size()
+        };
+        Ok((ptr, dirty))
+    }
+
+    /// Free the given allocation in the given heap block, deferring the merge
+    /// of its memory if enabled.
+    unsafe fn deallocate_in_block(
+        &self,
+        block: &mut HeapBlock<BS>,
+        ptr: *mut u8,
+        block_layout: Layout,
+    ) -> Result<(), DeallocError> {
+        let ptr = NonNull::new_unchecked(ptr);
+        if self.deferred_coalescing {
+            block.deallocate_segregated(ptr, block_layout)
+        } else if self.quick_list {
+            block.deallocate_quick(ptr, block_layout)
+        } else {
+            block.deallocate_with(ptr, block_layout, self.fit)
+        }
+    }
+
+    /// Free the given allocation in the heap block containing it.
+    ///
+    /// Must be called with the lock held.
+    unsafe fn dealloc_in_blocks(
+        &self,
+        ptr: *mut u8,
+        block_layout: Layout,
+    ) -> Result<(), DeallocError> {
+        let block = match self.find_allocation_block(ptr) {
+            Some(block) => &mut *block,
+            None => return Err(DeallocError::UnknownPointer),
+        };
+        let result = self.deallocate_in_block(block, ptr, block_layout);
+        if block.is_empty() {
+            block.generation = self.next_generation();
+            // the reserve block is not in the list, and never released as idle
+            let reserve = (*self.reserve.get())
+                .as_deref()
+                .map(|r| r as *const HeapBlock<BS>);
+            if let Some((clock, _)) = self.idle_release {
+                if reserve != Some(block as *const HeapBlock<BS>) {
+                    block.idle_since = clock.now();
+                }
+            }
+        }
+        result
+    }
+
+    /// Returns the alignment of the chunks requested for heap blocks.
+    fn chunk_align(&self) -> usize {
+        if self.aligned_blocks {
+            max(BS::to_usize(), BA::to_usize())
+        } else {
+            BA::to_usize()
+        }
+    }
+
+    /// Request memory from the backing allocator, if growth and quota allow it.
+    ///
+    /// Must be called with the lock held.
+    unsafe fn acquire(
+        &self,
+        layout: Layout,
+        criticality: Criticality,
+    ) -> Result<NonNull<u8>, AllocFailure> {
+        let acquired = &mut *self.acquired.get();
+        let quota = match criticality {
+            Criticality::Low => min(self.quota, self.low_limit),
+            _ => self.quota,
+        };
+        if !self.growth.load(Ordering::Relaxed) {
+            return Err(AllocFailure::NoGrowth);
+        } else if layout.size() > quota.saturating_sub(*acquired) {
+            return Err(AllocFailure::Quota);
+        }
+        let ptr = (*self.block_allocator.get())
+            .allocate(layout)
+            .map_err(|_| AllocFailure::OutOfMemory)?;
+        if self.zero_fill == ZeroFill::OnAcquire {
+            ptr.cast::<u8>().as_ptr().write_bytes(0, layout.size());
+        }
+        *acquired += layout.size();
+        self.update_watermarks();
+        Ok(ptr.cast())
+    }
+
+    /// Return memory to the backing allocator.
+    ///
+    /// Must be called with the lock held.
+    unsafe fn release(&self, ptr: NonNull<u8>, layout: Layout) {
+        (*self.block_allocator.get()).deallocate(ptr, layout);
+        *self.acquired.get() -= layout.size();
+        self.update_watermarks();
+    }
+
+    /// Report the watermark levels crossed since the last update.
+    ///
+    /// Must be called with the lock held.
+    unsafe fn update_watermarks(&self) {
+        if let Some(ref watermarks) = self.watermarks {
+            let level = &mut *self.watermark_level.get();
+            let acquired = *self.acquired.get();
+            reentrancy::call_hook(&self.hooks, || watermarks.update(level, acquired));
+        }
+    }
+
+    /// Obtain more memory from the backing allocator to fit `layout`.
+    ///
+    /// The size of the chunk requested is chosen by the growth policy. If the
+    /// new memory directly follows an existing heap block, it is used
+    /// to extend that block. Otherwise, a new heap block is linked at the end
+    /// of the list. Returns the heap block the memory was added to.
+    unsafe fn grow(
+        &self,
+        criticality: Criticality,
+        layout: Layout,
+        attempt: usize,
+    ) -> Result<*mut HeapBlock<BS>, AllocFailure> {
+        let exponential = ExponentialGrowth::new(self.growth_cap);
+        let policy: &dyn GrowthPolicy = match self.growth_policy {
+            Some(policy) => policy,
+            None => &exponential,
+        };
+        let request = self.growth_request(layout, attempt);
+        let chunk_size = |size: usize| align_up(max(size, BS::to_usize()), BS::to_usize());
+
+        let mut chunk = chunk_size(policy.chunk_size(&request).ok_or(AllocFailure::NoGrowth)?);
+        if self.aligned_blocks {
+            // the header of a heap block must be at the start of each chunk
+            chunk = BS::to_usize();
+        }
+        let new_heap_ptr = loop {
+            let acquired = self.acquire(
+                Layout::from_size_align_unchecked(chunk, self.chunk_align()),
+                criticality,
+            );
+            match acquired {
+                Ok(ptr) => break ptr.as_ptr() as *mut HeapBlock<BS>,
+                Err(reason) => match policy.retry_size(&request, chunk, reason).map(chunk_size) {
+                    Some(smaller) if smaller < chunk => chunk = smaller,
+                    _ => return Err(reason),
+                },
+            }
+        };
+
+        let mut next_block: *mut Option<&mut HeapBlock<BS>> = self.first_block.get();
+        while let Some(ref mut block) = *next_block {
+            if !self.aligned_blocks
+                && block.shared.is_none()
+                && block.chunk == chunk
+                && block.end() == Some(new_heap_ptr as usize)
+            {
+                block.extend();
+                return Ok(&mut **block);
+            }
+            next_block = &mut block.next;
+        }
+
+        let new_block = HeapBlock::<BS>::new_sized(
+            NonNull::new_unchecked(new_heap_ptr),
+            self.payload_align,
+            chunk,
+        );
+        new_block.generation = self.next_generation();
+        new_block.min_split = max(self.min_split, HeapBlock::<BS>::min_size());
+        if self.indexed_holes {
+            new_block.index_holes();
+        }
+        if let Some(init) = self.block_metadata {
+            new_block.metadata = reentrancy::call_hook(&self.hooks, init);
+        }
+        self.register(new_block);
+        *next_block = Some(new_block);
+        Ok(new_heap_ptr)
+    }
+
+    /// Returns the total size of the holes of the heap blocks.
+    ///
+    /// Must be called with the lock held.
+    unsafe fn free_bytes(&self) -> usize {
+        let mut free = 0;
+        let mut block = (*self.first_block.get()).as_deref();
+        while let Some(b) = block {
+            free += b.holes().map(|hole| hole.size).sum::<usize>();
+            block = b.next.as_deref();
+        }
+        free
+    }
+
+    /// Returns the state of the heap given to the growth policy.
+    ///
+    /// Must be called with the lock held.
+    unsafe fn growth_request(&self, layout: Layout, attempt: usize) -> GrowthRequest {
+        let mut blocks = 0;
+        let mut block = (*self.first_block.get()).as_deref();
+        while let Some(b) = block {
+            blocks += 1;
+            block = b.next.as_deref();
+        }
+        GrowthRequest {
+            layout,
+            block_size: BS::to_usize(),
+            blocks,
+            acquired: *self.acquired.get(),
+            attempt,
+        }
+    }
+
+    /// Returns a generation never given to a heap block before.
+    ///
+    /// Must be called with the lock held.
+    unsafe fn next_generation(&self) -> usize {
+        let generations = &mut *self.generations.get();
+        *generations = generations.wrapping_add(1);
+        *generations
+    }
+
+    /// Find the heap block containing the given pointer.
+    ///
+    /// Only the heap blocks of the allocator are read, so the pointer may
+    /// come from anywhere. Must be called with the lock held.
+    unsafe fn find_block(&self, ptr: *const u8) -> Option<*mut HeapBlock<BS>> {
+        let mut walk = true;
+        if let Some(registry) = self.registry {
+            if let Some(start) = registry.find(ptr as usize) {
+                let block = start as *mut HeapBlock<BS>;
+                if (*block).contains(ptr) {
+                    return Some(block);
+                }
+            }
+            walk = !registry.is_complete();
+        }
+        // blocks missing from an incomplete registry are found by walking the list
+        if walk {
+            let mut next_block: *mut Option<&mut HeapBlock<BS>> = self.first_block.get();
+            while let Some(ref mut block) = *next_block {
+                if block.contains(ptr) {
+                    return Some(&mut **block);
+                }
+                next_block = &mut block.next;
+            }
+        }
+        match *self.reserve.get() {
+            Some(ref mut block) if block.contains(ptr) => Some(&mut **block),
+            _ => None,
+        }
+    }
+
+    /// Find the heap block holding the given block-managed allocation.
+    ///
+    /// With aligned blocks, the pointer is masked to find the heap block
+    /// directly, which reads the memory below it: it must be an allocation
+    /// of the heap blocks, unlike the pointers given to `find_block`. Must be
+    /// called with the lock held.
+    unsafe fn find_allocation_block(&self, ptr: *const u8) -> Option<*mut HeapBlock<BS>> {
+        if self.aligned_blocks {
+            let block = (ptr as usize & !(BS::to_usize() - 1)) as *mut HeapBlock<BS>;
+            if (*block).contains(ptr) {
+                return Some(block);
+            }
+        }
+        self.find_block(ptr)
+    }
+
+    /// Record a heap block linked to the list in the block registry, if any.
+    fn register(&self, block: &HeapBlock<BS>) {
+        if let Some(registry) = self.registry {
+            registry.insert(block as *const HeapBlock<BS> as usize);
+        }
+    }
+
+    /// Forget a heap block unlinked from the list in the block registry, if any.
+    ///
+    /// A registry that could not hold every heap block is rebuilt instead,
+    /// since it may now have room for them. Must be called with the lock held.
+    unsafe fn unregister(&self, block: &HeapBlock<BS>) {
+        match self.registry {
+            Some(registry) if registry.is_complete() => {
+                registry.remove(block as *const HeapBlock<BS> as usize);
+            }
+            Some(registry) => {
+                registry.clear();
+                let mut next = (*self.first_block.get()).as_deref();
+                while let Some(b) = next {
+                    registry.insert(b as *const HeapBlock<BS> as usize);
+                    next = b.next.as_deref();
+                }
+            }
+            None => (),
+        }
+    }
+
+    /// Unlink and release all heap blocks matching the given predicate.
+    ///
+    /// Returns the number of bytes returned to the backing allocator. Must
+    /// be called with the lock held.
+    unsafe fn release_blocks<F>(&self, mut predicate: F) -> usize
+    where
+        F: FnMut(&HeapBlock<BS>) -> bool,
+    {
+        let mut released = 0;
+
+        let mut link: *mut Option<&'static mut HeapBlock<BS>> = self.first_block.get();
+        while let Some(block) = (*link).take() {
+            if predicate(block) {
+                *link = block.next.take();
+                self.unregister(block);
+                let start = block as *mut HeapBlock<BS> as *mut u8;
+                let size = block.size;
+                if let Some(region) = block.shared {
+                    // shared memory is given back to its owner instead, whose
+                    // release callback runs with the lock held
+                    reentrancy::call_hook(&self.hooks, || region.release());
+                    continue;
+                }
+                // a heap block may be made of several chunks, allocated separately
+                let chunk_layout =
+                    Layout::from_size_align_unchecked(block.chunk, self.chunk_align());
+                for offset in (0..size).step_by(block.chunk) {
+                    self.release(NonNull::new_unchecked(start.add(offset)), chunk_layout);
+                }
+                released += size;
+            } else {
+                link = &mut (*link).get_or_insert(block).next;
+            }
+        }
+
+        released
+    }
+
+    /// Release the heap blocks that have been idle for long enough.
+    ///
+    /// Must be called with the lock held.
+    unsafe fn release_idle_blocks(&self) -> usize {
+        match self.idle_release {
+            Some((clock, idle)) => {
+                let now = clock.now();
+                self.release_blocks(|block| {
+                    block.idle_since != HeapBlock::<BS>::BUSY
+                        && now.wrapping_sub(block.idle_since) >= idle
+                })
+            }
+            None => 0,
+        }
+    }
+
+    /// Release the empty heap blocks beyond the empty block limit.
+    ///
+    /// The first empty blocks in list order are kept. Must be called with
+    /// the lock held.
+    unsafe fn release_excess_blocks(&self) -> usize {
+        if self.empty_limit == usize::MAX {
+            return 0;
+        }
+        let mut empty = 0;
+        self.release_blocks(|block| {
+            if !block.is_empty() {
+                return false;
+            }
+            empty += 1;
+            empty > self.empty_limit
+        })
+    }
+
+    /// Perform the allocator housekeeping.
+    ///
+    /// Must be called with the lock held.
+    unsafe fn housekeeping(&self) -> usize {
+        self.release_idle_blocks() + self.release_excess_blocks()
+    }
+
+    /// Check if the layout is forwarded to the backing allocator.
+    fn is_large(&self, layout: Layout) -> bool {
+        for &(overridden, placement) in self.placements {
+            if overridden == layout {
+                return placement == Placement::Passthrough;
+            }
+        }
+        self.passthrough && layout.size() >= LS::to_usize()
+    }
+
+    /// Resize a block-managed allocation without moving it.
+    ///
+    /// Returns `false` if the allocation must be moved instead, for instance
+    /// if it is not followed by a large enough hole.
+    unsafe fn resize_in_place(&self, ptr: NonNull<u8>, old: Layout, new: Layout) -> bool {
+        if old.size() == 0
+            || new.size() == 0
+            || self.is_large(old)
+            || self.is_large(new)
+            || self.is_frozen()
+            || reentrancy::in_hook(&self.hooks)
+            || self.exceeds_ceiling(new.size())
+            || !(ptr.as_ptr() as usize).is_multiple_of(new.align())
+        {
+            return false;
+        }
+        if new.size() > old.size() && !scope::charge(new.size() - old.size()) {
+            return false;
+        }
+        let old_size = self.block_layout(old).size();
+        let new_size = self.block_layout(new).size();
+
+        let lock = self.lock();
+        let resized = match self.find_allocation_block(ptr.as_ptr()) {
+            Some(block) => (*block).resize(ptr, old_size, new_size),
+            None => false,
+        };
+        drop(lock);
+
+        // give back the growth charged above, or credit the shrinkage
+        match (resized, new.size() > old.size()) {
+            (false, true) => scope::credit(new.size() - old.size()),
+            (true, false) => scope::credit(old.size() - new.size()),
+            _ => (),
+        }
+        if resized {
+            self.user_bytes.fetch_add(new.size(), Ordering::Relaxed);
+            self.user_bytes.fetch_sub(old.size(), Ordering::Relaxed);
+        }
+        resized
+    }
+
+    /// Resize an allocation, in place when possible, or by moving it.
+    unsafe fn reallocate(
+        &self,
+        ptr: NonNull<u8>,
+        old: Layout,
+        new: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if self.resize_in_place(ptr, old, new) {
+            return Ok(NonNull::slice_from_raw_parts(ptr, new.size()));
+        }
+        let new_ptr = self.allocate(new)?;
+        ptr::copy_nonoverlapping(
+            ptr.as_ptr(),
+            new_ptr.cast::<u8>().as_ptr(),
+            min(old.size(), new.size()),
+        );
+        self.deallocate(ptr, old);
+        Ok(new_ptr)
+    }
+
+    /// Create a kernel-compatible layout that can fit the requested layout
+    unsafe fn padded(&self, layout: Layout, align: usize) -> Layout {
+        let padding = layout.padding_needed_for(align);
+        Layout::from_size_align_unchecked(layout.size() + padding, align)
+    }
+
+    /// Create the layout actually reserved in a heap block for the requested layout.
+    unsafe fn block_layout(&self, layout: Layout) -> Layout {
+        let size = max(self.min_hole(), self.rounding.round(layout.size()));
+        let layout =
+            Layout::from_size_align_unchecked(align_up(size, align_of::<Hole>()), layout.align());
+        match self.magazine_class(layout) {
+            Some(class) => magazine::class_layout(class),
+            None => layout,
+        }
+    }
+
+    /// Returns the size of the smallest hole in the heap blocks.
+    fn min_hole(&self) -> usize {
+        if self.indexed_holes {
+            HeapBlock::<BS>::min_indexed_size()
+        } else {
+            HeapBlock::<BS>::min_size()
+        }
+    }
+
+    /// Returns the magazine size class of a block layout, if magazines are enabled.
+    fn magazine_class(&self, block_layout: Layout) -> Option<usize> {
+        if self.magazines {
+            magazine::class(block_layout)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the address identifying the allocator.
+    fn owner(&self) -> *const () {
+        self as *const Self as *const ()
+    }
+
+    /// Take a chunk for the given layout from the magazine of the current thread.
+    ///
+    /// An empty magazine is refilled from the heap blocks, without growing
+    /// the heap. Returns `None` if the layout has no magazine size class, or
+    /// if the magazine cannot be used.
+    unsafe fn take_from_magazine(&self, layout: Layout, zeroed: bool) -> Option<NonNull<u8>> {
+        if !self.magazines || self.is_large(layout) || self.is_frozen() {
+            return None;
+        }
+        let block_layout = self.block_layout(layout);
+        let class = self.magazine_class(block_layout)?;
+        let ptr = magazine::take(self.owner(), Self::flush_chunks, class, |chunks| {
+            let _lock = self.lock();
+            let mut count = 0;
+            for chunk in chunks.iter_mut() {
+                match self.allocate_in_blocks(block_layout) {
+                    Some((ptr, _)) => *chunk = ptr.as_ptr(),
+                    None => break,
+                }
+                count += 1;
+            }
+            count
+        })?;
+        if zeroed {
+            ptr.as_ptr().write_bytes(0, layout.size());
+        }
+        Some(ptr)
+    }
+
+    /// Free chunks flushed from a magazine in the heap blocks of the allocator at `owner`.
+    unsafe fn flush_chunks(owner: *const (), chunks: &[*mut u8], class: usize) {
+        let this = &*(owner as *const Self);
+        let block_layout = magazine::class_layout(class);
+        let mut errors = [None; magazine::BATCH];
+        let lock = this.lock();
+        for (i, &chunk) in chunks.iter().enumerate() {
+            if let Err(error) = this.dealloc_in_blocks(chunk, block_layout) {
+                errors[i] = Some((chunk, error));
+            }
+        }
+        drop(lock);
+
+        // the lock is released here, so the callback may use the allocator
+        for (chunk, error) in errors.iter().flatten() {
+            this.dealloc_error.handle(*chunk, block_layout, *error);
+        }
+    }
+
+    /// Returns the offset and reserved size of a layout placed at `end` or after in a group.
+    unsafe fn group_offset(&self, end: usize, layout: Layout) -> (usize, usize) {
+        let block_layout = self.block_layout(layout);
+        let align = max(block_layout.align(), align_of::<Hole>());
+        let mut offset = align_up(end, align);
+        // the gap before the layout must be large enough to be freed as a hole
+        if offset > end && offset - end < self.min_hole() {
+            offset = align_up(end + self.min_hole(), align);
+        }
+        (offset, block_layout.size())
+    }
+
+    /// Returns the layout of a group of layouts placed contiguously, if possible.
+    unsafe fn group_layout(&self, layouts: &[Layout]) -> Option<Layout> {
+        let mut end = 0;
+        let mut align = align_of::<Hole>();
+        for layout in layouts {
+            if self.is_large(*layout) {
+                return None;
+            }
+            let (offset, size) = self.group_offset(end, *layout);
+            end = offset.checked_add(size)?;
+            align = max(align, layout.align());
+        }
+
+        // the group must be block-managed, and the memory reserved for it
+        // after rounding must leave a tail large enough to be freed
+        let group = Layout::from_size_align(end, align).ok()?;
+        let tail = self.block_layout(group).size() - end;
+        if self.is_large(group) || (tail > 0 && tail < self.min_hole()) {
+            return None;
+        }
+        Some(group)
+    }
+
+    /// Allocate memory preceded by a header recording its layout and owning heap block.
+    ///
+    /// The allocation can then be freed with [`free`](#method.free), without
+    /// its layout, as C code expects of `free(ptr)`. Only allocations made
+    /// with this function have a header, which takes ten words, plus the
+    /// padding needed to keep the allocation aligned. The header also records
+    /// the allocating thread, so that frees made by other threads are counted
+    /// (see [`cross_thread_frees`](#method.cross_thread_frees)), and the call
+    /// site, along with links to the other live allocations made with a
+    /// header (see [`snapshot`](#method.snapshot)).
+    ///
+    /// # Safety
+    ///
+    /// Same as `GlobalAlloc::alloc`: the layout must have a non-zero size.
+    #[track_caller]
+    pub unsafe fn alloc_with_header(&self, layout: Layout) -> *mut u8 {
+        self.alloc_with_cookie(layout, 0)
+    }
+
+    /// Allocate memory preceded by a header, as with
+    /// [`alloc_with_header`](#method.alloc_with_header), also recording the given cookie.
+    ///
+    /// The cookie can be anything identifying the allocation, such as a
+    /// subsystem or an epoch, and is read back with
+    /// [`cookie_of`](#method.cookie_of), for instance to attribute the
+    /// objects of a heap dump.
+    ///
+    /// # Safety
+    ///
+    /// Same as `GlobalAlloc::alloc`: the layout must have a non-zero size.
+    #[track_caller]
+    pub unsafe fn alloc_with_cookie(&self, layout: Layout, cookie: usize) -> *mut u8 {
+        let (headed, offset) = match Layout::new::<AllocHeader>().extend(layout) {
+            Ok((headed, offset)) => (headed.pad_to_align(), offset),
+            Err(_) => return self.fail(layout, AllocFailure::OutOfMemory),
+        };
+        let base = self.alloc(headed);
+        if base.is_null() {
+            return base;
+        }
+        if let Some(leaks) = self.leaks.filter(|_| self.is_large(headed)) {
+            // listed from the header instead
+            leaks.remove(base as usize);
+        }
+        let ptr = base.add(offset);
+        let header = (ptr as *mut AllocHeader).sub(1);
+        header.write(AllocHeader {
+            size: layout.size(),
+            align: layout.align(),
+            block: self.owning_block(base, headed),
+            cookie,
+            thread: scope::thread_id(),
+            site: Location::caller() as *const Location<'static> as usize,
+            serial: 0,
+            prev: 0,
+            next: 0,
+            check: HEADER_MAGIC ^ ptr as usize,
+        });
+        self.link_header(header);
+        if let Some(sites) = self.sites {
+            sites.record_alloc(Location::caller(), layout.size());
+        }
+        ptr
+    }
+
+    /// Number the header of a new allocation, and insert it at the head of
+    /// the list of live header allocations.
+    unsafe fn link_header(&self, header: *mut AllocHeader) {
+        let mut live = self.live.lock();
+        let (head, serial) = *live;
+        (*header).serial = serial;
+        (*header).next = head;
+        if head != 0 {
+            (*(head as *mut AllocHeader)).prev = header as usize;
+        }
+        *live = (header as usize, serial.wrapping_add(1));
+    }
+
+    /// Remove the header of an allocation being freed from the list of live
+    /// header allocations.
+    unsafe fn unlink_header(&self, header: *mut AllocHeader) {
+        let mut live = self.live.lock();
+        let (prev, next) = ((*header).prev, (*header).next);
+        if prev == 0 {
+            live.0 = next;
+        } else {
+            (*(prev as *mut AllocHeader)).next = next;
+        }
+        if next != 0 {
+            (*(next as *mut AllocHeader)).prev = prev;
+        }
+    }
+
+    /// Take a snapshot of the live allocations made with
+    /// [`alloc_with_header`](#method.alloc_with_header), up to `N` of them.
+    ///
+    /// The snapshot records the size, cookie and call site of the most
+    /// recent live allocations, and can be compared with a later one with
+    /// [`Snapshot::diff`] to find the allocations made in between and not
+    /// freed since. Allocations made without a header are not recorded.
+    ///
+    /// [`Snapshot::diff`]: struct.Snapshot.html#method.diff
+    pub fn snapshot<const N: usize>(&self) -> Snapshot<N> {
+        let live = self.live.lock();
+        let mut snapshot = Snapshot::new(live.1);
+        let mut next = live.0;
+        while next != 0 {
+            let header = unsafe { &*(next as *const AllocHeader) };
+            snapshot.record(LiveAllocation {
+                serial: header.serial,
+                addr: next + size_of::<AllocHeader>(),
+                size: header.size,
+                cookie: header.cookie,
+                site: unsafe { &*(header.site as *const Location<'static>) },
+            });
+            next = header.next;
+        }
+        snapshot
+    }
+
+    /// Write a report of the live allocations that can be listed, to find leaks at shutdown.
+    ///
+    /// The report lists the live allocations made with
+    /// [`alloc_with_header`](#method.alloc_with_header), from the most recent
+    /// one, then the live large allocations recorded in the leak table, if
+    /// any (see [`with_leak_table`](#method.with_leak_table)), each with its
+    /// size and call site. Other allocations are only counted, in the user
+    /// bytes of the first line.
+    ///
+    /// The live allocations are locked while writing, so the writer must not
+    /// allocate from the same `Deblockator`.
+    pub fn write_leak_report(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        let leaks = self
+            .leaks
+            .map_or((0, 0), |leaks| (leaks.len(), leaks.missed()));
+        writeln!(
+            out,
+            "leaks user {} passthrough {} missed {}",
+            self.user_bytes.load(Ordering::Relaxed),
+            leaks.0,
+            leaks.1
+        )?;
+        {
+            let live = self.live.lock();
+            let mut next = live.0;
+            while next != 0 {
+                let header = unsafe { &*(next as *const AllocHeader) };
+                let site = unsafe { &*(header.site as *const Location<'static>) };
+                writeln!(
+                    out,
+                    "header {:#x} size {} cookie {} site {}",
+                    next + size_of::<AllocHeader>(),
+                    header.size,
+                    header.cookie,
+                    site
+                )?;
+                next = header.next;
+            }
+        }
+        match self.leaks {
+            Some(leaks) => leaks.try_for_each(|allocation| {
+                write!(
+                    out,
+                    "passthrough {:#x} size {} align {}",
+                    allocation.addr, allocation.size, allocation.align
+                )?;
+                match allocation.site {
+                    Some(site) => writeln!(out, " site {}", site),
+                    None => writeln!(out),
+                }
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the cookie of an allocation made with
+    /// [`alloc_with_cookie`](#method.alloc_with_cookie), or `0` for one made
+    /// with [`alloc_with_header`](#method.alloc_with_header).
+    ///
+    /// Returns `None` if the header of the allocation was overwritten.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `alloc_with_header` or
+    /// `alloc_with_cookie`, and not freed.
+    pub unsafe fn cookie_of(&self, ptr: *const u8) -> Option<usize> {
+        let header = &*(ptr as *const AllocHeader).sub(1);
+        if header.check != HEADER_MAGIC ^ ptr as usize {
+            return None;
+        }
+        Some(header.cookie)
+    }
+
+    /// Returns the layout of an allocation made with
+    /// [`alloc_with_header`](#method.alloc_with_header).
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `alloc_with_header`, and not freed.
+    pub unsafe fn allocation_layout(&self, ptr: *const u8) -> Layout {
+        let header = &*(ptr as *const AllocHeader).sub(1);
+        Layout::from_size_align_unchecked(header.size, header.align)
+    }
+
+    /// Free an allocation made with [`alloc_with_header`](#method.alloc_with_header).
+    ///
+    /// The layout is read from the header of the allocation. A header that
+    /// was overwritten, or whose heap block does not hold the allocation, is
+    /// handled by the [deallocation error policy](#method.with_dealloc_error_policy)
+    /// as an unknown pointer, and the allocation is not freed. Freeing a null
+    /// pointer does nothing.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be null, or have been returned by `alloc_with_header` and
+    /// not freed yet.
+    pub unsafe fn free(&self, ptr: *mut u8) {
+        if ptr.is_null() {
+            return;
+        }
+        let header = (ptr as *const AllocHeader).sub(1).read();
+        let headed = Layout::from_size_align(header.size, header.align)
+            .and_then(|layout| Layout::new::<AllocHeader>().extend(layout));
+        let checked = header.check == HEADER_MAGIC ^ ptr as usize;
+        let (headed, offset) = match headed {
+            Ok((headed, offset)) if checked && offset <= ptr as usize => {
+                (headed.pad_to_align(), offset)
+            }
+            _ => {
+                let layout = Layout::new::<AllocHeader>();
+                self.dealloc_error
+                    .handle(ptr, layout, DeallocError::UnknownPointer);
+                return;
+            }
+        };
+        let base = ptr.sub(offset);
+        let emergency = self.emergency.is_some_and(|region| region.contains(base));
+        if reentrancy::in_hook(&self.hooks) && !emergency {
+            self.dealloc_error
+                .handle(ptr, headed, DeallocError::Reentrant);
+            return;
+        }
+        if self.owning_block(base, headed) != header.block {
+            self.dealloc_error
+                .handle(ptr, headed, DeallocError::UnknownPointer);
+            return;
+        }
+        self.unlink_header((ptr as *mut AllocHeader).sub(1));
+        if let Some(sites) = self.sites.filter(|_| header.site != 0) {
+            sites.record_free(&*(header.site as *const Location<'static>), header.size);
+        }
+        let thread = scope::thread_id();
+        self.header_frees.fetch_add(1, Ordering::Relaxed);
+        if header.thread != 0 && thread != 0 && header.thread != thread {
+            self.remote_frees.fetch_add(1, Ordering::Relaxed);
+        }
+        self.dealloc(base, headed);
+    }
+
+    /// Resize an allocation made with [`alloc_with_header`](#method.alloc_with_header),
+    /// as C code expects of `realloc(ptr, size)`.
+    ///
+    /// The allocation is moved to a new allocation of `size` bytes, keeping
+    /// its alignment and cookie, and its contents up to the smaller size. A
+    /// null pointer is allocated with the alignment `malloc` guarantees, and
+    /// a zero size allocates a single byte. Returns a null pointer if the
+    /// allocation failed, in which case the original allocation is left
+    /// untouched, or if its header was overwritten, which is handled by the
+    /// [deallocation error policy](#method.with_dealloc_error_policy).
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be null, or have been returned by `alloc_with_header` and
+    /// not freed yet.
+    #[track_caller]
+    pub unsafe fn realloc_with_header(&self, ptr: *mut u8, size: usize) -> *mut u8 {
+        if ptr.is_null() {
+            return self.aligned_alloc(MALLOC_ALIGN, size);
+        }
+        let cookie = match self.cookie_of(ptr) {
+            Some(cookie) => cookie,
+            None => {
+                let layout = Layout::new::<AllocHeader>();
+                self.dealloc_error
+                    .handle(ptr, layout, DeallocError::UnknownPointer);
+                return ptr::null_mut();
+            }
+        };
+        let old = self.allocation_layout(ptr);
+        let new = match Layout::from_size_align(max(size, 1), old.align()) {
+            Ok(new) => new,
+            Err(_) => return ptr::null_mut(),
+        };
+        let new_ptr = self.alloc_with_cookie(new, cookie);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, min(old.size(), new.size()));
+            self.free(ptr);
+        }
+        new_ptr
+    }
+
+    /// Allocate `size` bytes aligned to `align` with a header, as C code
+    /// expects of `posix_memalign(memptr, align, size)`.
+    ///
+    /// The allocation is written to `memptr`, and freed with
+    /// [`free`](#method.free). Returns `0` on success, `EINVAL` (22) if the
+    /// alignment is not a power of two multiple of the size of a pointer,
+    /// and `ENOMEM` (12) if the allocation failed, leaving `memptr` untouched
+    /// on error. A zero size allocates a single byte.
+    ///
+    /// # Safety
+    ///
+    /// `memptr` must be valid for writes.
+    #[track_caller]
+    pub unsafe fn posix_memalign(&self, memptr: *mut *mut u8, align: usize, size: usize) -> c_int {
+        if !align.is_power_of_two() || !align.is_multiple_of(size_of::<*mut u8>()) {
+            return EINVAL;
+        }
+        let ptr = self.aligned_alloc(align, size);
+        if ptr.is_null() {
+            return ENOMEM;
+        }
+        *memptr = ptr;
+        0
+    }
+
+    /// Allocate `size` bytes aligned to `align` with a header, as C code
+    /// expects of `aligned_alloc(align, size)`.
+    ///
+    /// The allocation is freed with [`free`](#method.free). Returns a null
+    /// pointer if the alignment is not a power of two, or if the allocation
+    /// failed. A zero size allocates a single byte.
+    ///
+    /// # Safety
+    ///
+    /// Same as `GlobalAlloc::alloc`.
+    #[track_caller]
+    pub unsafe fn aligned_alloc(&self, align: usize, size: usize) -> *mut u8 {
+        match Layout::from_size_align(max(size, 1), align) {
+            Ok(layout) => self.alloc_with_header(layout),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    /// Resize an allocation made with a header to `count` elements of
+    /// `size` bytes, as C code expects of `reallocarray(ptr, count, size)`.
+    ///
+    /// Same as [`realloc_with_header`](#method.realloc_with_header), except
+    /// that a null pointer is returned, leaving the allocation untouched, if
+    /// the total size overflows.
+    ///
+    /// # Safety
+    ///
+    /// Same as `realloc_with_header`.
+    #[track_caller]
+    pub unsafe fn reallocarray(&self, ptr: *mut u8, count: usize, size: usize) -> *mut u8 {
+        match count.checked_mul(size) {
+            Some(size) => self.realloc_with_header(ptr, size),
+            None => ptr::null_mut(),
+        }
+    }
+
+    /// Returns the number of allocations freed with [`free`](#method.free),
+    /// and how many of them were freed by another thread than the one that
+    /// made them.
+    ///
+    /// A high share of remote frees hints that per-thread heaps would not pay
+    /// off. Threads are only known with the `std` feature: without it, no
+    /// free is counted as remote. A thread identifier may also be reused once
+    /// its thread exits, so frees of allocations outliving their thread may
+    /// be missed.
+    pub fn cross_thread_frees(&self) -> (usize, usize) {
+        (
+            self.header_frees.load(Ordering::Relaxed),
+            self.remote_frees.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Returns the address of the heap block holding the given allocation, or
+    /// `0` if it is large or was served from the emergency region.
+    fn owning_block(&self, ptr: *const u8, layout: Layout) -> usize {
+        if self.is_large(layout) || self.emergency.is_some_and(|region| region.contains(ptr)) {
+            return 0;
+        }
+        let _lock = self.lock();
+        unsafe { self.find_block(ptr).map_or(0, |block| block as usize) }
+    }
+
+    /// Allocate memory with the given criticality.
+    ///
+    /// Behaves like `GlobalAlloc::alloc`, which uses [`Criticality::Normal`],
+    /// except that low-criticality allocations are limited by
+    /// [`with_low_criticality_limit`](#method.with_low_criticality_limit),
+    /// and high-criticality ones may use the reserve block whatever their
+    /// size (see [`with_reserve`](#method.with_reserve)).
+    ///
+    /// # Safety
+    ///
+    /// Same as `GlobalAlloc::alloc`: the layout must have a non-zero size.
+    ///
+    /// [`Criticality::Normal`]: enum.Criticality.html#variant.Normal
+    #[track_caller]
+    pub unsafe fn alloc_with_criticality(
+        &self,
+        layout: Layout,
+        criticality: Criticality,
+    ) -> *mut u8 {
+        self.alloc_inner(layout, criticality, false)
+    }
+
+    /// Allocate memory with the given criticality, zeroed if requested.
+    #[track_caller]
+    unsafe fn alloc_inner(
+        &self,
+        layout: Layout,
+        criticality: Criticality,
+        zeroed: bool,
+    ) -> *mut u8 {
+        if reentrancy::in_hook(&self.hooks) {
+            return self.alloc_reentrant(layout, zeroed);
+        }
+        self.sample_verification();
+        if self.exceeds_ceiling(layout.size()) {
+            return self.fail(layout, AllocFailure::Ceiling);
+        }
+        if !scope::charge(layout.size()) {
+            return self.fail(layout, AllocFailure::Budget);
+        }
+        if let Some(ptr) = self.take_from_magazine(layout, zeroed) {
+            self.user_bytes.fetch_add(layout.size(), Ordering::Relaxed);
+            return ptr.as_ptr();
+        }
+        let mut result = self.try_alloc(layout, criticality, zeroed);
+        if let Some((reclaim, retries)) = self.retry {
+            // the lock is released here, so the hook may use the allocator
+            for attempt in 0..retries {
+                if result.is_ok() || result == Err(AllocFailure::Frozen) || !reclaim(attempt) {
+                    break;
+                }
+                result = self.try_alloc(layout, criticality, zeroed);
+            }
+        }
+        match result {
+            Ok(ptr) => {
+                self.user_bytes.fetch_add(layout.size(), Ordering::Relaxed);
+                if let Some(leaks) = self.leaks.filter(|_| self.is_large(layout)) {
+                    leaks.insert(PassthroughAllocation {
+                        addr: ptr.as_ptr() as usize,
+                        size: layout.size(),
+                        align: layout.align(),
+                        site: Some(Location::caller()),
+                    });
+                }
+                ptr.as_ptr()
+            }
+            Err(failure) => {
+                scope::credit(layout.size());
+                self.fail(layout, failure)
+            }
+        }
+    }
+
+    /// Allocate from the emergency region, for an allocation made from a hook.
+    ///
+    /// The failure hook is not called, so that it can not recurse.
+    unsafe fn alloc_reentrant(&self, layout: Layout, zeroed: bool) -> *mut u8 {
+        let region = match self.emergency {
+            Some(region) => region,
+            None => return ptr::null_mut(),
+        };
+        let ptr = if zeroed {
+            region.allocate_zeroed(layout)
+        } else {
+            region.allocate(layout)
+        };
+        ptr.map_or(ptr::null_mut(), |ptr| ptr.cast::<u8>().as_ptr())
+    }
+
+    /// Check if an allocation of the given size exceeds the ceiling of the current tag.
+    fn exceeds_ceiling(&self, size: usize) -> bool {
+        if self.ceilings.is_empty() {
+            return false;
+        }
+        scope::current_tag().is_some_and(|tag| {
+            self.ceilings
+                .iter()
+                .any(|&(ceiling_tag, max)| ceiling_tag == tag && size > max)
+        })
+    }
+
+    /// Credit a freed allocation to the scope of the current thread, and to the user bytes.
+    fn credit(&self, size: usize) {
+        scope::credit(size);
+        self.user_bytes.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    /// Report a failed allocation to the failure hook, and return a null pointer.
+    fn fail(&self, layout: Layout, failure: AllocFailure) -> *mut u8 {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed);
+        if let Some((hook, every)) = self.failure_hook {
+            if failures.is_multiple_of(every) {
+                reentrancy::call_hook(&self.hooks, || hook(layout, failure));
+            }
+        }
+        if let Some((sink, first_only)) = self.oom_sink {
+            if !first_only || failures == 0 {
+                let report =
+                    Report(|out: &mut dyn fmt::Write| self.write_oom_report(out, layout, failure));
+                reentrancy::call_hook(&self.hooks, || sink(&report));
+            }
+        }
+        ::core::ptr::null_mut::<u8>()
+    }
+
+    /// Allocate several layouts, contiguously in a heap block when possible.
+    ///
+    /// Related allocations (such as a node and its buffers) are placed one
+    /// after the other in a single hole, which improves their locality, and
+    /// their pointers are written to `ptrs` in the order of `layouts`. When
+    /// the group cannot be placed contiguously (for instance, if one of the
+    /// layouts is large), the layouts are allocated separately. Either way,
+    /// each allocation must be freed on its own, with its own layout.
+    ///
+    /// Returns `false` if the allocations failed, in which case none is made.
+    ///
+    /// # Safety
+    ///
+    /// Same as `GlobalAlloc::alloc`, for each of the layouts.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ptrs` is shorter than `layouts`.
+    pub unsafe fn alloc_group(&self, layouts: &[Layout], ptrs: &mut [*mut u8]) -> bool {
+        assert!(
+            ptrs.len() >= layouts.len(),
+            "not enough room for the pointers"
+        );
+        if layouts.is_empty() {
+            return true;
+        }
+
+        // allocations made from hooks may come from outside of the heap blocks
+        let group = if reentrancy::in_hook(&self.hooks) {
+            None
+        } else {
+            self.group_layout(layouts)
+        };
+        if let Some(group) = group {
+            let base = GlobalAlloc::alloc(self, group);
+            if !base.is_null() {
+                let lock = self.lock();
+                let block = self
+                    .find_block(base)
+                    .filter(|&block| !(*block).in_small_slots(NonNull::new_unchecked(base)));
+                if let Some(block) = block {
+                    // free the gaps between the layouts, and the rounding tail
+                    let block = &mut *block;
+                    let group_size = self.block_layout(group).size();
+                    let free = |block: &mut HeapBlock<BS>, start: usize, end: usize| {
+                        let gap = Layout::from_size_align_unchecked(end - start, 1);
+                        end == start
+                            || block
+                                .deallocate(NonNull::new_unchecked(base.add(start)), gap)
+                                .is_ok()
+                    };
+                    let mut end = 0;
+                    let mut placed = 0;
+                    for (ptr, layout) in ptrs.iter_mut().zip(layouts) {
+                        let (offset, size) = self.group_offset(end, *layout);
+                        if !free(block, end, offset) {
+                            break;
+                        }
+                        *ptr = base.add(offset);
+                        end = offset + size;
+                        placed += 1;
+                    }
+                    if placed == layouts.len() && free(block, end, group_size) {
+                        // each allocation is freed on its own, the gaps are not user data
+                        let requested = layouts.iter().map(|layout| layout.size()).sum::<usize>();
+                        self.user_bytes
+                            .fetch_sub(group.size() - requested, Ordering::Relaxed);
+                        return true;
+                    }
+
+                    // a gap could not be freed: give back the rest of the group,
+                    // leaving whatever still fails to be freed out of the holes
+                    for (ptr, layout) in ptrs.iter().zip(layouts).take(placed) {
+                        let ptr = NonNull::new_unchecked(*ptr);
+                        let _ = block.deallocate(ptr, self.block_layout(*layout));
+                    }
+                    let _ = free(block, end, group_size);
+                    drop(lock);
+                    self.credit(group.size());
+                } else {
+                    // served from outside of the heap blocks or from the
+                    // small-object slots, so the gaps cannot be freed
+                    drop(lock);
+                    GlobalAlloc::dealloc(self, base, group);
+                }
+            }
+        }
+
+        for (i, layout) in layouts.iter().enumerate() {
+            ptrs[i] = GlobalAlloc::alloc(self, *layout);
+            if ptrs[i].is_null() {
+                for (ptr, layout) in ptrs.iter().zip(layouts).take(i) {
+                    GlobalAlloc::dealloc(self, *ptr, *layout);
+                }
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Allocate at least the given layout, rather than leave a hole too small to be split.
+    ///
+    /// The allocation takes the whole hole it is placed in if the remains
+    /// would be smaller than the minimum split size (see [`with_min_split`]),
+    /// and the returned memory has its actual size, which is then the size to
+    /// deallocate it with. Holes are searched in the existing heap blocks with
+    /// the first-fit strategy. Large layouts, allocations served from
+    /// magazines or with size rounding, heaps with region policies (see
+    /// [`RegionPolicy`]), and layouts fitting no hole are allocated with
+    /// their exact size instead, as with `allocate`.
+    ///
+    /// [`RegionPolicy`]: struct.RegionPolicy.html
+    ///
+    /// [`with_min_split`]: #method.with_min_split
+    #[cfg(feature = "allocate_at_least")]
+    pub fn allocate_at_least(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let exact = layout.size() == 0
+            || self.is_large(layout)
+            || self.magazines
+            || self.rounding != SizeRounding::Exact
+            || self.is_frozen()
+            || reentrancy::in_hook(&self.hooks)
+            || self.has_region_policies()
+            || self.exceeds_ceiling(layout.size());
+        if !exact {
+            if let Some(ptr) = unsafe { self.claim_at_least(layout) } {
+                return Ok(ptr);
+            }
+        }
+        Allocator::allocate(self, layout)
+    }
+
+    /// Allocate at least the given layout in a hole of the existing heap blocks.
+    #[cfg(feature = "allocate_at_least")]
+    unsafe fn claim_at_least(&self, layout: Layout) -> Option<NonNull<[u8]>> {
+        let block_layout = self.block_layout(layout);
+        let _lock = self.lock();
+        let mut next = (*self.first_block.get()).as_deref_mut();
+        let (block, ptr, size) = loop {
+            let block = next?;
+            if let Ok((ptr, size)) = block.allocate_at_least(block_layout) {
+                break (block, ptr, size);
+            }
+            next = block.next.as_deref_mut();
+        };
+        // the actual size must be freed the same way, and the exact
+        // allocation reports the exceeded budget
+        let taken = Layout::from_size_align_unchecked(size, block_layout.align());
+        if self.is_large(taken) || !scope::charge(size) {
+            let _ = self.deallocate_in_block(block, ptr.as_ptr(), taken);
+            return None;
+        }
+        block.touch(ptr, size);
+        block.idle_since = HeapBlock::<BS>::BUSY;
+        self.user_bytes.fetch_add(size, Ordering::Relaxed);
+        Some(NonNull::slice_from_raw_parts(ptr, size))
+    }
+
+    /// Make a single allocation attempt, growing the heap if needed.
+    unsafe fn try_alloc(
+        &self,
+        layout: Layout,
+        criticality: Criticality,
+        zeroed: bool,
+    ) -> Result<NonNull<u8>, AllocFailure> {
+        if self.is_frozen() {
+            return Err(AllocFailure::Frozen);
+        }
+        let lock = self.lock();
+        let claimed = self.claim(layout, criticality);
+        drop(lock);
+
+        // the memory is zeroed once the lock is released, since other
+        // threads have no reason to wait for it
+        let (ptr, dirty) = claimed?;
+        if zeroed {
+            ptr.as_ptr().write_bytes(0, dirty);
+        }
+        Ok(ptr)
+    }
+
+    /// Acquire the reserve block before memory gets scarce, if one should be
+    /// kept and was not tried yet. Must be called with the lock held.
+    unsafe fn acquire_reserve(&self) {
+        if self.reserve_threshold == 0 || *self.reserve_tried.get() {
+            return;
+        }
+        // a failure is not retried, since growth would only fail again
+        *self.reserve_tried.get() = true;
+        let reserve_layout = Layout::from_size_align_unchecked(BS::to_usize(), self.chunk_align());
+        if let Ok(ptr) = self.acquire(reserve_layout, Criticality::Normal) {
+            let block = HeapBlock::<BS>::new_aligned(ptr.cast(), self.payload_align);
+            block.generation = self.next_generation();
+            block.min_split = max(self.min_split, HeapBlock::<BS>::min_size());
+            if self.indexed_holes {
+                block.index_holes();
+            }
+            *self.reserve.get() = Some(block);
+        }
+    }
+
+    /// Claim memory for the given layout, growing the heap if needed.
+    ///
+    /// Returns the memory, and the number of its leading bytes to clear for
+    /// a zeroed allocation. Must be called with the lock held.
+    unsafe fn claim(
+        &self,
+        layout: Layout,
+        criticality: Criticality,
+    ) -> Result<(NonNull<u8>, usize), AllocFailure> {
+        // if the requested memory block is large, simply dedicate a single block
+        if self.is_large(layout) {
+            let large_layout = self.padded(layout, LA::to_usize());
+            let (ptr, dirty) = match (*self.large_cache.get()).take(large_layout) {
+                Some(ptr) => (ptr, layout.size()),
+                None => {
+                    let ptr = self.acquire(large_layout, criticality)?;
+                    if self.zero_fill == ZeroFill::Never {
+                        (ptr, layout.size())
+                    } else {
+                        (ptr, 0)
+                    }
+                }
+            };
+            let live = &mut *self.large_live.get();
+            live.0 += 1;
+            live.1 += large_layout.size();
+            live.2 += layout.size();
+            return Ok((ptr, dirty));
+        }
+
+        // Pad the layout to the minimum legal size
+        let block_layout = self.block_layout(layout);
+
+        // traverse the heap blocks to find an allocatable block
+        if let Some(claimed) = self.allocate_in_blocks(block_layout) {
+            return Ok(claimed);
+        }
+
+        // merge the memory freed in all blocks before resorting to growth
+        if self.sweep_before_grow {
+            self.coalesce_blocks();
+            if let Some(claimed) = self.allocate_in_blocks(block_layout) {
+                return Ok(claimed);
+            }
+        }
+
+        // No block can contain the requested layout: grow the heap ! Layouts
+        // larger than a block can only fit if contiguous blocks are obtained.
+        let mut failure = AllocFailure::NotContiguous;
+        let mut attempts = block_layout.size() / BS::to_usize() + 2;
+        if block_layout.size() > self.backing_policy.max_size() {
+            // the backing memory must not hold layouts this large
+            failure = AllocFailure::NoGrowth;
+            attempts = 0;
+        } else {
+            self.acquire_reserve();
+        }
+        for attempt in 0..attempts {
+            let block = match self.grow(criticality, block_layout, attempt) {
+                Ok(block) => &mut *block,
+                Err(reason) => {
+                    failure = reason;
+                    break;
+                }
+            };
+            if let Ok(claimed) = self.allocate_in_block(block, block_layout) {
+                block.idle_since = HeapBlock::<BS>::BUSY;
+                return Ok(claimed);
+            }
+        }
+
+        // only tiny or critical allocations may use the reserve block
+        if layout.size() <= self.reserve_threshold || criticality == Criticality::High {
+            if let Some(ref mut block) = *self.reserve.get() {
+                if let Ok(claimed) = self.allocate_in_block(block, block_layout) {
+                    return Ok(claimed);
+                }
+            }
+        }
+        Err(failure)
+    }
+}
+
+unsafe impl<A, BS, BA, LS, LA> GlobalAlloc for Deblockator<A, BS, BA, LS, LA>
+where
+    A: Allocator,
+    BS: Unsigned + 'static,
+    BA: Unsigned + PowerOfTwo,
+    LS: Unsigned,
+    LA: Unsigned + PowerOfTwo,
+{
+    #[track_caller]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_with_criticality(layout, Criticality::Normal)
+    }
+
+    #[track_caller]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.alloc_inner(layout, Criticality::Normal, true)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(region) = self.emergency.filter(|region| region.contains(ptr)) {
+            if let Err(error) = region.try_deallocate(NonNull::new_unchecked(ptr), layout) {
+                self.dealloc_error.handle(ptr, layout, error);
+            }
+            return;
+        }
+        if reentrancy::in_hook(&self.hooks) {
+            // the hook may run with the allocator locked
+            self.dealloc_error
+                .handle(ptr, layout, DeallocError::Reentrant);
+            return;
+        }
+        if self.is_frozen() {
+            self.dealloc_error.handle(ptr, layout, DeallocError::Frozen);
+            return;
+        }
+        self.sample_verification();
+        let large = self.is_large(layout);
+        if !large {
+            if let Some(class) = self.magazine_class(self.block_layout(layout)) {
+                // an unknown pointer must not be handed out again, so it is
+                // left to the heap blocks to report
+                let lock = self.lock();
+                let known = self.find_allocation_block(ptr).is_some();
+                drop(lock);
+                let chunk = NonNull::new_unchecked(ptr);
+                if known && magazine::put(self.owner(), Self::flush_chunks, class, chunk) {
+                    self.credit(layout.size());
+                    return;
+                }
+            }
+        }
+        let lock = self.lock();
+        let mut result = Ok(());
+        if large {
+            let large_layout = self.padded(layout, LA::to_usize());
+            let ptr = NonNull::new(ptr).unwrap();
+            let live = &mut *self.large_live.get();
+            live.0 = live.0.saturating_sub(1);
+            live.1 = live.1.saturating_sub(large_layout.size());
+            live.2 = live.2.saturating_sub(layout.size());
+            if let Some(leaks) = self.leaks {
+                leaks.remove(ptr.as_ptr() as usize);
+            }
+            if !(*self.large_cache.get()).store(ptr, large_layout) {
+                self.release(ptr, large_layout);
+            }
+        } else {
+            result = self.dealloc_in_blocks(ptr, self.block_layout(layout));
+        }
+        if self.inline_maintenance.load(Ordering::Relaxed) {
+            self.housekeeping();
+        }
+        drop(lock);
+
+        // the lock is released here, so the callback may use the allocator
+        match result {
+            Ok(()) => self.credit(layout.size()),
+            Err(error) => self.dealloc_error.handle(ptr, layout, error),
+        }
+    }
+}
+
+unsafe impl<A, BS, BA, LS, LA> Allocator for Deblockator<A, BS, BA, LS, LA>
+where
+    A: Allocator,
+    BS: Unsigned + 'static,
+    BA: Unsigned + PowerOfTwo,
+    LS: Unsigned,
+    LA: Unsigned + PowerOfTwo,
+{
+    #[track_caller]
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        // zero-sized layouts must not reach `GlobalAlloc`, and need no memory
+        if layout.size() == 0 {
+            let dangling = NonNull::new(layout.align() as *mut u8).ok_or(AllocError)?;
+            return Ok(NonNull::slice_from_raw_parts(dangling, 0));
+        }
+        let ptr = unsafe { GlobalAlloc::alloc(self, layout) };
+        NonNull::new(ptr)
+            .map(|ptr| NonNull::slice_from_raw_parts(ptr, layout.size()))
+            .ok_or(AllocError)
+    }
+
+    #[track_caller]
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() == 0 {
+            return self.allocate(layout);
+        }
+        let ptr = unsafe { GlobalAlloc::alloc_zeroed(self, layout) };
+        NonNull::new(ptr)
+            .map(|ptr| NonNull::slice_from_raw_parts(ptr, layout.size()))
+            .ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            GlobalAlloc::dealloc(self, ptr.as_ptr(), layout);
+        }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.reallocate(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let new_ptr = self.reallocate(ptr, old_layout, new_layout)?;
+        let added = new_layout.size() - old_layout.size();
+        ptr::write_bytes(
+            new_ptr.cast::<u8>().as_ptr().add(old_layout.size()),
+            0,
+            added,
+        );
+        Ok(new_ptr)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.reallocate(ptr, old_layout, new_layout)
+    }
+}
+
+/// A report written on demand by the given function.
+struct Report<F>(F);
+
+impl<F> fmt::Display for Report<F>
+where
+    F: Fn(&mut dyn fmt::Write) -> fmt::Result,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (self.0)(f)
+    }
+}
+
+/// Write the given bytes as hexadecimal lines of 16 bytes, prefixed with their offset.
+///
+/// # Safety
+///
+/// The memory must be valid for reads of `len` bytes.
+unsafe fn dump_bytes(out: &mut dyn fmt::Write, start: usize, len: usize) -> fmt::Result {
+    for offset in (0..len).step_by(16) {
+        write!(out, "    {:08x}:", offset)?;
+        for i in offset..min(offset + 16, len) {
+            write!(out, " {:02x}", *((start + i) as *const u8))?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// An iterator over the heap blocks of a `Deblockator`.
+///
+/// Created by [`Deblockator::blocks`](struct.Deblockator.html#method.blocks).
+pub struct Blocks<'a, BS: Unsigned + 'static> {
+    _lock: MutexGuard<'a, ()>,
+    next: Option<&'a HeapBlock<BS>>,
+}
+
+impl<'a, BS: Unsigned + 'static> Iterator for Blocks<'a, BS> {
+    type Item = BlockInfo;
+
+    fn next(&mut self) -> Option<BlockInfo> {
+        self.next.map(|block| {
+            self.next = block.next.as_deref();
+            block.info()
+        })
+    }
+}
+
+/// A heap block leased for the exclusive use of a subsystem.
+///
+/// Created by [`Deblockator::lease_block`](struct.Deblockator.html#method.lease_block).
+/// Allocations are served from the leased block only, without taking the
+/// lock of the allocator, and use its policies. Dropping the lease frees
+/// all of them at once, and returns the block to the heap.
+pub struct BlockLease<'a, A, BS, BA, LS, LA>
+where
+    A: Allocator,
+    BS: Unsigned + 'static,
+    BA: Unsigned + PowerOfTwo,
+    LS: Unsigned,
+    LA: Unsigned + PowerOfTwo,
+{
+    heap: &'a Deblockator<A, BS, BA, LS, LA>,
+    block: Mutex<Option<&'static mut HeapBlock<BS>>>,
+}
+
+impl<'a, A, BS, BA, LS, LA> BlockLease<'a, A, BS, BA, LS, LA>
+where
+    A: Allocator,
+    BS: Unsigned + 'static,
+    BA: Unsigned + PowerOfTwo,
+    LS: Unsigned,
+    LA: Unsigned + PowerOfTwo,
+{
+    /// Create a lease of the given block, unlinked from the heap.
+    fn new(heap: &'a Deblockator<A, BS, BA, LS, LA>, block: &'static mut HeapBlock<BS>) -> Self {
+        BlockLease {
+            heap,
+            block: Mutex::new(Some(block)),
+        }
+    }
+
+    /// Returns a summary of the leased block.
+    pub fn info(&self) -> BlockInfo {
+        self.block.lock().as_deref().unwrap().info()
+    }
+
+    /// Allocate the given layout in the leased block.
+    unsafe fn alloc_in_lease(
+        &self,
+        layout: Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<u8>, AllocError> {
+        let block_layout = self.heap.block_layout(layout);
+        let (ptr, dirty) = {
+            let mut block = self.block.lock();
+            let block = block.as_deref_mut().ok_or(AllocError)?;
+            self.heap.allocate_in_block(block, block_layout)?
+        };
+        if zeroed {
+            ptr.as_ptr().write_bytes(0, dirty);
+        }
+        Ok(ptr)
+    }
+
+    /// Free the given allocation in the leased block.
+    unsafe fn dealloc_in_lease(&self, ptr: *mut u8, layout: Layout) {
+        let block_layout = self.heap.block_layout(layout);
+        let result = match self.block.lock().as_deref_mut() {
+            Some(block) if block.contains(ptr as *const u8) => {
+                self.heap.deallocate_in_block(block, ptr, block_layout)
+            }
+            _ => Err(DeallocError::UnknownPointer),
+        };
+        if let Err(error) = result {
+            self.heap.dealloc_error.handle(ptr, layout, error);
+        }
+    }
+}
+
+impl<'a, A, BS, BA, LS, LA> Drop for BlockLease<'a, A, BS, BA, LS, LA>
+where
+    A: Allocator,
+    BS: Unsigned + 'static,
+    BA: Unsigned + PowerOfTwo,
+    LS: Unsigned,
+    LA: Unsigned + PowerOfTwo,
+{
+    fn drop(&mut self) {
+        if let Some(block) = self.block.get_mut().take() {
+            unsafe { self.heap.return_leased(block) };
+        }
+    }
+}
+
+unsafe impl<'a, A, BS, BA, LS, LA> GlobalAlloc for BlockLease<'a, A, BS, BA, LS, LA>
+where
+    A: Allocator,
+    BS: Unsigned + 'static,
+    BA: Unsigned + PowerOfTwo,
+    LS: Unsigned,
+    LA: Unsigned + PowerOfTwo,
+{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_in_lease(layout, false)
+            .map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.alloc_in_lease(layout, true)
+            .map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.dealloc_in_lease(ptr, layout)
+    }
+}
+
+unsafe impl<'a, A, BS, BA, LS, LA> Allocator for BlockLease<'a, A, BS, BA, LS, LA>
+where
+    A: Allocator,
+    BS: Unsigned + 'static,
+    BA: Unsigned + PowerOfTwo,
+    LS: Unsigned,
+    LA: Unsigned + PowerOfTwo,
+{
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() == 0 {
+            let dangling = NonNull::new(layout.align() as *mut u8).ok_or(AllocError)?;
+            return Ok(NonNull::slice_from_raw_parts(dangling, 0));
+        }
+        let ptr = unsafe { self.alloc_in_lease(layout, false)? };
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() == 0 {
+            return self.allocate(layout);
+        }
+        let ptr = unsafe { self.alloc_in_lease(layout, true)? };
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            self.dealloc_in_lease(ptr.as_ptr(), layout);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    use core::cell::Cell;
+    use core::mem::size_of;
+
+    use typenum::consts::U2048;
+    use typenum::consts::U4096;
+
+    use crate::sites::SiteStats;
+    use crate::stats::StatsAlloc;
+
+    /// A block of the mock allocator, aligned like the heap blocks made from it.
+    #[repr(C, align(4096))]
+    struct MockBlock([u8; 4096]);
+
+    struct MockAlloc {
+        pub allocated: [Cell<bool>; 3],
+        pub blocks: UnsafeCell<[MockBlock; 3]>,
+    }
+
+    impl MockAlloc {
+        pub fn new() -> Self {
+            Self::filled(0)
+        }
+
+        /// Create a mock allocator whose blocks are filled with the given byte.
+        pub fn filled(byte: u8) -> Self {
+            Self {
+                allocated: Default::default(),
+                blocks: UnsafeCell::new([
+                    MockBlock([byte; 4096]),
+                    MockBlock([byte; 4096]),
+                    MockBlock([byte; 4096]),
+                ]),
+            }
+        }
+
+        /// Returns which blocks are allocated.
+        pub fn allocated(&self) -> [bool; 3] {
+            [0, 1, 2].map(|i| self.allocated[i].get())
+        }
+
+        /// Returns the address of the `i`-th block.
+        fn block(&self, i: usize) -> *mut u8 {
+            unsafe { ::core::ptr::addr_of_mut!((*self.blocks.get())[i]) as *mut u8 }
+        }
+    }
+
+    unsafe impl Allocator for MockAlloc {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            if layout.size() > 4096 || layout.align() > 4096 {
+                return Err(AllocError);
+            }
+            for (i, allocated) in self.allocated.iter().enumerate() {
+                if !allocated.get() {
+                    allocated.set(true);
+                    let block = NonNull::new(self.block(i)).ok_or(AllocError)?;
+                    return Ok(NonNull::slice_from_raw_parts(block, 4096));
+                }
+            }
+            Err(AllocError)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout) {
+            for (i, allocated) in self.allocated.iter().enumerate() {
+                if ptr.as_ptr() == self.block(i) {
+                    if !allocated.get() {
+                        panic!("double free")
+                    } else {
+                        allocated.set(false);
+                        return;
+                    }
+                }
+            }
+            panic!("no such block !")
+        }
+    }
+
+    /// A layout such that two allocations fill a `4096`-byte heap block.
+    fn half_block() -> Layout {
+        let usable = 4096 - size_of::<HeapBlock<U4096>>();
+        let mut half = (usable / 2) & !7;
+        // the rest of the block must be empty, or large enough for a hole
+        let rest = usable - 2 * half;
+        if rest > 0 && rest < HeapBlock::<U4096>::min_size() {
+            half -= 8;
+        }
+        Layout::from_size_align(half, 8).unwrap()
+    }
+
+    #[test]
+    /// Test the mock allocator works as expected.
+    fn mockalloc() {
+        unsafe {
+            let ma = MockAlloc::new();
+            let layout = Layout::from_size_align_unchecked(4096, 4096);
+
+            let pt1 = ma.allocate(layout).expect("could not allocate block 1");
+            let _pt2 = ma.allocate(layout).expect("could not allocate block 2");
+            let pt3 = ma.allocate(layout).expect("could not allocate block 3");
+            ma.allocate(layout)
+                .expect_err("all blocks were not allocated");
+
+            assert_eq!(ma.allocated(), [true; 3]);
+            assert_eq!(pt1.as_ptr() as *mut u8 as usize % 4096, 0);
+
+            ma.deallocate(pt1.cast(), layout);
+            assert_eq!(ma.allocated(), [false, true, true]);
+
+            ma.deallocate(pt3.cast(), layout);
+            assert_eq!(ma.allocated(), [false, true, false]);
+
+            let pt4 = ma.allocate(layout).expect("could not allocate block 4");
+            assert_eq!(ma.allocated(), [true, true, false]);
+            assert_eq!(pt4.as_ptr() as *mut u8, pt1.as_ptr() as *mut u8);
+        }
+    }
+
+    #[test]
+    /// Check the underlying blocks are allocated as expected.
+    fn deblockator_blocks() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma);
+
+        unsafe {
+            // quick accessor to the allocated blocks
+            let allocated = || (*va.block_allocator.get()).allocated();
+
+            // Allocate a single boxed u32
+            let layout = Layout::from_size_align(32, 8).expect("bad layout");
+            let ptr1 = NonNull::new(va.alloc(layout)).expect("could not allocate 1");
+            ::core::ptr::write(ptr1.as_ptr(), 255);
+            assert_eq!(allocated(), [true, false, false]);
+
+            // Allocate a second boxed u32
+            let ptr2 = NonNull::new(va.alloc(layout)).expect("could not allocate 2");
+            ::core::ptr::write(ptr2.as_ptr(), 254);
+            assert_eq!(allocated(), [true, false, false]);
+
+            // Allocate a large object to the second block
+            let layout = Layout::from_size_align(3129, 4096).expect("bad layout");
+            let ptr3 = NonNull::new(va.alloc(layout)).expect("could not allocate 3");
+            assert_eq!(allocated(), [true, true, false]);
+
+            // Deallocate the first u32
+            let layout = Layout::from_size_align(32, 8).expect("bad layout");
+            va.dealloc(ptr1.as_ptr(), layout);
+
+            // FIXME: Reallocate the first u32 (hopefully at the same place)
+            let ptr4 = NonNull::new(va.alloc(layout)).expect("could not allocate 4");
+            assert_eq!(ptr4.as_ptr(), ptr1.as_ptr());
+
+            // Deallocate the large block
+            let layout = Layout::from_size_align(3129, 4096).expect("bad layout");
+            va.dealloc(ptr3.as_ptr(), layout);
+            assert_eq!(allocated(), [true, false, false]);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_free() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma);
+
+        let layout = Layout::from_size_align(32, 8).expect("bad layout");
+        unsafe {
+            let ptr1 = va.alloc(layout);
+            va.dealloc(ptr1, layout);
+            va.dealloc(ptr1, layout);
+        }
+    }
+
+    #[test]
+    /// Check the size rounding policy is applied to block allocations.
+    fn deblockator_rounding() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_size_rounding(SizeRounding::Multiple(64));
+
+        let layout = Layout::from_size_align(24, 8).expect("bad layout");
+        unsafe {
+            let ptr1 = va.alloc(layout);
+            let ptr2 = va.alloc(layout);
+            assert_eq!(ptr2 as usize - ptr1 as usize, 64);
+
+            // the rounded size is freed as well, so the space is reused
+            va.dealloc(ptr1, layout);
+            va.dealloc(ptr2, layout);
+            assert_eq!(va.alloc(Layout::from_size_align(128, 8).unwrap()), ptr1);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    /// Check sizes can not be rounded to multiples of zero.
+    fn deblockator_rounding_zero() {
+        let _: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(MockAlloc::new()).with_size_rounding(SizeRounding::Multiple(0));
+    }
+
+    #[test]
+    #[should_panic]
+    /// Check sizes can not be rounded to multiples of a number other than a power of two.
+    fn deblockator_rounding_not_power_of_two() {
+        let _: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(MockAlloc::new()).with_size_rounding(SizeRounding::Multiple(3));
+    }
+
+    #[test]
+    /// Check the fingerprint only changes with the heap state.
+    fn deblockator_fingerprint() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma);
+
+        let layout = Layout::from_size_align(32, 8).expect("bad layout");
+        let empty = va.fingerprint();
+        unsafe {
+            let ptr1 = va.alloc(layout);
+            let allocated = va.fingerprint();
+            assert_ne!(empty, allocated);
+
+            let ptr2 = va.alloc(layout);
+            assert_ne!(allocated, va.fingerprint());
+
+            va.dealloc(ptr2, layout);
+            assert_eq!(allocated, va.fingerprint());
+            va.dealloc(ptr1, layout);
+        }
+
+        // chunks kept out of the hole list, and small-object slots, are covered
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_quick_list().with_small_objects();
+        let small = Layout::from_size_align(16, 8).expect("bad layout");
+        unsafe {
+            let ptr1 = va.alloc(layout);
+            let ptr2 = va.alloc(layout);
+            let allocated = va.fingerprint();
+            va.dealloc(ptr1, layout);
+            let quick = va.fingerprint();
+            assert_ne!(allocated, quick);
+
+            let ptr3 = va.alloc(small);
+            assert_ne!(quick, va.fingerprint());
+            va.dealloc(ptr3, small);
+            va.dealloc(ptr2, layout);
+        }
+    }
+
+    #[test]
+    /// Check contiguous blocks are merged to fit layouts larger than a block.
+    fn deblockator_contiguous() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).without_passthrough();
+
+        unsafe {
+            let allocated = || (*va.block_allocator.get()).allocated();
+
+            let layout = Layout::from_size_align(6000, 8).expect("bad layout");
+            let ptr = va.alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(allocated(), [true, true, false]);
+
+            let first_block = (*va.first_block.get()).as_ref().unwrap();
+            assert_eq!(first_block.size, 8192);
+            assert!(first_block.next.is_none());
+
+            va.dealloc(ptr, layout);
+            assert_eq!(first_block.holes().count(), 1);
+        }
+    }
+
+    #[test]
+    /// Check freed large allocations are cached and reused.
+    fn deblockator_large_cache() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_large_cache(4096);
+
+        unsafe {
+            let allocated = || (*va.block_allocator.get()).allocated();
+
+            let layout = Layout::from_size_align(3000, 8).expect("bad layout");
+            let ptr1 = va.alloc(layout);
+            assert_eq!(allocated(), [true, false, false]);
+
+            va.dealloc(ptr1, layout);
+            assert_eq!(allocated(), [true, false, false]);
+
+            let ptr2 = va.alloc(layout);
+            assert_eq!(ptr1, ptr2);
+            assert_eq!(allocated(), [true, false, false]);
+
+            va.dealloc(ptr2, layout);
+            assert_eq!(va.flush_large_cache(), 4096);
+            assert_eq!(allocated(), [false, false, false]);
+        }
+    }
+
+    #[test]
+    /// Check empty blocks are only released after being idle long enough.
+    fn deblockator_idle_release() {
+        use core::sync::atomic::AtomicU64;
+
+        struct MockClock(AtomicU64);
+        impl Clock for MockClock {
+            fn now(&self) -> u64 {
+                self.0.load(Ordering::SeqCst)
+            }
+        }
+        static CLOCK: MockClock = MockClock(AtomicU64::new(0));
+
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_idle_release(&CLOCK, 10);
+
+        unsafe {
+            let allocated = || (*va.block_allocator.get()).allocated();
+
+            let small = Layout::from_size_align(32, 8).expect("bad layout");
+            let large = Layout::from_size_align(3000, 8).expect("bad layout");
+            let ptr1 = va.alloc(small);
+            let ptr2 = va.alloc(large);
+            assert_eq!(allocated(), [true, true, false]);
+
+            va.dealloc(ptr1, small);
+            assert_eq!(allocated(), [true, true, false]);
+
+            CLOCK.0.store(20, Ordering::SeqCst);
+            va.dealloc(ptr2, large);
+            assert_eq!(allocated(), [false, false, false]);
+            assert!((*va.first_block.get()).is_none());
+        }
+    }
+
+    #[test]
+    /// Check housekeeping can be scheduled manually.
+    fn deblockator_maintain() {
+        use core::sync::atomic::AtomicU64;
+
+        struct MockClock(AtomicU64);
+        impl Clock for MockClock {
+            fn now(&self) -> u64 {
+                self.0.load(Ordering::SeqCst)
+            }
+        }
+        static CLOCK: MockClock = MockClock(AtomicU64::new(0));
+
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_idle_release(&CLOCK, 10);
+        va.set_inline_maintenance(false);
+
+        unsafe {
+            let allocated = || (*va.block_allocator.get()).allocated();
+
+            let layout = Layout::from_size_align(32, 8).expect("bad layout");
+            va.dealloc(va.alloc(layout), layout);
+            CLOCK.0.store(20, Ordering::SeqCst);
+            va.dealloc(va.alloc(layout), layout);
+            assert_eq!(allocated(), [true, false, false]);
+
+            CLOCK.0.store(40, Ordering::SeqCst);
+            assert_eq!(va.maintain(), 4096);
+            assert_eq!(allocated(), [false, false, false]);
+        }
+    }
+
+    #[test]
+    /// Check block metadata can be read and written from any pointer in the block.
+    fn deblockator_block_metadata() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_block_metadata(|| 42);
+
+        let layout = Layout::from_size_align(32, 8).expect("bad layout");
+        unsafe {
+            let ptr1 = va.alloc(layout);
+            let ptr2 = va.alloc(layout);
+            assert_eq!(va.block_metadata(ptr1), Some(42));
+
+            assert!(va.set_block_metadata(ptr1, 7));
+            assert_eq!(va.block_metadata(ptr2), Some(7));
+
+            let large = Layout::from_size_align(3000, 8).expect("bad layout");
+            let ptr3 = va.alloc(large);
+            assert_eq!(va.block_metadata(ptr3), None);
+            assert!(!va.set_block_metadata(ptr3, 7));
+        }
+    }
+
+    #[test]
+    /// Check blocks are enumerated with their occupancy.
+    fn deblockator_blocks_info() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma);
+        assert_eq!(va.blocks().count(), 0);
+
+        let layout = Layout::from_size_align(64, 8).expect("bad layout");
+        unsafe {
+            let ptr = va.alloc(layout);
+            let blocks: Vec<BlockInfo> = va.blocks().collect();
+            assert_eq!(blocks.len(), 1);
+            assert_eq!(blocks[0].len, 4096);
+            assert_eq!(blocks[0].used, size_of::<HeapBlock<U4096>>() + 64);
+            assert_eq!(blocks[0].holes, 1);
+            assert!(blocks[0].start <= ptr as usize);
+            va.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    /// Check the block search starts at the block given by the stripe function.
+    fn deblockator_search_stripe() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_search_stripe(|| 1);
+
+        let layout = half_block();
+        let small = Layout::from_size_align(64, 8).expect("bad layout");
+        unsafe {
+            // a large allocation in between prevents the heap block extension
+            let ptr1 = va.alloc(layout);
+            let _large = va.alloc(Layout::from_size_align(3000, 8).expect("bad layout"));
+            let _ptr2 = va.alloc(layout);
+            let ptr3 = va.alloc(layout);
+            assert_eq!(va.blocks().count(), 2);
+            va.dealloc(ptr1, layout);
+
+            // the first block has room, but the search starts at the second one
+            let ptr4 = va.alloc(small);
+            let second = va.blocks().nth(1).unwrap();
+            assert!(second.start <= ptr4 as usize && ptr4 as usize - second.start < second.len);
+            assert!(second.start <= ptr3 as usize);
+        }
+    }
+
+    #[test]
+    /// Check heap blocks are reordered by free space after a search probed several blocks.
+    fn deblockator_free_space_order() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_free_space_order();
+
+        let layout = Layout::from_size_align(1500, 8).expect("bad layout");
+        unsafe {
+            let _ptr1 = va.alloc(layout);
+            let _ptr2 = va.alloc(layout);
+            // a large allocation in between prevents the heap block extension
+            let _large = va.alloc(Layout::from_size_align(3000, 8).expect("bad layout"));
+            let _ptr3 = va.alloc(Layout::from_size_align(1000, 8).expect("bad layout"));
+            let _ptr4 = va.alloc(Layout::from_size_align(500, 8).expect("bad layout"));
+            assert_eq!(va.blocks().count(), 2);
+
+            // the first block is full, so the second one is moved ahead
+            let ptr5 = va.alloc(Layout::from_size_align(600, 8).expect("bad layout"));
+            let first = va.blocks().next().unwrap();
+            assert!(first.start <= ptr5 as usize && ptr5 as usize - first.start < first.len);
+            let free: Vec<_> = va.blocks().map(|info| info.len - info.used).collect();
+            assert!(free.windows(2).all(|pair| pair[0] >= pair[1]));
+        }
+    }
+
+    #[test]
+    /// Check failed allocations are retried after calling the reclamation hook.
+    fn deblockator_retry() {
+        use core::sync::atomic::AtomicUsize;
+
+        static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+        fn reclaim(attempt: usize) -> bool {
+            ATTEMPTS.store(attempt + 1, Ordering::SeqCst);
+            attempt < 1
+        }
+
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_retry(reclaim, 5);
+
+        let layout = Layout::from_size_align(3000, 8).expect("bad layout");
+        unsafe {
+            let ptrs = [va.alloc(layout), va.alloc(layout), va.alloc(layout)];
+            assert!(ptrs.iter().all(|ptr| !ptr.is_null()));
+            assert_eq!(ATTEMPTS.load(Ordering::SeqCst), 0);
+
+            // the hook gives up on its second call
+            assert!(va.alloc(layout).is_null());
+            assert_eq!(ATTEMPTS.load(Ordering::SeqCst), 2);
+        }
+    }
+
+    #[test]
+    /// Check a `Deblockator` can be used as an `Allocator` for collections.
+    fn deblockator_allocator() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma);
+
+        let mut v = Vec::new_in(&va);
+        v.extend(0..100u32);
+        assert_eq!(v.iter().sum::<u32>(), 4950);
+        assert_eq!(va.blocks().count(), 1);
+
+        let empty: Vec<u64, _> = Vec::with_capacity_in(0, &va);
+        drop(empty);
+        drop(v);
+        assert!(va.blocks().all(|block| block.holes == 1));
+    }
+
+    #[test]
+    /// Check no memory is requested from the backing allocator without growth.
+    fn deblockator_without_growth() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).without_growth();
+
+        let layout = half_block();
+        let large = Layout::from_size_align(3000, 8).expect("bad layout");
+        unsafe {
+            let allocated = || (*va.block_allocator.get()).allocated();
+            assert!(va.alloc(layout).is_null());
+            assert!(va.alloc(large).is_null());
+            assert_eq!(allocated(), [false, false, false]);
+
+            // acquire a block during an initialization phase
+            va.set_growth(true);
+            let ptr = va.alloc(layout);
+            assert!(!ptr.is_null());
+            va.set_growth(false);
+
+            // the existing block is still used, but never extended
+            let ptr2 = va.alloc(layout);
+            assert!(!ptr2.is_null());
+            assert!(va.alloc(layout).is_null());
+            assert_eq!(allocated(), [true, false, false]);
+        }
+    }
+
+    #[test]
+    /// Check tiny allocations can use the reserve block once memory is exhausted.
+    fn deblockator_reserve() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_reserve(64);
+
+        let layout = half_block();
+        let large = Layout::from_size_align(3000, 8).expect("bad layout");
+        let tiny = Layout::from_size_align(32, 8).expect("bad layout");
+        unsafe {
+            // large allocations do not grow the heap, so they leave the reserve
+            let ptr = va.alloc(large);
+            assert_eq!(
+                (*va.block_allocator.get()).allocated(),
+                [true, false, false]
+            );
+            va.dealloc(ptr, large);
+
+            // the first growth acquires the reserve block and a heap block
+            assert!(!va.alloc(layout).is_null());
+            assert!(!va.alloc(large).is_null());
+            assert_eq!((*va.block_allocator.get()).allocated(), [true; 3]);
+
+            // fill the heap block, until a tiny allocation lands in the reserve
+            assert!(!va.alloc(layout).is_null());
+            assert!(va.alloc(layout).is_null());
+            let in_blocks = |ptr: *mut u8| {
+                va.blocks()
+                    .any(|block| (ptr as usize).wrapping_sub(block.start) < block.len)
+            };
+            let mut ptr = va.alloc(tiny);
+            for _ in 0..8 {
+                if !in_blocks(ptr) {
+                    break;
+                }
+                ptr = va.alloc(tiny);
+            }
+            assert!(!ptr.is_null());
+            assert!(!in_blocks(ptr));
+
+            // larger allocations are not served from the reserve
+            assert!(va.alloc(Layout::from_size_align(128, 8).unwrap()).is_null());
+            va.dealloc(ptr, tiny);
+        }
+    }
+
+    #[test]
+    /// Check a reserve block the backing allocator could not provide is not requested again.
+    fn deblockator_reserve_failure() {
+        let va: Deblockator<StatsAlloc<MockAlloc>, U4096, U4096, U2048, U4096> =
+            Deblockator::new(StatsAlloc::new(MockAlloc::new())).with_reserve(64);
+
+        let large = Layout::from_size_align(3000, 8).expect("bad layout");
+        let tiny = Layout::from_size_align(32, 8).expect("bad layout");
+        unsafe {
+            for _ in 0..3 {
+                assert!(!va.alloc(large).is_null());
+            }
+            let failures = || va.backing_allocator().stats().failures;
+            // the reserve block and the heap block are both refused
+            assert!(va.alloc(tiny).is_null());
+            assert_eq!(failures(), 2);
+            assert!(va.alloc(tiny).is_null());
+            assert_eq!(failures(), 3);
+        }
+    }
+
+    #[test]
+    /// Check the memory obtained from the backing allocator is limited by the quota.
+    fn deblockator_quota() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_quota(8192);
+
+        let layout = half_block();
+        let large = Layout::from_size_align(3000, 8).expect("bad layout");
+        unsafe {
+            let ptr = va.alloc(large);
+            assert!(!ptr.is_null());
+            assert!(!va.alloc(layout).is_null());
+            assert_eq!(va.acquired_bytes(), 8192);
+
+            // the backing allocator still has a block, but the quota is reached
+            assert!(va.alloc(large).is_null());
+            assert_eq!((*va.block_allocator.get()).allocated(), [true, true, false]);
+
+            va.dealloc(ptr, large);
+            assert_eq!(va.acquired_bytes(), 4096);
+            assert!(!va.alloc(large).is_null());
+        }
+    }
+
+    #[test]
+    /// Check all cached memory is returned by `flush_caches`.
+    fn deblockator_flush_caches() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_large_cache(4096);
+
+        let layout = half_block();
+        let large = Layout::from_size_align(3000, 8).expect("bad layout");
+        unsafe {
+            let allocated = || (*va.block_allocator.get()).allocated();
+            let ptr1 = va.alloc(layout);
+            let ptr2 = va.alloc(large);
+            let ptr3 = va.alloc(Layout::from_size_align(16, 8).unwrap());
+            va.dealloc(ptr1, layout);
+            va.dealloc(ptr2, large);
+            assert_eq!(allocated(), [true, true, false]);
+
+            // the heap block is still in use
+            assert_eq!(va.flush_caches(), 4096);
+            assert_eq!(allocated(), [true, false, false]);
+
+            va.dealloc(ptr3, Layout::from_size_align(16, 8).unwrap());
+            assert_eq!(va.flush_caches(), 4096);
+            assert_eq!(allocated(), [false, false, false]);
+        }
+    }
+
+    #[test]
+    /// Check failed allocations are reported to the failure hook with their reason.
+    fn deblockator_failure_hook() {
+        static FAILURES: Mutex<Vec<(usize, AllocFailure)>> = Mutex::new(Vec::new());
+        fn report(layout: Layout, failure: AllocFailure) {
+            FAILURES.lock().push((layout.size(), failure));
+        }
+
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma)
+            .with_quota(8192)
+            .with_failure_hook(report, 2);
+
+        let large = Layout::from_size_align(3000, 8).expect("bad layout");
+        unsafe {
+            assert!(!va.alloc(large).is_null());
+            assert!(!va.alloc(large).is_null());
+            for _ in 0..3 {
+                assert!(va.alloc(large).is_null());
+            }
+            va.set_growth(false);
+            assert!(va.alloc(large).is_null());
+            assert!(va.alloc(Layout::from_size_align(64, 8).unwrap()).is_null());
+        }
+
+        assert_eq!(
+            *FAILURES.lock(),
+            [
+                (3000, AllocFailure::Quota),
+                (3000, AllocFailure::Quota),
+                (64, AllocFailure::NoGrowth)
+            ]
+        );
+    }
+
+    #[test]
+    /// Check the first failed allocation is reported with the state of the heap.
+    fn deblockator_oom_report() {
+        static REPORTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+        fn sink(report: &dyn fmt::Display) {
+            REPORTS.lock().push(report.to_string());
+        }
+
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma)
+            .with_quota(4096)
+            .with_oom_report(sink, true);
+
+        let layout = Layout::from_size_align(1500, 8).expect("bad layout");
+        unsafe {
+            assert!(!va.alloc(layout).is_null());
+            assert!(!va.alloc(layout).is_null());
+            assert!(va.alloc(layout).is_null());
+            assert!(va.alloc(layout).is_null());
+        }
+
+        let block = va.blocks().next().unwrap();
+        let reports = REPORTS.lock();
+        assert_eq!(reports.len(), 1);
+        let lines: Vec<_> = reports[0].lines().collect();
+        assert_eq!(lines[0], "oom size 1500 align 8 reason Quota tag None");
+        assert!(lines[1].starts_with("failures 1 acquired 4096 "));
+        assert!(lines[3].starts_with(&format!("block {:#x} len 4096 ", block.start)));
+        assert_eq!(
+            lines[4],
+            format!("largest holes {}", block.len - block.used)
+        );
+        assert_eq!(lines.len(), 5);
+    }
+
+    #[test]
+    /// Check invalid deallocations are handled according to the policy.
+    fn deblockator_dealloc_error_policy() {
+        static ERRORS: Mutex<Vec<DeallocError>> = Mutex::new(Vec::new());
+        fn record(_ptr: *mut u8, _layout: Layout, error: DeallocError) {
+            ERRORS.lock().push(error);
+        }
+
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_dealloc_error_policy(DeallocErrorPolicy::Callback(record));
+
+        let layout = Layout::from_size_align(32, 8).expect("bad layout");
+        let mut outside = 0u64;
+        unsafe {
+            let kept = va.alloc(layout);
+            let ptr = va.alloc(layout);
+            va.dealloc(ptr, layout);
+            va.dealloc(ptr, layout);
+            va.dealloc(&mut outside as *mut u64 as *mut u8, layout);
+            // rejected deallocations are not credited
+            assert_eq!(va.overhead().user, layout.size());
+            va.dealloc(kept, layout);
+        }
+
+        assert_eq!(
+            *ERRORS.lock(),
+            [DeallocError::DoubleFree, DeallocError::UnknownPointer]
+        );
+    }
+
+    #[test]
+    /// Check allocations made with a header are freed without their layout.
+    fn deblockator_alloc_with_header() {
+        static ERRORS: Mutex<Vec<DeallocError>> = Mutex::new(Vec::new());
+        fn record(_ptr: *mut u8, _layout: Layout, error: DeallocError) {
+            ERRORS.lock().push(error);
+        }
+
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_dealloc_error_policy(DeallocErrorPolicy::Callback(record));
+
+        let layouts = [
+            Layout::from_size_align(24, 8).expect("bad layout"),
+            Layout::from_size_align(100, 64).expect("bad layout"),
+            Layout::from_size_align(3000, 8).expect("bad layout"),
+        ];
+        unsafe {
+            let ptrs: Vec<_> = layouts
+                .iter()
+                .map(|&layout| va.alloc_with_header(layout))
+                .collect();
+            for (&ptr, &layout) in ptrs.iter().zip(&layouts) {
+                assert!(!ptr.is_null());
+                assert!((ptr as usize).is_multiple_of(layout.align()));
+                assert_eq!(va.allocation_layout(ptr), layout);
+                assert_eq!(va.cookie_of(ptr), Some(0));
+                ptr.write_bytes(0xaa, layout.size());
+            }
+            for &ptr in &ptrs {
+                va.free(ptr);
+            }
+            va.free(ptr::null_mut());
+            assert_eq!(va.large_allocations(), (0, 0));
+            assert_eq!(va.overhead().user, 0);
+
+            // a header naming another heap block is rejected
+            let layout = Layout::from_size_align(128, 8).expect("bad layout");
+            let ptr = va.alloc(layout);
+            let user = ptr.add(size_of::<AllocHeader>());
+            (ptr as *mut AllocHeader).write(AllocHeader {
+                size: 16,
+                align: 8,
+                block: 1,
+                cookie: 0,
+                thread: 0,
+                site: 0,
+                serial: 0,
+                prev: 0,
+                next: 0,
+                check: HEADER_MAGIC ^ user as usize,
+            });
+            va.free(user);
+            va.dealloc(ptr, layout);
+        }
+
+        assert_eq!(*ERRORS.lock(), [DeallocError::UnknownPointer]);
+    }
+
+    #[test]
+    /// Check allocation cookies are read back, and overwritten headers are rejected.
+    fn deblockator_alloc_with_cookie() {
+        static ERRORS: Mutex<Vec<DeallocError>> = Mutex::new(Vec::new());
+        fn record(_ptr: *mut u8, _layout: Layout, error: DeallocError) {
+            ERRORS.lock().push(error);
+        }
+
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_dealloc_error_policy(DeallocErrorPolicy::Callback(record));
+
+        let layout = Layout::from_size_align(48, 8).expect("bad layout");
+        unsafe {
+            let ptr1 = va.alloc_with_cookie(layout, 0x51);
+            let ptr2 = va.alloc_with_cookie(layout, 0x52);
+            assert_eq!(va.cookie_of(ptr1), Some(0x51));
+            assert_eq!(va.cookie_of(ptr2), Some(0x52));
+
+            // an underflow of the second allocation overwrites its header
+            let header = (ptr2 as *mut AllocHeader).sub(1);
+            let saved = header.read();
+            ptr2.sub(size_of::<AllocHeader>())
+                .write_bytes(0x00, size_of::<AllocHeader>());
+            assert_eq!(va.cookie_of(ptr2), None);
+            va.free(ptr2);
+            assert_eq!(*ERRORS.lock(), [DeallocError::UnknownPointer]);
+
+            header.write(saved);
+            va.free(ptr2);
+            va.free(ptr1);
+            assert_eq!(va.overhead().user, 0);
+        }
+        assert_eq!(ERRORS.lock().len(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    #[cfg_attr(all(target_family = "wasm", not(target_feature = "atomics")), ignore)]
+    /// Check frees made by another thread than the allocating one are counted.
+    fn deblockator_cross_thread_frees() {
+        use std::alloc::System;
+
+        let va: Deblockator<System, U4096, U4096, U2048, U4096> = Deblockator::new(System);
+        let layout = Layout::from_size_align(64, 8).expect("bad layout");
+        unsafe {
+            let local = va.alloc_with_header(layout);
+            let remote = va.alloc_with_header(layout) as usize;
+            std::thread::scope(|scope| {
+                scope.spawn(|| va.free(remote as *mut u8));
+            });
+            va.free(local);
+        }
+        assert_eq!(va.cross_thread_frees(), (2, 1));
+        assert_eq!(va.overhead().user, 0);
+    }
+
+    #[test]
+    /// Check header allocations are aggregated by call site.
+    fn deblockator_site_table() {
+        static mut ENTRIES: [SiteStats; 4] = [SiteStats::EMPTY; 4];
+        static SITES: SiteTable = unsafe { SiteTable::from_array(ptr::addr_of_mut!(ENTRIES)) };
+
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_site_table(&SITES);
+
+        let layout = Layout::from_size_align(64, 8).expect("bad layout");
+        unsafe {
+            let ptrs: Vec<_> = (0..3).map(|_| va.alloc_with_header(layout)).collect();
+            let other = va.alloc_with_cookie(layout, 1);
+            va.free(ptrs[0]);
+
+            let mut top = [SiteStats::EMPTY; 1];
+            assert_eq!(SITES.top(&mut top), 1);
+            assert_eq!(top[0].site.map(Location::file), Some(file!()));
+            assert_eq!((top[0].live_bytes, top[0].allocations), (128, 3));
+            assert_eq!(top[0].peak_bytes, 192);
+
+            va.free(ptrs[1]);
+            va.free(ptrs[2]);
+            va.free(other);
+        }
+        let mut all = [SiteStats::EMPTY; 4];
+        assert_eq!(SITES.top(&mut all), 2);
+        assert!(all[..2].iter().all(|site| site.live == 0));
+    }
+
+    #[test]
+    /// Check the C allocation functions check their arguments and keep the header.
+    fn deblockator_c_functions() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma);
+
+        unsafe {
+            let mut ptr = ::core::ptr::null_mut();
+            assert_eq!(va.posix_memalign(&mut ptr, 24, 100), EINVAL);
+            assert_eq!(va.posix_memalign(&mut ptr, 4, 100), EINVAL);
+            assert!(ptr.is_null());
+            assert_eq!(va.posix_memalign(&mut ptr, 64, 100), 0);
+            assert_eq!(ptr as usize % 64, 0);
+            assert_eq!(va.allocation_layout(ptr).size(), 100);
+            va.free(ptr);
+
+            assert!(va.aligned_alloc(3, 100).is_null());
+            let ptr = va.aligned_alloc(256, 0);
+            assert_eq!(ptr as usize % 256, 0);
+            va.free(ptr);
+
+            // an overflowing size leaves the allocation untouched
+            let ptr = va.reallocarray(::core::ptr::null_mut(), 4, 8);
+            assert_eq!(ptr as usize % MALLOC_ALIGN, 0);
+            ptr.write_bytes(0x5A, 32);
+            assert!(va.reallocarray(ptr, usize::MAX / 2, 3).is_null());
+            let ptr = va.reallocarray(ptr, 16, 8);
+            assert_eq!(va.allocation_layout(ptr).size(), 128);
+            assert!((0..32).all(|i| *ptr.add(i) == 0x5A));
+
+            let cookie = va.alloc_with_cookie(Layout::from_size_align(16, 32).unwrap(), 7);
+            let cookie = va.realloc_with_header(cookie, 40);
+            assert_eq!(va.cookie_of(cookie), Some(7));
+            assert_eq!(cookie as usize % 32, 0);
+            va.free(cookie);
+            va.free(ptr);
+            assert_eq!(va.overhead().user, 0);
+            assert_eq!(va.cross_thread_frees().0, 6);
+        }
+    }
+
+    #[test]
+    /// Check the leak report lists header allocations and large allocations.
+    fn deblockator_leak_report() {
+        static mut ENTRIES: [PassthroughAllocation; 4] = [PassthroughAllocation::EMPTY; 4];
+        static LEAKS: LeakTable = unsafe { LeakTable::from_array(ptr::addr_of_mut!(ENTRIES)) };
+
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_leak_table(&LEAKS);
+
+        let small = Layout::from_size_align(64, 8).expect("bad layout");
+        let large = Layout::from_size_align(3000, 8).expect("bad layout");
+        unsafe {
+            let header = va.alloc_with_header(small);
+            let (ptr, line) = (va.alloc(large), line!());
+            let large_header = va.alloc_with_header(Layout::from_size_align(2500, 8).unwrap());
+            // large allocations made with a header are listed from it
+            assert_eq!(LEAKS.len(), 1);
+
+            let mut report = String::new();
+            va.write_leak_report(&mut report).unwrap();
+            let expected = format!(
+                "passthrough {:#x} size 3000 align 8 site {}:{}:",
+                ptr as usize,
+                file!(),
+                line
+            );
+            assert!(report.contains(&expected));
+            assert_eq!(report.matches("header ").count(), 2);
+            assert!(report.contains(&format!("header {:#x} size 64", header as usize)));
+
+            va.dealloc(ptr, large);
+            assert!(LEAKS.is_empty());
+            va.free(header);
+            va.free(large_header);
+            let mut report = String::new();
+            va.write_leak_report(&mut report).unwrap();
+            assert_eq!(report, "leaks user 0 passthrough 0 missed 0\n");
+        }
+    }
+
+    #[test]
+    /// Check snapshots list the allocations made between them and still live.
+    fn deblockator_snapshot_diff() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma);
+
+        let layout = Layout::from_size_align(48, 8).expect("bad layout");
+        let large = Layout::from_size_align(3000, 8).expect("bad layout");
+        unsafe {
+            let old = va.alloc_with_header(layout);
+            let freed = va.alloc_with_header(layout);
+            let earlier: Snapshot<4> = va.snapshot();
+            assert_eq!(earlier.allocations().count(), 2);
+
+            va.free(freed);
+            let leaked = va.alloc_with_cookie(layout, 7);
+            let site = line!() - 1;
+            let gone = va.alloc_with_header(layout);
+            va.free(gone);
+            let kept = va.alloc_with_header(large);
+            let later: Snapshot<4> = va.snapshot();
+
+            let new: Vec<_> = Snapshot::diff(&earlier, &later).collect();
+            assert_eq!(new.len(), 2);
+            assert_eq!((new[0].addr, new[0].size), (kept as usize, 3000));
+            assert_eq!((new[1].addr, new[1].cookie), (leaked as usize, 7));
+            assert_eq!(new[1].site.file(), file!());
+            assert_eq!(new[1].site.line(), site);
+
+            // a snapshot too small for all the allocations misses the oldest ones
+            let small: Snapshot<2> = va.snapshot();
+            assert_eq!(small.missed(), 1);
+            assert!(small
+                .allocations()
+                .all(|allocation| allocation.addr != old as usize));
+
+            for ptr in [old, leaked, kept] {
+                va.free(ptr);
+            }
+            let empty: Snapshot<4> = va.snapshot();
+            assert_eq!(empty.allocations().count(), 0);
+            assert_eq!(Snapshot::diff(&earlier, &empty).count(), 0);
+        }
+    }
+
+    #[test]
+    /// Check watermarks follow the memory obtained from the backing allocator.
+    fn deblockator_watermarks() {
+        static CROSSED: Mutex<Vec<(usize, bool)>> = Mutex::new(Vec::new());
+        fn record(level: usize, rising: bool) {
+            CROSSED.lock().push((level, rising));
+        }
+
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_watermarks(Watermarks::new(3 * 4096, &[50, 100], record));
+
+        let large = Layout::from_size_align(3000, 8).expect("bad layout");
+        unsafe {
+            let ptrs = [va.alloc(large), va.alloc(large), va.alloc(large)];
+            for ptr in ptrs.iter() {
+                va.dealloc(*ptr, large);
+            }
+        }
+
+        assert_eq!(
+            *CROSSED.lock(),
+            [(50, true), (100, true), (100, false), (50, false)]
+        );
+    }
+
+    #[test]
+    /// Check allocations are served according to their criticality.
+    fn deblockator_criticality() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma)
+            .with_reserve(16)
+            .with_low_criticality_limit(8192);
+
+        let large = Layout::from_size_align(3000, 8).expect("bad layout");
+        let layout = Layout::from_size_align(1000, 8).expect("bad layout");
+        unsafe {
+            // the reserve block and a heap block reach the low limit
+            assert!(!va
+                .alloc_with_criticality(layout, Criticality::Low)
+                .is_null());
+            assert!(va.alloc_with_criticality(large, Criticality::Low).is_null());
+            assert!(!va
+                .alloc_with_criticality(large, Criticality::Normal)
+                .is_null());
+
+            // only critical allocations may use the reserve block
+            assert!(!va.alloc(layout).is_null());
+            assert!(!va.alloc(layout).is_null());
+            assert!(va.alloc(layout).is_null());
+            assert!(!va
+                .alloc_with_criticality(layout, Criticality::High)
+                .is_null());
+        }
+    }
+
+    #[test]
+    /// Check the self test passes on a working allocator.
+    fn deblockator_self_test() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma);
+
+        assert_eq!(va.self_test(), Ok(()));
+        assert_eq!(va.check_integrity(), Ok(()));
+        assert!(va.blocks().all(|block| block.holes == 1));
+        let allocated = unsafe { (*va.block_allocator.get()).allocated() };
+        assert_eq!(allocated, [true, false, false]);
+    }
+
+    #[test]
+    /// Check large layouts are served from heap blocks without passthrough.
+    fn deblockator_without_passthrough() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).without_passthrough();
+
+        let large = Layout::from_size_align(3000, 8).expect("bad layout");
+        unsafe {
+            let ptr = va.alloc(large);
+            assert!(!ptr.is_null());
+            assert_eq!(va.blocks().count(), 1);
+            assert!(va.blocks().all(|block| block.used >= 3000));
+            va.dealloc(ptr, large);
+            assert!(va.blocks().all(|block| block.holes == 1));
+        }
+    }
+
+    #[test]
+    /// Check allocations can start on an aligned payload.
+    fn deblockator_payload_alignment() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_payload_alignment(1024);
+
+        let layout = Layout::from_size_align(64, 8).expect("bad layout");
+        unsafe {
+            let ptr = va.alloc(layout);
+            assert_eq!(ptr as usize % 1024, 0);
+            assert!(va.blocks().all(|block| block.used == 1024 + 64));
+
+            va.dealloc(ptr, layout);
+            assert_eq!(va.flush_caches(), 4096);
+        }
+    }
+
+    #[test]
+    /// Check groups of layouts are allocated contiguously, and freed separately.
+    fn deblockator_alloc_group() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma);
+
+        let layouts = [
+            Layout::from_size_align(24, 8).unwrap(),
+            Layout::from_size_align(100, 64).unwrap(),
+            Layout::from_size_align(8, 8).unwrap(),
+        ];
+        let mut ptrs = [::core::ptr::null_mut(); 3];
+        unsafe {
+            assert!(va.alloc_group(&layouts, &mut ptrs));
+            assert_eq!(ptrs[1] as usize % 64, 0);
+            assert!(ptrs[0] < ptrs[1] && ptrs[1] < ptrs[2]);
+            assert!(ptrs[2] as usize - ptrs[0] as usize <= 128 + 64);
+            assert_eq!(va.check_integrity(), Ok(()));
+
+            for (ptr, layout) in ptrs.iter().zip(layouts.iter()) {
+                va.dealloc(*ptr, *layout);
+            }
+            assert!(va.blocks().all(|block| block.holes == 1));
+
+            // a group in the small-object slots is allocated separately
+            let small: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+                Deblockator::new(MockAlloc::new()).with_small_objects();
+            let tiny = [
+                Layout::from_size_align(24, 8).unwrap(),
+                Layout::from_size_align(16, 16).unwrap(),
+            ];
+            assert!(small.alloc_group(&tiny, &mut ptrs));
+            assert_eq!(ptrs[1] as usize % 16, 0);
+            for (ptr, layout) in ptrs.iter().zip(tiny.iter()) {
+                small.dealloc(*ptr, *layout);
+            }
+            assert_eq!(small.overhead().user, 0);
+            assert_eq!(small.check_integrity(), Ok(()));
+
+            // a large layout prevents a contiguous placement
+            let layouts = [layouts[0], Layout::from_size_align(3000, 8).unwrap()];
+            assert!(va.alloc_group(&layouts, &mut ptrs));
+            assert_eq!((*va.block_allocator.get()).allocated(), [true, true, false]);
+        }
+    }
+
+    #[test]
+    /// Check the best-fit strategy uses the smallest hole of a block.
+    fn deblockator_best_fit() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_fit(Fit::Best);
+
+        let large = Layout::from_size_align(256, 8).unwrap();
+        let small = Layout::from_size_align(64, 8).unwrap();
+        unsafe {
+            let a = va.alloc(large);
+            va.alloc(small);
+            let b = va.alloc(small);
+            va.alloc(small);
+            va.dealloc(a, large);
+            va.dealloc(b, small);
+
+            // the 64-byte hole is used instead of the first one
+            assert_eq!(va.alloc(small), b);
+            assert_eq!(va.alloc(large), a);
+        }
+    }
+
+    #[test]
+    /// Check indexed holes keep heap blocks consistent, with small allocations padded.
+    fn deblockator_indexed_holes() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma)
+            .with_fit(Fit::Best)
+            .with_indexed_holes();
+
+        let large = Layout::from_size_align(256, 8).unwrap();
+        let small = Layout::from_size_align(8, 8).unwrap();
+        unsafe {
+            let a = va.alloc(large);
+            let s = va.alloc(small);
+            let b = va.alloc(small);
+            va.alloc(small);
+            assert!(b as usize - s as usize >= HeapBlock::<U4096>::min_indexed_size());
+            va.dealloc(a, large);
+            va.dealloc(b, small);
+            assert_eq!(va.check_integrity(), Ok(()));
+
+            // the smallest hole is still used first
+            assert_eq!(va.alloc(small), b);
+            assert_eq!(va.alloc(large), a);
+            assert_eq!(va.check_integrity(), Ok(()));
+        }
+    }
+
+    #[test]
+    /// Check heap blocks get a new generation each time they are emptied.
+    fn deblockator_block_generation() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma);
+
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        unsafe {
+            let a = va.alloc(layout);
+            let generation = va.block_generation(a).unwrap();
+            assert_ne!(generation, 0);
+
+            // the block is not emptied by freeing another allocation
+            let b = va.alloc(layout);
+            va.dealloc(b, layout);
+            assert_eq!(va.block_generation(a), Some(generation));
+
+            // a stale pointer to reused memory is detected
+            va.dealloc(a, layout);
+            let c = va.alloc(layout);
+            assert_eq!(c, a);
+            assert_ne!(va.block_generation(c), Some(generation));
+
+            let large = Layout::from_size_align(3000, 8).unwrap();
+            assert_eq!(va.block_generation(va.alloc(large)), None);
+        }
+    }
+
+    #[test]
+    /// Check a frozen heap rejects allocations and deallocations.
+    fn deblockator_freeze() {
+        static FAILURES: spin::Mutex<Vec<AllocFailure>> = spin::Mutex::new(Vec::new());
+        static ERRORS: spin::Mutex<Vec<DeallocError>> = spin::Mutex::new(Vec::new());
+        fn hook(_layout: Layout, failure: AllocFailure) {
+            FAILURES.lock().push(failure);
+        }
+        fn callback(_ptr: *mut u8, _layout: Layout, error: DeallocError) {
+            ERRORS.lock().push(error);
+        }
+
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma)
+            .with_failure_hook(hook, 1)
+            .with_dealloc_error_policy(DeallocErrorPolicy::Callback(callback));
+
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        unsafe {
+            let a = va.alloc(layout);
+            va.freeze();
+            assert!(va.is_frozen());
+            assert!(va.alloc(layout).is_null());
+            va.dealloc(a, layout);
+            assert_eq!(*FAILURES.lock(), [AllocFailure::Frozen]);
+            assert_eq!(*ERRORS.lock(), [DeallocError::Frozen]);
+
+            // the memory was not freed while frozen
+            va.thaw();
+            assert_ne!(va.alloc(layout), a);
+            va.dealloc(a, layout);
+            assert!(ERRORS.lock().len() == 1);
+        }
+    }
+
+    #[test]
+    /// Check placement overrides take precedence over the size threshold.
+    fn deblockator_placements() {
+        static PLACEMENTS: [(Layout, Placement); 2] = [
+            (Layout::new::<[u8; 3000]>(), Placement::Blocks),
+            (Layout::new::<[u64; 8]>(), Placement::Passthrough),
+        ];
+
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_placements(&PLACEMENTS);
+
+        unsafe {
+            let large = va.alloc(PLACEMENTS[0].0);
+            assert!(va.block_metadata(large).is_some());
+            let small = va.alloc(PLACEMENTS[1].0);
+            assert!(va.block_metadata(small).is_none());
+
+            // other layouts still use the threshold
+            let layout = Layout::from_size_align(3000, 8).unwrap();
+            assert!(va.block_metadata(va.alloc(layout)).is_none());
+
+            va.dealloc(small, PLACEMENTS[1].0);
+            va.dealloc(large, PLACEMENTS[0].0);
+            assert!(va.blocks().all(|block| block.holes == 1));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    /// Check shared blocks must be aligned to the block size with aligned blocks.
+    fn deblockator_shared_block_unaligned() {
+        #[repr(align(4096))]
+        struct Pages([u8; 8192]);
+        static mut PAGES: Pages = Pages([0; 8192]);
+        static REGION: SharedRegion = SharedRegion::new(|| ());
+
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(MockAlloc::new()).with_aligned_blocks();
+        unsafe {
+            let page = ::core::ptr::addr_of_mut!(PAGES.0).cast::<u8>().add(64);
+            va.add_shared_block(NonNull::new_unchecked(page), &REGION);
+        }
+    }
+
+    #[test]
+    /// Check shared blocks are given back to their region instead of the backing allocator.
+    fn deblockator_shared_block() {
+        use core::sync::atomic::AtomicBool;
+
+        #[repr(align(4096))]
+        struct Page([u8; 4096]);
+        static mut PAGE: Page = Page([0; 4096]);
+        static RELEASED: AtomicBool = AtomicBool::new(false);
+        fn on_release() {
+            RELEASED.store(true, Ordering::SeqCst);
+        }
+        static REGION: SharedRegion = SharedRegion::new(on_release);
+
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma);
+
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        unsafe {
+            let page = NonNull::new_unchecked(::core::ptr::addr_of_mut!(PAGE.0)).cast();
+            va.add_shared_block(page, &REGION);
+            REGION.retain();
+            assert_eq!(REGION.refs(), 2);
+
+            let ptr = va.alloc(layout);
+            assert_eq!(va.blocks().next().unwrap().start, page.as_ptr() as usize);
+            assert!(va.block_metadata(ptr).is_some());
+            assert_eq!((*va.block_allocator.get()).allocated(), [false; 3]);
+            assert_eq!(va.acquired_bytes(), 0);
+
+            va.dealloc(ptr, layout);
+            va.flush_caches();
+            assert_eq!(va.blocks().count(), 0);
+            assert_eq!(REGION.refs(), 1);
+            assert!(!RELEASED.load(Ordering::SeqCst));
+
+            REGION.release();
+            assert!(RELEASED.load(Ordering::SeqCst));
+        }
+    }
+
+    #[test]
+    /// Check heap blocks are searched and grown according to the policies of their regions.
+    fn deblockator_region_policies() {
+        #[repr(align(4096))]
+        struct Page([u8; 4096]);
+        static mut PAGE: Page = Page([0; 4096]);
+        fn on_release() {}
+        static REGION: SharedRegion = SharedRegion::new(on_release)
+            .with_policy(RegionPolicy::new(RegionPriority::Preferred).with_max_size(256));
+
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma)
+            .with_backing_policy(RegionPolicy::new(RegionPriority::Fallback).with_max_size(1200));
+
+        let small = Layout::from_size_align(32, 8).expect("bad layout");
+        let medium = Layout::from_size_align(1000, 8).expect("bad layout");
+        unsafe {
+            let ptr1 = va.alloc(medium);
+            let page = NonNull::new_unchecked(::core::ptr::addr_of_mut!(PAGE.0)).cast::<u8>();
+            va.add_shared_block(page, &REGION);
+            let backing = va.blocks().next().unwrap();
+            assert_ne!(backing.start, page.as_ptr() as usize);
+
+            // the shared block comes last, but is preferred for small layouts
+            let ptr2 = va.alloc(small);
+            assert!(ptr2 as usize - (page.as_ptr() as usize) < 4096);
+            let ptr3 = va.alloc(medium);
+            assert!(ptr3 as usize - backing.start < backing.len);
+
+            // the backing memory does not grow for layouts above its maximum size
+            assert!(va
+                .alloc(Layout::from_size_align(1500, 8).expect("bad layout"))
+                .is_null());
+            assert_eq!(va.blocks().count(), 2);
+
+            va.dealloc(ptr1, medium);
+            va.dealloc(ptr2, small);
+            va.dealloc(ptr3, medium);
+        }
+    }
+
+    #[test]
+    /// Check small allocations are reused from the segregated free lists.
+    fn deblockator_segregated() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_fit(Fit::Segregated);
+
+        let small = Layout::from_size_align(32, 8).unwrap();
+        let large = Layout::from_size_align(1024, 8).unwrap();
+        unsafe {
+            let a = va.alloc(small);
+            let b = va.alloc(large);
+            va.dealloc(a, small);
+            assert_eq!(va.alloc(small), a);
+
+            va.dealloc(a, small);
+            va.dealloc(b, large);
+            // empty blocks are detected despite the segregated chunks
+            assert_eq!(va.blocks().count(), 1);
+            va.flush_caches();
+            assert_eq!(va.blocks().count(), 0);
+        }
+    }
+
+    #[test]
+    /// Check vectors grow and shrink in place when the following memory is free.
+    fn deblockator_vec_resize() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma);
+
+        let mut v: Vec<u64, _> = Vec::with_capacity_in(4, &va);
+        let start = v.as_ptr();
+        v.extend(0..64);
+        assert_eq!(v.as_ptr(), start);
+
+        // the end of a shrunk vector is freed for other allocations
+        v.truncate(8);
+        v.shrink_to_fit();
+        assert_eq!(v.as_ptr(), start);
+        let other: Vec<u64, _> = Vec::with_capacity_in(8, &va);
+        assert_eq!(other.as_ptr(), start.wrapping_add(8));
+
+        // a vector followed by another allocation is moved
+        v.push(8);
+        assert_ne!(v.as_ptr(), start);
+        assert!(v.iter().copied().eq(0..9));
+        drop(other);
+        drop(v);
+        assert!(va.blocks().all(|block| block.holes == 1));
+    }
+
+    #[test]
+    /// Check the block search starts at the block chosen by a custom placement policy.
+    fn deblockator_choose_block() {
+        use crate::hole::FirstFit;
+        use crate::hole::HoleInfo;
+        use crate::hole::Holes;
+        use crate::hole::PlacementPolicy;
+
+        /// A policy allocating in the least used block.
+        struct LeastUsed;
+
+        impl PlacementPolicy for LeastUsed {
+            fn choose_block(
+                &self,
+                blocks: &mut dyn Iterator<Item = BlockInfo>,
+                _layout: Layout,
+            ) -> Option<usize> {
+                blocks
+                    .enumerate()
+                    .min_by_key(|(_, block)| block.used)
+                    .map(|(index, _)| index)
+            }
+
+            fn choose_hole(&self, holes: Holes<'_>, layout: Layout, _: usize) -> Option<HoleInfo> {
+                FirstFit.choose_hole(holes, layout, 0)
+            }
+        }
+
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_fit(Fit::Custom(&LeastUsed));
+
+        let layout = half_block();
+        let small = Layout::from_size_align(64, 8).expect("bad layout");
+        unsafe {
+            // a large allocation in between prevents the heap block extension
+            let ptr1 = va.alloc(layout);
+            let _large = va.alloc(Layout::from_size_align(3000, 8).expect("bad layout"));
+            let _ptr2 = va.alloc(layout);
+            let ptr3 = va.alloc(layout);
+            let ptr4 = va.alloc(small);
+            assert_eq!(va.blocks().count(), 2);
+            va.dealloc(ptr1, layout);
+            va.dealloc(ptr3, layout);
+
+            // the first block has room, but the second one is less used
+            let ptr5 = va.alloc(small);
+            let second = va.blocks().nth(1).unwrap();
+            assert!(second.start <= ptr5 as usize && ptr5 as usize - second.start < second.len);
+            assert!(second.start <= ptr4 as usize);
+        }
+    }
+
+    #[test]
+    /// Check small allocations are served from small-object slots when enabled.
+    fn deblockator_small_objects() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_small_objects();
+
+        let small = Layout::from_size_align(8, 8).expect("bad layout");
+        let large = Layout::from_size_align(128, 8).expect("bad layout");
+        unsafe {
+            let ptrs: Vec<_> = (0..40).map(|_| va.alloc(small)).collect();
+            let ptr = va.alloc(large);
+            assert!(ptrs
+                .windows(2)
+                .take(31)
+                .all(|w| w[1] as usize - w[0] as usize == 16));
+
+            // the slots are full, so further allocations split holes
+            assert!(ptrs[32] as usize - ptrs[0] as usize >= 32 * 16);
+            assert_eq!(va.blocks().count(), 1);
+            for &ptr in ptrs.iter() {
+                va.dealloc(ptr, small);
+            }
+            va.dealloc(ptr, large);
+            assert!(va.blocks().all(|block| block.holes == 1));
+        }
+    }
+
+    #[test]
+    /// Check acquired memory is zeroed, and zeroed allocations are cleared when needed.
+    fn deblockator_zero_fill() {
+        let ma = MockAlloc::filled(0xAA);
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_zero_fill(ZeroFill::OnAcquire);
+
+        let layout = Layout::from_size_align(256, 8).expect("bad layout");
+        let large = Layout::from_size_align(3000, 8).expect("bad layout");
+        let zeroed = |ptr: *mut u8, range: core::ops::Range<usize>| {
+            range.into_iter().all(|i| unsafe { *ptr.add(i) } == 0)
+        };
+        unsafe {
+            // fresh memory only holds heap block bookkeeping
+            let ptr1 = va.alloc_zeroed(layout);
+            let ptr2 = va.alloc(layout);
+            assert!(zeroed(ptr1, 0..layout.size()));
+            assert!(zeroed(ptr2, HeapBlock::<U4096>::min_size()..layout.size()));
+            assert!(zeroed(va.alloc_zeroed(large), 0..large.size()));
+
+            // memory used before is cleared entirely
+            ptr1.write_bytes(0xFF, layout.size());
+            va.dealloc(ptr1, layout);
+            let ptr3 = va.alloc_zeroed(layout);
+            assert_eq!(ptr3, ptr1);
+            assert!(zeroed(ptr3, 0..layout.size()));
+        }
+    }
+
+    #[test]
+    /// Check heap blocks are moved to another allocator without copying allocations.
+    fn deblockator_migrate() {
+        use core::sync::atomic::AtomicBool;
+
+        static RELEASED: AtomicBool = AtomicBool::new(false);
+        fn on_release() {
+            RELEASED.store(true, Ordering::SeqCst);
+        }
+        static REGION: SharedRegion = SharedRegion::new(on_release);
+
+        let boot: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(MockAlloc::new());
+        let heap: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(MockAlloc::new());
+
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        unsafe {
+            let ptr1 = boot.alloc(layout);
+            let ptr2 = boot.alloc(layout);
+            ptr1.write_bytes(0x42, layout.size());
+            assert_eq!(boot.migrate_to(&heap, &REGION), 1);
+            assert_eq!(boot.blocks().count(), 0);
+            assert_eq!(boot.acquired_bytes(), 0);
+            assert_eq!(REGION.refs(), 1);
+            assert_eq!(boot.overhead().user, 0);
+            assert_eq!(heap.overhead().user, 2 * layout.size());
+
+            // the allocations are untouched, and managed by the new allocator
+            assert!((0..layout.size()).all(|i| *ptr1.add(i) == 0x42));
+            heap.dealloc(ptr1, layout);
+            assert_eq!(heap.alloc(layout), ptr1);
+            heap.dealloc(ptr1, layout);
+            heap.dealloc(ptr2, layout);
+            assert_eq!(heap.acquired_bytes(), 0);
+
+            heap.flush_caches();
+            assert!(RELEASED.load(Ordering::SeqCst));
+            assert_eq!((*heap.block_allocator.get()).allocated(), [false; 3]);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    /// Check blocks can not be moved to an allocator placing allocations differently.
+    fn deblockator_migrate_incompatible() {
+        static REGION: SharedRegion = SharedRegion::new(|| ());
+
+        let boot: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(MockAlloc::new());
+        let heap: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(MockAlloc::new()).with_fit(Fit::Best);
+        boot.migrate_to(&heap, &REGION);
+    }
+
+    #[test]
+    /// Check freed memory is only merged into the holes when coalescing.
+    fn deblockator_deferred_coalescing() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma)
+            .with_fit(Fit::Best)
+            .with_deferred_coalescing();
+
+        let layout = Layout::from_size_align(64, 8).expect("bad layout");
+        unsafe {
+            let ptrs: Vec<_> = (0..4).map(|_| va.alloc(layout)).collect();
+            va.dealloc(ptrs[1], layout);
+            va.dealloc(ptrs[2], layout);
+            assert_eq!(va.blocks().next().unwrap().holes, 1);
+
+            // freed allocations are reused as is
+            assert_eq!(va.alloc(layout), ptrs[2]);
+            va.coalesce();
+            assert_eq!(va.blocks().next().unwrap().holes, 2);
+
+            for &ptr in ptrs.iter().filter(|&&ptr| ptr != ptrs[1]) {
+                va.dealloc(ptr, layout);
+            }
+            va.coalesce();
+            assert!(va.blocks().all(|block| block.holes == 1));
+        }
+    }
+
+    #[test]
+    /// Check memory freed with deferred coalescing is reused before growing the heap.
+    fn deblockator_sweep_before_grow() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma)
+            .with_deferred_coalescing()
+            .with_quick_list()
+            .with_sweep_before_grow();
+
+        let layout = Layout::from_size_align(256, 8).expect("bad layout");
+        let large = Layout::from_size_align(2000, 8).expect("bad layout");
+        unsafe {
+            let ptrs: Vec<_> = (0..8).map(|_| va.alloc(layout)).collect();
+            for &ptr in ptrs.iter() {
+                va.dealloc(ptr, layout);
+            }
+            assert_eq!(va.blocks().next().unwrap().holes, 1);
+
+            let ptr = va.alloc(large);
+            assert!(!ptr.is_null());
+            assert_eq!(va.blocks().count(), 1);
+            va.dealloc(ptr, large);
+        }
+    }
+
+    #[test]
+    /// Check heap dumps only contain user data when explicitly requested.
+    fn deblockator_dump() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma);
+
+        let layout = Layout::from_size_align(64, 8).expect("bad layout");
+        unsafe {
+            let ptr1 = va.alloc(layout);
+            let ptr2 = va.alloc(layout);
+            ptr2.write_bytes(0x5A, layout.size());
+            va.dealloc(ptr1, layout);
+
+            let mut map = String::new();
+            va.dump(&mut map, DumpContent::Metadata).unwrap();
+            let block = va.blocks().next().unwrap();
+            assert!(map.starts_with(&format!("block {:#x} len 4096", block.start)));
+            assert!(map.contains(&format!("  hole {:#x} 64\n", ptr1 as usize)));
+            assert!(map.contains(&format!("  used {:#x} 64\n", ptr2 as usize)));
+            assert!(!map.contains("5a 5a 5a 5a"));
+
+            let mut dump = String::new();
+            va.dump(&mut dump, DumpContent::Full).unwrap();
+            assert!(
+                dump.contains("    00000000: 5a 5a 5a 5a 5a 5a 5a 5a 5a 5a 5a 5a 5a 5a 5a 5a\n")
+            );
+            va.dealloc(ptr2, layout);
+        }
+    }
+
+    #[test]
+    /// Check live large allocations are counted apart from the heap blocks.
+    fn deblockator_large_allocations() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_large_cache(4096);
+
+        let large = Layout::from_size_align(3000, 8).expect("bad layout");
+        let small = Layout::from_size_align(64, 8).expect("bad layout");
+        unsafe {
+            let ptr1 = va.alloc(large);
+            let ptr2 = va.alloc(large);
+            let ptr3 = va.alloc(small);
+            assert_eq!(va.large_allocations(), (2, 8192));
+            assert_eq!(va.blocks().count(), 1);
+
+            // cached large allocations are not live anymore
+            va.dealloc(ptr1, large);
+            assert_eq!(va.large_allocations(), (1, 4096));
+            assert_eq!(va.alloc(large), ptr1);
+            assert_eq!(va.large_allocations(), (2, 8192));
+
+            va.dealloc(ptr1, large);
+            va.dealloc(ptr2, large);
+            va.dealloc(ptr3, small);
+            assert_eq!(va.large_allocations(), (0, 0));
+        }
+    }
+
+    #[test]
+    /// Check successive heap blocks double in size, up to the growth cap.
+    fn deblockator_exponential_growth() {
+        use std::alloc::System;
+
+        let va: Deblockator<System, U4096, U4096, U2048, U4096> =
+            Deblockator::new(System).with_exponential_growth(16384);
+
+        let layout = Layout::from_size_align(2000, 8).expect("bad layout");
+        unsafe {
+            let ptrs: Vec<_> = (0..20).map(|_| va.alloc(layout)).collect();
+            assert!(ptrs.iter().all(|ptr| !ptr.is_null()));
+            let sizes: Vec<_> = va.blocks().map(|block| block.len).collect();
+            assert_eq!(sizes, [4096, 8192, 16384, 16384]);
+            assert_eq!(va.acquired_bytes(), 45056);
+
+            for ptr in ptrs {
+                va.dealloc(ptr, layout);
+            }
+            assert_eq!(va.flush_caches(), 45056);
+            assert_eq!(va.acquired_bytes(), 0);
+        }
+    }
+
+    #[test]
+    /// Check a custom growth policy chooses chunk sizes and when to give up.
+    fn deblockator_growth_policy() {
+        use std::alloc::System;
+
+        // grow by 3 blocks at a time, with smaller chunks near the quota,
+        // and never use more than two heap blocks
+        struct TwoChunks;
+        impl GrowthPolicy for TwoChunks {
+            fn chunk_size(&self, request: &GrowthRequest) -> Option<usize> {
+                if request.blocks < 2 {
+                    Some(3 * request.block_size)
+                } else {
+                    None
+                }
+            }
+            fn retry_size(
+                &self,
+                request: &GrowthRequest,
+                _failed: usize,
+                reason: AllocFailure,
+            ) -> Option<usize> {
+                if reason == AllocFailure::Quota {
+                    Some(request.block_size)
+                } else {
+                    None
+                }
+            }
+        }
+        static POLICY: TwoChunks = TwoChunks;
+
+        let va: Deblockator<System, U4096, U4096, U2048, U4096> = Deblockator::new(System)
+            .with_quota(16384)
+            .with_growth_policy(&POLICY);
+
+        let layout = Layout::from_size_align(2000, 8).expect("bad layout");
+        unsafe {
+            let mut ptrs = Vec::new();
+            loop {
+                let ptr = va.alloc(layout);
+                if ptr.is_null() {
+                    break;
+                }
+                ptrs.push(ptr);
+            }
+            let sizes: Vec<_> = va.blocks().map(|block| block.len).collect();
+            assert_eq!(sizes, [12288, 4096]);
+            assert_eq!(va.acquired_bytes(), 16384);
+
+            for ptr in ptrs {
+                va.dealloc(ptr, layout);
+            }
+            assert_eq!(va.flush_caches(), 16384);
+        }
+    }
+
+    #[test]
+    /// Check the heat map renders one character per granule of each block.
+    fn deblockator_heat_map() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> =
+            Deblockator::new(ma).with_payload_alignment(256);
+
+        let layout = Layout::from_size_align(512, 8).expect("bad layout");
+        unsafe {
+            let ptr = va.alloc(layout);
+            let mut map = String::new();
+            va.heat_map(&mut map).unwrap();
+            let start = va.blocks().next().unwrap().start;
+            assert_eq!(
+                map,
+                format!("block {:#x} len 4096\n  ###.............\n", start)
+            );
+            va.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    /// Check the health of the heap follows fragmentation, quota usage and failures.
+    fn deblockator_health() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma)
+            .with_quota(8192)
+            .with_health_thresholds(HealthThresholds {
+                fragmentation: 10,
+                exhaustion: 100,
+                failures: 1,
+            });
+
+        let layout = Layout::from_size_align(256, 8).expect("bad layout");
+        let large = Layout::from_size_align(4096, 8).expect("bad layout");
+        unsafe {
+            let ptrs: Vec<_> = (0..8).map(|_| va.alloc(layout)).collect();
+            assert_eq!(va.health(), Health::Healthy);
+
+            // holes scattered between allocations fragment the heap
+            for ptr in ptrs.iter().step_by(2) {
+                va.dealloc(*ptr, layout);
+            }
+            assert_eq!(va.health(), Health::Fragmented);
+
+            let ptr = va.alloc(large);
+            assert!(!ptr.is_null());
+            assert_eq!(va.health(), Health::NearExhaustion);
+
+            // failures are only reported once
+            assert!(va.alloc(large).is_null());
+            assert_eq!(va.health(), Health::Failing);
+            assert_eq!(va.health(), Health::NearExhaustion);
+
+            va.dealloc(ptr, large);
+            for ptr in ptrs.iter().skip(1).step_by(2) {
+                va.dealloc(*ptr, layout);
+            }
+            assert_eq!(va.health(), Health::Healthy);
+        }
+    }
+
+    #[test]
+    /// Check a leased block serves allocations, and is emptied when returned.
+    fn deblockator_lease_block() {
+        let ma = MockAlloc::new();
+        let va: Deblockator<MockAlloc, U4096, U4096, U2048, U4096> = Deblockator::new(ma);
+
+        let layout = Layout::from_size_align(256, 8).expect("bad layout");
+        unsafe {
+            let ptr = va.alloc(layout);
+            let empty = {
+                // the heap block is in use, so a new one is leased
+                let lease = va.lease_block().unwrap();
+                let empty = lease.info().used;
+                assert_eq!(va.blocks().count(), 1);
+                assert_eq!(va.acquired_bytes(), 8192);
+
+                let mut values: Vec<u64, _> = Vec::with_capacity_in(64, &lease);
+                values.extend(0..64);
+                let leaked = lease.alloc(layout);
+                assert!(!leaked.is_null());
+                assert!(lease.info().used >= empty + 64 * 8 + 256);
+                assert!(!va.blocks().any(|block| block.start == lease.info().start));
+                empty
+            };
+
+            // the lease is freed in bulk, and the empty block leased again
+            assert_eq!(va.blocks().count(), 2);
+            assert_eq!(va.blocks().nth(1).unwrap().used, empty);
+            let lease = va.lease_block().unwrap();
+            assert_eq!(lease.info().used, empty);
+            assert_eq!(va.acquired_bytes(), 8192);
+            drop(lease);
+
+            va.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    /// Check empty heap blocks beyond the limit are returned to the backing allocator.
+    fn deblockator_empty_block_limit() {
+        use std::alloc::System;
+
+        let va: Deblockator<System, U4096, U4096, U2048, U4096> =
+            Deblockator::new(System).with_empty_block_limit(1);
+
+        let layout = Layout::from_size_align(2000, 8).expect("bad layout");
+        unsafe {
+            let ptrs: Vec<_> = (0..3).map(|_| va.alloc(layout)).collect();
+            assert!(ptrs.iter().all(|ptr| !ptr.is_null()));
+            assert_eq!(va.acquired_bytes(), 3 * 4096);
+
+            // a single empty block is kept, the other ones are released
+            va.dealloc(ptrs[0], layout);
+            assert_eq!(va.acquired_bytes(), 3 * 4096);
+            va.dealloc(ptrs[1], layout);
+            assert_eq!(va.acquired_bytes(), 2 * 4096);
+            va.dealloc(ptrs[2], layout);
+            assert_eq!(va.acquired_bytes(), 4096);
+            assert_eq!(va.blocks().count(), 1);
+        }
+    }
+
+    #[test]
+    /// Check trimming releases the empty heap blocks, and keeps the large cache.
+    fn deblockator_trim() {
+        use std::alloc::System;
+
+        let va: Deblockator<System, U4096, U4096, U2048, U4096> =
+            Deblockator::new(System).with_large_cache(8192);
+
+        let layout = Layout::from_size_align(2000, 8).expect("bad layout");
+        let large = Layout::from_size_align(3000, 8).expect("bad layout");
+        unsafe {
+            let ptrs: Vec<_> = (0..3).map(|_| va.alloc(layout)).collect();
+            va.dealloc(va.alloc(large), large);
+            va.dealloc(ptrs[0], layout);
+            va.dealloc(ptrs[2], layout);
+            assert_eq!(va.acquired_bytes(), 3 * 4096 + 4096);
+
+            assert_eq!(va.trim(), 2 * 4096);
+            assert_eq!(va.trim(), 0);
+            assert_eq!(va.blocks().count(), 1);
+            assert_eq!(va.acquired_bytes(), 4096 + 4096);
+
+            va.dealloc(ptrs[1], layout);
+            assert_eq!(va.flush_caches(), 4096 + 4096);
+        }
+    }
+
+    #[test]
+    /// Check the retention policy decides whether empty heap blocks are released.
+    fn deblockator_retention() {
+        use std::alloc::System;
+
+        let layout = Layout::from_size_align(2000, 8).expect("bad layout");
+        for &(retention, kept) in &[
+            (Retention::KeepAll, 2),
+            (Retention::KeepSpare(1), 1),
+            (Retention::KeepSpare(0), 0),
+        ] {
+            let va: Deblockator<System, U4096, U4096, U2048, U4096> =
+                Deblockator::new(System).with_retention(retention);
+            unsafe {
+                let ptrs: Vec<_> = (0..2).map(|_| va.alloc(layout)).collect();
+                for ptr in ptrs {
+                    va.dealloc(ptr, layout);
+                }
+            }
+            assert_eq!(va.blocks().count(), kept, "{:?}", retention);
+            va.flush_caches();
+        }
+    }
+
+    #[test]
+    /// Check allocations made from hooks are served from the emergency region.
+    fn deblockator_reentrant_hooks() {
+        use std::alloc::System;
+
+        static mut MEMORY: [u8; 1024] = [0; 1024];
+        static EMERGENCY: Region = unsafe { Region::from_array(::core::ptr::addr_of_mut!(MEMORY)) };
+        static VA: Deblockator<System, U4096, U4096, U2048, U4096> = Deblockator::new(System)
+            .with_watermarks(Watermarks::new(4096, &[50], log))
+            .with_failure_hook(log_failure, 1)
+            .with_emergency_region(&EMERGENCY);
+        static LOGGED: Mutex<Vec<bool>> = Mutex::new(Vec::new());
+
+        // a logger allocating from the heap it reports on
+        fn log(_level: usize, _up: bool) {
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            unsafe {
+                let ptr = VA.alloc(layout);
+                LOGGED.lock().push(EMERGENCY.contains(ptr));
+                VA.dealloc(ptr, layout);
+            }
+        }
+        fn log_failure(_layout: Layout, _failure: AllocFailure) {
+            log(0, true);
+        }
+
+        let layout = Layout::from_size_align(256, 8).expect("bad layout");
+        unsafe {
+            let ptr = VA.alloc(layout);
+            assert!(!ptr.is_null());
+            VA.set_growth(false);
+            assert!(VA
+                .alloc(Layout::from_size_align(8192, 8).unwrap())
+                .is_null());
+            VA.set_growth(true);
+            VA.dealloc(ptr, layout);
+        }
+        assert_eq!(*LOGGED.lock(), [true, true]);
+    }
+
+    #[test]
+    /// Check frees made from hooks are rejected rather than deadlock on the heap lock.
+    fn deblockator_reentrant_free() {
+        use core::sync::atomic::AtomicPtr;
+        use std::alloc::System;
+
+        static VA: Deblockator<System, U4096, U4096, U2048, U4096> = Deblockator::new(System)
+            .with_watermarks(Watermarks::new(8192, &[50, 100], release))
+            .with_dealloc_error_policy(DeallocErrorPolicy::Callback(record));
+        static HELD: AtomicPtr<u8> = AtomicPtr::new(::core::ptr::null_mut());
+        static ERRORS: Mutex<Vec<DeallocError>> = Mutex::new(Vec::new());
+
+        // a watermark callback trying to give memory back, with the heap locked
+        fn release(_level: usize, _up: bool) {
+            let ptr = HELD.swap(::core::ptr::null_mut(), Ordering::SeqCst);
+            if !ptr.is_null() {
+                unsafe { VA.dealloc(ptr, Layout::from_size_align(64, 8).unwrap()) };
+            }
+        }
+        fn record(_ptr: *mut u8, _layout: Layout, error: DeallocError) {
+            ERRORS.lock().push(error);
+        }
+
+        let small = Layout::from_size_align(64, 8).expect("bad layout");
+        let layout = Layout::from_size_align(2000, 8).expect("bad layout");
+        unsafe {
+            let held = VA.alloc(small);
+            HELD.store(held, Ordering::SeqCst);
+            let ptr = VA.alloc(layout);
+            let other = VA.alloc(layout);
+            assert!(!other.is_null());
+            assert_eq!(*ERRORS.lock(), [DeallocError::Reentrant]);
+
+            // the rejected memory is still allocated, and can be freed normally
+            VA.dealloc(held, small);
+            VA.dealloc(ptr, layout);
+            VA.dealloc(other, layout);
+            assert_eq!(VA.overhead().user, 0);
+        }
+    }
+
+    #[test]
+    /// Check allocations made from hooks are only served from the emergency region of the allocator running them.
+    fn deblockator_reentrant_hooks_owner() {
+        use std::alloc::System;
+
+        static mut MEMORY: [u8; 1024] = [0; 1024];
+        static EMERGENCY: Region = unsafe { Region::from_array(::core::ptr::addr_of_mut!(MEMORY)) };
+        static VA: Deblockator<System, U4096, U4096, U2048, U4096> = Deblockator::new(System)
+            .with_failure_hook(log_failure, 1)
+            .with_emergency_region(&EMERGENCY);
+        static OTHER: Deblockator<System, U4096, U4096, U2048, U4096> = Deblockator::new(System);
+        static LOGGED: Mutex<Vec<bool>> = Mutex::new(Vec::new());
+
+        // a logger allocating from another heap
+        fn log_failure(_layout: Layout, _failure: AllocFailure) {
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            unsafe {
+                let ptr = OTHER.alloc(layout);
+                LOGGED
+                    .lock()
+                    .push(!ptr.is_null() && !EMERGENCY.contains(ptr));
+                OTHER.dealloc(ptr, layout);
+            }
+        }
+
+        VA.set_growth(false);
+        unsafe {
+            assert!(VA
+                .alloc(Layout::from_size_align(8192, 8).unwrap())
+                .is_null());
+        }
+        assert_eq!(*LOGGED.lock(), [true]);
+        assert_eq!(OTHER.blocks().count(), 1);
+    }
+
+    #[test]
+    /// Check groups allocated from hooks are served from the emergency region.
+    fn deblockator_reentrant_group() {
+        use std::alloc::System;
+
+        static mut MEMORY: [u8; 1024] = [0; 1024];
+        static EMERGENCY: Region = unsafe { Region::from_array(::core::ptr::addr_of_mut!(MEMORY)) };
+        static VA: Deblockator<System, U4096, U4096, U2048, U4096> = Deblockator::new(System)
+            .with_failure_hook(log_failure, 1)
+            .with_emergency_region(&EMERGENCY);
+        static LOGGED: Mutex<Vec<bool>> = Mutex::new(Vec::new());
+
+        fn log_failure(_layout: Layout, _failure: AllocFailure) {
+            let layouts = [
+                Layout::from_size_align(24, 8).unwrap(),
+                Layout::from_size_align(40, 8).unwrap(),
+            ];
+            let mut ptrs = [ptr::null_mut(); 2];
+            unsafe {
+                assert!(VA.alloc_group(&layouts, &mut ptrs));
+                LOGGED
+                    .lock()
+                    .extend(ptrs.iter().map(|&ptr| EMERGENCY.contains(ptr)));
+                for (ptr, layout) in ptrs.iter().zip(&layouts) {
+                    VA.dealloc(*ptr, *layout);
+                }
+            }
+        }
+
+        let layout = Layout::from_size_align(256, 8).expect("bad layout");
+        unsafe {
+            let ptr = VA.alloc(layout);
+            VA.set_growth(false);
+            assert!(VA
+                .alloc(Layout::from_size_align(8192, 8).unwrap())
+                .is_null());
+            VA.dealloc(ptr, layout);
+        }
+        assert_eq!(*LOGGED.lock(), [true, true]);
+    }
+
+    #[test]
+    /// Check reserving acquires blocks up front, so that later allocations do not grow the heap.
+    fn deblockator_reserve_bytes() {
+        use std::alloc::System;
+
+        let va: Deblockator<System, U4096, U4096, U2048, U4096> = Deblockator::new(System);
+        let layout = Layout::from_size_align(1000, 8).expect("bad layout");
+
+        assert_eq!(va.reserve(10000), Ok(()));
+        assert_eq!(va.acquired_bytes(), 3 * 4096);
+        assert_eq!(va.reserve(10000), Ok(()));
+        assert_eq!(va.acquired_bytes(), 3 * 4096);
+
+        va.set_growth(false);
+        unsafe {
+            let ptrs: Vec<_> = (0..9).map(|_| va.alloc(layout)).collect();
+            assert!(ptrs.iter().all(|ptr| !ptr.is_null()));
+            for ptr in ptrs {
+                va.dealloc(ptr, layout);
+            }
+        }
+        assert_eq!(va.reserve(20000), Err(AllocFailure::NoGrowth));
+    }
+
+    #[test]
+    /// Check aligned heap blocks are found by masking pointers.
+    fn deblockator_aligned_blocks() {
+        use std::alloc::System;
+        use typenum::consts::U16;
+
+        let va: Deblockator<System, U4096, U16, U2048, U4096> = Deblockator::new(System)
+            .with_exponential_growth(4 * 4096)
+            .with_aligned_blocks();
+
+        let layout = Layout::from_size_align(2000, 8).expect("bad layout");
+        unsafe {
+            let ptrs: Vec<_> = (0..4).map(|_| va.alloc(layout)).collect();
+            assert!(ptrs.iter().all(|ptr| !ptr.is_null()));
+
+            // every block is made of a single chunk aligned to its size
+            assert_eq!(va.blocks().count(), 4);
+            assert!(va
+                .blocks()
+                .all(|block| block.start % 4096 == 0 && block.len == 4096));
+            for ptr in ptrs.iter() {
+                let owner = va.find_allocation_block(*ptr).expect("no owner");
+                assert_eq!(owner as usize, *ptr as usize & !4095);
+            }
+
+            for ptr in ptrs.iter().rev() {
+                va.dealloc(*ptr, layout);
+            }
+            assert_eq!(va.trim(), 4 * 4096);
+        }
+    }
+
+    #[test]
+    /// Check lookups with aligned heap blocks do not mask pointers outside of the heap blocks.
+    fn deblockator_aligned_blocks_lookup() {
+        use std::alloc::System;
+        use typenum::consts::U16;
+
+        let va: Deblockator<System, U4096, U16, U2048, U4096> =
+            Deblockator::new(System).with_aligned_blocks();
+
+        let small = Layout::from_size_align(64, 8).expect("bad layout");
+        let large = Layout::from_size_align(3000, 8).expect("bad layout");
+        unsafe {
+            let ptr1 = va.alloc(small);
+            let ptr2 = va.alloc(large);
+            assert!((ptr2 as usize).is_multiple_of(4096));
+            // the large allocation would pass for a heap block holding the pointer
+            ptr2.write_bytes(0x10, large.size());
+
+            assert!(va.block_metadata(ptr1).is_some());
+            assert_eq!(va.block_metadata(ptr2.add(8)), None);
+            assert_eq!(va.block_generation(ptr2.add(8)), None);
+            assert!(!va.set_block_metadata(ptr2.add(8), 1));
+            va.dealloc(ptr2, large);
+            va.dealloc(ptr1, small);
+        }
+    }
+
+    #[test]
+    /// Check the memory of the allocator is split between user data and overhead.
+    fn deblockator_overhead() {
+        use std::alloc::System;
+
+        let va: Deblockator<System, U4096, U4096, U2048, U4096> = Deblockator::new(System);
+        let header = size_of::<HeapBlock<U4096>>();
+
+        let small = Layout::from_size_align(100, 8).expect("bad layout");
+        let large = Layout::from_size_align(3000, 8).expect("bad layout");
+        unsafe {
+            let a = va.alloc(small);
+            let b = va.alloc(large);
+            let overhead = va.overhead();
+            assert_eq!(overhead.user, 3100);
+            assert_eq!(overhead.headers, header);
+            // rounded up to a multiple of the hole alignment, and to the large alignment
+            assert_eq!(overhead.padding, 4 + 1096);
+            assert_eq!(overhead.free, 4096 - header - 104);
+            assert_eq!(overhead.total(), va.acquired_bytes());
+
+            va.dealloc(a, small);
+            va.dealloc(b, large);
+            let overhead = va.overhead();
+            assert_eq!(overhead.user, 0);
+            assert_eq!(overhead.metadata(), header);
+            assert_eq!(overhead.total(), va.acquired_bytes());
+        }
+    }
+
+    #[test]
+    /// Check heap blocks are found through the registry, even when it overflows.
+    fn deblockator_block_registry() {
+        use std::alloc::System;
+
+        let entries = Box::leak(Box::new([0usize; 2]));
+        let registry: &'static BlockRegistry =
+            Box::leak(Box::new(unsafe { BlockRegistry::from_array(entries) }));
+        let va: Deblockator<System, U4096, U4096, U2048, U4096> =
+            Deblockator::new(System).with_block_registry(registry);
+
+        let layout = Layout::from_size_align(2000, 8).expect("bad layout");
+        unsafe {
+            let ptrs: Vec<_> = (0..3).map(|_| va.alloc(layout)).collect();
+            assert!(ptrs.iter().all(|ptr| !ptr.is_null()));
+            assert_eq!(registry.len(), 2);
+            assert!(!registry.is_complete());
+            assert!(ptrs.iter().all(|ptr| va.block_generation(*ptr).is_some()));
+
+            // the registry is rebuilt once a block is released
+            va.dealloc(ptrs[0], layout);
+            assert_eq!(va.trim(), 4096);
+            assert!(registry.is_complete());
+            assert_eq!(registry.len(), 2);
+
+            va.dealloc(ptrs[1], layout);
+            va.dealloc(ptrs[2], layout);
+            assert_eq!(va.trim(), 2 * 4096);
+            assert!(registry.is_empty());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "allocate_at_least")]
+    /// Check an allocation takes the whole hole rather than leave a too small one.
+    fn deblockator_allocate_at_least() {
+        use std::alloc::System;
+
+        let va: Deblockator<System, U4096, U4096, U2048, U4096> =
+            Deblockator::new(System).with_min_split(256);
+        let header = size_of::<HeapBlock<U4096>>();
+
+        // leave a hole of 1024 bytes in the block
+        let filler = Layout::from_size_align(1024, 8).expect("bad layout");
+        let rest = Layout::from_size_align(4096 - header - 2048, 8).expect("bad layout");
+        let layout = Layout::from_size_align(1024 - 200, 8).expect("bad layout");
+        unsafe {
+            let fill = va.alloc(filler);
+            let fill_rest = va.alloc(rest);
+            let ptr = va.allocate_at_least(layout).expect("could not allocate");
+            assert_eq!(ptr.len(), 1024);
+            assert_eq!(va.overhead().user, 4096 - header);
+            assert_eq!(va.acquired_bytes(), 4096);
+
+            // the actual size frees the whole hole
+            let actual = Layout::from_size_align(ptr.len(), 8).expect("bad layout");
+            va.deallocate(ptr.cast(), actual);
+            va.dealloc(fill, filler);
+            va.dealloc(fill_rest, rest);
+            assert_eq!(va.overhead().user, 0);
+            assert_eq!(va.blocks().next().unwrap().holes, 1);
+
+            // large layouts are allocated with their exact size
+            let large = Layout::from_size_align(5000, 8).expect("bad layout");
+            let ptr = va.allocate_at_least(large).expect("could not allocate");
+            assert_eq!(ptr.len(), 5000);
+            va.deallocate(ptr.cast(), large);
+        }
+    }
+
+    #[test]
+    /// Check the heap is verified once every few operations.
+    fn deblockator_verification() {
+        use std::alloc::System;
+
+        static CORRUPTED: AtomicUsize = AtomicUsize::new(0);
+        fn report(block: usize) {
+            CORRUPTED.store(block, Ordering::Relaxed);
+        }
+
+        let va: Deblockator<System, U4096, U4096, U2048, U4096> =
+            Deblockator::new(System).with_verification(report, 3);
+
+        let layout = Layout::from_size_align(64, 8).expect("bad layout");
+        unsafe {
+            let ptr = va.alloc(layout);
+            va.dealloc(ptr, layout);
+            va.dealloc(va.alloc(layout), layout);
+            assert_eq!(CORRUPTED.load(Ordering::Relaxed), 0);
+
+            // corrupt the hole list of the block: the next sampled operation reports it
+            let block = (*va.first_block.get()).as_deref_mut().unwrap();
+            block.first.next.as_deref_mut().unwrap().size = 1;
+            let addr = block as *mut HeapBlock<U4096> as usize;
+            va.alloc(layout);
+            assert_eq!(CORRUPTED.load(Ordering::Relaxed), 0);
+            va.alloc(layout);
+            assert_eq!(CORRUPTED.load(Ordering::Relaxed), addr);
         }
     }
 }
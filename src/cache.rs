@@ -0,0 +1,95 @@
+//! Cache of recently freed large allocations.
+
+use core::alloc::Layout;
+use core::ptr::NonNull;
+
+/// The maximum number of large allocations kept in the cache.
+const CACHE_ENTRIES: usize = 8;
+
+/// A cache of large allocations, waiting to be returned to the backing allocator.
+///
+/// Allocations are cached with the exact layout used to obtain them from the
+/// backing allocator, so a cached allocation is only reused for an identical
+/// layout, and can always be released with the layout it was stored with.
+pub struct LargeCache {
+    entries: [Option<(NonNull<u8>, Layout)>; CACHE_ENTRIES],
+    bytes: usize,
+    limit: usize,
+}
+
+impl LargeCache {
+    /// Create a new cache holding at most `limit` bytes.
+    pub const fn new(limit: usize) -> Self {
+        LargeCache {
+            entries: [None; CACHE_ENTRIES],
+            bytes: 0,
+            limit,
+        }
+    }
+
+    /// Returns the number of bytes currently held in the cache.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Take a cached allocation with the given layout, if any.
+    pub fn take(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        for entry in self.entries.iter_mut() {
+            if let Some((ptr, cached)) = *entry {
+                if cached == layout {
+                    *entry = None;
+                    self.bytes -= layout.size();
+                    return Some(ptr);
+                }
+            }
+        }
+        None
+    }
+
+    /// Store a freed allocation in the cache.
+    ///
+    /// Returns `false` if the allocation does not fit in the cache, in which
+    /// case it must be returned to the backing allocator.
+    pub fn store(&mut self, ptr: NonNull<u8>, layout: Layout) -> bool {
+        if layout.size() > self.limit - self.bytes {
+            return false;
+        }
+        match self.entries.iter_mut().find(|entry| entry.is_none()) {
+            Some(entry) => {
+                *entry = Some((ptr, layout));
+                self.bytes += layout.size();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove all allocations from the cache.
+    pub fn drain(&mut self) -> Drain<'_> {
+        Drain {
+            cache: self,
+            index: 0,
+        }
+    }
+}
+
+/// An iterator over the allocations removed from a `LargeCache`.
+pub struct Drain<'a> {
+    cache: &'a mut LargeCache,
+    index: usize,
+}
+
+impl<'a> Iterator for Drain<'a> {
+    type Item = (NonNull<u8>, Layout);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.index < CACHE_ENTRIES {
+            self.index += 1;
+            if let Some((ptr, layout)) = self.cache.entries[self.index - 1].take() {
+                self.cache.bytes -= layout.size();
+                return Some((ptr, layout));
+            }
+        }
+        None
+    }
+}
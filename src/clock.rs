@@ -0,0 +1,37 @@
+//! Time sources for time-based allocation policies.
+
+use core::fmt;
+
+/// A monotonic clock used by time-based policies.
+///
+/// The unit of the returned timestamps is up to the implementation, and
+/// durations given to the policies using a clock are expressed in the same
+/// unit (for instance, milliseconds or system ticks).
+pub trait Clock: Sync {
+    /// Returns the current timestamp.
+    fn now(&self) -> u64;
+}
+
+impl fmt::Debug for dyn Clock + '_ {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Clock")
+    }
+}
+
+/// A clock using the monotonic system clock, in milliseconds.
+///
+/// Timestamps are counted from the first time a `StdClock` is read, so they
+/// are not affected by changes of the wall-clock time.
+#[cfg(feature = "std")]
+pub struct StdClock;
+
+#[cfg(feature = "std")]
+impl Clock for StdClock {
+    fn now(&self) -> u64 {
+        static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+        START
+            .get_or_init(std::time::Instant::now)
+            .elapsed()
+            .as_millis() as u64
+    }
+}
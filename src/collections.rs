@@ -0,0 +1,72 @@
+//! Collections allocated in a specific allocator instance.
+//!
+//! These helpers build `Box` and `Vec` values using a given allocator (for
+//! instance, a reference to a `Deblockator` dedicated to a subsystem)
+//! instead of the global allocator. They require the `alloc` feature.
+
+use core::alloc::Allocator;
+
+use super::__alloc::boxed::Box;
+
+/// Move a value to the heap of the given allocator.
+///
+/// # Example
+/// ```rust,ignore
+/// let heap: Deblockator<System> = Deblockator::new(System);
+/// let value = deblockator::boxed_in(42, &heap);
+/// ```
+pub fn boxed_in<T, A: Allocator>(value: T, alloc: A) -> Box<T, A> {
+    Box::new_in(value, alloc)
+}
+
+/// Create a `Vec` using the given allocator.
+///
+/// The allocator comes first, followed by a semicolon and the same element
+/// syntax as the `vec!` macro of the standard library.
+///
+/// # Example
+/// ```rust,ignore
+/// let heap: Deblockator<System> = Deblockator::new(System);
+/// let primes = vec_in![&heap; 2, 3, 5, 7];
+/// let zeros = vec_in![&heap; 0u8; 512];
+/// ```
+#[macro_export]
+macro_rules! vec_in {
+    ($alloc:expr; $elem:expr; $n:expr) => {{
+        let n = $n;
+        let mut v = $crate::__alloc::vec::Vec::with_capacity_in(n, $alloc);
+        v.resize(n, $elem);
+        v
+    }};
+    ($alloc:expr; $($x:expr),* $(,)?) => {{
+        #[allow(unused_mut)]
+        let mut v = $crate::__alloc::vec::Vec::new_in($alloc);
+        $(v.push($x);)*
+        v
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::System;
+
+    use crate::Deblockator;
+
+    #[test]
+    /// Check boxes and vectors are allocated in the given allocator.
+    fn collections_in_deblockator() {
+        let heap: Deblockator<System> = Deblockator::new(System);
+
+        let boxed = boxed_in([1u64; 4], &heap);
+        let primes = vec_in![&heap; 2, 3, 5, 7];
+        let zeros = vec_in![&heap; 0u8; 512];
+        let empty: crate::__alloc::vec::Vec<u32, _> = vec_in![&heap;];
+
+        assert_eq!(boxed.iter().sum::<u64>(), 4);
+        assert_eq!(primes, [2, 3, 5, 7]);
+        assert_eq!(zeros.len(), 512);
+        assert!(empty.is_empty());
+        assert_eq!(heap.blocks().count(), 1);
+    }
+}
@@ -0,0 +1,40 @@
+//! Platform-dependent default parameters.
+//!
+//! The defaults target 32 and 64-bit platforms, where a `64kB` heap block is
+//! a reasonable trade-off. On 16-bit targets (such as the MSP430 or the AVR),
+//! a single such block would span the entire address space, so much smaller
+//! defaults are used instead.
+
+#[cfg(not(target_pointer_width = "16"))]
+use typenum::consts::{U16384, U4096, U65536};
+
+#[cfg(target_pointer_width = "16")]
+use typenum::consts::{U1024, U16, U256};
+
+/// The default size of a heap block.
+#[cfg(not(target_pointer_width = "16"))]
+pub type DefaultBlockSize = U65536;
+/// The default size of a heap block.
+#[cfg(target_pointer_width = "16")]
+pub type DefaultBlockSize = U1024;
+
+/// The default alignment of a heap block.
+#[cfg(not(target_pointer_width = "16"))]
+pub type DefaultBlockAlign = U4096;
+/// The default alignment of a heap block.
+#[cfg(target_pointer_width = "16")]
+pub type DefaultBlockAlign = U16;
+
+/// The default size above which layouts are allocated in a dedicated block.
+#[cfg(not(target_pointer_width = "16"))]
+pub type DefaultLargeSize = U16384;
+/// The default size above which layouts are allocated in a dedicated block.
+#[cfg(target_pointer_width = "16")]
+pub type DefaultLargeSize = U256;
+
+/// The default alignment of a dedicated large block.
+#[cfg(not(target_pointer_width = "16"))]
+pub type DefaultLargeAlign = U4096;
+/// The default alignment of a dedicated large block.
+#[cfg(target_pointer_width = "16")]
+pub type DefaultLargeAlign = U16;
@@ -0,0 +1,125 @@
+//! Type-erased access to a `Deblockator`.
+//!
+//! The [`DynDeblockator`] trait is implemented by every `Deblockator`, and
+//! can be used as a trait object, so that code holding a reference to an
+//! allocator does not need to know its backing allocator or parameters.
+//!
+//! [`DynDeblockator`]: trait.DynDeblockator.html
+
+use core::alloc::AllocError;
+use core::alloc::Allocator;
+use core::alloc::GlobalAlloc;
+use core::alloc::Layout;
+use core::ptr::NonNull;
+
+use typenum::PowerOfTwo;
+use typenum::Unsigned;
+
+use super::alloc::Deblockator;
+use super::hole::BlockInfo;
+use super::stats::Overhead;
+
+/// An object-safe interface to a `Deblockator`.
+///
+/// Allocations go through the `GlobalAlloc` supertrait, and a
+/// `&dyn DynDeblockator` also implements `Allocator`, so it can back
+/// collections directly.
+pub trait DynDeblockator: GlobalAlloc + Sync {
+    /// Returns the number of bytes currently obtained from the backing allocator.
+    fn acquired_bytes(&self) -> usize;
+
+    /// Returns how the memory held by the allocator is split between user data and overhead.
+    fn overhead(&self) -> Overhead;
+
+    /// Call the given function with the information of every heap block.
+    fn for_each_block(&self, f: &mut dyn FnMut(BlockInfo));
+
+    /// Perform the allocator housekeeping.
+    fn maintain(&self) -> usize;
+
+    /// Release every empty heap block to the backing allocator.
+    fn trim(&self) -> usize;
+
+    /// Return all cached memory to the backing allocator.
+    fn flush_caches(&self) -> usize;
+}
+
+impl<A, BS, BA, LS, LA> DynDeblockator for Deblockator<A, BS, BA, LS, LA>
+where
+    A: Allocator,
+    BS: Unsigned + 'static,
+    BA: Unsigned + PowerOfTwo,
+    LS: Unsigned,
+    LA: Unsigned + PowerOfTwo,
+{
+    fn acquired_bytes(&self) -> usize {
+        Deblockator::acquired_bytes(self)
+    }
+
+    fn overhead(&self) -> Overhead {
+        Deblockator::overhead(self)
+    }
+
+    fn for_each_block(&self, f: &mut dyn FnMut(BlockInfo)) {
+        self.blocks().for_each(f)
+    }
+
+    fn maintain(&self) -> usize {
+        Deblockator::maintain(self)
+    }
+
+    fn trim(&self) -> usize {
+        Deblockator::trim(self)
+    }
+
+    fn flush_caches(&self) -> usize {
+        Deblockator::flush_caches(self)
+    }
+}
+
+unsafe impl Allocator for dyn DynDeblockator + '_ {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        // zero-sized layouts must not reach `GlobalAlloc`, and need no memory
+        if layout.size() == 0 {
+            let dangling = NonNull::new(layout.align() as *mut u8).ok_or(AllocError)?;
+            return Ok(NonNull::slice_from_raw_parts(dangling, 0));
+        }
+        let ptr = unsafe { self.alloc(layout) };
+        NonNull::new(ptr)
+            .map(|ptr| NonNull::slice_from_raw_parts(ptr, layout.size()))
+            .ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            self.dealloc(ptr.as_ptr(), layout);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::System;
+
+    #[test]
+    /// Check a `Deblockator` can be used through a trait object.
+    fn dyn_deblockator() {
+        let heap: Deblockator<System> = Deblockator::new(System);
+        let heap: &dyn DynDeblockator = &heap;
+
+        let mut v = Vec::new_in(heap);
+        v.extend(0..100u32);
+        let mut blocks = 0;
+        heap.for_each_block(&mut |block| {
+            assert!(block.used > 400);
+            blocks += 1;
+        });
+        assert_eq!(blocks, 1);
+        assert!(heap.acquired_bytes() > 0);
+
+        drop(v);
+        heap.flush_caches();
+        assert_eq!(heap.acquired_bytes(), 0);
+    }
+}
@@ -5,40 +5,155 @@
 
 use core::alloc::AllocError;
 use core::alloc::Layout;
+use core::cmp::max;
+use core::cmp::min;
+use core::cmp::Ordering;
+use core::fmt;
 use core::marker::PhantomData;
+use core::mem::align_of;
 use core::mem::size_of;
+use core::mem::MaybeUninit;
 use core::ptr::NonNull;
 
 use typenum::consts::U1;
-use typenum::consts::U65536;
 use typenum::Unsigned;
 
+use super::consts::DefaultBlockSize;
+use super::policy::DeallocError;
+use super::policy::Fit;
+use super::shared::SharedRegion;
+use super::tree::SizeTree;
+use super::tree::MIN_INDEXED_SIZE;
+use super::utils::align_down;
 use super::utils::align_up;
+use super::utils::checked_align_up;
+
+// The block header is followed by the first hole, so it must keep holes aligned.
+// On 32-bit targets with an 8-byte aligned `u64` (such as `wasm32`), the
+// header is padded before `idle_since`.
+const _: () = assert!(
+    size_of::<HeapBlock<U1>>()
+        == (size_of::<[usize; 26]>() + size_of::<u64>()).next_multiple_of(align_of::<u64>())
+);
+const _: () = assert!(align_of::<HeapBlock<U1>>() >= align_of::<usize>());
+const _: () = assert!(size_of::<HeapBlock<U1>>().is_multiple_of(align_of::<Hole>()));
+
+/// The number of segregated free lists of a heap block.
+///
+/// The list `k` holds chunks of `min_size << k` bytes up to twice that, so
+/// that chunks up to `256 * min_size` bytes are segregated.
+const SIZE_CLASSES: usize = 8;
+
+/// The number of recently freed chunks kept in the quick list of a heap block.
+const QUICK_SLOTS: usize = 4;
+
+/// The number of small-object slots of a heap block, one bit of the bitmap each.
+const SMALL_SLOTS: usize = 32;
+
+/// The size of a small-object slot, which is also its alignment.
+const SMALL_SLOT_SIZE: usize = 16;
+
+/// The largest allocation served from small-object slots, taking contiguous slots.
+const SMALL_OBJECT_SIZE: usize = 4 * SMALL_SLOT_SIZE;
+
+// A hole must fit in the smallest allocation, and be word-aligned.
+const _: () = assert!(size_of::<Hole>() == 2 * size_of::<usize>());
+const _: () = assert!(align_of::<Hole>() == align_of::<usize>());
 
 /// A heap block.
-pub struct HeapBlock<BS = U65536>
+///
+/// The header is `repr(C)`, and is followed by the first hole in the block
+/// memory, possibly after some padding (see [`new_aligned`](#method.new_aligned)). A heap block is made of one or more contiguous chunks
+/// of the same size (`BS` bytes unless created with [`new_sized`](#method.new_sized))
+/// obtained from the backing allocator.
+#[repr(C)]
+pub struct HeapBlock<BS = DefaultBlockSize>
 where
     BS: 'static + Unsigned,
 {
     __block_size: PhantomData<BS>,
     pub next: Option<&'static mut HeapBlock<BS>>, // a reference to the next heap block.
+    pub size: usize,                              // the total size of this heap block.
+    pub chunk: usize,                             // the size of the chunks making this heap block.
+    pub metadata: usize,                          // user-defined metadata for this heap block.
+    pub payload: usize,                           // the offset of the first usable byte.
     pub first: Hole,                              // a reference to the next hole in this heap.
+    pub generation: usize,                        // bumped each time this heap block is emptied.
+    pub cursor: usize,                            // the address where next-fit searches resume.
+    pub shared: Option<&'static SharedRegion>,    // the region lending this heap block, if any.
+    pub free_lists: Option<&'static mut FreeLists>, // the segregated free lists, if any.
+    pub small: Option<&'static mut SmallSlots>,   // the small-object slots, if any.
+    pub clean: usize,                             // the address from which memory was never used.
+    pub min_split: usize,                         // the smallest hole left by splitting a hole.
+    pub quick: Option<&'static mut Hole>,         // the recently freed chunks, most recent first.
+    pub free: usize,                              // the number of bytes in the hole list.
+    pub largest: usize,                           // an upper bound of the largest hole size.
+    pub tree: Option<SizeTree>,                   // the holes ordered by size, if indexed.
+    pub idle_since: u64,                          // the time this heap block became empty.
 }
 
 impl<BS> HeapBlock<BS>
 where
     BS: Unsigned,
 {
+    /// The `idle_since` value of a heap block that is not empty.
+    pub const BUSY: u64 = u64::MAX;
+
     /// Create a new heap block stored at the given location.
-    /// FIXME: use constant block size ?
-    pub unsafe fn new(block_ptr: NonNull<HeapBlock>) -> &'static mut HeapBlock {
+    ///
+    /// See [`from_slice`](#method.from_slice) for a safe version.
+    ///
+    /// # Safety
+    ///
+    /// The location must be valid for writes of `BS` bytes, aligned for a
+    /// `HeapBlock`, and must not be used by anything else for the rest of
+    /// the program.
+    pub unsafe fn new(block_ptr: NonNull<HeapBlock<BS>>) -> &'static mut HeapBlock<BS> {
+        Self::new_aligned(block_ptr, align_of::<Hole>())
+    }
+
+    /// Create a new heap block whose usable memory starts on the given alignment.
+    ///
+    /// The memory between the header and the first aligned address is left
+    /// unused, so that allocations can start on a page boundary for instance.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`new`](#method.new), and the aligned start of the usable
+    /// memory must leave room for at least `min_size` bytes in the block.
+    pub unsafe fn new_aligned(
+        block_ptr: NonNull<HeapBlock<BS>>,
+        align: usize,
+    ) -> &'static mut HeapBlock<BS> {
+        Self::new_sized(block_ptr, align, BS::to_usize())
+    }
+
+    /// Create a new heap block of `size` bytes, instead of `BS`.
+    ///
+    /// The block is then extended by chunks of `size` bytes as well.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`new_aligned`](#method.new_aligned), with the location valid
+    /// for writes of `size` bytes instead of `BS`.
+    pub unsafe fn new_sized(
+        block_ptr: NonNull<HeapBlock<BS>>,
+        align: usize,
+        size: usize,
+    ) -> &'static mut HeapBlock<BS> {
         // The first hole comes right after the HeapBlock data in the
-        // block, so we shift the block_ptr offset by size_of::<HeapBlock>()
-        let hole_ptr = block_ptr.as_ptr().add(1) as *mut Hole; // FIXME ?
+        // block, at the first address with the required alignment
+        let block_addr = block_ptr.as_ptr() as usize;
+        let hole_addr = align_up(
+            block_addr + size_of::<Self>(),
+            max(align, align_of::<Hole>()),
+        );
+        let payload = hole_addr - block_addr;
+        let hole_ptr = hole_addr as *mut Hole;
 
         // Write the hole data
         hole_ptr.write(Hole {
-            size: BS::to_usize() - size_of::<HeapBlock>(),
+            size: size - payload,
             next: None,
         });
 
@@ -46,15 +161,128 @@ where
         block_ptr.as_ptr().write(HeapBlock {
             __block_size: PhantomData,
             next: None,
+            size,
+            chunk: size,
+            metadata: 0,
+            payload,
             first: Hole {
                 size: 0,
                 next: Some(&mut *hole_ptr),
             },
+            generation: 0,
+            cursor: 0,
+            shared: None,
+            free_lists: None,
+            small: None,
+            clean: hole_addr,
+            min_split: Self::min_size(),
+            quick: None,
+            free: size - payload,
+            largest: size - payload,
+            tree: None,
+            idle_since: Self::BUSY,
         });
 
         &mut *block_ptr.as_ptr()
     }
 
+    /// Create a new heap block in the given memory region.
+    ///
+    /// Only the first `BS` bytes of the region are used. Fails if the region
+    /// is smaller than that, or not aligned for a `HeapBlock`.
+    pub fn from_slice(
+        region: &'static mut [MaybeUninit<u8>],
+    ) -> Result<&'static mut HeapBlock<BS>, AllocError> {
+        let block_size = BS::to_usize();
+        if block_size < size_of::<Self>() + Self::min_size() || region.len() < block_size {
+            return Err(AllocError);
+        }
+        if !(region.as_ptr() as usize).is_multiple_of(align_of::<Self>()) {
+            return Err(AllocError);
+        }
+
+        // the region is borrowed for 'static, so it can't be used elsewhere
+        let block_ptr =
+            NonNull::new(region.as_mut_ptr() as *mut HeapBlock<BS>).ok_or(AllocError)?;
+        Ok(unsafe { Self::new(block_ptr) })
+    }
+
+    /// Extend the heap block with the chunk following its current end.
+    ///
+    /// The new memory is added as a hole, merged with the last hole of the
+    /// block if they are adjacent, so that allocations can span the former
+    /// block boundary.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be valid for writes, and must not be used by anything
+    /// else for the rest of the program.
+    pub unsafe fn extend(&mut self) {
+        let end = self.end().expect("heap block at the top of memory");
+        self.release(end, self.chunk)
+            .expect("heap block extended twice");
+        self.size += self.chunk;
+    }
+
+    /// Free all the allocations of the heap block at once.
+    ///
+    /// The free lists, small-object slots and quick list are dropped, and
+    /// the whole payload becomes a single hole. The size, chunks, metadata
+    /// and generation of the block are kept.
+    ///
+    /// # Safety
+    ///
+    /// The memory previously allocated in the block must not be used anymore.
+    pub unsafe fn reset(&mut self) {
+        let hole_ptr = (self as *mut Self as *mut u8).add(self.payload) as *mut Hole;
+        hole_ptr.write(Hole {
+            size: self.size - self.payload,
+            next: None,
+        });
+        self.first.next = Some(&mut *hole_ptr);
+        if let Some(tree) = self.tree.as_mut() {
+            tree.clear();
+            tree.insert((*hole_ptr).info());
+        }
+        self.free = self.size - self.payload;
+        self.largest = self.free;
+        self.cursor = 0;
+        self.free_lists = None;
+        self.small = None;
+        self.quick = None;
+    }
+
+    /// Index the holes of the heap block by size, so that best-fit searches find the smallest
+    /// hole large enough in `O(log n)` instead of scanning the whole list. Holes are filed by the
+    /// alignment of their start, so over-aligned layouts only check the holes that can hold them.
+    ///
+    /// Each hole then stores a tree node, so holes must stay at least
+    /// [`min_indexed_size`](#method.min_indexed_size) bytes large: the minimum split size is
+    /// raised to it, and allocations made and freed in the block must be as large.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a hole of the block is too small to be indexed.
+    pub fn index_holes(&mut self) {
+        let mut tree = SizeTree::new();
+        for hole in self.holes() {
+            assert!(
+                hole.size >= MIN_INDEXED_SIZE,
+                "hole too small to be indexed"
+            );
+            // the hole is in the list, and is not linked twice
+            unsafe { tree.insert(hole) };
+        }
+        self.min_split = max(self.min_split, MIN_INDEXED_SIZE);
+        self.tree = Some(tree);
+    }
+
+    /// Returns the address of the first byte after the heap block, or `None` if the heap block
+    /// ends at the top of the address space.
+    pub fn end(&self) -> Option<usize> {
+        (self as *const Self as usize).checked_add(self.size)
+    }
+
     /// Searches the list for a big enough hole. A hole is big enough if it can hold an allocation
     /// of `layout.size()` bytes with the given `layout.align()`. If such a hole is found in the
     /// list, a block of the required size is allocated from it. Then the start address of that
@@ -62,67 +290,966 @@ where
     ///
     /// This function uses the “first fit” strategy, so it uses the first hole that is big
     /// enough. Thus the runtime is in O(n) but it should be reasonably fast for small allocations.
+    /// Blocks that [cannot fit](#method.cannot_fit) the layout are skipped without walking the
+    /// list.
     pub fn allocate_first_fit(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
         assert!(layout.size() >= Self::min_size());
+        if self.cannot_fit(layout) {
+            return Err(AllocError);
+        }
+        match allocate_first_fit(&mut self.first, layout, self.min_split) {
+            Ok(allocation) => Ok(self.take(allocation)),
+            Err(error) => {
+                self.refresh_largest();
+                Err(error)
+            }
+        }
+    }
+
+    /// Check if no hole of the heap block can be large enough for `layout`.
+    ///
+    /// This only compares the layout with the [`free`](#structfield.free) bytes and the
+    /// [`largest`](#structfield.largest) hole bound, without walking the hole list, so a `false`
+    /// result does not mean an allocation will succeed.
+    pub fn cannot_fit(&self, layout: Layout) -> bool {
+        layout.size() > min(self.free, self.largest)
+    }
+
+    /// Lowers the largest hole bound to the size of the actual largest hole.
+    ///
+    /// Freed holes only raise the bound and allocations leave it as is, so it is refreshed after
+    /// a search of the whole list failed.
+    fn refresh_largest(&mut self) {
+        self.largest = match self.tree.as_ref() {
+            Some(tree) => tree.largest(),
+            None => self.holes().map(|hole| hole.size).max().unwrap_or(0),
+        };
+    }
+
+    /// Removes the hole of the given allocation from the free bytes, frees its padding back into
+    /// the hole list, and returns its start address.
+    fn take(&mut self, allocation: Allocation) -> NonNull<u8> {
+        let padding = |padding: Option<HoleInfo>| padding.map_or(0, |padding| padding.size);
+        let hole = HoleInfo {
+            addr: allocation
+                .front_padding
+                .map_or(allocation.info.addr, |padding| padding.addr),
+            size: allocation.info.size
+                + padding(allocation.front_padding)
+                + padding(allocation.back_padding),
+        };
+        if let Some(tree) = self.tree.as_mut() {
+            // the hole was just unlinked from the list, and its padding is not freed yet
+            unsafe { tree.remove(hole) };
+        }
+        self.free -= hole.size;
+        self.place(allocation)
+    }
+
+    /// Frees the padding of the given allocation back into the hole list, and returns its start
+    /// address.
+    fn place(&mut self, allocation: Allocation) -> NonNull<u8> {
+        if let Some(padding) = allocation.front_padding {
+            self.release(padding.addr, padding.size)
+                .expect("padding is not free");
+        }
+        if let Some(padding) = allocation.back_padding {
+            self.release(padding.addr, padding.size)
+                .expect("padding is not free");
+        }
+        NonNull::new(allocation.info.addr as *mut u8).unwrap()
+    }
+
+    /// Allocates a block of memory for the internal tables of the heap block, using the
+    /// “first fit” strategy.
+    fn allocate_table(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        let min_split = self.min_hole();
+        let allocation = allocate_first_fit(&mut self.first, layout, min_split)?;
+        Ok(self.take(allocation))
+    }
+
+    /// Frees `size` bytes at `addr` into the hole list, and counts them as free.
+    fn release(&mut self, addr: usize, size: usize) -> Result<(), DeallocError> {
+        let merged = deallocate_merged(&mut self.first, addr, size)?;
+        if let Some(tree) = self.tree.as_mut() {
+            // the holes merged with the freed memory still have their nodes
+            let before = addr - merged.addr;
+            let after = merged.size - before - size;
+            unsafe {
+                if before > 0 {
+                    tree.remove(HoleInfo {
+                        addr: merged.addr,
+                        size: before,
+                    });
+                }
+                if after > 0 {
+                    tree.remove(HoleInfo {
+                        addr: addr + size,
+                        size: after,
+                    });
+                }
+                tree.insert(merged);
+            }
+        }
+        self.free += size;
+        self.largest = max(self.largest, merged.size);
+        Ok(())
+    }
+
+    /// Searches the list for a big enough hole, like [`allocate_first_fit`], but uses the whole
+    /// hole when splitting it would leave a hole smaller than `min_split` bytes. Then the start
+    /// address of the allocation is returned, along with its actual size.
+    ///
+    /// [`allocate_first_fit`]: #method.allocate_first_fit
+    #[cfg(feature = "allocate_at_least")]
+    pub fn allocate_at_least(
+        &mut self,
+        layout: Layout,
+    ) -> Result<(NonNull<u8>, usize), AllocError> {
+        assert!(layout.size() >= Self::min_size());
+        if self.cannot_fit(layout) {
+            return Err(AllocError);
+        }
+        let allocation = allocate_first_fit_with(&mut self.first, layout, self.min_split, true)?;
+        let size = allocation.info.size;
+        Ok((self.take(allocation), size))
+    }
 
-        allocate_first_fit(&mut self.first, layout).map(|allocation| {
-            if let Some(padding) = allocation.front_padding {
-                deallocate(&mut self.first, padding.addr, padding.size);
+    /// Searches the list for the smallest hole big enough to hold an allocation of `layout`, and
+    /// allocates a block of the required size from it. Then the start address of that block is
+    /// returned.
+    ///
+    /// This function uses the “best fit” strategy, so it always scans the whole list (unless a
+    /// hole of the exact size is found), but it leaves larger holes for larger allocations. If the
+    /// holes are [indexed](#method.index_holes), the hole is found in the size tree instead.
+    pub fn allocate_best_fit(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        assert!(layout.size() >= Self::min_size());
+        if self.cannot_fit(layout) {
+            return Err(AllocError);
+        }
+        if let Some(tree) = self.tree.as_ref() {
+            let min_split = self.min_split;
+            let hole = tree.find(layout, min_split, |hole| {
+                split_hole(hole, layout, min_split).is_some()
+            });
+            return match hole.and_then(|hole| allocate_at(&mut self.first, hole, layout, min_split))
+            {
+                Some(allocation) => Ok(self.take(allocation)),
+                None => {
+                    self.refresh_largest();
+                    Err(AllocError)
+                }
+            };
+        }
+        match allocate_best_fit(&mut self.first, layout, self.min_split) {
+            Ok(allocation) => Ok(self.take(allocation)),
+            Err(error) => {
+                self.refresh_largest();
+                Err(error)
+            }
+        }
+    }
+
+    /// Searches the list for a big enough hole, starting at the end of the previous allocation
+    /// made with this function, and allocates a block of the required size from it. Then the
+    /// start address of that block is returned.
+    ///
+    /// This function uses the “next fit” strategy, so it wraps around to the start of the list
+    /// when no hole after the cursor is big enough. Short-lived allocations no longer re-scan
+    /// the same early holes, but are spread over the whole block.
+    pub fn allocate_next_fit(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        assert!(layout.size() >= Self::min_size());
+        if self.cannot_fit(layout) {
+            return Err(AllocError);
+        }
+        let allocation = allocate_next_fit(&mut self.first, layout, self.cursor, self.min_split)
+            .inspect_err(|_| self.refresh_largest())?;
+        self.cursor = allocation.info.addr + allocation.info.size;
+        Ok(self.take(allocation))
+    }
+
+    /// Allocates a block of memory in the hole chosen by the given policy. Then the start address
+    /// of that block is returned.
+    ///
+    /// Fails if the policy chooses no hole, a hole that is not in the list or not big enough, or
+    /// rejects splitting the chosen hole.
+    pub fn allocate_with(
+        &mut self,
+        layout: Layout,
+        policy: &dyn PlacementPolicy,
+    ) -> Result<NonNull<u8>, AllocError> {
+        assert!(layout.size() >= Self::min_size());
+        if self.cannot_fit(layout) {
+            return Err(AllocError);
+        }
+        let allocation =
+            allocate_with(&mut self.first, layout, policy, self.cursor, self.min_split)?;
+        self.cursor = allocation.info.addr + allocation.info.size;
+        Ok(self.take(allocation))
+    }
+
+    /// Allocates a block of memory using the given fit strategy.
+    ///
+    /// The chunks of the [quick list](#method.deallocate_quick) are checked first, and merged back
+    /// into the hole list before a last attempt if the strategy fails.
+    pub fn allocate(&mut self, layout: Layout, fit: Fit) -> Result<NonNull<u8>, AllocError> {
+        if let Some(chunk) = take_chunk(&mut self.quick, layout, self.min_split) {
+            let allocation = split_hole(chunk, layout, self.min_split).unwrap();
+            return Ok(self.place(allocation));
+        }
+        let result = match fit {
+            Fit::First => self.allocate_first_fit(layout),
+            Fit::Best => self.allocate_best_fit(layout),
+            Fit::Next => self.allocate_next_fit(layout),
+            Fit::Custom(policy) => self.allocate_with(layout, policy),
+            Fit::Segregated => self.allocate_segregated(layout),
+        };
+        if result.is_err() && self.quick.is_some() {
+            self.flush_quick_list();
+            return self.allocate(layout, fit);
+        }
+        result
+    }
+
+    /// Allocates a block of memory from the segregated free lists, or from the hole list if no
+    /// freed chunk is big enough. Then the start address of that block is returned.
+    ///
+    /// The search starts at the free list of the size class of the layout, so small allocations
+    /// usually find a chunk directly. The part of a chunk not needed for the allocation is freed
+    /// into the hole list. When the hole list has no hole big enough either, the free lists are
+    /// [flushed](#method.flush_free_lists) to merge their chunks before a last attempt.
+    pub fn allocate_segregated(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        self.allocate_deferred(layout, Fit::First)
+    }
+
+    /// Allocates a block of memory from the segregated free lists, or with the given fit strategy
+    /// if no freed chunk is big enough. Then the start address of that block is returned.
+    ///
+    /// This is the allocation side of deferred coalescing: chunks freed with
+    /// [`deallocate_segregated`](#method.deallocate_segregated) are only merged back into the hole
+    /// list when the fit strategy fails, before a last attempt.
+    pub fn allocate_deferred(
+        &mut self,
+        layout: Layout,
+        fit: Fit,
+    ) -> Result<NonNull<u8>, AllocError> {
+        assert!(layout.size() >= Self::min_size());
+        if let (Some(lists), Some(class)) =
+            (self.free_lists.as_deref_mut(), size_class(layout.size()))
+        {
+            for list in lists.lists[class..].iter_mut() {
+                if let Some(chunk) = take_chunk(list, layout, self.min_split) {
+                    lists.bytes -= chunk.size;
+                    let allocation = split_hole(chunk, layout, self.min_split).unwrap();
+                    return Ok(self.place(allocation));
+                }
             }
-            if let Some(padding) = allocation.back_padding {
-                deallocate(&mut self.first, padding.addr, padding.size);
+        }
+
+        let fit = match fit {
+            Fit::Segregated => Fit::First,
+            fit => fit,
+        };
+        self.allocate(layout, fit).or_else(|_| {
+            if self.free_lists.is_none() {
+                return Err(AllocError);
             }
-            NonNull::new(allocation.info.addr as *mut u8).unwrap()
+            self.flush_free_lists();
+            self.allocate(layout, fit)
         })
     }
 
+    /// Frees the allocation given by `ptr` and `layout` into the segregated free lists.
+    ///
+    /// Allocations too large for a size class are freed into the hole list directly. The free
+    /// lists themselves are allocated in the block on first use, and freed when flushed.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`deallocate`](#method.deallocate), except that double frees of small allocations
+    /// are not detected.
+    pub unsafe fn deallocate_segregated(
+        &mut self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+    ) -> Result<(), DeallocError> {
+        if self.in_small_slots(ptr) {
+            return self.deallocate(ptr, layout);
+        }
+        let class = match size_class(layout.size()) {
+            Some(class) => class,
+            None => return self.deallocate(ptr, layout),
+        };
+        if self.free_lists.is_none() {
+            match self.allocate_table(Layout::new::<FreeLists>()) {
+                Ok(lists_ptr) => {
+                    self.touch(lists_ptr, size_of::<FreeLists>());
+                    let lists_ptr = lists_ptr.cast::<FreeLists>().as_ptr();
+                    lists_ptr.write(FreeLists {
+                        lists: Default::default(),
+                        bytes: 0,
+                    });
+                    self.free_lists = Some(&mut *lists_ptr);
+                }
+                Err(_) => return self.deallocate(ptr, layout),
+            }
+        }
+
+        let lists = self.free_lists.as_deref_mut().unwrap();
+        let hole_ptr = ptr.as_ptr() as *mut Hole;
+        hole_ptr.write(Hole {
+            size: layout.size(),
+            next: lists.lists[class].take(),
+        });
+        lists.lists[class] = Some(&mut *hole_ptr);
+        lists.bytes += layout.size();
+        Ok(())
+    }
+
+    /// Frees the allocation given by `ptr` and `layout`, using the free lists of the given fit
+    /// strategy if it has any.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`deallocate`](#method.deallocate).
+    pub unsafe fn deallocate_with(
+        &mut self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        fit: Fit,
+    ) -> Result<(), DeallocError> {
+        match fit {
+            Fit::Segregated => self.deallocate_segregated(ptr, layout),
+            _ => self.deallocate(ptr, layout),
+        }
+    }
+
+    /// Frees the allocation given by `ptr` and `layout` into the quick list of the heap block.
+    ///
+    /// The chunk is not merged with its neighbours, so that an allocation of the same size made
+    /// right after takes it back without walking the hole list. Once the quick list holds more
+    /// than a few chunks, the oldest one is merged into the hole list.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`deallocate`](#method.deallocate), except that double frees of recently freed
+    /// allocations are not detected.
+    pub unsafe fn deallocate_quick(
+        &mut self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+    ) -> Result<(), DeallocError> {
+        if self.in_small_slots(ptr) {
+            return self.deallocate(ptr, layout);
+        }
+        let hole_ptr = ptr.as_ptr() as *mut Hole;
+        hole_ptr.write(Hole {
+            size: layout.size(),
+            next: self.quick.take(),
+        });
+        self.quick = Some(&mut *hole_ptr);
+
+        let mut last = self.quick.as_deref_mut().unwrap();
+        for _ in 1..QUICK_SLOTS {
+            last = match last.next.as_deref_mut() {
+                Some(next) => next,
+                None => return Ok(()),
+            };
+        }
+        match last.next.take() {
+            Some(evicted) => self.release(evicted.info().addr, evicted.size),
+            None => Ok(()),
+        }
+    }
+
+    /// Move the chunks of the quick list back into the hole list.
+    pub fn flush_quick_list(&mut self) {
+        while let Some(chunk) = self.quick.take() {
+            self.quick = chunk.next.take();
+            let chunk = chunk.info();
+            self.release(chunk.addr, chunk.size)
+                .expect("chunk is not free");
+        }
+    }
+
+    /// Returns the number of bytes freed but not merged into the hole list yet.
+    fn pending_bytes(&self) -> usize {
+        self.pending_chunks().map(|chunk| chunk.size).sum()
+    }
+
+    /// Returns an iterator over the chunks freed but not merged into the hole list yet.
+    ///
+    /// The chunks of the segregated free lists come first, by size class,
+    /// followed by the chunks of the quick list, most recent first.
+    pub fn pending_chunks(&self) -> PendingChunks<'_> {
+        PendingChunks {
+            lists: self
+                .free_lists
+                .as_deref()
+                .map_or(&[][..], |lists| &lists.lists[..])
+                .iter(),
+            quick: self.quick.as_deref(),
+            chunks: Holes { next: None },
+        }
+    }
+
+    /// Returns the address of the small-object slots of the heap block, and
+    /// the bitmap of the slots in use, if the slots are allocated.
+    pub fn small_slots(&self) -> Option<(usize, u32)> {
+        self.small
+            .as_deref()
+            .map(|small| (small.slots.as_ptr() as usize, small.used))
+    }
+
+    /// Move the chunks of the segregated free lists back into the hole list, and free the lists.
+    ///
+    /// Adjacent chunks and holes are merged again, so that larger allocations can use them.
+    pub fn flush_free_lists(&mut self) {
+        let lists = match self.free_lists.take() {
+            Some(lists) => lists,
+            None => return,
+        };
+        for list in lists.lists.iter_mut() {
+            while let Some(chunk) = list.take() {
+                *list = chunk.next.take();
+                let chunk = chunk.info();
+                self.release(chunk.addr, chunk.size)
+                    .expect("chunk is not free");
+            }
+        }
+        let addr = lists as *mut FreeLists as usize;
+        self.release(addr, size_of::<FreeLists>())
+            .expect("free lists are not free");
+    }
+
+    /// Check if an allocation of the given layout can be made in small-object slots.
+    pub fn is_small(layout: Layout) -> bool {
+        layout.size() <= SMALL_OBJECT_SIZE && layout.align() <= SMALL_SLOT_SIZE
+    }
+
+    /// Allocates a block of memory from the small-object slots of the heap block. Then the start
+    /// address of that block is returned.
+    ///
+    /// The allocation takes the first run of free slots large enough, found by scanning the slot
+    /// bitmap, so small allocations do not split holes. The slots themselves are allocated in
+    /// the block on first use, and freed once all of them are free again. Fails if the layout is
+    /// not [small](#method.is_small), or if no run of slots is free.
+    pub fn allocate_small(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        if !Self::is_small(layout) {
+            return Err(AllocError);
+        }
+        if self.small.is_none() {
+            let slots_ptr = self.allocate_table(Layout::new::<SmallSlots>())?;
+            self.touch(slots_ptr, size_of::<SmallSlots>());
+            let slots_ptr = slots_ptr.cast::<SmallSlots>().as_ptr();
+            unsafe {
+                slots_ptr.write(SmallSlots {
+                    slots: [[0; SMALL_SLOT_SIZE]; SMALL_SLOTS],
+                    used: 0,
+                });
+                self.small = Some(&mut *slots_ptr);
+            }
+        }
+
+        let small = self.small.as_deref_mut().unwrap();
+        let mask = slot_mask(layout.size());
+        let index = (0..=SMALL_SLOTS - mask.count_ones() as usize)
+            .find(|&index| small.used & (mask << index) == 0)
+            .ok_or(AllocError)?;
+        small.used |= mask << index;
+        Ok(NonNull::from(&mut small.slots[index]).cast())
+    }
+
+    /// Check if the given pointer is in the small-object slots of the heap block.
+    pub fn in_small_slots(&self, ptr: NonNull<u8>) -> bool {
+        self.small.as_deref().is_some_and(|small| {
+            let start = small.slots.as_ptr() as usize;
+            (start..start + size_of::<[[u8; SMALL_SLOT_SIZE]; SMALL_SLOTS]>())
+                .contains(&(ptr.as_ptr() as usize))
+        })
+    }
+
+    /// Frees the small-object slots of the allocation at `ptr`, and the slots themselves once
+    /// all of them are free.
+    fn deallocate_small(&mut self, ptr: NonNull<u8>, size: usize) -> Result<(), DeallocError> {
+        let small = self.small.as_deref_mut().unwrap();
+        let offset = ptr.as_ptr() as usize - small.slots.as_ptr() as usize;
+        let mask = slot_mask(size);
+        let index = offset / SMALL_SLOT_SIZE;
+        if !offset.is_multiple_of(SMALL_SLOT_SIZE)
+            || size > SMALL_OBJECT_SIZE
+            || index + mask.count_ones() as usize > SMALL_SLOTS
+        {
+            return Err(DeallocError::UnknownPointer);
+        }
+        let mask = mask << index;
+        if small.used & mask != mask {
+            return Err(DeallocError::DoubleFree);
+        }
+        small.used &= !mask;
+        if small.used == 0 {
+            let addr = self.small.take().unwrap() as *mut SmallSlots as usize;
+            self.release(addr, size_of::<SmallSlots>())
+                .expect("small-object slots are not free");
+        }
+        Ok(())
+    }
+
+    /// Marks the given range of the heap block as used, and returns whether it had never been
+    /// used before.
+    ///
+    /// Memory never used is left as it was when the heap block was created, except for the
+    /// first [`min_size`](#method.min_size) bytes of the range, which may have held a hole.
+    /// Allocations must be marked for this to hold.
+    pub fn touch(&mut self, ptr: NonNull<u8>, size: usize) -> bool {
+        let addr = ptr.as_ptr() as usize;
+        let clean = addr >= self.clean;
+        self.clean = max(self.clean, addr + size);
+        clean
+    }
+
     /// Returns the minimal allocation size.
     ///
-    /// Smaller allocations or deallocations are not allowed.
+    /// Smaller allocations or deallocations are not allowed, since a freed
+    /// allocation must be able to store a `Hole`. This is two words on most
+    /// targets, but only `4` bytes on 16-bit ones.
     pub fn min_size() -> usize {
-        size_of::<usize>() * 2
+        size_of::<Hole>()
+    }
+
+    /// Returns the minimal allocation size in a heap block with [indexed](#method.index_holes)
+    /// holes, which must store a tree node once freed.
+    pub fn min_indexed_size() -> usize {
+        MIN_INDEXED_SIZE
+    }
+
+    /// Returns the size of the smallest hole of the heap block.
+    fn min_hole(&self) -> usize {
+        if self.tree.is_some() {
+            MIN_INDEXED_SIZE
+        } else {
+            Self::min_size()
+        }
     }
 
-    /// Frees the allocation given by `ptr` and `layout`. `ptr` must be a pointer returned by a call
-    /// to the `allocate_first_fit` function with identical layout. Undefined behavior may occur for
-    /// invalid arguments.
+    /// Frees the allocation given by `ptr` and `layout`.
     ///
     /// This function walks the list and inserts the given block at the correct place. If the freed
     /// block is adjacent to another free block, the blocks are merged again.
     /// This operation is in `O(n)` since the list needs to be sorted by address.
-    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
-        deallocate(&mut self.first, ptr.as_ptr() as usize, layout.size())
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a pointer returned by a call to the `allocate_first_fit` function with
+    /// identical layout. Undefined behavior may occur for invalid arguments, although some
+    /// double frees are detected and reported as errors.
+    pub unsafe fn deallocate(
+        &mut self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+    ) -> Result<(), DeallocError> {
+        if self.in_small_slots(ptr) {
+            return self.deallocate_small(ptr, layout.size());
+        }
+        self.release(ptr.as_ptr() as usize, layout.size())
+    }
+
+    /// Resizes the allocation at `ptr` from `old_size` to `new_size` bytes without moving it.
+    ///
+    /// A growing allocation takes the memory it needs from the hole directly following it, and
+    /// a shrinking one frees its end into the hole list. Returns `false` if the allocation could
+    /// not be resized in place, in which case the block is left unchanged.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be an allocation of `old_size` bytes made in this heap block, and both sizes
+    /// must have been padded like the sizes given to `allocate_first_fit`.
+    pub unsafe fn resize(&mut self, ptr: NonNull<u8>, old_size: usize, new_size: usize) -> bool {
+        let addr = ptr.as_ptr() as usize;
+        if self.in_small_slots(ptr) {
+            return new_size == old_size;
+        }
+        match new_size.cmp(&old_size) {
+            Ordering::Equal => true,
+            Ordering::Less => {
+                let tail = old_size - new_size;
+                tail >= self.min_hole() && self.release(addr + new_size, tail).is_ok()
+            }
+            Ordering::Greater => {
+                let grown = take_front(
+                    &mut self.first,
+                    addr + old_size,
+                    new_size - old_size,
+                    self.min_split,
+                    self.tree.as_mut(),
+                );
+                if grown {
+                    self.free -= new_size - old_size;
+                    self.touch(ptr, new_size);
+                }
+                grown
+            }
+        }
+    }
+
+    /// Check the hole list of the heap block is consistent.
+    ///
+    /// Holes must be sorted by address, must not overlap, must be at least
+    /// `min_size` bytes large, and must be within the heap block. They must
+    /// also add up to the free bytes, and fit the largest hole bound. If the
+    /// holes are indexed, the size tree must hold exactly the same holes.
+    pub fn check(&self) -> bool {
+        // offsets from the start of the block, which can end at the top of the address space
+        let start = self as *const Self as usize;
+        let mut min_offset = self.payload;
+        let mut free = 0;
+        for hole in self.holes() {
+            let offset = hole.addr.wrapping_sub(start);
+            if hole.addr < start
+                || offset < min_offset
+                || offset >= self.size
+                || hole.size < self.min_hole()
+                || hole.size > self.size - offset
+                || hole.size > self.largest
+            {
+                return false;
+            }
+            min_offset = offset + hole.size;
+            free += hole.size;
+        }
+        free == self.free
+            && self
+                .tree
+                .as_ref()
+                .is_none_or(|tree| tree.check(self.holes()))
+    }
+
+    /// Check if the heap block does not contain any allocation.
+    pub fn is_empty(&self) -> bool {
+        let payload = self.size - self.payload;
+        if self.free_lists.is_some() || self.quick.is_some() {
+            let table = if self.free_lists.is_some() {
+                size_of::<FreeLists>()
+            } else {
+                0
+            };
+            let free: usize = self.holes().map(|hole| hole.size).sum();
+            return free + self.pending_bytes() + table == payload;
+        }
+        let mut holes = self.holes();
+        match (holes.next(), holes.next()) {
+            (Some(hole), None) => hole.size == payload,
+            _ => false,
+        }
+    }
+
+    /// Returns an iterator over the holes of this block, sorted by address.
+    pub fn holes(&self) -> Holes<'_> {
+        Holes {
+            next: self.first.next.as_deref(),
+        }
+    }
+
+    /// Returns the occupancy of this block, one granule of 256 bytes at a time.
+    ///
+    /// The map is computed from the hole list, without any bookkeeping on
+    /// allocation: a granule is free if a single hole covers it entirely,
+    /// and used otherwise.
+    pub fn occupancy(&self) -> Occupancy<'_> {
+        let start = self as *const Self as usize;
+        Occupancy {
+            holes: self.holes(),
+            hole: None,
+            addr: start,
+            end: start + self.size,
+        }
+    }
+
+    /// Returns the address range and occupancy of this block.
+    pub fn info(&self) -> BlockInfo {
+        let (free, holes) = self
+            .holes()
+            .fold((0, 0), |(free, holes), hole| (free + hole.size, holes + 1));
+        BlockInfo {
+            start: self as *const Self as usize,
+            len: self.size,
+            used: self.size - free - self.pending_bytes(),
+            holes,
+            generation: self.generation,
+        }
     }
 
     /// Check if the given pointer maps to a memory location that begins in the `HeapBlock`.
+    ///
+    /// # Safety
+    ///
+    /// The heap block must be valid for its whole size.
     pub unsafe fn contains<T>(&self, ptr: *const T) -> bool {
-        let self_ptr = self as *const Self as *const u8;
-        let that_ptr = ptr as *const u8;
-        (self_ptr <= that_ptr) && (that_ptr <= self_ptr.add(BS::to_usize()))
+        let self_addr = self as *const Self as usize;
+        let that_addr = ptr as usize;
+        (self_addr <= that_addr) && (that_addr - self_addr < self.size)
     }
 }
 
+/// The segregated free lists of a heap block, stored in the block itself.
+pub struct FreeLists {
+    lists: [Option<&'static mut Hole>; SIZE_CLASSES],
+    bytes: usize,
+}
+
+/// The small-object slots of a heap block, stored in the block itself.
+///
+/// Each bit of the bitmap tells whether the slot of the same index is used.
+#[repr(C, align(16))]
+pub struct SmallSlots {
+    slots: [[u8; SMALL_SLOT_SIZE]; SMALL_SLOTS],
+    used: u32,
+}
+
+const _: () = assert!(align_of::<SmallSlots>() == SMALL_SLOT_SIZE);
+const _: () = assert!(SMALL_SLOTS == u32::BITS as usize);
+
+/// Returns the bitmap mask of the slots taken by an allocation of the given size, from slot 0.
+fn slot_mask(size: usize) -> u32 {
+    let slots = size.div_ceil(SMALL_SLOT_SIZE).clamp(1, SMALL_SLOTS);
+    u32::MAX >> (SMALL_SLOTS - slots)
+}
+
 /// A hole in a heap block.
+#[repr(C)]
 pub struct Hole {
     pub size: usize,
     pub next: Option<&'static mut Hole>,
 }
 
-impl Hole {
-    /// Returns basic information about the hole.
-    fn info(&self) -> HoleInfo {
-        HoleInfo {
-            addr: self as *const _ as usize,
-            size: self.size,
+impl Hole {
+    /// Returns basic information about the hole.
+    fn info(&self) -> HoleInfo {
+        HoleInfo {
+            addr: self as *const _ as usize,
+            size: self.size,
+        }
+    }
+}
+
+/// Basic information about a hole.
+#[derive(Debug, Clone, Copy)]
+pub struct HoleInfo {
+    /// The start address of the hole.
+    pub addr: usize,
+    /// The size of the hole, in bytes.
+    pub size: usize,
+}
+
+impl HoleInfo {
+    /// Check if an allocation of the given layout fits in the hole, with its padding.
+    pub fn fits(&self, layout: Layout) -> bool {
+        split_hole(*self, layout, HeapBlock::<U1>::min_size()).is_some()
+    }
+
+    /// Check if the hole ends right before `addr`.
+    ///
+    /// The end address itself is not computed, as it overflows for a hole
+    /// at the top of the address space.
+    fn ends_at(&self, addr: usize) -> bool {
+        addr.checked_sub(self.addr) == Some(self.size)
+    }
+
+    /// Check if the hole ends after `addr`.
+    fn ends_after(&self, addr: usize) -> bool {
+        addr < self.addr || addr - self.addr < self.size
+    }
+}
+
+/// A placement heuristic choosing where an allocation is made.
+///
+/// The policy only chooses among the heap blocks and holes it is given;
+/// the hole lists themselves are updated by the heap blocks, so a policy
+/// can not corrupt them. Use it with `Fit::Custom`.
+///
+/// The built-in strategies are also available as policies ([`FirstFit`],
+/// [`BestFit`] and [`NextFit`]), to be wrapped by custom ones. The `Fit`
+/// variants of the built-in strategies do not go through this trait.
+///
+/// [`FirstFit`]: struct.FirstFit.html
+/// [`BestFit`]: struct.BestFit.html
+/// [`NextFit`]: struct.NextFit.html
+pub trait PlacementPolicy: Sync {
+    /// Returns the index of the heap block to search first, if any.
+    ///
+    /// The blocks are searched in list order from that block, wrapping
+    /// around to the head of the list. By default, the search starts at the
+    /// block given by the search stripe, if any, or at the head of the list.
+    fn choose_block(
+        &self,
+        _blocks: &mut dyn Iterator<Item = BlockInfo>,
+        _layout: Layout,
+    ) -> Option<usize> {
+        None
+    }
+
+    /// Returns the hole to allocate the given layout in, if any.
+    ///
+    /// The holes of the heap block are given sorted by address, along with
+    /// the end address of the previous allocation made with a policy in the
+    /// block (or `0`). The chosen hole must be one of `holes`, and must
+    /// [`fit`](struct.HoleInfo.html#method.fits) the layout, otherwise the
+    /// allocation fails.
+    fn choose_hole(&self, holes: Holes<'_>, layout: Layout, cursor: usize) -> Option<HoleInfo>;
+
+    /// Check if the chosen hole may be split, leaving some of it free.
+    ///
+    /// Rejecting the split fails the allocation in this heap block. By
+    /// default, all splits are accepted.
+    fn accept_split(&self, _hole: HoleInfo, _layout: Layout) -> bool {
+        true
+    }
+}
+
+impl fmt::Debug for dyn PlacementPolicy + '_ {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PlacementPolicy")
+    }
+}
+
+/// Address range and occupancy of a heap block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockInfo {
+    /// The start address of the block.
+    pub start: usize,
+    /// The total size of the block, in bytes.
+    pub len: usize,
+    /// The number of bytes not available for allocation, including the block header.
+    pub used: usize,
+    /// The number of holes in the block.
+    pub holes: usize,
+    /// The generation of the block, which changes each time it is emptied.
+    pub generation: usize,
+}
+
+/// The first-fit strategy, as a placement policy.
+#[derive(Debug, Clone, Copy)]
+pub struct FirstFit;
+
+impl PlacementPolicy for FirstFit {
+    fn choose_hole(
+        &self,
+        mut holes: Holes<'_>,
+        layout: Layout,
+        _cursor: usize,
+    ) -> Option<HoleInfo> {
+        holes.find(|hole| hole.fits(layout))
+    }
+}
+
+/// The best-fit strategy, as a placement policy.
+#[derive(Debug, Clone, Copy)]
+pub struct BestFit;
+
+impl PlacementPolicy for BestFit {
+    fn choose_hole(&self, holes: Holes<'_>, layout: Layout, _cursor: usize) -> Option<HoleInfo> {
+        holes
+            .filter(|hole| hole.fits(layout))
+            .min_by_key(|hole| hole.size)
+    }
+}
+
+/// The next-fit strategy, as a placement policy.
+#[derive(Debug, Clone, Copy)]
+pub struct NextFit;
+
+impl PlacementPolicy for NextFit {
+    fn choose_hole(&self, holes: Holes<'_>, layout: Layout, cursor: usize) -> Option<HoleInfo> {
+        let mut after = holes.clone().filter(|hole| hole.addr >= cursor);
+        after
+            .find(|hole| hole.fits(layout))
+            .or_else(|| holes.clone().find(|hole| hole.fits(layout)))
+    }
+}
+
+/// An iterator over the holes of a heap block.
+#[derive(Clone)]
+pub struct Holes<'a> {
+    next: Option<&'a Hole>,
+}
+
+impl<'a> Iterator for Holes<'a> {
+    type Item = HoleInfo;
+
+    fn next(&mut self) -> Option<HoleInfo> {
+        self.next.map(|hole| {
+            self.next = hole.next.as_deref();
+            hole.info()
+        })
+    }
+}
+
+/// An iterator over the chunks of a heap block freed but not merged into its hole list.
+///
+/// Created by [`HeapBlock::pending_chunks`](struct.HeapBlock.html#method.pending_chunks).
+pub struct PendingChunks<'a> {
+    lists: core::slice::Iter<'a, Option<&'static mut Hole>>,
+    quick: Option<&'a Hole>,
+    chunks: Holes<'a>,
+}
+
+impl<'a> Iterator for PendingChunks<'a> {
+    type Item = HoleInfo;
+
+    fn next(&mut self) -> Option<HoleInfo> {
+        loop {
+            if let Some(chunk) = self.chunks.next() {
+                return Some(chunk);
+            }
+            let next = match self.lists.next() {
+                Some(list) => list.as_deref(),
+                None => Some(self.quick.take()?),
+            };
+            self.chunks = Holes { next };
         }
     }
 }
 
-/// Basic information about a hole.
-#[derive(Debug, Clone, Copy)]
-struct HoleInfo {
+/// The size of the granules of an occupancy map.
+pub const GRANULE: usize = 256;
+
+/// An iterator over the granules of a heap block, yielding `true` for used granules.
+///
+/// Created by [`HeapBlock::occupancy`](struct.HeapBlock.html#method.occupancy).
+pub struct Occupancy<'a> {
+    holes: Holes<'a>,
+    hole: Option<HoleInfo>,
     addr: usize,
-    size: usize,
+    end: usize,
+}
+
+impl<'a> Iterator for Occupancy<'a> {
+    type Item = bool;
+
+    fn next(&mut self) -> Option<bool> {
+        if self.addr >= self.end {
+            return None;
+        }
+        let end = min(self.addr + GRANULE, self.end);
+        // skip the holes ending before the granule
+        while self
+            .hole
+            .is_none_or(|hole| hole.addr + hole.size <= self.addr)
+        {
+            match self.holes.next() {
+                Some(hole) => self.hole = Some(hole),
+                None => {
+                    self.hole = None;
+                    break;
+                }
+            }
+        }
+        let free = self
+            .hole
+            .is_some_and(|hole| hole.addr <= self.addr && hole.addr + hole.size >= end);
+        self.addr = end;
+        Some(!free)
+    }
 }
 
 /// The result returned by `split_hole` and `allocate_first_fit`. Contains the address and size of
@@ -138,16 +1265,33 @@ struct Allocation {
 /// Front padding occurs if the required alignment is higher than the hole's alignment. Back
 /// padding occurs if the required size is smaller than the size of the aligned hole. All padding
 /// must be at least `HoleList::min_size()` big or the hole is unusable.
-fn split_hole(hole: HoleInfo, required_layout: Layout) -> Option<Allocation> {
+fn split_hole(hole: HoleInfo, required_layout: Layout, min_split: usize) -> Option<Allocation> {
+    split_hole_with(hole, required_layout, min_split, false)
+}
+
+/// Same as `split_hole`, but if `absorb` is set, a hole whose remains would be too small to form
+/// a new hole is used whole, and the allocation is made larger than required instead.
+fn split_hole_with(
+    hole: HoleInfo,
+    required_layout: Layout,
+    min_split: usize,
+    absorb: bool,
+) -> Option<Allocation> {
+    let min_split = max(min_split, HeapBlock::<U1>::min_size());
     let required_size = required_layout.size();
     let required_align = required_layout.align();
 
-    let (aligned_addr, front_padding) = if hole.addr == align_up(hole.addr, required_align) {
+    // NB: on 16-bit targets, a hole can end at the very top of the address
+    // space, so any overflowing address computation means the hole is unusable.
+    let (aligned_addr, front_padding) = if hole.addr == align_down(hole.addr, required_align) {
         // hole has already the required alignment
         (hole.addr, None)
     } else {
         // the required alignment causes some padding before the allocation
-        let aligned_addr = align_up(hole.addr + HeapBlock::<U1>::min_size(), required_align);
+        let aligned_addr = hole
+            .addr
+            .checked_add(min_split)
+            .and_then(|addr| checked_align_up(addr, required_align))?;
         (
             aligned_addr,
             Some(HoleInfo {
@@ -158,7 +1302,9 @@ fn split_hole(hole: HoleInfo, required_layout: Layout) -> Option<Allocation> {
     };
 
     let aligned_hole = {
-        if aligned_addr + required_size > hole.addr + hole.size {
+        if aligned_addr - hole.addr > hole.size
+            || required_size > hole.size - (aligned_addr - hole.addr)
+        {
             // hole is too small
             return None;
         }
@@ -171,7 +1317,15 @@ fn split_hole(hole: HoleInfo, required_layout: Layout) -> Option<Allocation> {
     let back_padding = if aligned_hole.size == required_size {
         // the aligned hole has exactly the size that's needed, no padding accrues
         None
-    } else if aligned_hole.size - required_size < HeapBlock::<U1>::min_size() {
+    } else if aligned_hole.size - required_size < min_split {
+        if absorb {
+            // the remains are too small to form a new hole, so the allocation takes them
+            return Some(Allocation {
+                info: aligned_hole,
+                front_padding,
+                back_padding: None,
+            });
+        }
         // we can't use this hole since its remains would form a new, too small hole
         return None;
     } else {
@@ -199,12 +1353,26 @@ fn split_hole(hole: HoleInfo, required_layout: Layout) -> Option<Allocation> {
 /// care of freeing it again.
 /// This function uses the “first fit” strategy, so it breaks as soon as a big enough hole is
 /// found (and returns it).
-fn allocate_first_fit(mut previous: &mut Hole, layout: Layout) -> Result<Allocation, AllocError> {
+fn allocate_first_fit(
+    previous: &mut Hole,
+    layout: Layout,
+    min_split: usize,
+) -> Result<Allocation, AllocError> {
+    allocate_first_fit_with(previous, layout, min_split, false)
+}
+
+/// Same as `allocate_first_fit`, with holes split as by `split_hole_with`.
+fn allocate_first_fit_with(
+    mut previous: &mut Hole,
+    layout: Layout,
+    min_split: usize,
+    absorb: bool,
+) -> Result<Allocation, AllocError> {
     loop {
         let allocation: Option<Allocation> = previous
             .next
             .as_mut()
-            .and_then(|current| split_hole(current.info(), layout));
+            .and_then(|current| split_hole_with(current.info(), layout, min_split, absorb));
         match allocation {
             Some(allocation) => {
                 // hole is big enough, so remove it from the list by updating the previous pointer
@@ -223,9 +1391,244 @@ fn allocate_first_fit(mut previous: &mut Hole, layout: Layout) -> Result<Allocat
     }
 }
 
+/// Searches the list starting at the next hole of `previous` for the smallest big enough hole,
+/// and removes it from the list. As with `allocate_first_fit`, the caller must take care of
+/// freeing the padding of the returned `Allocation`.
+fn allocate_best_fit(
+    mut previous: &mut Hole,
+    layout: Layout,
+    min_split: usize,
+) -> Result<Allocation, AllocError> {
+    // find the index of the smallest hole that fits
+    let mut best: Option<(usize, usize)> = None;
+    let mut current = previous.next.as_deref();
+    let mut index = 0;
+    while let Some(hole) = current {
+        if best.is_none_or(|(_, size)| hole.size < size)
+            && split_hole(hole.info(), layout, min_split).is_some()
+        {
+            best = Some((index, hole.size));
+            if hole.size == layout.size() {
+                // no hole can fit better
+                break;
+            }
+        }
+        current = hole.next.as_deref();
+        index += 1;
+    }
+
+    // remove the hole from the list by updating the previous pointer
+    let (index, _) = best.ok_or(AllocError)?;
+    for _ in 0..index {
+        previous = move_helper(previous).next.as_mut().unwrap();
+    }
+    let hole = previous.next.as_ref().unwrap().info();
+    let allocation = split_hole(hole, layout, min_split).unwrap();
+    previous.next = previous.next.as_mut().unwrap().next.take();
+    Ok(allocation)
+}
+
+/// Searches the list starting after `first` for a big enough hole, beginning with the first hole
+/// at or after the `cursor` address and wrapping around to the start of the list, and removes it
+/// from the list. As with `allocate_first_fit`, the caller must take care of freeing the padding
+/// of the returned `Allocation`.
+fn allocate_next_fit(
+    first: &mut Hole,
+    layout: Layout,
+    cursor: usize,
+    min_split: usize,
+) -> Result<Allocation, AllocError> {
+    // the list is sorted by address, so count the holes before the cursor
+    let mut skipped = 0;
+    let mut current = first.next.as_deref();
+    while let Some(hole) = current.filter(|hole| hole.info().addr < cursor) {
+        skipped += 1;
+        current = hole.next.as_deref();
+    }
+
+    let mut previous = &mut *first;
+    for _ in 0..skipped {
+        previous = move_helper(previous).next.as_mut().unwrap();
+    }
+    match allocate_first_fit(previous, layout, min_split) {
+        Ok(allocation) => Ok(allocation),
+        Err(_) if skipped > 0 => allocate_first_fit(first, layout, min_split),
+        Err(error) => Err(error),
+    }
+}
+
+/// Searches the list starting after `first` for the hole chosen by `policy`, and removes it from
+/// the list if it is big enough. As with `allocate_first_fit`, the caller must take care of
+/// freeing the padding of the returned `Allocation`.
+fn allocate_with(
+    first: &mut Hole,
+    layout: Layout,
+    policy: &dyn PlacementPolicy,
+    cursor: usize,
+    min_split: usize,
+) -> Result<Allocation, AllocError> {
+    let holes = Holes {
+        next: first.next.as_deref(),
+    };
+    let chosen = policy
+        .choose_hole(holes, layout, cursor)
+        .ok_or(AllocError)?;
+
+    let previous = previous_of(first, chosen.addr);
+    let hole = previous
+        .next
+        .as_ref()
+        .filter(|hole| hole.info().addr == chosen.addr)
+        .ok_or(AllocError)?
+        .info();
+    let allocation = split_hole(hole, layout, min_split).ok_or(AllocError)?;
+    let split = allocation.front_padding.is_some() || allocation.back_padding.is_some();
+    if split && !policy.accept_split(hole, layout) {
+        return Err(AllocError);
+    }
+    previous.next = previous.next.as_mut().unwrap().next.take();
+    Ok(allocation)
+}
+
+/// Removes the given hole from the list starting after `first` if it is big enough, as found in
+/// the size tree of the block. As with `allocate_first_fit`, the caller must take care of freeing
+/// the padding of the returned `Allocation`.
+fn allocate_at(
+    first: &mut Hole,
+    hole: HoleInfo,
+    layout: Layout,
+    min_split: usize,
+) -> Option<Allocation> {
+    let previous = previous_of(first, hole.addr);
+    previous
+        .next
+        .as_ref()
+        .filter(|next| next.info().addr == hole.addr)?;
+    let allocation = split_hole(hole, layout, min_split)?;
+    previous.next = previous.next.as_mut().unwrap().next.take();
+    Some(allocation)
+}
+
+/// Returns the last hole starting before `addr` in the list starting after `first`, or `first`
+/// itself.
+fn previous_of(mut previous: &mut Hole, addr: usize) -> &mut Hole {
+    // the list is sorted by address, so stop at the first hole at or after `addr`
+    while previous
+        .next
+        .as_ref()
+        .is_some_and(|hole| hole.info().addr < addr)
+    {
+        previous = move_helper(previous).next.as_mut().unwrap();
+    }
+    previous
+}
+
+/// Returns the segregated free list for chunks of the given size, if any.
+fn size_class(size: usize) -> Option<usize> {
+    let units = size / HeapBlock::<U1>::min_size();
+    if units == 0 {
+        return None;
+    }
+    let class = (usize::BITS - 1 - units.leading_zeros()) as usize;
+    if class < SIZE_CLASSES {
+        Some(class)
+    } else {
+        None
+    }
+}
+
+/// Removes the first chunk big enough for `layout` from the given free list, and returns it.
+fn take_chunk(
+    mut link: &mut Option<&'static mut Hole>,
+    layout: Layout,
+    min_split: usize,
+) -> Option<HoleInfo> {
+    loop {
+        let fits = split_hole(link.as_deref()?.info(), layout, min_split).is_some();
+        if fits {
+            let chunk = link.take().unwrap();
+            *link = chunk.next.take();
+            return Some(chunk.info());
+        }
+        link = &mut move_helper(link).as_mut().unwrap().next;
+    }
+}
+
+/// Takes `size` bytes from the front of the hole starting at `addr`, if there is such a hole in
+/// the list starting after `previous`. The rest of the hole, if any, must be large enough to
+/// remain a hole, and replaces the hole in the size tree of the block, if given.
+unsafe fn take_front(
+    previous: &mut Hole,
+    addr: usize,
+    size: usize,
+    min_split: usize,
+    mut tree: Option<&mut SizeTree>,
+) -> bool {
+    let previous = previous_of(previous, addr);
+    let hole = match previous.next.as_ref() {
+        Some(hole) if hole.info().addr == addr => hole.info(),
+        _ => return false,
+    };
+    if hole.size < size
+        || (hole.size > size && hole.size - size < max(min_split, HeapBlock::<U1>::min_size()))
+    {
+        return false;
+    }
+
+    // the node of the hole is overwritten by the rest
+    if let Some(tree) = tree.as_deref_mut() {
+        tree.remove(hole);
+    }
+    let next = previous.next.as_mut().unwrap().next.take();
+    if hole.size == size {
+        previous.next = next;
+    } else {
+        // the rest starts before the end of the hole, so this does not overflow
+        let rest_ptr = (addr + size) as *mut Hole;
+        rest_ptr.write(Hole {
+            size: hole.size - size,
+            next,
+        });
+        previous.next = Some(&mut *rest_ptr);
+        if let Some(tree) = tree {
+            tree.insert((*rest_ptr).info());
+        }
+    }
+    true
+}
+
+/// Frees the padding of the given allocation back into the hole list starting after `first`, and
+/// returns the start address of the allocation.
+fn place(first: &mut Hole, allocation: Allocation) -> NonNull<u8> {
+    if let Some(padding) = allocation.front_padding {
+        deallocate(first, padding.addr, padding.size).expect("padding is not free");
+    }
+    if let Some(padding) = allocation.back_padding {
+        deallocate(first, padding.addr, padding.size).expect("padding is not free");
+    }
+    NonNull::new(allocation.info.addr as *mut u8).unwrap()
+}
+
+/// Allocates a block of memory from the hole list starting after `first`, using the “first fit”
+/// strategy, and frees the padding needed for the allocation back into the list.
+pub fn allocate_in(first: &mut Hole, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+    allocate_first_fit(first, layout, HeapBlock::<U1>::min_size())
+        .map(|allocation| place(first, allocation))
+}
+
 /// Frees the allocation given by `(addr, size)`. It starts at the given hole and walks the list to
-/// find the correct place (the list is sorted by address).
-fn deallocate(mut hole: &mut Hole, addr: usize, mut size: usize) {
+/// find the correct place (the list is sorted by address). Returns an error if the allocation
+/// overlaps a hole that comes before it.
+pub fn deallocate(hole: &mut Hole, addr: usize, size: usize) -> Result<(), DeallocError> {
+    deallocate_merged(hole, addr, size).map(drop)
+}
+
+/// Same as `deallocate`, but returns the hole the allocation was merged into.
+fn deallocate_merged(
+    mut hole: &mut Hole,
+    addr: usize,
+    mut size: usize,
+) -> Result<HoleInfo, DeallocError> {
     loop {
         // FIXME: this was in original code, but fails
         //        when using as #[global_allocator]
@@ -241,26 +1644,32 @@ fn deallocate(mut hole: &mut Hole, addr: usize, mut size: usize) {
             hole as *mut _ as usize
         };
 
+        let this = HoleInfo {
+            addr: hole_addr,
+            size: hole.size,
+        };
+        let freed = HoleInfo { addr, size };
+
         // Each freed block must be handled by the previous hole in memory. Thus the freed
         // address must be always behind the current hole.
-        assert!(
-            hole_addr + hole.size <= addr,
-            "invalid deallocation (probably a double free)"
-        );
+        if this.ends_after(addr) {
+            return Err(DeallocError::DoubleFree);
+        }
 
         // get information about the next block
         let next_hole_info = hole.next.as_ref().map(|next| next.info());
 
-        match next_hole_info {
-            Some(next) if hole_addr + hole.size == addr && addr + size == next.addr => {
+        let merged = match next_hole_info {
+            Some(next) if this.ends_at(addr) && freed.ends_at(next.addr) => {
                 // block fills the gap between this hole and the next hole
                 // before:  ___XXX____YYYYY____    where X is this hole and Y the next hole
                 // after:   ___XXXFFFFYYYYY____    where F is the freed block
 
                 hole.size += size + next.size; // merge the F and Y blocks to this X block
                 hole.next = hole.next.as_mut().unwrap().next.take(); // remove the Y block
+                hole.info()
             }
-            _ if hole_addr + hole.size == addr => {
+            _ if this.ends_at(addr) => {
                 // block is right behind this hole but there is used memory after it
                 // before:  ___XXX______YYYYY____    where X is this hole and Y the next hole
                 // after:   ___XXXFFFF__YYYYY____    where F is the freed block
@@ -270,8 +1679,9 @@ fn deallocate(mut hole: &mut Hole, addr: usize, mut size: usize) {
                 // after:   ___XXXFFFF___________    where F is the freed block
 
                 hole.size += size; // merge the F block to this X block
+                hole.info()
             }
-            Some(next) if addr + size == next.addr => {
+            Some(next) if freed.ends_at(next.addr) => {
                 // block is right before the next hole but there is used memory before it
                 // before:  ___XXX______YYYYY____    where X is this hole and Y the next hole
                 // after:   ___XXX__FFFFYYYYY____    where F is the freed block
@@ -306,9 +1716,10 @@ fn deallocate(mut hole: &mut Hole, addr: usize, mut size: usize) {
                 unsafe { ptr.write(new_hole) };
                 // add the F block as the next block of the X block
                 hole.next = Some(unsafe { &mut *ptr });
+                HoleInfo { addr, size }
             }
-        }
-        break;
+        };
+        break Ok(merged);
     }
 }
 
@@ -353,7 +1764,9 @@ mod tests {
 
             if let Ok(alloc) = block.allocate_first_fit(layout) {
                 assert_eq!(alloc.cast().as_ptr(), addr.as_ptr().add(1));
-                block.deallocate(alloc, Layout::from_size_align_unchecked(32, 1));
+                block
+                    .deallocate(alloc, Layout::from_size_align_unchecked(32, 1))
+                    .expect("could not deallocate");
             } else {
                 panic!("Could not allocate block.")
             }
@@ -365,4 +1778,545 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    /// Check holes ending at the top of the address space do not overflow.
+    fn split_hole_top_of_memory() {
+        let size = 4 * HeapBlock::<U4096>::min_size();
+        let hole = HoleInfo {
+            addr: 0usize.wrapping_sub(size),
+            size,
+        };
+
+        let layout = Layout::from_size_align(size / 2, 1).unwrap();
+        let allocation = split_hole(hole, layout, 0).expect("hole should fit");
+        assert_eq!(allocation.info.addr, hole.addr);
+        assert!(allocation.back_padding.is_some());
+
+        let layout = Layout::from_size_align(size / 2, size * 2).unwrap();
+        assert!(split_hole(hole, layout, 0).is_none());
+    }
+
+    #[test]
+    /// Check freed blocks are merged with holes ending at the top of the address space.
+    fn merge_hole_top_of_memory() {
+        let size = 4 * HeapBlock::<U4096>::min_size();
+        let top = HoleInfo {
+            addr: 0usize.wrapping_sub(size),
+            size,
+        };
+        let freed = HoleInfo {
+            addr: top.addr - size,
+            size,
+        };
+
+        assert!(freed.ends_at(top.addr));
+        assert!(!freed.ends_after(top.addr));
+        assert!(!top.ends_at(0));
+        assert!(top.ends_after(usize::MAX));
+    }
+
+    #[test]
+    /// Check the first hole is written right after the block header.
+    fn heapblock_layout() {
+        unsafe {
+            let mut block = [0u64; 512];
+            let addr = NonNull::new_unchecked(block[..].as_mut_ptr()).cast::<HeapBlock<U4096>>();
+            let heap = HeapBlock::<U4096>::new(addr);
+
+            let first_hole = heap.first.next.as_ref().unwrap().info();
+            let header_end = addr.as_ptr() as usize + size_of::<HeapBlock<U4096>>();
+            assert_eq!(addr.as_ptr().add(1) as usize, header_end);
+            assert_eq!(first_hole.addr, header_end);
+            assert_eq!(first_hole.addr % align_of::<Hole>(), 0);
+            assert_eq!(first_hole.size, 4096 - size_of::<HeapBlock<U4096>>());
+        }
+    }
+
+    #[test]
+    /// Check heap blocks can be created from a static memory region.
+    fn heapblock_from_slice() {
+        let memory = Box::leak(Box::new([MaybeUninit::<u64>::uninit(); 1536]));
+        let region = unsafe {
+            ::core::slice::from_raw_parts_mut(memory.as_mut_ptr() as *mut MaybeUninit<u8>, 12288)
+        };
+        let (region, rest) = region.split_at_mut(4096);
+        let (misaligned, small) = rest.split_at_mut(4096);
+
+        assert!(HeapBlock::<U4096>::from_slice(&mut misaligned[1..]).is_err());
+        assert!(HeapBlock::<U4096>::from_slice(&mut small[..4000]).is_err());
+
+        let block = HeapBlock::<U4096>::from_slice(region).expect("could not create block");
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        assert!(block.allocate_first_fit(layout).is_ok());
+    }
+
+    #[test]
+    /// Check a heap block can be extended with contiguous memory.
+    fn heapblock_extend() {
+        unsafe {
+            let mut block = [0u64; 1024];
+            let addr = NonNull::new_unchecked(block.as_mut_ptr()).cast::<HeapBlock<U4096>>();
+            let block = HeapBlock::<U4096>::new(addr);
+
+            let layout = Layout::from_size_align(6000, 8).unwrap();
+            assert!(block.allocate_first_fit(layout).is_err());
+
+            block.extend();
+            assert_eq!(block.size, 8192);
+            assert_eq!(block.holes().count(), 1);
+            assert!(block.contains(addr.cast::<u8>().as_ptr().add(8191)));
+            assert!(!block.contains(addr.cast::<u8>().as_ptr().add(8192)));
+
+            let ptr = block
+                .allocate_first_fit(layout)
+                .expect("could not allocate");
+            assert_eq!(ptr.cast().as_ptr(), addr.as_ptr().add(1));
+        }
+    }
+
+    #[test]
+    /// Check inconsistent hole lists are detected.
+    fn heapblock_check() {
+        unsafe {
+            let mut block = [0u64; 512];
+            let addr = NonNull::new_unchecked(block.as_mut_ptr()).cast::<HeapBlock<U4096>>();
+            let block = HeapBlock::<U4096>::new(addr);
+            assert!(block.check());
+
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            block
+                .allocate_first_fit(layout)
+                .expect("could not allocate");
+            assert!(block.check());
+
+            block.first.next.as_mut().unwrap().size += 64;
+            assert!(!block.check());
+        }
+    }
+
+    #[test]
+    /// Check the usable memory of a heap block can start on a given alignment.
+    fn heapblock_new_aligned() {
+        unsafe {
+            let mut block = [0u64; 512];
+            let addr = NonNull::new_unchecked(block.as_mut_ptr()).cast::<HeapBlock<U4096>>();
+            let block = HeapBlock::<U4096>::new_aligned(addr, 256);
+            assert_eq!(block.holes().next().unwrap().addr % 256, 0);
+            assert!(block.is_empty());
+            assert!(block.check());
+
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            let ptr = block
+                .allocate_first_fit(layout)
+                .expect("could not allocate");
+            assert_eq!(ptr.as_ptr() as usize % 256, 0);
+            assert!(!block.is_empty());
+        }
+    }
+
+    /// Allocate in a block with two freed holes (of `256` then `64` bytes), a
+    /// `64`-byte layout then a `256`-byte one, and return the second result.
+    fn fragmentation_scenario(fit: Fit) -> bool {
+        unsafe {
+            let mut block = [0u64; 512];
+            let addr = NonNull::new_unchecked(block.as_mut_ptr()).cast();
+            let block = HeapBlock::<U4096>::new(addr);
+            let alloc = |block: &mut HeapBlock<U4096>, size| {
+                let layout = Layout::from_size_align(size, 1).unwrap();
+                block
+                    .allocate_first_fit(layout)
+                    .expect("could not allocate")
+            };
+
+            let large = alloc(block, 256);
+            alloc(block, 32);
+            let small = alloc(block, 64);
+            alloc(block, 32);
+            let rest = block.holes().last().unwrap().size;
+            alloc(block, rest);
+
+            for (ptr, size) in [(large, 256), (small, 64)] {
+                let layout = Layout::from_size_align(size, 1).unwrap();
+                block.deallocate(ptr, layout).expect("could not deallocate");
+            }
+
+            let layout = Layout::from_size_align(64, 1).unwrap();
+            assert!(block.allocate(layout, fit).is_ok());
+            let layout = Layout::from_size_align(256, 1).unwrap();
+            block.allocate(layout, fit).is_ok()
+        }
+    }
+
+    #[test]
+    /// Check best-fit keeps large holes available where first-fit splits them.
+    fn heapblock_best_fit() {
+        assert!(!fragmentation_scenario(Fit::First));
+        assert!(fragmentation_scenario(Fit::Best));
+    }
+
+    #[test]
+    /// Check indexed holes give the same best-fit placements as a scan, and
+    /// the size tree follows allocations, frees, resizes and resets.
+    fn heapblock_indexed_holes() {
+        unsafe {
+            let mut indexed = [0u64; 512];
+            let mut scanned = [0u64; 512];
+            let indexed =
+                HeapBlock::<U4096>::new(NonNull::new_unchecked(indexed.as_mut_ptr()).cast());
+            let scanned =
+                HeapBlock::<U4096>::new(NonNull::new_unchecked(scanned.as_mut_ptr()).cast());
+            indexed.index_holes();
+            scanned.min_split = HeapBlock::<U4096>::min_indexed_size();
+            let offset = |block: &HeapBlock<U4096>, ptr: NonNull<u8>| {
+                ptr.as_ptr() as usize - block as *const _ as usize
+            };
+
+            let mut seed = 0x2545_f491_u32;
+            let mut live: Vec<(NonNull<u8>, NonNull<u8>, Layout)> = Vec::new();
+            for _ in 0..1000 {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                if seed.is_multiple_of(3) && !live.is_empty() {
+                    let (a, b, layout) = live.swap_remove(seed as usize % live.len());
+                    indexed.deallocate(a, layout).unwrap();
+                    scanned.deallocate(b, layout).unwrap();
+                } else if seed.is_multiple_of(7) && !live.is_empty() {
+                    // grow or shrink an allocation in place
+                    let index = seed as usize % live.len();
+                    let (a, b, layout) = live[index];
+                    let size = match layout.size().checked_sub(48) {
+                        Some(size) if size >= 40 && seed & 256 == 0 => size,
+                        _ => layout.size() + 48,
+                    };
+                    let resized = indexed.resize(a, layout.size(), size);
+                    assert_eq!(scanned.resize(b, layout.size(), size), resized);
+                    if resized {
+                        live[index].2 = Layout::from_size_align(size, layout.align()).unwrap();
+                    }
+                } else {
+                    let size = 40 + (seed as usize >> 8) % 32 * 8;
+                    let align = if seed.is_multiple_of(5) { 64 } else { 8 };
+                    let layout = Layout::from_size_align(size, align).unwrap();
+                    match indexed.allocate(layout, Fit::Best) {
+                        Ok(a) => {
+                            let b = scanned.allocate(layout, Fit::Best).unwrap();
+                            assert_eq!(offset(indexed, a), offset(scanned, b));
+                            live.push((a, b, layout));
+                        }
+                        Err(_) => assert!(scanned.allocate(layout, Fit::Best).is_err()),
+                    }
+                }
+                assert!(indexed.check());
+            }
+
+            // first-fit allocations keep the tree updated as well
+            let layout = Layout::from_size_align(48, 8).unwrap();
+            while indexed.allocate(layout, Fit::First).is_ok() {}
+            assert!(indexed.check());
+            indexed.reset();
+            assert!(indexed.check());
+            assert!(indexed.allocate(layout, Fit::Best).is_ok());
+        }
+    }
+
+    #[test]
+    /// Check next-fit resumes after the previous allocation, and wraps around.
+    fn heapblock_next_fit() {
+        unsafe {
+            let mut block = [0u64; 512];
+            let addr = NonNull::new_unchecked(block.as_mut_ptr()).cast();
+            let block = HeapBlock::<U4096>::new(addr);
+            let layout = Layout::from_size_align(32, 1).unwrap();
+
+            let a = block.allocate_next_fit(layout).unwrap();
+            let b = block.allocate_next_fit(layout).unwrap();
+            block.deallocate(a, layout).expect("could not deallocate");
+            let c = block.allocate_next_fit(layout).unwrap();
+            assert!(c > b);
+
+            // fill the rest of the block to wrap around to the first hole
+            let rest = block.holes().last().unwrap().size;
+            let rest = Layout::from_size_align(rest, 1).unwrap();
+            assert!(block.allocate_next_fit(rest).is_ok());
+            assert_eq!(block.allocate_next_fit(layout), Ok(a));
+            assert!(block.allocate_next_fit(layout).is_err());
+        }
+    }
+
+    /// A policy using the last hole large enough.
+    struct LastFit;
+
+    impl PlacementPolicy for LastFit {
+        fn choose_hole(&self, holes: Holes<'_>, layout: Layout, _: usize) -> Option<HoleInfo> {
+            holes.filter(|hole| hole.fits(layout)).last()
+        }
+    }
+
+    /// A policy choosing a hole that is not in the list.
+    struct Bogus;
+
+    impl PlacementPolicy for Bogus {
+        fn choose_hole(&self, _: Holes<'_>, _: Layout, _: usize) -> Option<HoleInfo> {
+            Some(HoleInfo {
+                addr: 8,
+                size: 4096,
+            })
+        }
+    }
+
+    #[test]
+    /// Check custom fit policies choose the hole, and can not corrupt the list.
+    fn heapblock_custom_fit() {
+        unsafe {
+            let mut block = [0u64; 512];
+            let addr = NonNull::new_unchecked(block.as_mut_ptr()).cast();
+            let block = HeapBlock::<U4096>::new(addr);
+            let layout = Layout::from_size_align(32, 1).unwrap();
+
+            let a = block.allocate_first_fit(layout).unwrap();
+            let b = block.allocate_first_fit(layout).unwrap();
+            block.deallocate(a, layout).expect("could not deallocate");
+            assert!(block.allocate(layout, Fit::Custom(&LastFit)).unwrap() > b);
+
+            assert!(block.allocate(layout, Fit::Custom(&Bogus)).is_err());
+            assert!(block.check());
+        }
+    }
+
+    #[test]
+    /// Check freed chunks are reused by size class, and merged when needed.
+    fn heapblock_segregated() {
+        unsafe {
+            let mut block = [0u64; 512];
+            let addr = NonNull::new_unchecked(block.as_mut_ptr()).cast();
+            let block = HeapBlock::<U4096>::new(addr);
+            let small = Layout::from_size_align(32, 1).unwrap();
+            let tiny = Layout::from_size_align(16, 1).unwrap();
+
+            let a = block.allocate_segregated(small).unwrap();
+            block.deallocate_segregated(a, small).unwrap();
+            assert_eq!(block.info().holes, 1);
+            assert!(block.is_empty());
+
+            // the chunk is split for a smaller layout
+            assert_eq!(block.allocate_segregated(tiny), Ok(a));
+            assert_eq!(block.info().holes, 2);
+            block.deallocate_segregated(a, tiny).unwrap();
+
+            // the whole block is only available once chunks are merged
+            let rest = block.size - block.payload;
+            let rest = Layout::from_size_align(rest, 1).unwrap();
+            assert_eq!(block.allocate_segregated(rest), Ok(a));
+            assert!(block.free_lists.is_none());
+            assert!(block.check());
+        }
+    }
+
+    #[test]
+    /// Check allocations are resized in place only when the following memory allows it.
+    fn heapblock_resize() {
+        unsafe {
+            let mut block = [0u64; 512];
+            let addr = NonNull::new_unchecked(block.as_mut_ptr()).cast();
+            let block = HeapBlock::<U4096>::new(addr);
+            let layout = Layout::from_size_align(32, 1).unwrap();
+
+            let a = block.allocate_first_fit(layout).unwrap();
+            assert!(block.resize(a, 32, 64));
+            let b = block.allocate_first_fit(layout).unwrap();
+            assert_eq!(b.as_ptr(), a.as_ptr().add(64));
+            assert!(!block.resize(a, 64, 96));
+
+            // a tail too small for a hole can not be freed
+            assert!(!block.resize(a, 64, 56));
+            assert!(block.resize(a, 64, 32));
+            assert_eq!(block.allocate_first_fit(layout), Ok(a.add(32)));
+            assert!(block.check());
+        }
+    }
+
+    /// A policy only using holes of the exact size.
+    struct ExactFit;
+
+    impl PlacementPolicy for ExactFit {
+        fn choose_hole(&self, holes: Holes<'_>, layout: Layout, _: usize) -> Option<HoleInfo> {
+            FirstFit.choose_hole(holes, layout, 0)
+        }
+
+        fn accept_split(&self, _hole: HoleInfo, _layout: Layout) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    /// Check the built-in strategies behave the same as policies.
+    fn heapblock_builtin_policies() {
+        assert!(!fragmentation_scenario(Fit::Custom(&FirstFit)));
+        assert!(fragmentation_scenario(Fit::Custom(&BestFit)));
+
+        unsafe {
+            let mut block = [0u64; 512];
+            let addr = NonNull::new_unchecked(block.as_mut_ptr()).cast();
+            let block = HeapBlock::<U4096>::new(addr);
+            let layout = Layout::from_size_align(32, 1).unwrap();
+
+            let a = block.allocate(layout, Fit::Custom(&NextFit)).unwrap();
+            let b = block.allocate(layout, Fit::Custom(&NextFit)).unwrap();
+            block.deallocate(a, layout).expect("could not deallocate");
+            assert!(block.allocate(layout, Fit::Custom(&NextFit)).unwrap() > b);
+
+            // the freed hole has the exact size, but not the last one
+            assert_eq!(block.allocate(layout, Fit::Custom(&ExactFit)), Ok(a));
+            assert!(block.allocate(layout, Fit::Custom(&ExactFit)).is_err());
+        }
+    }
+
+    #[test]
+    /// Check small allocations take runs of slots, which are freed once all unused.
+    fn heapblock_small_objects() {
+        unsafe {
+            let mut block = [0u64; 512];
+            let addr = NonNull::new_unchecked(block.as_mut_ptr()).cast();
+            let block = HeapBlock::<U4096>::new(addr);
+            let small = Layout::from_size_align(16, 8).unwrap();
+            let medium = Layout::from_size_align(48, 16).unwrap();
+
+            assert!(block
+                .allocate_small(Layout::from_size_align(65, 8).unwrap())
+                .is_err());
+            let a = block.allocate_small(small).unwrap();
+            let holes = block.holes().count();
+            let b = block.allocate_small(medium).unwrap();
+            let c = block.allocate_small(small).unwrap();
+            assert_eq!(b.as_ptr() as usize - a.as_ptr() as usize, 16);
+            assert_eq!(c.as_ptr() as usize - b.as_ptr() as usize, 48);
+            assert_eq!(block.holes().count(), holes);
+            assert!(!block.resize(a, 16, 32));
+
+            // a freed run is reused by allocations fitting in it
+            assert_eq!(block.deallocate(b, medium), Ok(()));
+            assert_eq!(block.deallocate(b, medium), Err(DeallocError::DoubleFree));
+            assert_eq!(block.allocate_small(small), Ok(b));
+
+            for ptr in [a, b, c] {
+                block.deallocate_with(ptr, small, Fit::Segregated).unwrap();
+            }
+            assert!(block.small.is_none());
+            assert!(block.free_lists.is_none());
+            assert!(block.is_empty());
+        }
+    }
+
+    #[test]
+    /// Check recently freed chunks are reused before the holes, and evicted when too many.
+    fn heapblock_quick_list() {
+        unsafe {
+            let mut block = [0u64; 512];
+            let addr = NonNull::new_unchecked(block.as_mut_ptr()).cast();
+            let block = HeapBlock::<U4096>::new(addr);
+            let layout = Layout::from_size_align(32, 8).unwrap();
+
+            let ptrs: Vec<_> = (0..6)
+                .map(|_| block.allocate(layout, Fit::Best).unwrap())
+                .collect();
+            for &ptr in ptrs.iter().take(5) {
+                block.deallocate_quick(ptr, layout).unwrap();
+            }
+            // the oldest chunk was evicted into the hole list
+            assert_eq!(block.holes().count(), 2);
+            assert_eq!(block.info().used, block.payload + 32);
+
+            // the most recently freed chunk is reused first, whatever the strategy
+            assert_eq!(block.allocate(layout, Fit::Best), Ok(ptrs[4]));
+            block.flush_quick_list();
+            assert_eq!(block.holes().count(), 2);
+            block.deallocate(ptrs[4], layout).unwrap();
+            block.deallocate_quick(ptrs[5], layout).unwrap();
+            assert!(block.is_empty());
+
+            // a failing allocation merges the quick list first
+            let large = Layout::from_size_align(4096 - block.payload, 8).unwrap();
+            assert!(block.allocate(large, Fit::First).is_ok());
+            assert!(block.quick.is_none());
+        }
+    }
+
+    #[test]
+    /// Check the occupancy map follows the holes of the block.
+    fn heapblock_occupancy() {
+        unsafe {
+            let mut block = [0u64; 512];
+            let addr = NonNull::new_unchecked(block.as_mut_ptr()).cast();
+            let block = HeapBlock::<U4096>::new(addr);
+            assert_eq!(block.occupancy().count(), 16);
+            assert!(block.occupancy().eq((0..16).map(|i| i == 0)));
+
+            // an allocation spanning granules marks them all as used
+            let layout = Layout::from_size_align(256, 8).unwrap();
+            let ptr = block.allocate(layout, Fit::First).unwrap();
+            assert!(block.occupancy().eq((0..16).map(|i| i < 2)));
+
+            block.deallocate(ptr, layout).unwrap();
+            assert!(block.occupancy().eq((0..16).map(|i| i == 0)));
+        }
+    }
+
+    #[test]
+    /// Check holes are not split into slivers smaller than the threshold.
+    fn heapblock_min_split() {
+        unsafe {
+            let mut block = [0u64; 512];
+            let addr = NonNull::new_unchecked(block.as_mut_ptr()).cast();
+            let block = HeapBlock::<U4096>::new(addr);
+            block.min_split = 64;
+
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            let ptrs: Vec<_> = (0..3)
+                .map(|_| block.allocate(layout, Fit::First).unwrap())
+                .collect();
+            block.deallocate(ptrs[1], layout).unwrap();
+
+            // the freed hole would leave a 16 bytes sliver, so it is skipped
+            let small = Layout::from_size_align(48, 8).unwrap();
+            let ptr = block.allocate(small, Fit::First).unwrap();
+            assert!(ptr > ptrs[2]);
+            assert_eq!(block.allocate(layout, Fit::First), Ok(ptrs[1]));
+        }
+    }
+
+    #[test]
+    /// Check the free bytes and largest hole bound follow allocations.
+    fn heapblock_free_space() {
+        unsafe {
+            let mut block = [0u64; 512];
+            let addr = NonNull::new_unchecked(block.as_mut_ptr()).cast();
+            let block = HeapBlock::<U4096>::new(addr);
+            let payload = 4096 - block.payload;
+            assert_eq!((block.free, block.largest), (payload, payload));
+
+            let layout = Layout::from_size_align(768, 8).unwrap();
+            let ptrs: Vec<_> = (0..3)
+                .map(|_| block.allocate_first_fit(layout).unwrap())
+                .collect();
+            assert_eq!(block.free, payload - 3 * 768);
+            block.deallocate(ptrs[1], layout).unwrap();
+            assert_eq!(block.free, payload - 2 * 768);
+            assert!(block.check());
+
+            // the bound is only lowered once a search fails
+            let large = Layout::from_size_align(2048, 8).unwrap();
+            assert!(!block.cannot_fit(large));
+            assert!(block.allocate_first_fit(large).is_err());
+            assert_eq!(block.largest, payload - 3 * 768);
+            assert!(block.cannot_fit(large));
+            assert!(block.check());
+
+            block.deallocate(ptrs[2], layout).unwrap();
+            assert_eq!(block.largest, payload - 768);
+            assert_eq!(block.allocate_first_fit(large), Ok(ptrs[1]));
+            assert!(block.check());
+        }
+    }
 }
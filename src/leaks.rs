@@ -0,0 +1,193 @@
+//! Tracking of passthrough allocations, for leak reports.
+//!
+//! Allocations made with `alloc_with_header` are linked through their
+//! headers, but large allocations forwarded to the backing allocator have no
+//! room for bookkeeping. A [`LeakTable`] records them apart, with their
+//! layout and call site, so that a leak report at shutdown covers the whole
+//! allocator surface. The table is stored in memory given by the user, since
+//! the allocator can not allocate its own bookkeeping.
+//!
+//! [`LeakTable`]: struct.LeakTable.html
+
+use core::panic::Location;
+use core::slice;
+
+use spin::Mutex;
+
+/// A live passthrough allocation recorded in a [`LeakTable`](struct.LeakTable.html).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PassthroughAllocation {
+    /// The address of the allocation, or `0` for a free entry.
+    pub addr: usize,
+    /// The size of the allocation.
+    pub size: usize,
+    /// The alignment of the allocation.
+    pub align: usize,
+    /// The call site of the allocation.
+    pub site: Option<&'static Location<'static>>,
+}
+
+impl PassthroughAllocation {
+    /// A free entry, to initialize the memory of a table with.
+    pub const EMPTY: PassthroughAllocation = PassthroughAllocation {
+        addr: 0,
+        size: 0,
+        align: 0,
+        site: None,
+    };
+}
+
+/// The state of a leak table.
+struct LeakState {
+    len: usize,
+    missed: usize,
+}
+
+/// A fixed-capacity table of the live passthrough allocations of an allocator.
+///
+/// Allocations made once the table is full are not recorded, and only
+/// counted, so a report of a full table may miss leaks.
+pub struct LeakTable {
+    entries: *mut PassthroughAllocation,
+    capacity: usize,
+    state: Mutex<LeakState>,
+}
+
+unsafe impl Sync for LeakTable {}
+unsafe impl Send for LeakTable {}
+
+impl LeakTable {
+    /// Create a new table storing its entries in the given memory.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be valid for reads and writes of `capacity` entries,
+    /// and must not be used by anything else for as long as the table is in
+    /// use. A table must only be given to a single allocator.
+    pub const unsafe fn from_raw_parts(
+        entries: *mut PassthroughAllocation,
+        capacity: usize,
+    ) -> Self {
+        LeakTable {
+            entries,
+            capacity,
+            state: Mutex::new(LeakState { len: 0, missed: 0 }),
+        }
+    }
+
+    /// Create a new table from a pointer to a static array.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`from_raw_parts`](#method.from_raw_parts): the array must
+    /// not be accessed through any other mean once the table is used.
+    pub const unsafe fn from_array<const N: usize>(array: *mut [PassthroughAllocation; N]) -> Self {
+        Self::from_raw_parts(array as *mut PassthroughAllocation, N)
+    }
+
+    /// Returns the number of allocations the table can hold.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the number of recorded allocations.
+    pub fn len(&self) -> usize {
+        self.state.lock().len
+    }
+
+    /// Check if no allocation is recorded.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of allocations not recorded because the table was full.
+    pub fn missed(&self) -> usize {
+        self.state.lock().missed
+    }
+
+    /// Record a live allocation, or count it as missed if the table is full.
+    pub fn insert(&self, allocation: PassthroughAllocation) {
+        let mut state = self.state.lock();
+        if state.len == self.capacity {
+            state.missed += 1;
+            return;
+        }
+        unsafe { self.entries.add(state.len).write(allocation) };
+        state.len += 1;
+    }
+
+    /// Forget the allocation at the given address, and return whether it was recorded.
+    pub fn remove(&self, addr: usize) -> bool {
+        let mut state = self.state.lock();
+        let entries = unsafe { slice::from_raw_parts_mut(self.entries, state.len) };
+        match entries.iter().position(|entry| entry.addr == addr) {
+            Some(index) => {
+                // the order of the entries does not matter
+                entries.swap(index, state.len - 1);
+                state.len -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Write the recorded allocations to `allocations`, and return the number written.
+    pub fn allocations(&self, allocations: &mut [PassthroughAllocation]) -> usize {
+        let state = self.state.lock();
+        let entries = unsafe { slice::from_raw_parts(self.entries, state.len) };
+        let len = entries.len().min(allocations.len());
+        allocations[..len].copy_from_slice(&entries[..len]);
+        len
+    }
+
+    /// Call `f` for each recorded allocation, until it fails.
+    pub(crate) fn try_for_each<E>(
+        &self,
+        f: impl FnMut(&PassthroughAllocation) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let state = self.state.lock();
+        let entries = unsafe { slice::from_raw_parts(self.entries, state.len) };
+        entries.iter().try_for_each(f)
+    }
+
+    /// Forget every allocation.
+    pub fn clear(&self) {
+        let mut state = self.state.lock();
+        state.len = 0;
+        state.missed = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Check allocations are recorded until the table is full, and forgotten when freed.
+    fn leak_table_insert_remove() {
+        let entries = Box::leak(Box::new([PassthroughAllocation::EMPTY; 2]));
+        let table = unsafe { LeakTable::from_array(entries) };
+        let allocation = |addr| PassthroughAllocation {
+            addr,
+            size: 8000,
+            align: 8,
+            site: Some(Location::caller()),
+        };
+
+        table.insert(allocation(0x1000));
+        table.insert(allocation(0x2000));
+        table.insert(allocation(0x3000));
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.missed(), 1);
+
+        assert!(table.remove(0x1000));
+        assert!(!table.remove(0x3000));
+        let mut allocations = [PassthroughAllocation::EMPTY; 4];
+        assert_eq!(table.allocations(&mut allocations), 1);
+        assert_eq!(allocations[0], allocation(0x2000));
+
+        table.clear();
+        assert!(table.is_empty());
+        assert_eq!(table.missed(), 0);
+    }
+}
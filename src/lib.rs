@@ -29,25 +29,103 @@
 //! When a request is made to allocate memory, the allocator will iterate
 //! through all the heapblocks, using a **first-fit** allocation method to try
 //! to find an appropriate free memory location. If no heapblock can fit the
-//! requested layout, then a new heapblock is allocated.
+//! requested layout, then a new heapblock is allocated. When the underlying
+//! allocator returns memory directly following an existing heapblock, that
+//! heapblock is extended instead, so allocations can span both. The size of
+//! the memory blocks requested, and when to stop requesting them, can be
+//! chosen with a [`GrowthPolicy`]. With `with_free_space_order`, heapblocks
+//! are kept ordered by free space, so that searches start with the heapblock
+//! most likely to fit the layout. A [`RegionPolicy`] attached to a memory
+//! region or to the backing allocator gives the heapblocks of heterogeneous
+//! memories a search priority and a maximum allocation size.
+//!
+//! A **best-fit** strategy, using the smallest hole large enough, can be
+//! selected instead with a [`Fit`] policy, which reduces fragmentation in
+//! long-running heaps at the cost of scanning whole hole lists, unless
+//! `with_indexed_holes` links the holes in trees ordered by size, one per
+//! alignment of their start. A
+//! **next-fit** strategy resumes each search where the previous allocation
+//! in the block left off, and a **segregated** strategy keeps freed small
+//! allocations in free lists by size class. Custom heuristics can also be
+//! plugged in with a [`PlacementPolicy`]. Programs needing a bounded
+//! latency can use a [`Tlsf`] allocator instead of the [`Deblockator`]: it
+//! manages its heapblocks with a two-level segregated fit scheme, whose
+//! allocations and frees run in constant time, at the cost of a two-word
+//! header per allocation. A [`Buddy`] allocator splits its heapblocks in
+//! power-of-two halves instead, merged back with a bit flip of their
+//! address, at the cost of rounding allocations up to a power of two.
+//!
+//! Allocations of up to 64 bytes can also be served from bitmap-managed
+//! slots in each block, enabled with `with_small_objects`, which avoids
+//! splitting holes for tiny allocations. With the `std` feature, allocations
+//! of up to 128 bytes can be served from per-thread magazines instead,
+//! enabled with `with_magazines`, which move chunks from and to the heap
+//! blocks by batches so that most allocations do not take the lock.
+//!
+//! Requested sizes can optionally be rounded up using a [`SizeRounding`]
+//! policy, to avoid leaving small unusable holes between odd-sized
+//! allocations. With the `allocate_at_least` feature, `allocate_at_least`
+//! takes such holes whole instead, and returns the actual size of the
+//! memory, which suits buffer pools preferring slightly larger buffers.
 //!
 //! Allocation of very large layouts (more than `16kB`) are done using the
 //! underlying allocator directly. This avoids the possible case of memory
 //! retention with small blocks preventing the deallocation of a very large
-//! block, were the small block to outlive the larger one.
+//! block, were the small block to outlive the larger one. Freed large
+//! allocations can optionally be cached, up to a given number of bytes, to
+//! be reused for identical layouts without a round-trip to the underlying
+//! allocator.
+//!
+//! Memory obtained from the underlying allocator can be zeroed, or trusted
+//! to be, with a [`ZeroFill`] policy, so that zeroed allocations made in
+//! memory never used before are not cleared a second time.
 //!
 //! ## Deallocation
 //!
 //! If the allocated layout size is larger than the large layout limit, we
 //! simply transmit the deallocation request to the underlying allocator.
 //! Otherwise, we traverse the heapblocks to find the one the memory block
-//! belongs to. A heapblock is deallocated when it is completely empty.
+//! belongs to, or mask the pointer if heapblocks are aligned to their size.
+//! With many heapblocks, a [`BlockRegistry`] keeps their addresses sorted, so
+//! that the heapblock is found with a binary search instead.
+//! Empty heapblocks are kept for later use, unless a [`Retention`]
+//! policy releasing them is set, in which case heapblocks are returned to the
+//! underlying allocator after staying empty for a given duration, measured
+//! with a user-provided [`Clock`] (or the [`StdClock`] with the `std` feature),
+//! or as soon as too many heapblocks are empty.
+//! This housekeeping is done during deallocations, unless it is scheduled
+//! manually or delegated to a background thread with the `std` feature.
+//!
+//! Deallocations need the layout of the allocation, as with any Rust
+//! allocator. For C code calling `free(ptr)`, allocations made with
+//! `alloc_with_header` are preceded by a header recording their layout and
+//! heapblock, so that they can be freed with `free` alone. The header can
+//! also record a user cookie, read back with `cookie_of`, and records the
+//! allocating thread, so that `cross_thread_frees` tells how often memory is
+//! freed by another thread. The other C allocation functions are built on
+//! the same header, such as `posix_memalign`, `aligned_alloc` and an
+//! overflow-checked `reallocarray`, to be exported by the program:
+//! ```rust,ignore
+//! #[no_mangle]
+//! pub unsafe extern "C" fn reallocarray(ptr: *mut u8, count: usize, size: usize) -> *mut u8 {
+//!     GLOBAL.reallocarray(ptr, count, size)
+//! }
+//! ```
 //!
 //! ## Synchronisation
 //!
 //! The [`Deblockator`] can wraps non-global allocator, and needs a synchronisation
 //! primitive to avoid race conditions. This is done using a *spinning mutex*
-//! from the [`spin`] crate.
+//! from the [`spin`] crate. With the `watchdog` feature, a [`LockWatchdog`]
+//! can report the call site holding the lock when it is held for too long,
+//! which helps debugging deadlocks involving the global allocator.
+//! The mutex only relies on atomic instructions, so the same allocator can
+//! be shared by threads on `wasm32` with the threads proposal, where the
+//! heap lives in shared memory, as well as on single-threaded `wasm32-wasip1`.
+//! Allocations made from the hooks of the allocator, such as a logger
+//! called from the failure hook, could also deadlock or recurse: they are
+//! detected, and served from an emergency [`Region`] instead, while the
+//! frees they make from the heap are rejected.
 //!
 //! # Usage
 //!
@@ -56,7 +134,7 @@
 //! The provided [`Deblockator`] wraps any object implementing [`Alloc`]. For
 //! instance, to use [`Deblockator`] with `jemalloc` to allocate the
 //! heapblocks:
-//! ```rust,no_run
+//! ```rust,ignore
 //! #![feature(global_allocator)]
 //! extern crate jemallocator;
 //! extern crate deblockator;
@@ -69,6 +147,103 @@
 //! # fn main() {}
 //! ```
 //!
+//! ## Static memory
+//!
+//! On systems without any allocator to wrap, a [`Region`] can be used to
+//! serve blocks from a fixed memory region, such as a static array or a
+//! linker-defined section. The [`global_deblockator!`] macro installs such
+//! an allocator over a static array in a single line:
+//! ```rust,ignore
+//! #[macro_use]
+//! extern crate deblockator;
+//!
+//! static mut HEAP: [u8; 1 << 20] = [0; 1 << 20];
+//! global_deblockator!(GLOBAL, HEAP);
+//! ```
+//!
+//! The [`static_deblockator!`] macro also reserves the array itself, given
+//! its size and optionally the link section to place it in:
+//! ```rust,ignore
+//! static_deblockator!(GLOBAL, 1 << 20, ".bss.heap");
+//! ```
+//!
+//! ## Local heaps
+//!
+//! A [`Deblockator`] also implements [`Allocator`], so a dedicated instance
+//! can back the collections of a single subsystem. With the `alloc` feature,
+//! the [`boxed_in`] function and the [`vec_in!`] macro build such collections:
+//! ```rust,ignore
+//! let heap: Deblockator<System> = Deblockator::new(System);
+//! let value = deblockator::boxed_in(42, &heap);
+//! let values = vec_in![&heap; 1, 2, 3];
+//! ```
+//!
+//! A [`Pool`] built on such an instance serves fixed-size slots for a
+//! single type from a free-list, for programs allocating many objects of
+//! the same size. An [`AccessOrder`] tracks which allocations were used
+//! least recently, for cache-like subsystems deciding what to free under
+//! memory pressure.
+//!
+//! A subsystem can also lease a whole heap block from a shared instance with
+//! `lease_block`, and allocate from it through the returned [`BlockLease`],
+//! which frees all of its allocations at once when dropped.
+//!
+//! Code that should not depend on the type parameters of the allocator, such
+//! as a plugin interface, can use a `&dyn` [`DynDeblockator`] instead.
+//!
+//! A [`Tiered`] allocator routes layouts by size to allocators with
+//! different block sizes, so that small layouts use small blocks without
+//! making large ones request many blocks from the backing allocator.
+//!
+//! ## Statistics
+//!
+//! The [`StatsAlloc`] wrapper counts operations and bytes of the allocator it
+//! wraps. It can be used around the backing allocator to instrument block
+//! acquisitions, or around the [`Deblockator`] to instrument user allocations:
+//! ```rust,ignore
+//! #[global_allocator]
+//! static GLOBAL: StatsAlloc<Deblockator<StatsAlloc<Jemalloc>>> =
+//!     StatsAlloc::new(Deblockator::new(StatsAlloc::new(Jemalloc)));
+//!
+//! let user_bytes = GLOBAL.stats().live_bytes();
+//! let block_bytes = GLOBAL.inner().backing_allocator().stats().live_bytes();
+//! ```
+//!
+//! The memory held by the allocator can also be broken down into user data
+//! and metadata, such as block headers and padding, with [`Overhead`]:
+//! ```rust,ignore
+//! let overhead = GLOBAL.inner().overhead();
+//! assert!(overhead.metadata() <= BUDGET);
+//! ```
+//!
+//! The allocations made with `alloc_with_header` are linked together, so
+//! that a [`Snapshot`] of the live ones can be taken at a checkpoint, and
+//! compared with a later one to list the allocations made in between and
+//! never freed, along with their call sites:
+//! ```rust,ignore
+//! let before: Snapshot<256> = GLOBAL.inner().snapshot();
+//! run_for_an_hour();
+//! let after: Snapshot<256> = GLOBAL.inner().snapshot();
+//! for allocation in Snapshot::diff(&before, &after) {
+//!     println!("{} bytes from {}", allocation.size, allocation.site);
+//! }
+//! ```
+//!
+//! A [`SiteTable`] set with `with_site_table` also aggregates these
+//! allocations by call site, with their live bytes, number and peak, so
+//! that a debug shell can list the heaviest consumers of the heap at any
+//! time with `SiteTable::top`.
+//!
+//! Large allocations forwarded to the backing allocator have no header, but
+//! a [`LeakTable`] set with `with_leak_table` records them with their layout
+//! and call site, so that `write_leak_report` lists every live allocation
+//! of the allocator at shutdown, with or without a header.
+//!
+//! When an allocation fails, `with_oom_report` sends a compact report of the
+//! heap to a sink, with its statistics, the occupancy of each heapblock and
+//! the largest holes, which is often all there is to debug a device running
+//! out of memory in the field.
+//!
 //! ## PS Vita target
 //!
 //! If you're compiling to PS Vita: use the [`Vitallocator`], which
@@ -88,7 +263,36 @@
 //! ```
 //!
 //! [`spin`]: https://docs.rs/spin/
+//! [`LockWatchdog`]: struct.LockWatchdog.html
 //! [`typenum`]: https://docs.rs/typenum/
+//! [`SizeRounding`]: enum.SizeRounding.html
+//! [`Fit`]: enum.Fit.html
+//! [`PlacementPolicy`]: trait.PlacementPolicy.html
+//! [`GrowthPolicy`]: trait.GrowthPolicy.html
+//! [`RegionPolicy`]: struct.RegionPolicy.html
+//! [`ZeroFill`]: enum.ZeroFill.html
+//! [`Retention`]: enum.Retention.html
+//! [`Clock`]: trait.Clock.html
+//! [`StdClock`]: struct.StdClock.html
+//! [`Overhead`]: struct.Overhead.html
+//! [`Snapshot`]: struct.Snapshot.html
+//! [`SiteTable`]: struct.SiteTable.html
+//! [`LeakTable`]: struct.LeakTable.html
+//! [`BlockRegistry`]: struct.BlockRegistry.html
+//! [`StatsAlloc`]: struct.StatsAlloc.html
+//! [`Region`]: struct.Region.html
+//! [`global_deblockator!`]: macro.global_deblockator.html
+//! [`static_deblockator!`]: macro.static_deblockator.html
+//! [`boxed_in`]: fn.boxed_in.html
+//! [`DynDeblockator`]: trait.DynDeblockator.html
+//! [`BlockLease`]: struct.BlockLease.html
+//! [`Tiered`]: struct.Tiered.html
+//! [`Tlsf`]: struct.Tlsf.html
+//! [`Buddy`]: struct.Buddy.html
+//! [`Pool`]: struct.Pool.html
+//! [`AccessOrder`]: struct.AccessOrder.html
+//! [`vec_in!`]: macro.vec_in.html
+//! [`Allocator`]: https://doc.rust-lang.org/nightly/std/alloc/trait.Allocator.html
 //! [`Alloc`]: https://doc.rust-lang.org/nightly/std/alloc/trait.Alloc.html
 //! [`Vitallocator`]: https://docs.rs/vitallocator/latest/vitallocator/struct.Vitallocator.html
 //! [`KernelAllocator`]: struct.KernelAllocator.html
@@ -99,15 +303,150 @@
 #![feature(alloc_layout_extra)]
 #![feature(const_mut_refs)]
 
-#[cfg(test)]
-use std as core;
+#[cfg(all(feature = "std", not(test)))]
+extern crate std;
+
+#[cfg(feature = "alloc")]
+#[doc(hidden)]
+pub extern crate alloc as __alloc;
 
 extern crate spin;
 extern crate typenum;
 
 mod alloc;
+mod buddy;
+mod cache;
+mod clock;
+#[cfg(feature = "alloc")]
+mod collections;
+mod consts;
+mod dynamic;
 mod hole;
+mod leaks;
+mod lru;
+mod magazine;
+mod policy;
+mod pool;
+mod reentrancy;
+mod region;
+mod registry;
+mod scope;
+mod shared;
+mod sites;
+mod snapshot;
+mod stats;
+mod tiered;
+mod tlsf;
+mod tree;
 mod utils;
+#[cfg(feature = "watchdog")]
+mod watchdog;
 
 // Public reexport of the generic allocator.
+pub use alloc::AllocFailure;
+pub use alloc::BlockLease;
+pub use alloc::Blocks;
 pub use alloc::Deblockator;
+pub use alloc::SelfTestError;
+
+// Public reexport of the buddy allocator.
+pub use buddy::Buddy;
+
+// Public reexport of the collection helpers.
+#[cfg(feature = "alloc")]
+pub use collections::boxed_in;
+
+// Public reexport of the type-erased interface.
+pub use dynamic::DynDeblockator;
+
+// Public reexport of the heap block, to build blocks from static memory.
+pub use hole::BestFit;
+pub use hole::BlockInfo;
+pub use hole::FirstFit;
+pub use hole::HeapBlock;
+pub use hole::HoleInfo;
+pub use hole::Holes;
+pub use hole::NextFit;
+pub use hole::Occupancy;
+pub use hole::PendingChunks;
+pub use hole::PlacementPolicy;
+
+// Public reexport of the passthrough allocation tracking.
+pub use leaks::LeakTable;
+pub use leaks::PassthroughAllocation;
+
+// Public reexport of the access ordering of allocations.
+pub use lru::AccessOrder;
+pub use lru::Coldest;
+
+// Public reexport of the time sources.
+pub use clock::Clock;
+#[cfg(feature = "std")]
+pub use clock::StdClock;
+
+// Public reexport of the object pool.
+pub use pool::Pool;
+
+// Public reexport of the region block source.
+pub use region::Region;
+
+// Public reexport of the heap block registry.
+pub use registry::BlockRegistry;
+
+// Public reexport of the per-thread allocation scopes.
+#[cfg(feature = "std")]
+pub use scope::HeapScope;
+#[cfg(feature = "std")]
+pub use scope::TagScope;
+
+// Public reexport of the shared memory regions.
+pub use shared::SharedRegion;
+
+// Public reexport of the allocation policies.
+pub use policy::Criticality;
+pub use policy::DeallocError;
+pub use policy::DeallocErrorPolicy;
+pub use policy::DumpContent;
+pub use policy::ExponentialGrowth;
+pub use policy::Fit;
+pub use policy::GrowthPolicy;
+pub use policy::GrowthRequest;
+pub use policy::Health;
+pub use policy::HealthThresholds;
+pub use policy::Placement;
+pub use policy::RegionPolicy;
+pub use policy::RegionPriority;
+pub use policy::Retention;
+pub use policy::SizeRounding;
+pub use policy::Watermarks;
+pub use policy::ZeroFill;
+
+// Public reexport of the per-call-site statistics.
+pub use sites::SiteStats;
+pub use sites::SiteTable;
+
+// Public reexport of the live allocation snapshots.
+pub use snapshot::Allocations;
+pub use snapshot::LiveAllocation;
+pub use snapshot::Snapshot;
+
+// Public reexport of the statistics wrapper.
+pub use stats::Overhead;
+pub use stats::Stats;
+pub use stats::StatsAlloc;
+
+// Public reexport of the size tiers.
+pub use tiered::Tiered;
+
+// Public reexport of the two-level segregated fit allocator.
+pub use tlsf::Tlsf;
+
+// Public reexport of the lock watchdog.
+#[cfg(feature = "watchdog")]
+pub use watchdog::LockWatchdog;
+
+// Public reexport of the platform-dependent defaults.
+pub use consts::DefaultBlockAlign;
+pub use consts::DefaultBlockSize;
+pub use consts::DefaultLargeAlign;
+pub use consts::DefaultLargeSize;
@@ -0,0 +1,207 @@
+//! Access ordering of allocations.
+//!
+//! An [`AccessOrder`] records when tracked allocations were last used, as
+//! reported by explicit [`touch`] calls, so that cache-like consumers can
+//! find their coldest allocations when memory gets scarce, for instance once
+//! a watermark is crossed. The ordering is approximate, since accesses that
+//! are not reported are not seen.
+//!
+//! [`AccessOrder`]: struct.AccessOrder.html
+//! [`touch`]: struct.AccessOrder.html#method.touch
+
+use core::ptr::NonNull;
+
+use spin::Mutex;
+
+/// A tracked allocation, and the tick of its last access.
+///
+/// Free entries have a null address.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    addr: usize,
+    tick: u64,
+}
+
+/// The tracked allocations of an access order.
+struct AccessState<const N: usize> {
+    entries: [Entry; N],
+    tick: u64,
+}
+
+impl<const N: usize> AccessState<N> {
+    /// Returns the entry of the given allocation, if tracked.
+    fn find(&mut self, ptr: NonNull<u8>) -> Option<&mut Entry> {
+        let addr = ptr.as_ptr() as usize;
+        self.entries.iter_mut().find(|entry| entry.addr == addr)
+    }
+
+    /// Returns a tick more recent than all the previous ones.
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+/// The access order of up to `N` allocations.
+///
+/// Allocations are tracked explicitly, and are not freed by the tracker:
+/// they must be untracked when freed. Each operation scans the tracked
+/// allocations, so `N` should be kept small.
+pub struct AccessOrder<const N: usize> {
+    state: Mutex<AccessState<N>>,
+}
+
+impl<const N: usize> AccessOrder<N> {
+    /// Create a tracker without any allocation.
+    pub const fn new() -> Self {
+        AccessOrder {
+            state: Mutex::new(AccessState {
+                entries: [Entry { addr: 0, tick: 0 }; N],
+                tick: 0,
+            }),
+        }
+    }
+
+    /// Start tracking the given allocation, as the most recently used one.
+    ///
+    /// Returns `false` if `N` allocations are already tracked.
+    pub fn track(&self, ptr: NonNull<u8>) -> bool {
+        let mut state = self.state.lock();
+        let tick = state.next_tick();
+        if let Some(entry) = state.find(ptr) {
+            entry.tick = tick;
+            return true;
+        }
+        match state.entries.iter_mut().find(|entry| entry.addr == 0) {
+            Some(entry) => {
+                *entry = Entry {
+                    addr: ptr.as_ptr() as usize,
+                    tick,
+                };
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Mark the given allocation as the most recently used one.
+    ///
+    /// Returns `false` if the allocation is not tracked.
+    pub fn touch(&self, ptr: NonNull<u8>) -> bool {
+        let mut state = self.state.lock();
+        let tick = state.next_tick();
+        match state.find(ptr) {
+            Some(entry) => {
+                entry.tick = tick;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stop tracking the given allocation.
+    ///
+    /// Returns `false` if the allocation was not tracked.
+    pub fn untrack(&self, ptr: NonNull<u8>) -> bool {
+        let mut state = self.state.lock();
+        match state.find(ptr) {
+            Some(entry) => {
+                entry.addr = 0;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the number of tracked allocations.
+    pub fn len(&self) -> usize {
+        let state = self.state.lock();
+        state.entries.iter().filter(|entry| entry.addr != 0).count()
+    }
+
+    /// Check if no allocation is tracked.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the tracked allocations, from the least to the most recently used.
+    ///
+    /// The iterator works on a snapshot, so the tracker can be updated while
+    /// iterating, for instance to untrack the allocations being freed.
+    pub fn coldest(&self) -> Coldest<N> {
+        let mut entries = self.state.lock().entries;
+        entries.sort_unstable_by_key(|entry| (entry.addr == 0, entry.tick));
+        Coldest { entries, next: 0 }
+    }
+}
+
+impl<const N: usize> Default for AccessOrder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An iterator over tracked allocations, from the least to the most recently used.
+///
+/// Created by [`AccessOrder::coldest`](struct.AccessOrder.html#method.coldest).
+pub struct Coldest<const N: usize> {
+    entries: [Entry; N],
+    next: usize,
+}
+
+impl<const N: usize> Iterator for Coldest<N> {
+    type Item = NonNull<u8>;
+
+    fn next(&mut self) -> Option<NonNull<u8>> {
+        let entry = self.entries.get(self.next)?;
+        self.next += 1;
+        NonNull::new(entry.addr as *mut u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::alloc::GlobalAlloc;
+    use std::alloc::Layout;
+    use std::alloc::System;
+
+    use crate::Deblockator;
+
+    #[test]
+    /// Check allocations are listed from the least to the most recently touched.
+    fn access_order_coldest() {
+        let va: Deblockator<System> = Deblockator::new(System);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let order: AccessOrder<3> = AccessOrder::new();
+
+        unsafe {
+            let ptrs: Vec<_> = (0..4)
+                .map(|_| NonNull::new(va.alloc(layout)).unwrap())
+                .collect();
+            assert!(order.track(ptrs[0]));
+            assert!(order.track(ptrs[1]));
+            assert!(order.track(ptrs[2]));
+            assert!(!order.track(ptrs[3]));
+            assert!(!order.touch(ptrs[3]));
+
+            assert!(order.touch(ptrs[0]));
+            assert!(order.coldest().eq([ptrs[1], ptrs[2], ptrs[0]]));
+
+            // free the coldest allocations until enough memory is reclaimed
+            for ptr in order.coldest().take(2) {
+                assert!(order.untrack(ptr));
+                va.dealloc(ptr.as_ptr(), layout);
+            }
+            assert_eq!(order.len(), 1);
+            assert!(order.coldest().eq([ptrs[0]]));
+            assert!(order.track(ptrs[3]));
+            assert!(order.coldest().eq([ptrs[0], ptrs[3]]));
+
+            for ptr in [ptrs[0], ptrs[3]] {
+                va.dealloc(ptr.as_ptr(), layout);
+            }
+        }
+    }
+}
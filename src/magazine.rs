@@ -0,0 +1,272 @@
+//! Per-thread magazines of small allocations.
+//!
+//! A magazine keeps a few free chunks of each small size class for the
+//! current thread, so that a `Deblockator` with magazines enabled serves and
+//! frees hot sizes without taking its lock. Chunks are moved between the
+//! magazine and the heap blocks by batches, and the magazine is flushed back
+//! to the heap blocks when the thread exits. Magazines need thread-local
+//! storage, and are only available with the `std` feature.
+
+use core::alloc::Layout;
+use core::cmp::max;
+#[cfg(feature = "std")]
+use core::ptr;
+use core::ptr::NonNull;
+
+#[cfg(feature = "std")]
+use std::cell::RefCell;
+
+/// The number of size classes, from 16 to 128 bytes.
+const CLASSES: usize = 4;
+
+/// The size of the smallest class.
+const MIN_CLASS_SIZE: usize = 16;
+
+/// The alignment of the chunks of all classes.
+const CLASS_ALIGN: usize = 16;
+
+/// The number of chunks a magazine holds for each class.
+#[cfg(feature = "std")]
+const ROUNDS: usize = 16;
+
+/// The number of chunks moved at once between a magazine and the heap blocks.
+pub const BATCH: usize = 8;
+
+/// Free the given chunks of a size class in the heap blocks of their owner.
+///
+/// At most `BATCH` chunks are freed at once.
+pub type Flush = unsafe fn(owner: *const (), chunks: &[*mut u8], class: usize);
+
+/// Returns the size class of a block layout, if it is small enough.
+pub fn class(layout: Layout) -> Option<usize> {
+    if layout.align() > CLASS_ALIGN {
+        return None;
+    }
+    let size = max(layout.size(), MIN_CLASS_SIZE).next_power_of_two();
+    let class = (size / MIN_CLASS_SIZE).trailing_zeros() as usize;
+    if class < CLASSES {
+        Some(class)
+    } else {
+        None
+    }
+}
+
+/// Returns the block layout of the chunks of a size class.
+pub fn class_layout(class: usize) -> Layout {
+    unsafe { Layout::from_size_align_unchecked(MIN_CLASS_SIZE << class, CLASS_ALIGN) }
+}
+
+/// The free chunks kept by a thread for a single allocator.
+#[cfg(feature = "std")]
+struct Magazine {
+    owner: *const (),
+    flush: Flush,
+    rounds: [[*mut u8; ROUNDS]; CLASSES],
+    counts: [usize; CLASSES],
+}
+
+#[cfg(feature = "std")]
+impl Magazine {
+    /// Return all the chunks of the magazine to their owner, by batches.
+    fn empty(&mut self) {
+        for class in 0..CLASSES {
+            let count = self.counts[class];
+            self.counts[class] = 0;
+            for chunks in self.rounds[class][..count].chunks(BATCH) {
+                unsafe { (self.flush)(self.owner, chunks, class) };
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for Magazine {
+    fn drop(&mut self) {
+        self.empty();
+    }
+}
+
+#[cfg(feature = "std")]
+std::thread_local! {
+    static MAGAZINE: RefCell<Option<Magazine>> = const { RefCell::new(None) };
+}
+
+/// Run `f` on the magazine of the current thread, if it belongs to `owner`.
+///
+/// The magazine of a thread is bound to the first allocator using it. It is
+/// unavailable while the thread-local storage is destroyed, or if an
+/// allocation is made while it is already in use.
+#[cfg(feature = "std")]
+fn with_magazine<R, F>(owner: *const (), flush: Flush, f: F) -> Option<R>
+where
+    F: FnOnce(&mut Magazine) -> R,
+{
+    MAGAZINE
+        .try_with(|cell| {
+            let mut magazine = cell.try_borrow_mut().ok()?;
+            let magazine = magazine.get_or_insert_with(|| Magazine {
+                owner,
+                flush,
+                rounds: [[ptr::null_mut(); ROUNDS]; CLASSES],
+                counts: [0; CLASSES],
+            });
+            if magazine.owner == owner {
+                Some(f(magazine))
+            } else {
+                None
+            }
+        })
+        .ok()
+        .flatten()
+}
+
+/// Take a free chunk of the given class from the magazine of the current thread.
+///
+/// When the magazine has no chunk of the class, `refill` is called to move
+/// up to `BATCH` chunks into it, and returns how many it moved.
+#[cfg(feature = "std")]
+pub fn take<F>(owner: *const (), flush: Flush, class: usize, refill: F) -> Option<NonNull<u8>>
+where
+    F: FnOnce(&mut [*mut u8]) -> usize,
+{
+    with_magazine(owner, flush, |magazine| {
+        let count = &mut magazine.counts[class];
+        if *count == 0 {
+            *count = refill(&mut magazine.rounds[class][..BATCH]);
+        }
+        if *count == 0 {
+            return None;
+        }
+        *count -= 1;
+        NonNull::new(magazine.rounds[class][*count])
+    })
+    .flatten()
+}
+
+/// Give a free chunk of the given class to the magazine of the current thread.
+///
+/// When the magazine is full, its oldest `BATCH` chunks of the class are
+/// flushed to the owner first. Returns `false` if the chunk must be freed in
+/// the heap blocks instead.
+#[cfg(feature = "std")]
+pub fn put(owner: *const (), flush: Flush, class: usize, chunk: NonNull<u8>) -> bool {
+    with_magazine(owner, flush, |magazine| {
+        let rounds = &mut magazine.rounds[class];
+        let count = &mut magazine.counts[class];
+        if *count == ROUNDS {
+            unsafe { (magazine.flush)(owner, &rounds[..BATCH], class) };
+            rounds.copy_within(BATCH.., 0);
+            *count -= BATCH;
+        }
+        rounds[*count] = chunk.as_ptr();
+        *count += 1;
+    })
+    .is_some()
+}
+
+/// Return the chunks of the magazine of the current thread to `owner`.
+#[cfg(feature = "std")]
+pub fn flush(owner: *const (), flush: Flush) {
+    with_magazine(owner, flush, Magazine::empty);
+}
+
+/// Take a free chunk of the given class from the magazine of the current thread.
+#[cfg(not(feature = "std"))]
+pub fn take<F>(_owner: *const (), _flush: Flush, _class: usize, _refill: F) -> Option<NonNull<u8>>
+where
+    F: FnOnce(&mut [*mut u8]) -> usize,
+{
+    None
+}
+
+/// Give a free chunk of the given class to the magazine of the current thread.
+#[cfg(not(feature = "std"))]
+pub fn put(_owner: *const (), _flush: Flush, _class: usize, _chunk: NonNull<u8>) -> bool {
+    false
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::alloc::GlobalAlloc;
+    use std::alloc::System;
+
+    use crate::Deblockator;
+
+    use super::*;
+
+    static VA: Deblockator<System> = unsafe { Deblockator::new(System).with_magazines() };
+
+    /// Returns the bytes used in the heap blocks of the test allocator.
+    fn used() -> usize {
+        VA.blocks().map(|block| block.used).sum()
+    }
+
+    #[test]
+    #[cfg_attr(all(target_family = "wasm", not(target_feature = "atomics")), ignore)]
+    /// Check small allocations are served by batches from a per-thread magazine.
+    fn magazine_batches() {
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let held = std::thread::spawn(move || unsafe {
+            // the first allocation grows the heap, and fills no magazine
+            let ptr = VA.alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % CLASS_ALIGN, 0);
+            let held = used();
+
+            // the chunk goes to the magazine when freed, and is handed out again
+            VA.dealloc(ptr, layout);
+            assert_eq!(VA.alloc(layout), ptr);
+            assert_eq!(used(), held);
+
+            // the empty magazine is refilled by a single batch
+            let ptrs: Vec<_> = (0..BATCH).map(|_| VA.alloc(layout)).collect();
+            assert_eq!(used(), held + BATCH * 32);
+            VA.dealloc(ptr, layout);
+            for ptr in ptrs {
+                VA.dealloc(ptr, layout);
+            }
+            assert_eq!(used(), held + BATCH * 32);
+            held
+        })
+        .join()
+        .unwrap();
+
+        // the magazine is flushed back to the heap blocks on thread exit
+        assert_eq!(used() + 32, held);
+    }
+
+    #[test]
+    #[cfg_attr(all(target_family = "wasm", not(target_feature = "atomics")), ignore)]
+    /// Check unknown pointers are reported instead of going to the magazine.
+    fn magazine_unknown_pointer() {
+        use crate::DeallocError;
+        use crate::DeallocErrorPolicy;
+        use core::sync::atomic::AtomicUsize;
+        use core::sync::atomic::Ordering;
+
+        static ERRORS: AtomicUsize = AtomicUsize::new(0);
+        fn record(_ptr: *mut u8, _layout: Layout, _error: DeallocError) {
+            ERRORS.fetch_add(1, Ordering::Relaxed);
+        }
+        static VA: Deblockator<System> = unsafe {
+            Deblockator::new(System)
+                .with_magazines()
+                .with_dealloc_error_policy(DeallocErrorPolicy::Callback(record))
+        };
+
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        std::thread::spawn(move || unsafe {
+            let ptr = VA.alloc(layout);
+            let mut outside = [0u64; 4];
+            let outside = outside.as_mut_ptr() as *mut u8;
+            VA.dealloc(outside, layout);
+            assert_eq!(ERRORS.load(Ordering::Relaxed), 1);
+
+            VA.dealloc(ptr, layout);
+            assert_eq!(VA.alloc(layout), ptr);
+            assert_ne!(VA.alloc(layout), outside);
+        })
+        .join()
+        .unwrap();
+    }
+}
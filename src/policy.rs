@@ -0,0 +1,466 @@
+//! Runtime allocation policies.
+//!
+//! Unlike the numeric parameters of the `Deblockator`, which are given as
+//! `typenum` types, policies are plain values passed to the allocator at
+//! construction time, using the `with_*` methods.
+
+use core::alloc::Layout;
+
+use super::alloc::AllocFailure;
+use super::clock::Clock;
+use super::hole::PlacementPolicy;
+use super::utils::align_up;
+
+/// A policy used to round up the size of block-managed allocations.
+///
+/// Rounding trades internal fragmentation (the unused bytes at the end of
+/// an allocation) for external fragmentation: odd-sized allocations will no
+/// longer leave holes too small to be reused between each other.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SizeRounding {
+    /// Only round sizes up to the minimal allocation size.
+    #[default]
+    Exact,
+    /// Round sizes up to a multiple of the given power of two.
+    Multiple(usize),
+    /// Round sizes up to the next power of two.
+    PowerOfTwo,
+}
+
+impl SizeRounding {
+    /// Round the given size according to the policy.
+    pub fn round(&self, size: usize) -> usize {
+        match *self {
+            SizeRounding::Exact => size,
+            SizeRounding::Multiple(n) => align_up(size, n),
+            SizeRounding::PowerOfTwo => size.next_power_of_two(),
+        }
+    }
+}
+
+/// A strategy used to choose the hole an allocation is placed in.
+///
+/// The first hole large enough is the fastest to find, but it tends to
+/// split large holes for small allocations. Searching for the smallest hole
+/// large enough costs a full scan of the hole list, but keeps large holes
+/// available for large allocations in long-running heaps. Resuming the
+/// search where the previous allocation left off spreads many short-lived
+/// allocations over a block instead of re-scanning the same early holes.
+/// Other heuristics can be plugged in with a [`PlacementPolicy`](trait.PlacementPolicy.html).
+#[derive(Debug, Default, Clone, Copy)]
+pub enum Fit {
+    /// Use the first hole large enough for the allocation.
+    #[default]
+    First,
+    /// Use the smallest hole large enough for the allocation.
+    Best,
+    /// Use the first hole large enough after the previous allocation in the
+    /// same block, wrapping around to the start of the block.
+    Next,
+    /// Use the hole chosen by the given policy.
+    Custom(&'static dyn PlacementPolicy),
+    /// Keep freed small allocations in free lists segregated by size class,
+    /// so that small allocations find a chunk without scanning the holes.
+    Segregated,
+}
+
+/// A policy deciding how long empty heap blocks are kept.
+///
+/// Keeping empty blocks avoids requesting them again from the backing
+/// allocator on the next allocation burst, which suits embedded systems
+/// pinning their memory, while servers sharing a machine rather return
+/// memory as soon as possible.
+#[derive(Debug, Default, Clone, Copy)]
+pub enum Retention {
+    /// Keep empty heap blocks forever.
+    #[default]
+    KeepAll,
+    /// Keep at most the given number of empty heap blocks, releasing the
+    /// other ones as soon as they are found.
+    KeepSpare(usize),
+    /// Release heap blocks once they have been empty for the given number
+    /// of ticks of the clock.
+    ReleaseIdle(&'static dyn Clock, u64),
+}
+
+/// Where the allocations of a layout are served from, overriding the size threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// Forward the allocations to the backing allocator.
+    Passthrough,
+    /// Serve the allocations from heap blocks.
+    Blocks,
+}
+
+/// What a heap dump contains.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DumpContent {
+    /// Only the heap metadata: addresses, sizes and block metadata.
+    #[default]
+    Metadata,
+    /// The heap metadata, and the bytes of the memory in use.
+    Full,
+}
+
+/// Whether memory obtained from the backing allocator is known to be zeroed.
+///
+/// When it is, zeroed allocations made in memory never used before only
+/// need their first few bytes cleared, which held heap block bookkeeping.
+/// Unless memory is never zeroed, shared blocks are zeroed when lent too,
+/// so that no stale data crosses subsystem boundaries.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ZeroFill {
+    /// Memory is not zeroed, so zeroed allocations are cleared entirely.
+    #[default]
+    Never,
+    /// Zero memory when it is obtained from the backing allocator.
+    OnAcquire,
+    /// The backing allocator guarantees that the memory it returns is
+    /// zeroed, like freshly mapped pages.
+    Trusted,
+}
+
+/// A summary of the state of a heap, for supervisory code.
+///
+/// Variants are ordered by severity, and the most severe one that applies
+/// is reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Health {
+    /// None of the thresholds is reached.
+    Healthy,
+    /// The free memory of the heap blocks is scattered in small holes.
+    Fragmented,
+    /// The memory obtained from the backing allocator is close to the quota.
+    NearExhaustion,
+    /// Allocations failed since the previous assessment.
+    Failing,
+}
+
+/// The thresholds used to assess the [`Health`] of a heap.
+///
+/// [`Health`]: enum.Health.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HealthThresholds {
+    /// The percentage of the free memory of the heap blocks outside of the
+    /// largest hole above which the heap is fragmented.
+    pub fragmentation: usize,
+    /// The percentage of the quota above which the heap is near exhaustion.
+    pub exhaustion: usize,
+    /// The number of failed allocations since the previous assessment from
+    /// which the heap is failing.
+    pub failures: usize,
+}
+
+impl HealthThresholds {
+    /// Create the default thresholds: 50% fragmentation, 90% of the quota,
+    /// and a single failure.
+    pub const fn new() -> Self {
+        HealthThresholds {
+            fragmentation: 50,
+            exhaustion: 90,
+            failures: 1,
+        }
+    }
+}
+
+impl Default for HealthThresholds {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The state of a heap about to grow, given to a [`GrowthPolicy`].
+///
+/// [`GrowthPolicy`]: trait.GrowthPolicy.html
+#[derive(Debug, Clone, Copy)]
+pub struct GrowthRequest {
+    /// The block layout that does not fit in the heap blocks.
+    pub layout: Layout,
+    /// The block size of the allocator, `BS`.
+    pub block_size: usize,
+    /// The number of heap blocks.
+    pub blocks: usize,
+    /// The number of bytes obtained from the backing allocator.
+    pub acquired: usize,
+    /// The number of chunks already obtained for this layout.
+    pub attempt: usize,
+}
+
+/// A heuristic deciding how the heap grows when no heap block fits a layout.
+///
+/// A policy chooses the size of the chunks requested from the backing
+/// allocator, and when to stop requesting them, for instance to limit the
+/// number of heap blocks or to stay well below a quota. Chunk sizes are
+/// rounded up to a multiple of `BS`. Use it with `with_growth_policy`.
+///
+/// The exponential growth is also available as a policy ([`ExponentialGrowth`]),
+/// to be wrapped by custom ones.
+///
+/// [`ExponentialGrowth`]: struct.ExponentialGrowth.html
+pub trait GrowthPolicy: Sync {
+    /// Returns the size of the next chunk to request, or `None` to fail
+    /// the allocation without growing the heap.
+    ///
+    /// By default, chunks of `BS` bytes are requested.
+    fn chunk_size(&self, request: &GrowthRequest) -> Option<usize> {
+        Some(request.block_size)
+    }
+
+    /// Returns the size of a chunk to request after the chunk of `failed`
+    /// bytes could not be obtained, or `None` to give up.
+    ///
+    /// The new size must be smaller than `failed`, otherwise the policy
+    /// gives up. By default, a chunk of `BS` bytes is requested instead of
+    /// larger ones, whatever the reason of the failure.
+    fn retry_size(
+        &self,
+        request: &GrowthRequest,
+        failed: usize,
+        reason: AllocFailure,
+    ) -> Option<usize> {
+        let _ = reason;
+        if failed > request.block_size {
+            Some(request.block_size)
+        } else {
+            None
+        }
+    }
+}
+
+/// A growth policy requesting chunks doubling in size, up to a cap.
+///
+/// Each chunk is twice as large as the previous one, starting from `BS`
+/// bytes. The size is computed from the number of heap blocks, so it
+/// decreases again once blocks are released.
+#[derive(Debug, Clone, Copy)]
+pub struct ExponentialGrowth {
+    cap: usize,
+}
+
+impl ExponentialGrowth {
+    /// Create a policy requesting chunks of up to `cap` bytes.
+    pub const fn new(cap: usize) -> Self {
+        ExponentialGrowth { cap }
+    }
+}
+
+impl GrowthPolicy for ExponentialGrowth {
+    fn chunk_size(&self, request: &GrowthRequest) -> Option<usize> {
+        let mut chunk = request.block_size;
+        for _ in 0..request.blocks {
+            match chunk.checked_mul(2) {
+                Some(double) if double <= self.cap => chunk = double,
+                _ => break,
+            }
+        }
+        Some(chunk)
+    }
+}
+
+/// The criticality of an allocation, used to degrade service under pressure.
+///
+/// Allocations made through `GlobalAlloc` or `Allocator` are `Normal`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Criticality {
+    /// Allocations rejected first, once a lower memory limit is reached.
+    Low,
+    /// Regular allocations.
+    #[default]
+    Normal,
+    /// Allocations allowed to use the reserve block, whatever their size.
+    High,
+}
+
+/// An invalid or rejected deallocation detected by the allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeallocError {
+    /// The pointer does not belong to any heap block.
+    UnknownPointer,
+    /// The memory is already free, probably because of a double free.
+    DoubleFree,
+    /// The heap was frozen, so the memory was not freed.
+    Frozen,
+    /// The memory was freed from a hook of the allocator, which may run
+    /// with the heap locked, so it was not freed.
+    Reentrant,
+}
+
+/// A policy deciding what to do when an invalid deallocation is detected.
+///
+/// Panicking is best during development, while a device in the field may
+/// rather record the error and keep running, at the cost of leaking the
+/// memory (if any) behind the invalid pointer.
+#[derive(Debug, Default, Clone, Copy)]
+pub enum DeallocErrorPolicy {
+    /// Panic with a message describing the error.
+    #[default]
+    Panic,
+    /// Ignore the deallocation.
+    Leak,
+    /// Call the given function with the pointer, layout and error, then
+    /// ignore the deallocation.
+    Callback(fn(*mut u8, Layout, DeallocError)),
+}
+
+impl DeallocErrorPolicy {
+    /// Handle an invalid deallocation according to the policy.
+    pub fn handle(&self, ptr: *mut u8, layout: Layout, error: DeallocError) {
+        match *self {
+            DeallocErrorPolicy::Panic => panic!("invalid deallocation of {:p}: {:?}", ptr, error),
+            DeallocErrorPolicy::Leak => (),
+            DeallocErrorPolicy::Callback(callback) => callback(ptr, layout, error),
+        }
+    }
+}
+
+/// The order in which the heap blocks of a memory region are searched.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RegionPriority {
+    /// Search the heap blocks of the region before the other ones.
+    Preferred,
+    /// Search the heap blocks of the region in list order.
+    #[default]
+    Normal,
+    /// Only search the heap blocks of the region once the other ones are full.
+    Fallback,
+}
+
+/// A policy controlling which allocations the heap blocks of a memory region serve.
+///
+/// Heap blocks are otherwise treated the same, whatever the speed or the
+/// constraints of their memory. Attached to a [`SharedRegion`] or to the
+/// backing allocator of a `Deblockator` (see `with_backing_policy`), a
+/// policy gives the heap blocks of a memory, such as tightly-coupled memory
+/// or external SDRAM, a search priority and a maximum allocation size.
+///
+/// [`SharedRegion`]: struct.SharedRegion.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionPolicy {
+    priority: RegionPriority,
+    max_size: usize,
+}
+
+impl RegionPolicy {
+    /// The policy of memory regions without one.
+    pub const NORMAL: RegionPolicy = RegionPolicy::new(RegionPriority::Normal);
+
+    /// Create a new policy with the given priority, and no maximum size.
+    pub const fn new(priority: RegionPriority) -> Self {
+        RegionPolicy {
+            priority,
+            max_size: usize::MAX,
+        }
+    }
+
+    /// Never serve allocations larger than `size` bytes from the region.
+    pub const fn with_max_size(mut self, size: usize) -> Self {
+        self.max_size = size;
+        self
+    }
+
+    /// Returns the search priority of the region.
+    pub fn priority(&self) -> RegionPriority {
+        self.priority
+    }
+
+    /// Returns the size of the largest allocation served from the region.
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Check if the region serves the given layout when searching blocks of the given priority.
+    pub fn admits(&self, priority: RegionPriority, layout: Layout) -> bool {
+        self.priority == priority && layout.size() <= self.max_size
+    }
+}
+
+impl Default for RegionPolicy {
+    fn default() -> Self {
+        Self::NORMAL
+    }
+}
+
+/// A policy reporting when the memory usage crosses given percentages.
+///
+/// The usage is the memory obtained from the backing allocator, relative to
+/// a given capacity. The callback is called with the percentage of a level
+/// and whether it was crossed upwards, for instance to drive status LEDs.
+/// A level is only crossed downwards once the usage is below it by the
+/// hysteresis, so that the callback is not called repeatedly when the usage
+/// oscillates around a level.
+#[derive(Debug, Clone, Copy)]
+pub struct Watermarks {
+    capacity: usize,
+    levels: &'static [usize],
+    hysteresis: usize,
+    callback: fn(usize, bool),
+}
+
+impl Watermarks {
+    /// Create a new policy with the given capacity and increasing levels, in percent.
+    pub const fn new(capacity: usize, levels: &'static [usize], callback: fn(usize, bool)) -> Self {
+        Watermarks {
+            capacity,
+            levels,
+            hysteresis: 0,
+            callback,
+        }
+    }
+
+    /// Use the given hysteresis, in percent, when crossing levels downwards.
+    pub const fn with_hysteresis(mut self, hysteresis: usize) -> Self {
+        self.hysteresis = hysteresis;
+        self
+    }
+
+    /// Update the number of levels reached for the given usage, in bytes.
+    ///
+    /// The callback is called for every level crossed, in order.
+    pub fn update(&self, reached: &mut usize, used: usize) {
+        let percent = match self.capacity {
+            0 => 100,
+            capacity => used.saturating_mul(100) / capacity,
+        };
+        while *reached < self.levels.len() && percent >= self.levels[*reached] {
+            (self.callback)(self.levels[*reached], true);
+            *reached += 1;
+        }
+        while *reached > 0 && percent + self.hysteresis < self.levels[*reached - 1] {
+            *reached -= 1;
+            (self.callback)(self.levels[*reached], false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spin::Mutex;
+
+    #[test]
+    /// Check levels are reported when crossed, with hysteresis downwards.
+    fn watermarks_update() {
+        static CROSSED: Mutex<Vec<(usize, bool)>> = Mutex::new(Vec::new());
+        fn record(level: usize, rising: bool) {
+            CROSSED.lock().push((level, rising));
+        }
+
+        let watermarks = Watermarks::new(1000, &[50, 90], record).with_hysteresis(10);
+        let mut reached = 0;
+        for used in [400, 950, 850, 750, 960, 300] {
+            watermarks.update(&mut reached, used);
+        }
+
+        assert_eq!(
+            *CROSSED.lock(),
+            [
+                (50, true),
+                (90, true),
+                (90, false),
+                (90, true),
+                (90, false),
+                (50, false)
+            ]
+        );
+    }
+}
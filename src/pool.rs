@@ -0,0 +1,192 @@
+//! Fixed-size object pools.
+//!
+//! A [`Pool`] serves slots for values of a single type from chunks obtained
+//! from another allocator, typically a `Deblockator`. Allocating a slot only
+//! pops a free-list, without any search or per-allocation overhead, which
+//! suits programs allocating thousands of same-sized objects.
+//!
+//! [`Pool`]: struct.Pool.html
+
+use core::alloc::AllocError;
+use core::alloc::Allocator;
+use core::alloc::Layout;
+use core::cmp::max;
+use core::marker::PhantomData;
+use core::mem::align_of;
+use core::mem::size_of;
+use core::ptr::NonNull;
+
+use spin::Mutex;
+
+use super::utils::align_up;
+
+/// A free slot, linked to the next free slot of the pool.
+struct FreeSlot {
+    next: Option<NonNull<FreeSlot>>,
+}
+
+/// The header of a chunk of slots, linked to the next chunk of the pool.
+struct Chunk {
+    next: Option<NonNull<Chunk>>,
+}
+
+/// The free-list and chunks of a pool.
+struct PoolState {
+    free: Option<NonNull<FreeSlot>>,
+    chunks: Option<NonNull<Chunk>>,
+    capacity: usize,
+}
+
+/// A pool of fixed-size slots for values of type `T`.
+///
+/// Chunks of `slots_per_chunk` slots are allocated from the wrapped
+/// allocator when the pool runs out of free slots, and are only returned
+/// to it when the pool is dropped. Slots are not initialized, and values
+/// left in the pool are not dropped.
+pub struct Pool<T, A: Allocator> {
+    alloc: A,
+    slots_per_chunk: usize,
+    state: Mutex<PoolState>,
+    __slot: PhantomData<T>,
+}
+
+unsafe impl<T: Send, A: Allocator + Send> Send for Pool<T, A> {}
+unsafe impl<T: Send, A: Allocator + Sync> Sync for Pool<T, A> {}
+
+impl<T, A: Allocator> Pool<T, A> {
+    /// Create an empty pool allocating chunks from the given allocator.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `slots_per_chunk` is zero.
+    pub const fn new(alloc: A, slots_per_chunk: usize) -> Self {
+        assert!(slots_per_chunk > 0, "chunks must hold at least one slot");
+        Pool {
+            alloc,
+            slots_per_chunk,
+            state: Mutex::new(PoolState {
+                free: None,
+                chunks: None,
+                capacity: 0,
+            }),
+            __slot: PhantomData,
+        }
+    }
+
+    /// Allocate an uninitialized slot.
+    pub fn allocate(&self) -> Result<NonNull<T>, AllocError> {
+        let mut state = self.state.lock();
+        if state.free.is_none() {
+            self.grow(&mut state)?;
+        }
+        let slot = state.free.ok_or(AllocError)?;
+        state.free = unsafe { slot.as_ref().next };
+        Ok(slot.cast())
+    }
+
+    /// Return a slot to the pool.
+    ///
+    /// # Safety
+    ///
+    /// The slot must have been allocated from this pool, and must not be
+    /// used afterwards. The value it holds, if any, is not dropped.
+    pub unsafe fn deallocate(&self, ptr: NonNull<T>) {
+        let mut state = self.state.lock();
+        let slot = ptr.cast::<FreeSlot>();
+        slot.as_ptr().write(FreeSlot { next: state.free });
+        state.free = Some(slot);
+    }
+
+    /// Returns the number of slots in the chunks allocated so far.
+    pub fn capacity(&self) -> usize {
+        self.state.lock().capacity
+    }
+
+    /// Returns the layout of a slot, large enough to link it when free.
+    fn slot_layout() -> Layout {
+        let size = max(size_of::<T>(), size_of::<FreeSlot>());
+        let align = max(align_of::<T>(), align_of::<FreeSlot>());
+        unsafe { Layout::from_size_align_unchecked(align_up(size, align), align) }
+    }
+
+    /// Returns the layout of a chunk, and the offset of its first slot.
+    fn chunk_layout(&self) -> Result<(Layout, usize), AllocError> {
+        let slot = Self::slot_layout();
+        let offset = align_up(size_of::<Chunk>(), slot.align());
+        let size = slot
+            .size()
+            .checked_mul(self.slots_per_chunk)
+            .and_then(|slots| slots.checked_add(offset))
+            .ok_or(AllocError)?;
+        let align = max(slot.align(), align_of::<Chunk>());
+        let layout = Layout::from_size_align(size, align).map_err(|_| AllocError)?;
+        Ok((layout, offset))
+    }
+
+    /// Allocate a new chunk, and add its slots to the free-list.
+    fn grow(&self, state: &mut PoolState) -> Result<(), AllocError> {
+        let (layout, offset) = self.chunk_layout()?;
+        let chunk = self.alloc.allocate(layout)?.cast::<Chunk>();
+        unsafe {
+            chunk.as_ptr().write(Chunk { next: state.chunks });
+            state.chunks = Some(chunk);
+
+            // link the slots so that they are handed out in address order
+            let slot_size = Self::slot_layout().size();
+            let first = chunk.cast::<u8>().as_ptr().add(offset);
+            for i in (0..self.slots_per_chunk).rev() {
+                let slot = first.add(i * slot_size) as *mut FreeSlot;
+                slot.write(FreeSlot { next: state.free });
+                state.free = Some(NonNull::new_unchecked(slot));
+            }
+        }
+        state.capacity += self.slots_per_chunk;
+        Ok(())
+    }
+}
+
+impl<T, A: Allocator> Drop for Pool<T, A> {
+    fn drop(&mut self) {
+        let (layout, _) = match self.chunk_layout() {
+            Ok(layout) => layout,
+            Err(_) => return,
+        };
+        let state = self.state.get_mut();
+        while let Some(chunk) = state.chunks {
+            unsafe {
+                state.chunks = chunk.as_ref().next;
+                self.alloc.deallocate(chunk.cast(), layout);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::alloc::System;
+
+    use crate::Deblockator;
+
+    #[test]
+    /// Check slots are served from chunks, and reused once freed.
+    fn pool_alloc_dealloc() {
+        let va: Deblockator<System> = Deblockator::new(System);
+        let pool: Pool<[u64; 3], _> = Pool::new(&va, 4);
+
+        let slots: Vec<_> = (0..10).map(|_| pool.allocate().unwrap()).collect();
+        assert_eq!(pool.capacity(), 12);
+        for (i, slot) in slots.iter().enumerate() {
+            assert_eq!(slot.as_ptr() as usize % align_of::<[u64; 3]>(), 0);
+            unsafe { slot.as_ptr().write([i as u64; 3]) };
+        }
+        for (i, slot) in slots.iter().enumerate() {
+            assert_eq!(unsafe { slot.as_ptr().read() }, [i as u64; 3]);
+        }
+
+        unsafe { pool.deallocate(slots[3]) };
+        assert_eq!(pool.allocate(), Ok(slots[3]));
+        assert_eq!(pool.capacity(), 12);
+    }
+}
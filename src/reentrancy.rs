@@ -0,0 +1,146 @@
+//! Detection of allocations made from the hooks of the allocator.
+//!
+//! User hooks, such as the failure hook or the watermark callback, may log
+//! and therefore allocate. An allocation made from a hook called while the
+//! heap is locked would deadlock, and one made from the failure hook could
+//! fail again and recurse forever. Hooks are called through [`call_hook`],
+//! which flags the [`Hooks`] of the allocator calling them on the current
+//! thread, and the allocations made from a flagged allocator are served from
+//! an emergency region instead of the heap blocks. Other allocators are not
+//! affected, so that a hook can still use the global allocator.
+//!
+//! Frees can not be served elsewhere: the memory they give back is in the
+//! heap, which may be locked, so the frees made from a flagged allocator are
+//! rejected instead, unless they give back emergency memory.
+//!
+//! The flags are per-thread with the `std` feature. Without it, each
+//! allocator counts the hooks it is running, whatever the thread, which is
+//! exact on single-core targets.
+//!
+//! [`call_hook`]: fn.call_hook.html
+//! [`Hooks`]: struct.Hooks.html
+
+#[cfg(feature = "std")]
+use core::ptr;
+#[cfg(not(feature = "std"))]
+use core::sync::atomic::AtomicUsize;
+#[cfg(not(feature = "std"))]
+use core::sync::atomic::Ordering;
+
+#[cfg(feature = "std")]
+use std::cell::Cell;
+
+/// The hooks of an allocator running on the current thread.
+pub struct Hooks {
+    #[cfg(not(feature = "std"))]
+    depth: AtomicUsize,
+}
+
+impl Hooks {
+    /// Create the flags of an allocator, running no hook.
+    pub const fn new() -> Self {
+        Hooks {
+            #[cfg(not(feature = "std"))]
+            depth: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// A hook running on the current thread, linked to the hook it was called from, if any.
+///
+/// Frames live on the stack of the thread running the hooks, and are only
+/// linked from a thread-local, so that no other thread ever sees them.
+#[cfg(feature = "std")]
+struct HookFrame {
+    hooks: *const Hooks,
+    previous: *const HookFrame,
+}
+
+#[cfg(feature = "std")]
+std::thread_local! {
+    static CURRENT: Cell<*const HookFrame> = const { Cell::new(ptr::null()) };
+}
+
+/// Returns the innermost hook running on the current thread.
+#[cfg(feature = "std")]
+fn current() -> *const HookFrame {
+    CURRENT
+        .try_with(|current| current.get())
+        .unwrap_or(ptr::null())
+}
+
+/// Set the innermost hook running on the current thread.
+#[cfg(feature = "std")]
+fn set_current(frame: *const HookFrame) {
+    let _ = CURRENT.try_with(|current| current.set(frame));
+}
+
+/// The flag of a running hook, restoring the enclosing hook when dropped.
+#[cfg(feature = "std")]
+struct HookGuard {
+    previous: *const HookFrame,
+}
+
+#[cfg(feature = "std")]
+impl Drop for HookGuard {
+    fn drop(&mut self) {
+        set_current(self.previous);
+    }
+}
+
+/// The flag of a running hook, counting it out of its allocator when dropped.
+#[cfg(not(feature = "std"))]
+struct HookGuard<'a> {
+    hooks: &'a Hooks,
+}
+
+#[cfg(not(feature = "std"))]
+impl Drop for HookGuard<'_> {
+    fn drop(&mut self) {
+        self.hooks.depth.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Call a user hook of the allocator owning `hooks`, flagging the
+/// allocations and frees it makes from that allocator as reentrant.
+#[cfg(feature = "std")]
+pub fn call_hook<R, F: FnOnce() -> R>(hooks: &Hooks, hook: F) -> R {
+    let frame = HookFrame {
+        hooks,
+        previous: current(),
+    };
+    let _guard = HookGuard {
+        previous: frame.previous,
+    };
+    set_current(&frame);
+    hook()
+}
+
+/// Call a user hook of the allocator owning `hooks`, flagging the
+/// allocations and frees it makes from that allocator as reentrant.
+#[cfg(not(feature = "std"))]
+pub fn call_hook<R, F: FnOnce() -> R>(hooks: &Hooks, hook: F) -> R {
+    hooks.depth.fetch_add(1, Ordering::Acquire);
+    let _guard = HookGuard { hooks };
+    hook()
+}
+
+/// Check if the current thread is running a hook of the allocator owning `hooks`.
+#[cfg(feature = "std")]
+pub fn in_hook(hooks: &Hooks) -> bool {
+    let mut frame = current();
+    // the frames are kept alive by the `call_hook` calls still running
+    while let Some(hook) = unsafe { frame.as_ref() } {
+        if ptr::eq(hook.hooks, hooks) {
+            return true;
+        }
+        frame = hook.previous;
+    }
+    false
+}
+
+/// Check if the current thread is running a hook of the allocator owning `hooks`.
+#[cfg(not(feature = "std"))]
+pub fn in_hook(hooks: &Hooks) -> bool {
+    hooks.depth.load(Ordering::Acquire) > 0
+}
@@ -0,0 +1,295 @@
+//! A block source using a fixed memory region.
+//!
+//! The [`Region`] allocator serves both heap blocks and large allocations
+//! from a single memory region, such as a static array or a region defined
+//! by the linker script. It uses the same linked hole list as heap blocks.
+//!
+//! [`Region`]: struct.Region.html
+
+use core::alloc::AllocError;
+use core::alloc::Allocator;
+use core::alloc::Layout;
+use core::cmp::max;
+use core::mem::align_of;
+use core::mem::size_of;
+use core::mem::MaybeUninit;
+use core::ptr::NonNull;
+
+use spin::Mutex;
+
+use super::hole::allocate_in;
+use super::hole::deallocate;
+use super::hole::Hole;
+use super::policy::DeallocError;
+use super::policy::DeallocErrorPolicy;
+use super::utils::align_down;
+use super::utils::align_up;
+
+/// The state of a region, initialized on first use.
+struct RegionState {
+    initialized: bool,
+    first: Hole,
+}
+
+/// An allocator managing a fixed memory region.
+///
+/// The region is only written to on the first allocation, so that it can be
+/// created in a `const` context, for instance to back a `#[global_allocator]`
+/// (see the [`global_deblockator!`] macro).
+///
+/// [`global_deblockator!`]: macro.global_deblockator.html
+pub struct Region {
+    start: *mut u8,
+    size: usize,
+    state: Mutex<RegionState>,
+    dealloc_error: DeallocErrorPolicy,
+}
+
+unsafe impl Sync for Region {}
+unsafe impl Send for Region {}
+
+impl Region {
+    /// Create a new region from a start address and a size in bytes.
+    ///
+    /// # Safety
+    ///
+    /// The memory range must be valid for reads and writes, and must not be
+    /// used by anything else for as long as the region (or any allocation
+    /// made from it) is in use.
+    pub const unsafe fn from_raw_parts(start: *mut u8, size: usize) -> Self {
+        Region {
+            start,
+            size,
+            state: Mutex::new(RegionState {
+                initialized: false,
+                first: Hole {
+                    size: 0,
+                    next: None,
+                },
+            }),
+            dealloc_error: DeallocErrorPolicy::Panic,
+        }
+    }
+
+    /// Use the given policy when an invalid deallocation is detected.
+    ///
+    /// Deallocations of memory that is already free are otherwise reported
+    /// with a panic. The policy only applies to deallocations made through
+    /// the `Allocator` implementation: a `Deblockator` serving allocations
+    /// from the region uses its own policy instead.
+    pub const fn with_dealloc_error_policy(mut self, policy: DeallocErrorPolicy) -> Self {
+        self.dealloc_error = policy;
+        self
+    }
+
+    /// Create a new region from a pointer to a static array.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`from_raw_parts`](#method.from_raw_parts): the array must
+    /// not be accessed through any other mean once the region is used.
+    pub const unsafe fn from_array<const N: usize>(array: *mut [u8; N]) -> Self {
+        Self::from_raw_parts(array as *mut u8, N)
+    }
+
+    /// Create a new region from a static memory slice.
+    pub fn from_static(region: &'static mut [MaybeUninit<u8>]) -> Self {
+        // the region is borrowed for 'static, so it can't be used elsewhere
+        unsafe { Self::from_raw_parts(region.as_mut_ptr() as *mut u8, region.len()) }
+    }
+
+    /// Returns the total size of the region, in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Check if the given pointer points inside the region.
+    pub fn contains<T>(&self, ptr: *const T) -> bool {
+        let addr = ptr as usize;
+        let start = self.start as usize;
+        start <= addr && addr - start < self.size
+    }
+
+    /// Deallocate the memory given by `ptr` and `layout`, returning an error
+    /// for an invalid deallocation instead of handling it with the policy.
+    ///
+    /// # Safety
+    ///
+    /// Same as `Allocator::deallocate`, except that invalid deallocations
+    /// detected as such are rejected without changing the region.
+    pub unsafe fn try_deallocate(
+        &self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+    ) -> Result<(), DeallocError> {
+        match Self::region_layout(layout) {
+            Ok(layout) => {
+                let mut state = self.state.lock();
+                deallocate(&mut state.first, ptr.as_ptr() as usize, layout.size())
+            }
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Create the layout actually reserved in the region for the requested layout.
+    fn region_layout(layout: Layout) -> Result<Layout, AllocError> {
+        let size = max(
+            size_of::<Hole>(),
+            align_up(layout.size(), align_of::<Hole>()),
+        );
+        Layout::from_size_align(size, layout.align()).map_err(|_| AllocError)
+    }
+}
+
+unsafe impl Allocator for Region {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let layout = Self::region_layout(layout)?;
+        let mut state = self.state.lock();
+
+        // Write the first hole on first use
+        if !state.initialized {
+            state.initialized = true;
+            let start = align_up(self.start as usize, align_of::<Hole>());
+            let end = align_down(self.start as usize + self.size, align_of::<Hole>());
+            if end > start && end - start >= size_of::<Hole>() {
+                let hole_ptr = start as *mut Hole;
+                unsafe {
+                    hole_ptr.write(Hole {
+                        size: end - start,
+                        next: None,
+                    });
+                    state.first.next = Some(&mut *hole_ptr);
+                }
+            }
+        }
+
+        let ptr = allocate_in(&mut state.first, layout)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        // the lock is released here, so the callback may use the region
+        if let Err(error) = self.try_deallocate(ptr, layout) {
+            self.dealloc_error.handle(ptr.as_ptr(), layout, error);
+        }
+    }
+}
+
+/// Install a `Deblockator` using a static array as its `#[global_allocator]`.
+///
+/// The first argument is the name of the allocator static, optionally
+/// followed by its type; the second one is the name of a `static mut` byte
+/// array that will be used as the heap memory. The array must not be used
+/// anywhere else.
+///
+/// # Example
+/// ```rust,ignore
+/// #[macro_use]
+/// extern crate deblockator;
+///
+/// static mut HEAP: [u8; 1 << 20] = [0; 1 << 20];
+/// global_deblockator!(GLOBAL, HEAP);
+/// ```
+#[macro_export]
+macro_rules! global_deblockator {
+    ($name:ident, $memory:ident) => {
+        global_deblockator!($name: $crate::Deblockator<$crate::Region>, $memory);
+    };
+    ($name:ident: $ty:ty, $memory:ident) => {
+        #[global_allocator]
+        static $name: $ty = $crate::Deblockator::new(unsafe {
+            $crate::Region::from_array(::core::ptr::addr_of_mut!($memory))
+        });
+    };
+}
+
+/// Install a `Deblockator` over a static heap of the given size as the `#[global_allocator]`.
+///
+/// The first argument is the name of the allocator static, optionally
+/// followed by its type; the second one is the size of the heap, in bytes.
+/// The heap is a zeroed static array reserved by the macro, which can be
+/// placed in a given link section with a third argument. The allocator then
+/// carves its heap blocks out of the array, as with [`global_deblockator!`].
+///
+/// # Example
+/// ```rust,ignore
+/// #[macro_use]
+/// extern crate deblockator;
+///
+/// static_deblockator!(GLOBAL, 1 << 20, ".bss.heap");
+/// ```
+///
+/// [`global_deblockator!`]: macro.global_deblockator.html
+#[macro_export]
+macro_rules! static_deblockator {
+    ($name:ident, $size:expr) => {
+        static_deblockator!($name: $crate::Deblockator<$crate::Region>, $size);
+    };
+    ($name:ident, $size:expr, $section:literal) => {
+        static_deblockator!($name: $crate::Deblockator<$crate::Region>, $size, $section);
+    };
+    ($name:ident: $ty:ty, $size:expr) => {
+        #[global_allocator]
+        static $name: $ty = $crate::Deblockator::new({
+            static mut HEAP: [u8; $size] = [0; $size];
+            unsafe { $crate::Region::from_array(::core::ptr::addr_of_mut!(HEAP)) }
+        });
+    };
+    ($name:ident: $ty:ty, $size:expr, $section:literal) => {
+        #[global_allocator]
+        static $name: $ty = $crate::Deblockator::new({
+            #[link_section = $section]
+            static mut HEAP: [u8; $size] = [0; $size];
+            unsafe { $crate::Region::from_array(::core::ptr::addr_of_mut!(HEAP)) }
+        });
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Check memory can be allocated and reused from a region.
+    fn region_alloc_dealloc() {
+        let memory = Box::leak(Box::new([MaybeUninit::<u64>::uninit(); 128]));
+        let region = unsafe { Region::from_raw_parts(memory.as_mut_ptr() as *mut u8, 1024) };
+
+        let layout = Layout::from_size_align(256, 64).unwrap();
+        let ptr1 = region.allocate(layout).expect("could not allocate 1");
+        assert_eq!(ptr1.cast::<u8>().as_ptr() as usize % 64, 0);
+        assert!(region.contains(ptr1.cast::<u8>().as_ptr()));
+
+        let large = Layout::from_size_align(1024, 8).unwrap();
+        assert!(region.allocate(large).is_err());
+
+        unsafe { region.deallocate(ptr1.cast(), layout) };
+        let ptr2 = region.allocate(layout).expect("could not allocate 2");
+        assert_eq!(ptr1.cast::<u8>(), ptr2.cast::<u8>());
+    }
+
+    #[test]
+    /// Check double frees are handled by the deallocation error policy.
+    fn region_double_free() {
+        static ERRORS: Mutex<Vec<DeallocError>> = Mutex::new(Vec::new());
+        fn record(_ptr: *mut u8, _layout: Layout, error: DeallocError) {
+            ERRORS.lock().push(error);
+        }
+
+        let memory = Box::leak(Box::new([MaybeUninit::<u64>::uninit(); 128]));
+        let region = unsafe { Region::from_raw_parts(memory.as_mut_ptr() as *mut u8, 1024) }
+            .with_dealloc_error_policy(DeallocErrorPolicy::Callback(record));
+
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let ptr = region.allocate(layout).expect("could not allocate");
+        unsafe {
+            region.deallocate(ptr.cast(), layout);
+            assert!(region.try_deallocate(ptr.cast(), layout).is_err());
+            region.deallocate(ptr.cast(), layout);
+        }
+        assert_eq!(ERRORS.lock().len(), 1);
+        assert!(region
+            .allocate(Layout::from_size_align(1024, 8).unwrap())
+            .is_ok());
+    }
+}
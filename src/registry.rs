@@ -0,0 +1,169 @@
+//! An address-sorted registry of heap blocks.
+//!
+//! Finding the heap block containing a pointer otherwise walks the list of
+//! heap blocks, which gets slow once a heap has hundreds of them. A
+//! [`BlockRegistry`] keeps the start addresses of the heap blocks sorted in
+//! a fixed array, so that the block is found with a binary search instead.
+//!
+//! [`BlockRegistry`]: struct.BlockRegistry.html
+
+use core::slice;
+
+use spin::Mutex;
+
+/// The state of a registry.
+struct RegistryState {
+    len: usize,
+    complete: bool,
+}
+
+/// A fixed-capacity registry of heap block addresses, sorted by address.
+///
+/// When more blocks are registered than the registry can hold, it is no
+/// longer complete: lookups missing the registry then fall back to walking
+/// the heap blocks, until the registry is rebuilt with fewer blocks.
+pub struct BlockRegistry {
+    entries: *mut usize,
+    capacity: usize,
+    state: Mutex<RegistryState>,
+}
+
+unsafe impl Sync for BlockRegistry {}
+unsafe impl Send for BlockRegistry {}
+
+impl BlockRegistry {
+    /// Create a new registry storing its entries in the given memory.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be valid for reads and writes of `capacity` entries,
+    /// and must not be used by anything else for as long as the registry is
+    /// in use. A registry must only be given to a single allocator.
+    pub const unsafe fn from_raw_parts(entries: *mut usize, capacity: usize) -> Self {
+        BlockRegistry {
+            entries,
+            capacity,
+            state: Mutex::new(RegistryState {
+                len: 0,
+                complete: true,
+            }),
+        }
+    }
+
+    /// Create a new registry from a pointer to a static array.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`from_raw_parts`](#method.from_raw_parts): the array must
+    /// not be accessed through any other mean once the registry is used.
+    pub const unsafe fn from_array<const N: usize>(array: *mut [usize; N]) -> Self {
+        Self::from_raw_parts(array as *mut usize, N)
+    }
+
+    /// Returns the number of heap blocks the registry can hold.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the number of registered heap blocks.
+    pub fn len(&self) -> usize {
+        self.state.lock().len
+    }
+
+    /// Check if no heap block is registered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Check if every heap block inserted since the last clear is registered.
+    pub fn is_complete(&self) -> bool {
+        self.state.lock().complete
+    }
+
+    /// Register the heap block starting at the given address.
+    ///
+    /// The registry is no longer complete if it is full.
+    pub fn insert(&self, addr: usize) {
+        let mut state = self.state.lock();
+        let len = state.len;
+        if len == self.capacity {
+            state.complete = false;
+            return;
+        }
+        unsafe {
+            let entries = slice::from_raw_parts_mut(self.entries, len + 1);
+            let index = match entries[..len].binary_search(&addr) {
+                Ok(_) => return,
+                Err(index) => index,
+            };
+            entries.copy_within(index..len, index + 1);
+            entries[index] = addr;
+        }
+        state.len += 1;
+    }
+
+    /// Forget the heap block starting at the given address, if registered.
+    pub fn remove(&self, addr: usize) {
+        let mut state = self.state.lock();
+        let len = state.len;
+        unsafe {
+            let entries = slice::from_raw_parts_mut(self.entries, len);
+            if let Ok(index) = entries.binary_search(&addr) {
+                entries.copy_within(index + 1.., index);
+                state.len -= 1;
+            }
+        }
+    }
+
+    /// Forget every heap block, making the registry complete again.
+    pub fn clear(&self) {
+        let mut state = self.state.lock();
+        state.len = 0;
+        state.complete = true;
+    }
+
+    /// Returns the start of the last registered heap block starting at or before `addr`.
+    ///
+    /// The heap block may end before `addr`, which must then be checked.
+    pub fn find(&self, addr: usize) -> Option<usize> {
+        let state = self.state.lock();
+        let entries = unsafe { slice::from_raw_parts(self.entries, state.len) };
+        match entries.binary_search(&addr) {
+            Ok(index) => Some(entries[index]),
+            Err(0) => None,
+            Err(index) => Some(entries[index - 1]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Check blocks are found by address, and a full registry is incomplete.
+    fn registry_find() {
+        let entries = Box::leak(Box::new([0usize; 3]));
+        let registry = unsafe { BlockRegistry::from_array(entries) };
+
+        registry.insert(0x3000);
+        registry.insert(0x1000);
+        registry.insert(0x2000);
+        assert_eq!(registry.len(), 3);
+        assert!(registry.is_complete());
+        assert_eq!(registry.find(0x0fff), None);
+        assert_eq!(registry.find(0x1000), Some(0x1000));
+        assert_eq!(registry.find(0x2fff), Some(0x2000));
+        assert_eq!(registry.find(0x4000), Some(0x3000));
+
+        registry.insert(0x4000);
+        assert!(!registry.is_complete());
+        registry.remove(0x2000);
+        assert_eq!(registry.find(0x2fff), Some(0x1000));
+        assert_eq!(registry.len(), 2);
+
+        registry.clear();
+        assert!(registry.is_complete());
+        assert_eq!(registry.find(0x4000), None);
+    }
+}
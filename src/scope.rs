@@ -0,0 +1,270 @@
+//! Per-thread allocation scopes.
+//!
+//! A [`HeapScope`] limits the memory allocated by the current thread while
+//! it is alive, for instance to bound the memory a single request handler
+//! can consume. A [`TagScope`] attributes the allocations of the current
+//! thread to a subsystem, whose allocations can then be limited in size.
+//! Scopes need thread-local storage, and are only available with the `std`
+//! feature.
+//!
+//! [`HeapScope`]: struct.HeapScope.html
+//! [`TagScope`]: struct.TagScope.html
+
+#[cfg(feature = "std")]
+use std::cell::Cell;
+#[cfg(feature = "std")]
+use std::marker::PhantomData;
+
+/// The budget of the innermost scope of a thread.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy)]
+struct Budget {
+    limit: usize,
+    used: usize,
+}
+
+#[cfg(feature = "std")]
+std::thread_local! {
+    static BUDGET: Cell<Option<Budget>> = const { Cell::new(None) };
+    static TAG: Cell<Option<usize>> = const { Cell::new(None) };
+    static THREAD: u8 = const { 0 };
+}
+
+/// A guard limiting the memory allocated by the current thread.
+///
+/// While the guard is alive, allocations made by the thread through a
+/// `Deblockator` fail with `AllocFailure::Budget` once the bytes they hold
+/// would exceed the budget, and memory freed by the thread is credited back
+/// to the innermost scope (down to zero).
+///
+/// Scopes can be nested: an inner scope never gets more than what remains
+/// of the outer one, and its usage is charged to the outer scope when it is
+/// dropped.
+#[cfg(feature = "std")]
+pub struct HeapScope {
+    previous: Option<Budget>,
+    __not_send: PhantomData<*const ()>,
+}
+
+#[cfg(feature = "std")]
+impl HeapScope {
+    /// Limit the allocations of the current thread to the given number of bytes.
+    pub fn with_budget(bytes: usize) -> Self {
+        let previous = BUDGET.with(|budget| budget.get());
+        let limit = match previous {
+            Some(outer) => bytes.min(outer.limit.saturating_sub(outer.used)),
+            None => bytes,
+        };
+        BUDGET.with(|budget| budget.set(Some(Budget { limit, used: 0 })));
+        HeapScope {
+            previous,
+            __not_send: PhantomData,
+        }
+    }
+
+    /// Returns the number of bytes currently charged to the scope.
+    pub fn used(&self) -> usize {
+        BUDGET.with(|budget| budget.get().map_or(0, |budget| budget.used))
+    }
+
+    /// Returns the number of bytes the scope can still allocate.
+    pub fn remaining(&self) -> usize {
+        BUDGET.with(|budget| {
+            budget
+                .get()
+                .map_or(0, |budget| budget.limit.saturating_sub(budget.used))
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for HeapScope {
+    fn drop(&mut self) {
+        let used = self.used();
+        let previous = self.previous.map(|mut outer| {
+            outer.used = outer.used.saturating_add(used);
+            outer
+        });
+        BUDGET.with(|budget| budget.set(previous));
+    }
+}
+
+/// A guard tagging the allocations of the current thread.
+///
+/// While the guard is alive, allocations made by the thread through a
+/// `Deblockator` larger than the ceiling registered for the tag (see
+/// `Deblockator::with_size_ceilings`) fail with `AllocFailure::Ceiling`.
+/// Scopes can be nested, in which case the innermost tag applies until the
+/// inner scope is dropped.
+#[cfg(feature = "std")]
+pub struct TagScope {
+    previous: Option<usize>,
+    __not_send: PhantomData<*const ()>,
+}
+
+#[cfg(feature = "std")]
+impl TagScope {
+    /// Tag the allocations of the current thread with the given tag.
+    pub fn enter(tag: usize) -> Self {
+        let previous = TAG.with(|current| current.replace(Some(tag)));
+        TagScope {
+            previous,
+            __not_send: PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for TagScope {
+    fn drop(&mut self) {
+        TAG.with(|current| current.set(self.previous));
+    }
+}
+
+/// Returns the tag of the current thread, if any.
+#[cfg(feature = "std")]
+pub fn current_tag() -> Option<usize> {
+    TAG.try_with(|current| current.get()).unwrap_or(None)
+}
+
+/// Charge an allocation to the scope of the current thread, if any.
+///
+/// Returns `false` if the allocation would exceed the budget.
+#[cfg(feature = "std")]
+pub fn charge(size: usize) -> bool {
+    // the thread-local storage may already be destroyed on thread exit
+    BUDGET
+        .try_with(|budget| match budget.get() {
+            Some(mut current) if size <= current.limit.saturating_sub(current.used) => {
+                current.used += size;
+                budget.set(Some(current));
+                true
+            }
+            Some(_) => false,
+            None => true,
+        })
+        .unwrap_or(true)
+}
+
+/// Credit a deallocation to the scope of the current thread, if any.
+#[cfg(feature = "std")]
+pub fn credit(size: usize) {
+    let _ = BUDGET.try_with(|budget| {
+        if let Some(mut current) = budget.get() {
+            current.used = current.used.saturating_sub(size);
+            budget.set(Some(current));
+        }
+    });
+}
+
+/// Returns an identifier of the current thread, or `0` if it is unknown.
+///
+/// Identifiers are unique among the running threads, but may be reused once
+/// a thread exits.
+#[cfg(feature = "std")]
+pub fn thread_id() -> usize {
+    THREAD
+        .try_with(|thread| thread as *const u8 as usize)
+        .unwrap_or(0)
+}
+
+/// Charge an allocation to the scope of the current thread, if any.
+#[cfg(not(feature = "std"))]
+pub fn charge(_size: usize) -> bool {
+    true
+}
+
+/// Credit a deallocation to the scope of the current thread, if any.
+#[cfg(not(feature = "std"))]
+pub fn credit(_size: usize) {}
+
+/// Returns the tag of the current thread, if any.
+#[cfg(not(feature = "std"))]
+pub fn current_tag() -> Option<usize> {
+    None
+}
+
+/// Returns an identifier of the current thread, or `0` if it is unknown.
+#[cfg(not(feature = "std"))]
+pub fn thread_id() -> usize {
+    0
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use std::alloc::GlobalAlloc;
+    use std::alloc::Layout;
+    use std::alloc::System;
+
+    use crate::Deblockator;
+
+    #[test]
+    #[cfg_attr(all(target_family = "wasm", not(target_feature = "atomics")), ignore)]
+    /// Check allocations are limited by the budget of the current scope.
+    fn heap_scope_budget() {
+        let va: Deblockator<System> = Deblockator::new(System);
+        let small = Layout::from_size_align(512, 8).unwrap();
+        let large = Layout::from_size_align(600, 8).unwrap();
+
+        unsafe {
+            let scope = HeapScope::with_budget(1024);
+            let ptr = va.alloc(small);
+            assert!(!ptr.is_null());
+            assert!(va.alloc(large).is_null());
+            assert_eq!(scope.used(), 512);
+
+            // other threads are not limited
+            let other = std::thread::spawn(move || charge(4096)).join();
+            assert!(other.unwrap());
+
+            va.dealloc(ptr, small);
+            let ptr = va.alloc(large);
+            assert!(!ptr.is_null());
+
+            // an inner scope only gets what remains of the outer one
+            {
+                let inner = HeapScope::with_budget(4096);
+                assert_eq!(inner.remaining(), 424);
+                assert!(va.alloc(small).is_null());
+                va.dealloc(ptr, large);
+            }
+            // frees are credited to the innermost scope only
+            assert_eq!(scope.used(), 600);
+            drop(scope);
+            assert!(charge(usize::MAX));
+        }
+    }
+
+    #[test]
+    /// Check allocations larger than the ceiling of the current tag fail.
+    fn tag_scope_ceiling() {
+        const NETWORK: usize = 1;
+        const STORAGE: usize = 2;
+        static CEILINGS: [(usize, usize); 1] = [(NETWORK, 4096)];
+
+        let va: Deblockator<System> = Deblockator::new(System).with_size_ceilings(&CEILINGS);
+        let huge = Layout::from_size_align(1 << 20, 8).unwrap();
+
+        unsafe {
+            let network = TagScope::enter(NETWORK);
+            let mut v: Vec<u8, _> = Vec::new_in(&va);
+            assert!(v.try_reserve(4096).is_ok());
+            assert!(v.try_reserve(1 << 20).is_err());
+
+            // the innermost tag applies, and untagged allocations are not limited
+            {
+                let _storage = TagScope::enter(STORAGE);
+                let ptr = va.alloc(huge);
+                assert!(!ptr.is_null());
+                va.dealloc(ptr, huge);
+            }
+            assert!(va.alloc(huge).is_null());
+            drop(network);
+            let ptr = va.alloc(huge);
+            assert!(!ptr.is_null());
+            va.dealloc(ptr, huge);
+        }
+    }
+}
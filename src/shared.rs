@@ -0,0 +1,95 @@
+//! Memory shared with other subsystems.
+//!
+//! Some platforms hand out memory that is not owned by a single allocator,
+//! such as memory-mapped firmware regions managed by a region manager. Such
+//! memory can be lent to a `Deblockator` as heap blocks, and given back to
+//! its owner once neither the allocator nor anyone else uses it anymore.
+
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+use super::policy::RegionPolicy;
+
+/// A reference-counted memory region shared with the allocator.
+///
+/// Each heap block using the region holds a reference, and so can other
+/// subsystems using it. The release callback is called when the last
+/// reference is dropped, instead of deallocating the blocks through the
+/// backing allocator.
+pub struct SharedRegion {
+    refs: AtomicUsize,
+    on_release: fn(),
+    policy: RegionPolicy,
+}
+
+impl SharedRegion {
+    /// Create a new shared region without references.
+    pub const fn new(on_release: fn()) -> Self {
+        SharedRegion {
+            refs: AtomicUsize::new(0),
+            on_release,
+            policy: RegionPolicy::NORMAL,
+        }
+    }
+
+    /// Apply the given policy to the heap blocks using the region.
+    pub const fn with_policy(mut self, policy: RegionPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Returns the policy of the heap blocks using the region.
+    pub fn policy(&self) -> RegionPolicy {
+        self.policy
+    }
+
+    /// Take a reference to the region.
+    pub fn retain(&self) {
+        self.refs.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Drop a reference to the region, calling the release callback if it was the last one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the region has no references.
+    pub fn release(&self) {
+        match self.refs.fetch_sub(1, Ordering::AcqRel) {
+            0 => panic!("shared region released more times than retained"),
+            1 => (self.on_release)(),
+            _ => (),
+        }
+    }
+
+    /// Returns the number of references to the region.
+    pub fn refs(&self) -> usize {
+        self.refs.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Check the release callback is called when the last reference is dropped.
+    fn shared_region_release() {
+        use core::sync::atomic::AtomicBool;
+
+        static RELEASED: AtomicBool = AtomicBool::new(false);
+        fn on_release() {
+            RELEASED.store(true, Ordering::SeqCst);
+        }
+
+        let region = SharedRegion::new(on_release);
+        region.retain();
+        region.retain();
+        assert_eq!(region.refs(), 2);
+
+        region.release();
+        assert!(!RELEASED.load(Ordering::SeqCst));
+        region.release();
+        assert!(RELEASED.load(Ordering::SeqCst));
+        assert_eq!(region.refs(), 0);
+    }
+}
@@ -0,0 +1,219 @@
+//! Per-call-site allocation statistics.
+//!
+//! A [`SiteTable`] aggregates the allocations made with `alloc_with_header`
+//! by call site, so that the heaviest consumers of a heap can be listed at
+//! runtime, for instance from the debug shell of a device. The table is
+//! stored in memory given by the user, since the allocator can not allocate
+//! its own bookkeeping.
+//!
+//! [`SiteTable`]: struct.SiteTable.html
+
+use core::panic::Location;
+use core::slice;
+
+use spin::Mutex;
+
+/// The statistics of the allocations made at a call site.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SiteStats {
+    /// The call site, or `None` for a free entry.
+    pub site: Option<&'static Location<'static>>,
+    /// The number of bytes allocated at the site and not freed yet.
+    pub live_bytes: usize,
+    /// The number of allocations made at the site and not freed yet.
+    pub live: usize,
+    /// The total number of allocations made at the site.
+    pub allocations: usize,
+    /// The highest number of bytes allocated at the site at once.
+    pub peak_bytes: usize,
+}
+
+impl SiteStats {
+    /// A free entry, to initialize the memory of a table with.
+    pub const EMPTY: SiteStats = SiteStats {
+        site: None,
+        live_bytes: 0,
+        live: 0,
+        allocations: 0,
+        peak_bytes: 0,
+    };
+}
+
+/// The state of a site table.
+struct SiteState {
+    len: usize,
+    missed: usize,
+}
+
+/// A fixed-capacity table of allocation statistics, by call site.
+///
+/// Each allocation looks its call site up in the table, so the capacity
+/// should be kept to the few dozen sites worth watching. Allocations made at
+/// new sites once the table is full are not recorded, and only counted.
+pub struct SiteTable {
+    entries: *mut SiteStats,
+    capacity: usize,
+    state: Mutex<SiteState>,
+}
+
+unsafe impl Sync for SiteTable {}
+unsafe impl Send for SiteTable {}
+
+impl SiteTable {
+    /// Create a new table storing its entries in the given memory.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be valid for reads and writes of `capacity` entries,
+    /// and must not be used by anything else for as long as the table is in
+    /// use. A table must only be given to a single allocator.
+    pub const unsafe fn from_raw_parts(entries: *mut SiteStats, capacity: usize) -> Self {
+        SiteTable {
+            entries,
+            capacity,
+            state: Mutex::new(SiteState { len: 0, missed: 0 }),
+        }
+    }
+
+    /// Create a new table from a pointer to a static array.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`from_raw_parts`](#method.from_raw_parts): the array must
+    /// not be accessed through any other mean once the table is used.
+    pub const unsafe fn from_array<const N: usize>(array: *mut [SiteStats; N]) -> Self {
+        Self::from_raw_parts(array as *mut SiteStats, N)
+    }
+
+    /// Returns the number of call sites the table can hold.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the number of recorded call sites.
+    pub fn len(&self) -> usize {
+        self.state.lock().len
+    }
+
+    /// Check if no call site is recorded.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of allocations not recorded because the table was full.
+    pub fn missed(&self) -> usize {
+        self.state.lock().missed
+    }
+
+    /// Record an allocation of `size` bytes made at the given site.
+    pub fn record_alloc(&self, site: &'static Location<'static>, size: usize) {
+        let mut state = self.state.lock();
+        let len = state.len;
+        let entries = unsafe { slice::from_raw_parts_mut(self.entries, self.capacity) };
+        let entry = match entries[..len]
+            .iter()
+            .position(|entry| entry.site == Some(site))
+        {
+            Some(index) => &mut entries[index],
+            None if len < self.capacity => {
+                state.len += 1;
+                entries[len] = SiteStats {
+                    site: Some(site),
+                    ..SiteStats::EMPTY
+                };
+                &mut entries[len]
+            }
+            None => {
+                state.missed += 1;
+                return;
+            }
+        };
+        entry.live_bytes += size;
+        entry.live += 1;
+        entry.allocations += 1;
+        entry.peak_bytes = entry.peak_bytes.max(entry.live_bytes);
+    }
+
+    /// Record that an allocation of `size` bytes made at the given site was freed.
+    pub fn record_free(&self, site: &'static Location<'static>, size: usize) {
+        let state = self.state.lock();
+        let entries = unsafe { slice::from_raw_parts_mut(self.entries, state.len) };
+        if let Some(entry) = entries.iter_mut().find(|entry| entry.site == Some(site)) {
+            entry.live_bytes = entry.live_bytes.saturating_sub(size);
+            entry.live = entry.live.saturating_sub(1);
+        }
+    }
+
+    /// Write the statistics of the call sites with the most live bytes to
+    /// `top`, from the heaviest one, and return the number of sites written.
+    pub fn top(&self, top: &mut [SiteStats]) -> usize {
+        let state = self.state.lock();
+        let entries = unsafe { slice::from_raw_parts(self.entries, state.len) };
+        let mut len = 0;
+        for entry in entries {
+            // insert the entry in order, dropping the lightest site if full
+            let index = top[..len]
+                .iter()
+                .position(|heavier| heavier.live_bytes < entry.live_bytes)
+                .unwrap_or(len);
+            if index == top.len() {
+                continue;
+            }
+            len = (len + 1).min(top.len());
+            top.copy_within(index..len - 1, index + 1);
+            top[index] = *entry;
+        }
+        len
+    }
+
+    /// Forget every call site.
+    pub fn clear(&self) {
+        let mut state = self.state.lock();
+        state.len = 0;
+        state.missed = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Check allocations are aggregated by site, and the heaviest sites listed first.
+    fn site_table_top() {
+        let entries = Box::leak(Box::new([SiteStats::EMPTY; 3]));
+        let table = unsafe { SiteTable::from_array(entries) };
+        let sites = [
+            Location::caller(),
+            Location::caller(),
+            Location::caller(),
+            Location::caller(),
+        ];
+
+        table.record_alloc(sites[0], 100);
+        table.record_alloc(sites[1], 300);
+        table.record_alloc(sites[0], 100);
+        table.record_alloc(sites[2], 50);
+        table.record_alloc(sites[3], 1000);
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.missed(), 1);
+
+        table.record_free(sites[1], 300);
+        let mut top = [SiteStats::EMPTY; 2];
+        assert_eq!(table.top(&mut top), 2);
+        assert_eq!(top[0].site, Some(sites[0]));
+        assert_eq!(
+            (top[0].live_bytes, top[0].live, top[0].allocations),
+            (200, 2, 2)
+        );
+        assert_eq!(top[1].site, Some(sites[2]));
+        assert_eq!(top[0].peak_bytes, 200);
+
+        let mut all = [SiteStats::EMPTY; 4];
+        assert_eq!(table.top(&mut all), 3);
+        assert_eq!((all[2].live_bytes, all[2].peak_bytes), (0, 300));
+
+        table.clear();
+        assert!(table.is_empty());
+    }
+}
@@ -0,0 +1,120 @@
+//! Snapshots of live allocations.
+//!
+//! A [`Snapshot`] records the allocations made with `alloc_with_header` that
+//! are live when it is taken, with their size, cookie and call site. Two
+//! snapshots taken at different checkpoints can then be compared with
+//! [`Snapshot::diff`], which lists the allocations made since the first one
+//! and still live at the second one: the usual suspects of a heap creeping
+//! up over time.
+//!
+//! [`Snapshot`]: struct.Snapshot.html
+//! [`Snapshot::diff`]: struct.Snapshot.html#method.diff
+
+use core::panic::Location;
+use core::slice;
+
+/// A live allocation recorded in a [`Snapshot`](struct.Snapshot.html).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LiveAllocation {
+    /// The number of header allocations made before this one.
+    pub serial: usize,
+    /// The address of the allocation.
+    pub addr: usize,
+    /// The size of the allocation.
+    pub size: usize,
+    /// The cookie of the allocation, or `0`.
+    pub cookie: usize,
+    /// The call site of the allocation.
+    pub site: &'static Location<'static>,
+}
+
+/// The live header allocations of an allocator at some point, up to `N` of them.
+///
+/// Created by [`Deblockator::snapshot`]. Allocations are recorded from the
+/// most recent one, so that a snapshot too small to hold all of them only
+/// misses the oldest ones.
+///
+/// [`Deblockator::snapshot`]: struct.Deblockator.html#method.snapshot
+pub struct Snapshot<const N: usize> {
+    pub(crate) serial: usize,
+    pub(crate) allocations: [Option<LiveAllocation>; N],
+    pub(crate) len: usize,
+    pub(crate) missed: usize,
+}
+
+impl<const N: usize> Snapshot<N> {
+    /// Create a snapshot of a heap without any allocation made yet.
+    pub(crate) const fn new(serial: usize) -> Self {
+        Snapshot {
+            serial,
+            allocations: [None; N],
+            len: 0,
+            missed: 0,
+        }
+    }
+
+    /// Record a live allocation, or count it as missed if the snapshot is full.
+    pub(crate) fn record(&mut self, allocation: LiveAllocation) {
+        match self.allocations.get_mut(self.len) {
+            Some(slot) => {
+                *slot = Some(allocation);
+                self.len += 1;
+            }
+            None => self.missed += 1,
+        }
+    }
+
+    /// Returns the number of header allocations made before the snapshot.
+    pub fn serial(&self) -> usize {
+        self.serial
+    }
+
+    /// Returns the live allocations recorded, from the most recent one.
+    pub fn allocations(&self) -> Allocations<'_> {
+        Allocations {
+            allocations: self.allocations[..self.len].iter(),
+            since: 0,
+        }
+    }
+
+    /// Returns the number of live allocations the snapshot was too small to record.
+    pub fn missed(&self) -> usize {
+        self.missed
+    }
+
+    /// Returns the allocations of `later` that are not in `earlier`.
+    ///
+    /// Since an allocation live in both snapshots was made before the
+    /// earlier one, these are the allocations of `later` made after
+    /// `earlier` was taken, which can then be grouped by size, cookie or
+    /// call site. Allocations `later` was too small to record are missed.
+    pub fn diff<'a>(earlier: &Self, later: &'a Self) -> Allocations<'a> {
+        Allocations {
+            allocations: later.allocations[..later.len].iter(),
+            since: earlier.serial,
+        }
+    }
+}
+
+/// An iterator over the allocations of a snapshot.
+///
+/// Created by [`Snapshot::allocations`] and [`Snapshot::diff`].
+///
+/// [`Snapshot::allocations`]: struct.Snapshot.html#method.allocations
+/// [`Snapshot::diff`]: struct.Snapshot.html#method.diff
+pub struct Allocations<'a> {
+    allocations: slice::Iter<'a, Option<LiveAllocation>>,
+    since: usize,
+}
+
+impl<'a> Iterator for Allocations<'a> {
+    type Item = &'a LiveAllocation;
+
+    fn next(&mut self) -> Option<&'a LiveAllocation> {
+        let since = self.since;
+        self.allocations
+            .by_ref()
+            .flatten()
+            .find(|allocation| allocation.serial >= since)
+    }
+}
@@ -0,0 +1,274 @@
+//! Allocation statistics.
+//!
+//! The [`StatsAlloc`] wrapper counts operations and bytes on either side of
+//! the block boundary: it can wrap the backing allocator given to a
+//! `Deblockator` (as an [`Allocator`]) to instrument block acquisitions, or
+//! wrap the `Deblockator` itself (as a [`GlobalAlloc`]) to instrument user
+//! allocations.
+//!
+//! [`StatsAlloc`]: struct.StatsAlloc.html
+//! [`Allocator`]: https://doc.rust-lang.org/nightly/std/alloc/trait.Allocator.html
+//! [`GlobalAlloc`]: https://doc.rust-lang.org/nightly/std/alloc/trait.GlobalAlloc.html
+
+use core::alloc::AllocError;
+use core::alloc::Allocator;
+use core::alloc::GlobalAlloc;
+use core::alloc::Layout;
+use core::ptr::NonNull;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+/// A snapshot of the statistics collected by a [`StatsAlloc`].
+///
+/// [`StatsAlloc`]: struct.StatsAlloc.html
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of successful allocations.
+    pub allocations: usize,
+    /// The number of deallocations.
+    pub deallocations: usize,
+    /// The number of successful reallocations.
+    pub reallocations: usize,
+    /// The number of failed allocations or reallocations.
+    pub failures: usize,
+    /// The total number of bytes allocated.
+    pub bytes_allocated: usize,
+    /// The total number of bytes deallocated.
+    pub bytes_deallocated: usize,
+}
+
+impl Stats {
+    /// Returns the number of allocations that were not deallocated yet.
+    pub fn live_allocations(&self) -> usize {
+        self.allocations.wrapping_sub(self.deallocations)
+    }
+
+    /// Returns the number of bytes that were not deallocated yet.
+    pub fn live_bytes(&self) -> usize {
+        self.bytes_allocated.wrapping_sub(self.bytes_deallocated)
+    }
+}
+
+/// A breakdown of the memory held by a `Deblockator`, between user data and overhead.
+///
+/// Created by [`Deblockator::overhead`]. Free holes keep their own header
+/// in the free memory, so their cost only shows as the minimum size of an
+/// allocation, counted in `padding`.
+///
+/// [`Deblockator::overhead`]: struct.Deblockator.html#method.overhead
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Overhead {
+    /// The bytes requested by the live allocations.
+    pub user: usize,
+    /// The bytes taken by the heap block headers, and the padding after them.
+    pub headers: usize,
+    /// The bytes taken by live allocations and allocator structures beyond
+    /// the requested sizes, such as rounding and alignment losses.
+    pub padding: usize,
+    /// The free bytes in the heap blocks.
+    pub free: usize,
+    /// The bytes of freed large allocations kept in the large cache.
+    pub cached: usize,
+}
+
+impl Overhead {
+    /// Returns the number of bytes consumed by the allocator itself.
+    pub fn metadata(&self) -> usize {
+        self.headers + self.padding
+    }
+
+    /// Returns the number of bytes accounted for, user data included.
+    pub fn total(&self) -> usize {
+        self.user + self.headers + self.padding + self.free + self.cached
+    }
+}
+
+/// An allocator wrapper counting operations and bytes.
+///
+/// Counters are updated with relaxed atomic operations, so a snapshot taken
+/// while other threads are allocating may be slightly inconsistent.
+pub struct StatsAlloc<A> {
+    inner: A,
+    allocations: AtomicUsize,
+    deallocations: AtomicUsize,
+    reallocations: AtomicUsize,
+    failures: AtomicUsize,
+    bytes_allocated: AtomicUsize,
+    bytes_deallocated: AtomicUsize,
+}
+
+impl<A> StatsAlloc<A> {
+    /// Create a new statistics wrapper around the given allocator.
+    pub const fn new(inner: A) -> Self {
+        StatsAlloc {
+            inner,
+            allocations: AtomicUsize::new(0),
+            deallocations: AtomicUsize::new(0),
+            reallocations: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+            bytes_allocated: AtomicUsize::new(0),
+            bytes_deallocated: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns a reference to the wrapped allocator.
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Returns a snapshot of the collected statistics.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            allocations: self.allocations.load(Ordering::Relaxed),
+            deallocations: self.deallocations.load(Ordering::Relaxed),
+            reallocations: self.reallocations.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            bytes_allocated: self.bytes_allocated.load(Ordering::Relaxed),
+            bytes_deallocated: self.bytes_deallocated.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reset all counters to zero.
+    pub fn reset(&self) {
+        self.allocations.store(0, Ordering::Relaxed);
+        self.deallocations.store(0, Ordering::Relaxed);
+        self.reallocations.store(0, Ordering::Relaxed);
+        self.failures.store(0, Ordering::Relaxed);
+        self.bytes_allocated.store(0, Ordering::Relaxed);
+        self.bytes_deallocated.store(0, Ordering::Relaxed);
+    }
+
+    fn record_alloc(&self, success: bool, size: usize) {
+        if success {
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+            self.bytes_allocated.fetch_add(size, Ordering::Relaxed);
+        } else {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn record_dealloc(&self, size: usize) {
+        self.deallocations.fetch_add(1, Ordering::Relaxed);
+        self.bytes_deallocated.fetch_add(size, Ordering::Relaxed);
+    }
+
+    fn record_realloc(&self, success: bool, old_size: usize, new_size: usize) {
+        if success {
+            self.reallocations.fetch_add(1, Ordering::Relaxed);
+            self.bytes_deallocated
+                .fetch_add(old_size, Ordering::Relaxed);
+            self.bytes_allocated.fetch_add(new_size, Ordering::Relaxed);
+        } else {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for StatsAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        self.record_alloc(!ptr.is_null(), layout.size());
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc_zeroed(layout);
+        self.record_alloc(!ptr.is_null(), layout.size());
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        self.record_dealloc(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        self.record_realloc(!new_ptr.is_null(), layout.size(), new_size);
+        new_ptr
+    }
+}
+
+unsafe impl<A: Allocator> Allocator for StatsAlloc<A> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let result = self.inner.allocate(layout);
+        self.record_alloc(result.is_ok(), layout.size());
+        result
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let result = self.inner.allocate_zeroed(layout);
+        self.record_alloc(result.is_ok(), layout.size());
+        result
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.inner.deallocate(ptr, layout);
+        self.record_dealloc(layout.size());
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let result = self.inner.grow(ptr, old_layout, new_layout);
+        self.record_realloc(result.is_ok(), old_layout.size(), new_layout.size());
+        result
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let result = self.inner.grow_zeroed(ptr, old_layout, new_layout);
+        self.record_realloc(result.is_ok(), old_layout.size(), new_layout.size());
+        result
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let result = self.inner.shrink(ptr, old_layout, new_layout);
+        self.record_realloc(result.is_ok(), old_layout.size(), new_layout.size());
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::System;
+
+    #[test]
+    /// Check operations and bytes are counted on both allocator traits.
+    fn statsalloc_counts() {
+        let stats = StatsAlloc::new(System);
+        let layout = Layout::from_size_align(64, 8).expect("bad layout");
+
+        unsafe {
+            let ptr = GlobalAlloc::alloc(&stats, layout);
+            let ptr = GlobalAlloc::realloc(&stats, ptr, layout, 128);
+            let block = Allocator::allocate(&stats, layout).expect("could not allocate");
+
+            let snapshot = stats.stats();
+            assert_eq!(snapshot.allocations, 2);
+            assert_eq!(snapshot.reallocations, 1);
+            assert_eq!(snapshot.live_allocations(), 2);
+            assert_eq!(snapshot.live_bytes(), 128 + 64);
+
+            GlobalAlloc::dealloc(&stats, ptr, Layout::from_size_align(128, 8).unwrap());
+            Allocator::deallocate(&stats, block.cast(), layout);
+        }
+
+        let snapshot = stats.stats();
+        assert_eq!(snapshot.live_allocations(), 0);
+        assert_eq!(snapshot.live_bytes(), 0);
+    }
+}
@@ -0,0 +1,249 @@
+//! Allocators routing layouts to tiers by size.
+//!
+//! A single block size is a trade-off between the memory wasted in partly
+//! used blocks and the number of requests made to the backing allocator. A
+//! [`Tiered`] allocator splits allocations between two allocators, typically
+//! `Deblockator`s with different block sizes, so that small layouts are
+//! served from small blocks and larger ones from larger blocks. Tiers can be
+//! nested to get more than two block sizes.
+//!
+//! [`Tiered`]: struct.Tiered.html
+
+use core::alloc::AllocError;
+use core::alloc::Allocator;
+use core::alloc::GlobalAlloc;
+use core::alloc::Layout;
+use core::cmp::min;
+use core::ptr;
+use core::ptr::NonNull;
+
+/// An allocator serving layouts from one of two tiers, depending on their size.
+///
+/// Layouts smaller than the threshold are served by the `small` tier, and
+/// the other ones by the `large` tier. Deallocations are routed the same
+/// way, so the same layout must be used to free an allocation. Resizing an
+/// allocation across the threshold moves it to the other tier.
+///
+/// For instance, with blocks of `4kB`, `64kB` and `1MB`:
+/// ```rust,ignore
+/// type Small = Deblockator<Jemalloc, U4096, U4096, U2048>;
+/// type Medium = Deblockator<Jemalloc, U65536>;
+/// type Large = Deblockator<Jemalloc, U1048576, U4096, U262144>;
+///
+/// #[global_allocator]
+/// static GLOBAL: Tiered<Small, Tiered<Medium, Large>> = Tiered::new(
+///     Deblockator::new(Jemalloc),
+///     Tiered::new(Deblockator::new(Jemalloc), Deblockator::new(Jemalloc), 16384),
+///     1024,
+/// );
+/// ```
+pub struct Tiered<S, L> {
+    small: S,
+    large: L,
+    threshold: usize,
+}
+
+impl<S, L> Tiered<S, L> {
+    /// Create a tiered allocator, serving layouts smaller than `threshold` bytes from `small`.
+    pub const fn new(small: S, large: L, threshold: usize) -> Self {
+        Tiered {
+            small,
+            large,
+            threshold,
+        }
+    }
+
+    /// Returns a reference to the tier serving small layouts.
+    pub fn small(&self) -> &S {
+        &self.small
+    }
+
+    /// Returns a reference to the tier serving large layouts.
+    pub fn large(&self) -> &L {
+        &self.large
+    }
+
+    /// Check if a layout of the given size is served by the small tier.
+    fn is_small(&self, size: usize) -> bool {
+        size < self.threshold
+    }
+}
+
+unsafe impl<S: GlobalAlloc, L: GlobalAlloc> GlobalAlloc for Tiered<S, L> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if self.is_small(layout.size()) {
+            self.small.alloc(layout)
+        } else {
+            self.large.alloc(layout)
+        }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if self.is_small(layout.size()) {
+            self.small.alloc_zeroed(layout)
+        } else {
+            self.large.alloc_zeroed(layout)
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if self.is_small(layout.size()) {
+            self.small.dealloc(ptr, layout)
+        } else {
+            self.large.dealloc(ptr, layout)
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        match (self.is_small(layout.size()), self.is_small(new_size)) {
+            (true, true) => self.small.realloc(ptr, layout, new_size),
+            (false, false) => self.large.realloc(ptr, layout, new_size),
+            _ => {
+                let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+                let new_ptr = self.alloc(new_layout);
+                if !new_ptr.is_null() {
+                    ptr::copy_nonoverlapping(ptr, new_ptr, min(layout.size(), new_size));
+                    self.dealloc(ptr, layout);
+                }
+                new_ptr
+            }
+        }
+    }
+}
+
+impl<S: Allocator, L: Allocator> Tiered<S, L> {
+    /// Move an allocation to the tier of the new layout.
+    unsafe fn move_to_tier(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let new_ptr = if zeroed {
+            self.allocate_zeroed(new_layout)?
+        } else {
+            self.allocate(new_layout)?
+        };
+        ptr::copy_nonoverlapping(
+            ptr.as_ptr(),
+            new_ptr.cast::<u8>().as_ptr(),
+            min(old_layout.size(), new_layout.size()),
+        );
+        self.deallocate(ptr, old_layout);
+        Ok(new_ptr)
+    }
+}
+
+unsafe impl<S: Allocator, L: Allocator> Allocator for Tiered<S, L> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if self.is_small(layout.size()) {
+            self.small.allocate(layout)
+        } else {
+            self.large.allocate(layout)
+        }
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if self.is_small(layout.size()) {
+            self.small.allocate_zeroed(layout)
+        } else {
+            self.large.allocate_zeroed(layout)
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if self.is_small(layout.size()) {
+            self.small.deallocate(ptr, layout)
+        } else {
+            self.large.deallocate(ptr, layout)
+        }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        match (
+            self.is_small(old_layout.size()),
+            self.is_small(new_layout.size()),
+        ) {
+            (true, true) => self.small.grow(ptr, old_layout, new_layout),
+            (false, false) => self.large.grow(ptr, old_layout, new_layout),
+            _ => self.move_to_tier(ptr, old_layout, new_layout, false),
+        }
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        match (
+            self.is_small(old_layout.size()),
+            self.is_small(new_layout.size()),
+        ) {
+            (true, true) => self.small.grow_zeroed(ptr, old_layout, new_layout),
+            (false, false) => self.large.grow_zeroed(ptr, old_layout, new_layout),
+            _ => self.move_to_tier(ptr, old_layout, new_layout, true),
+        }
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        match (
+            self.is_small(old_layout.size()),
+            self.is_small(new_layout.size()),
+        ) {
+            (true, true) => self.small.shrink(ptr, old_layout, new_layout),
+            (false, false) => self.large.shrink(ptr, old_layout, new_layout),
+            _ => self.move_to_tier(ptr, old_layout, new_layout, false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::alloc::System;
+
+    use typenum::consts::{U2048, U4096};
+
+    use crate::Deblockator;
+
+    #[test]
+    /// Check layouts are served by the tier matching their size, and moved across tiers.
+    fn tiered_routing() {
+        let tiered: Tiered<Deblockator<System, U4096, U4096, U2048>, Deblockator<System>> =
+            Tiered::new(Deblockator::new(System), Deblockator::new(System), 1024);
+        let small = Layout::from_size_align(100, 8).unwrap();
+        let large = Layout::from_size_align(8000, 8).unwrap();
+
+        unsafe {
+            let ptr1 = tiered.alloc(small);
+            let ptr2 = tiered.alloc(large);
+            assert_eq!(tiered.small().blocks().count(), 1);
+            assert_eq!(tiered.large().blocks().count(), 1);
+            assert!(tiered.small().blocks().all(|block| block.len == 4096));
+
+            // growing past the threshold moves the allocation to the large tier
+            ptr1.write_bytes(0x5A, small.size());
+            let ptr1 = tiered.realloc(ptr1, small, large.size());
+            assert!(!ptr1.is_null());
+            assert!((0..small.size()).all(|i| *ptr1.add(i) == 0x5A));
+
+            tiered.dealloc(ptr1, large);
+            tiered.dealloc(ptr2, large);
+            assert_eq!(tiered.small().flush_caches(), 4096);
+            assert_eq!(tiered.large().flush_caches(), 65536);
+        }
+    }
+}
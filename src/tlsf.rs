@@ -0,0 +1,537 @@
+//! Two-level segregated fit allocation.
+//!
+//! A [`Tlsf`] manages heap blocks obtained from another allocator like a
+//! `Deblockator`, but replaces the address-ordered hole lists with the
+//! two-level segregated fit scheme: free chunks are kept in lists by size
+//! class, indexed by two levels of bitmaps, and chunks carry boundary tags
+//! so that freed memory is merged with its neighbours without any search.
+//! Allocations and frees run in constant time, whatever the number of free
+//! chunks, which suits real-time code needing a bounded latency more than
+//! the smallest memory overhead.
+//!
+//! [`Tlsf`]: struct.Tlsf.html
+
+use core::alloc::AllocError;
+use core::alloc::Allocator;
+use core::alloc::GlobalAlloc;
+use core::alloc::Layout;
+use core::cmp::max;
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::ptr;
+use core::ptr::NonNull;
+
+use spin::Mutex;
+use typenum::Unsigned;
+
+use super::consts::DefaultBlockSize;
+use super::consts::DefaultLargeSize;
+use super::utils::align_up;
+use super::utils::checked_align_up;
+
+/// The alignment of every chunk, and the granularity of their sizes.
+const ALIGN: usize = 2 * size_of::<usize>();
+/// The number of second-level classes in each first-level class, as a power of two.
+const SL_LOG: u32 = 4;
+/// The number of second-level classes in each first-level class.
+const SL_COUNT: usize = 1 << SL_LOG;
+/// The size below which classes are linear instead of logarithmic, as a power of two.
+const FL_SHIFT: u32 = SL_LOG + ALIGN.trailing_zeros();
+/// The size below which classes are linear instead of logarithmic.
+const SMALL_SIZE: usize = 1 << FL_SHIFT;
+/// The number of first-level classes.
+const FL_COUNT: usize = (usize::BITS - FL_SHIFT + 1) as usize;
+
+/// The size of the header of a chunk.
+const HEADER: usize = size_of::<Chunk>();
+/// The size of the smallest chunk, large enough to be linked when free.
+const MIN_CHUNK: usize = size_of::<FreeChunk>();
+/// The size of the header of a block.
+const BLOCK_HEADER: usize = size_of::<Block>();
+
+/// Flags the chunk as free.
+const FREE: usize = 0b01;
+/// Flags the chunk preceding this one in memory as free.
+const PREV_FREE: usize = 0b10;
+
+/// The header of a chunk, used or free.
+///
+/// The last chunk of each block is followed by an empty sentinel header,
+/// flagged as used, so that merges stop at the end of the block.
+#[repr(C)]
+struct Chunk {
+    /// The address of the chunk preceding this one in memory, or `0`.
+    prev_phys: usize,
+    /// The size of the chunk, header included, and its flags.
+    size: usize,
+}
+
+/// A free chunk, linked in the list of its size class.
+#[repr(C)]
+struct FreeChunk {
+    chunk: Chunk,
+    next: Option<NonNull<FreeChunk>>,
+    prev: Option<NonNull<FreeChunk>>,
+}
+
+/// The header of a block, linked to the next block of the allocator.
+#[repr(C)]
+struct Block {
+    next: Option<NonNull<Block>>,
+    /// Keeps the first chunk aligned.
+    _reserved: usize,
+}
+
+/// Returns the size class of a free chunk of `size` bytes.
+fn mapping(size: usize) -> (usize, usize) {
+    if size < SMALL_SIZE {
+        (0, size / ALIGN)
+    } else {
+        let log = usize::BITS - 1 - size.leading_zeros();
+        let sl = (size >> (log - SL_LOG)) ^ SL_COUNT;
+        ((log - FL_SHIFT + 1) as usize, sl)
+    }
+}
+
+/// Round `size` up so that every chunk in its class is at least `size` bytes.
+fn round_up(size: usize) -> Option<usize> {
+    if size < SMALL_SIZE {
+        Some(size)
+    } else {
+        let log = usize::BITS - 1 - size.leading_zeros();
+        size.checked_add((1 << (log - SL_LOG)) - 1)
+    }
+}
+
+/// The free lists of an allocator, and the bitmaps indexing them.
+struct TlsfState {
+    fl_bitmap: usize,
+    sl_bitmaps: [usize; FL_COUNT],
+    heads: [[Option<NonNull<FreeChunk>>; SL_COUNT]; FL_COUNT],
+    blocks: Option<NonNull<Block>>,
+    block_count: usize,
+    free: usize,
+}
+
+impl TlsfState {
+    /// Link a free chunk in the list of its size class.
+    unsafe fn insert(&mut self, chunk: NonNull<FreeChunk>) {
+        let (fl, sl) = mapping((*chunk.as_ptr()).chunk.size & !(FREE | PREV_FREE));
+        let head = self.heads[fl][sl];
+        (*chunk.as_ptr()).next = head;
+        (*chunk.as_ptr()).prev = None;
+        if let Some(head) = head {
+            (*head.as_ptr()).prev = Some(chunk);
+        }
+        self.heads[fl][sl] = Some(chunk);
+        self.fl_bitmap |= 1 << fl;
+        self.sl_bitmaps[fl] |= 1 << sl;
+    }
+
+    /// Unlink a free chunk from the list of its size class.
+    unsafe fn remove(&mut self, chunk: NonNull<FreeChunk>) {
+        let (fl, sl) = mapping((*chunk.as_ptr()).chunk.size & !(FREE | PREV_FREE));
+        let FreeChunk { next, prev, .. } = chunk.as_ptr().read();
+        if let Some(next) = next {
+            (*next.as_ptr()).prev = prev;
+        }
+        match prev {
+            Some(prev) => (*prev.as_ptr()).next = next,
+            None => {
+                self.heads[fl][sl] = next;
+                if next.is_none() {
+                    self.sl_bitmaps[fl] &= !(1 << sl);
+                    if self.sl_bitmaps[fl] == 0 {
+                        self.fl_bitmap &= !(1 << fl);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Find a free chunk of at least `size` bytes, with two bitmap lookups.
+    fn find(&self, size: usize) -> Option<NonNull<FreeChunk>> {
+        let (fl, sl) = mapping(round_up(size)?);
+        if fl >= FL_COUNT {
+            return None;
+        }
+        let (fl, sl_map) = match self.sl_bitmaps[fl] & (!0 << sl) {
+            0 => {
+                let fl_map = self.fl_bitmap & (!0 << (fl + 1));
+                if fl_map == 0 {
+                    return None;
+                }
+                let fl = fl_map.trailing_zeros() as usize;
+                (fl, self.sl_bitmaps[fl])
+            }
+            sl_map => (fl, sl_map),
+        };
+        self.heads[fl][sl_map.trailing_zeros() as usize]
+    }
+}
+
+/// Returns the header of the chunk at `addr`.
+unsafe fn chunk_at<'a>(addr: usize) -> &'a mut Chunk {
+    &mut *(addr as *mut Chunk)
+}
+
+/// Write a free chunk header at `addr`, and flag it in the next chunk.
+unsafe fn write_free(addr: usize, prev_phys: usize, size: usize) -> NonNull<FreeChunk> {
+    chunk_at(addr).prev_phys = prev_phys;
+    chunk_at(addr).size = size | FREE;
+    let next = chunk_at(addr + size);
+    next.prev_phys = addr;
+    next.size |= PREV_FREE;
+    NonNull::new_unchecked(addr as *mut FreeChunk)
+}
+
+/// An allocator managing heap blocks with two-level segregated fit.
+///
+/// Blocks of `BS` bytes are allocated from the wrapped allocator when no
+/// free chunk fits a layout, and are only returned to it when the allocator
+/// is dropped. Each allocation carries a header of two words, and layouts
+/// of `LS` bytes or more, or too aligned to fit a block, are forwarded to
+/// the wrapped allocator. Only the allocations which grow the heap or are
+/// forwarded wait on the wrapped allocator: the others run in constant time.
+pub struct Tlsf<A: Allocator, BS = DefaultBlockSize, LS = DefaultLargeSize>
+where
+    BS: Unsigned,
+    LS: Unsigned,
+{
+    alloc: A,
+    state: Mutex<TlsfState>,
+    __block_size: PhantomData<BS>,
+    __large_size: PhantomData<LS>,
+}
+
+unsafe impl<A: Allocator + Send, BS: Unsigned, LS: Unsigned> Send for Tlsf<A, BS, LS> {}
+unsafe impl<A: Allocator + Sync, BS: Unsigned, LS: Unsigned> Sync for Tlsf<A, BS, LS> {}
+
+impl<A: Allocator, BS: Unsigned, LS: Unsigned> Tlsf<A, BS, LS> {
+    /// Evaluated when `new` is instantiated, so that an invalid configuration
+    /// fails the build instead of corrupting the heap at runtime.
+    const VALID_CONFIGURATION: () = {
+        assert!(
+            BS::USIZE.is_multiple_of(ALIGN),
+            "the block size (BS) must be a multiple of the chunk alignment"
+        );
+        assert!(
+            BS::USIZE >= BLOCK_HEADER + MIN_CHUNK + HEADER,
+            "the block size (BS) cannot fit the block header and a chunk"
+        );
+        assert!(
+            LS::USIZE > 0,
+            "the large layout limit (LS) must not be zero"
+        );
+    };
+
+    /// Create a new allocator instance, wrapping the given allocator.
+    pub const fn new(alloc: A) -> Self {
+        let () = Self::VALID_CONFIGURATION;
+        Tlsf {
+            alloc,
+            state: Mutex::new(TlsfState {
+                fl_bitmap: 0,
+                sl_bitmaps: [0; FL_COUNT],
+                heads: [[None; SL_COUNT]; FL_COUNT],
+                blocks: None,
+                block_count: 0,
+                free: 0,
+            }),
+            __block_size: PhantomData,
+            __large_size: PhantomData,
+        }
+    }
+
+    /// Returns the number of blocks allocated so far.
+    pub fn blocks(&self) -> usize {
+        self.state.lock().block_count
+    }
+
+    /// Returns the number of free bytes in the blocks, chunk headers included.
+    pub fn free(&self) -> usize {
+        self.state.lock().free
+    }
+
+    /// Returns the layout of a block.
+    fn block_layout() -> Layout {
+        unsafe { Layout::from_size_align_unchecked(BS::USIZE, ALIGN) }
+    }
+
+    /// Returns the size of the free chunk spanning a new block.
+    fn block_chunk_size() -> usize {
+        BS::USIZE - BLOCK_HEADER - HEADER
+    }
+
+    /// Returns the size of the chunk holding `layout`, and the size of the
+    /// free chunk to look for so that it can be aligned in it.
+    fn chunk_sizes(layout: Layout) -> Option<(usize, usize)> {
+        let size = max(
+            MIN_CHUNK,
+            HEADER.checked_add(checked_align_up(layout.size(), ALIGN)?)?,
+        );
+        if layout.align() <= ALIGN {
+            Some((size, size))
+        } else {
+            // leave room for a free chunk in front of the aligned one
+            let search = size.checked_add(layout.align())?.checked_add(MIN_CHUNK)?;
+            Some((size, search))
+        }
+    }
+
+    /// Check if `layout` is forwarded to the wrapped allocator.
+    fn is_large(layout: Layout) -> bool {
+        layout.size() >= LS::USIZE
+            || match Self::chunk_sizes(layout).and_then(|(_, search)| round_up(search)) {
+                Some(search) => search > Self::block_chunk_size(),
+                None => true,
+            }
+    }
+
+    /// Allocate a new block, and add its memory to the free lists.
+    fn grow(&self, state: &mut TlsfState) -> Result<(), AllocError> {
+        let block = self.alloc.allocate(Self::block_layout())?.cast::<Block>();
+        unsafe {
+            block.as_ptr().write(Block {
+                next: state.blocks,
+                _reserved: 0,
+            });
+            state.blocks = Some(block);
+            state.block_count += 1;
+
+            let addr = block.as_ptr() as usize + BLOCK_HEADER;
+            let size = Self::block_chunk_size();
+            chunk_at(addr + size).size = 0;
+            state.insert(write_free(addr, 0, size));
+            state.free += size;
+        }
+        Ok(())
+    }
+
+    /// Allocate a chunk for `layout` from the blocks, growing the heap if needed.
+    fn allocate_chunk(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        let (size, search) = Self::chunk_sizes(layout).ok_or(AllocError)?;
+        let mut state = self.state.lock();
+        let chunk = match state.find(search) {
+            Some(chunk) => chunk,
+            None => {
+                self.grow(&mut state)?;
+                state.find(search).ok_or(AllocError)?
+            }
+        };
+
+        unsafe {
+            state.remove(chunk);
+            let mut addr = chunk.as_ptr() as usize;
+            let mut total = chunk_at(addr).size & !(FREE | PREV_FREE);
+            let prev_phys = chunk_at(addr).prev_phys;
+            state.free -= total;
+
+            // free the memory in front of the aligned payload
+            let mut flags = 0;
+            if layout.align() > ALIGN {
+                let mut payload = align_up(addr + HEADER, layout.align());
+                if payload - HEADER - addr < MIN_CHUNK && payload - HEADER != addr {
+                    payload = align_up(addr + HEADER + MIN_CHUNK, layout.align());
+                }
+                let gap = payload - HEADER - addr;
+                if gap > 0 {
+                    state.insert(write_free(addr, prev_phys, gap));
+                    state.free += gap;
+                    addr += gap;
+                    total -= gap;
+                    flags = PREV_FREE;
+                }
+            }
+
+            // free the memory after the chunk, if it can hold a chunk
+            if total - size >= MIN_CHUNK {
+                state.insert(write_free(addr + size, addr, total - size));
+                state.free += total - size;
+                total = size;
+            } else {
+                let next = chunk_at(addr + total);
+                next.prev_phys = addr;
+                next.size &= !PREV_FREE;
+            }
+            chunk_at(addr).size = total | flags;
+            Ok(NonNull::new_unchecked((addr + HEADER) as *mut u8))
+        }
+    }
+
+    /// Free the chunk holding `ptr`, merging it with its free neighbours.
+    unsafe fn deallocate_chunk(&self, ptr: NonNull<u8>) {
+        let mut state = self.state.lock();
+        let mut addr = ptr.as_ptr() as usize - HEADER;
+        let header = chunk_at(addr);
+        let mut total = header.size & !(FREE | PREV_FREE);
+        let mut prev_phys = header.prev_phys;
+        state.free += total;
+
+        let next = addr + total;
+        if chunk_at(next).size & FREE != 0 {
+            state.remove(NonNull::new_unchecked(next as *mut FreeChunk));
+            total += chunk_at(next).size & !(FREE | PREV_FREE);
+        }
+        if header.size & PREV_FREE != 0 {
+            let prev = prev_phys;
+            state.remove(NonNull::new_unchecked(prev as *mut FreeChunk));
+            total += chunk_at(prev).size & !(FREE | PREV_FREE);
+            prev_phys = chunk_at(prev).prev_phys;
+            addr = prev;
+        }
+        state.insert(write_free(addr, prev_phys, total));
+    }
+
+    /// Allocate memory for `layout`.
+    pub fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if Self::is_large(layout) {
+            return self.alloc.allocate(layout);
+        }
+        let ptr = self.allocate_chunk(layout)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    /// Free memory allocated for `layout`.
+    ///
+    /// # Safety
+    ///
+    /// The memory must have been allocated from this allocator with the same
+    /// layout, and must not be used afterwards.
+    pub unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if Self::is_large(layout) {
+            self.alloc.deallocate(ptr, layout)
+        } else {
+            self.deallocate_chunk(ptr)
+        }
+    }
+}
+
+impl<A: Allocator, BS: Unsigned, LS: Unsigned> Drop for Tlsf<A, BS, LS> {
+    fn drop(&mut self) {
+        let state = self.state.get_mut();
+        while let Some(block) = state.blocks {
+            unsafe {
+                state.blocks = block.as_ref().next;
+                self.alloc.deallocate(block.cast(), Self::block_layout());
+            }
+        }
+    }
+}
+
+unsafe impl<A: Allocator, BS: Unsigned, LS: Unsigned> GlobalAlloc for Tlsf<A, BS, LS> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.allocate(layout)
+            .map_or(ptr::null_mut(), |ptr| ptr.as_ptr() as *mut u8)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(ptr) = NonNull::new(ptr) {
+            self.deallocate(ptr, layout)
+        }
+    }
+}
+
+unsafe impl<A: Allocator, BS: Unsigned, LS: Unsigned> Allocator for Tlsf<A, BS, LS> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        Tlsf::allocate(self, layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        Tlsf::deallocate(self, ptr, layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::alloc::System;
+
+    use typenum::consts::{U1024, U4096};
+
+    #[test]
+    /// Check sizes map to increasing classes, and rounded sizes to classes fitting them.
+    fn tlsf_mapping() {
+        assert_eq!(mapping(ALIGN), (0, 1));
+        assert_eq!(mapping(SMALL_SIZE - ALIGN), (0, SL_COUNT - 1));
+        assert_eq!(mapping(SMALL_SIZE), (1, 0));
+        assert_eq!(mapping(2 * SMALL_SIZE - 1), (1, SL_COUNT - 1));
+        assert_eq!(mapping(2 * SMALL_SIZE), (2, 0));
+
+        let mut last = (0, 0);
+        for size in (ALIGN..1 << 16).step_by(ALIGN) {
+            let class = mapping(size);
+            assert!(class >= last);
+            last = class;
+            // chunks smaller than `size` are in lower classes than the rounded size
+            assert!(mapping(size - ALIGN) < mapping(round_up(size).unwrap()));
+        }
+    }
+
+    #[test]
+    /// Check allocations are aligned and disjoint, and fully merged once freed.
+    fn tlsf_alloc_dealloc() {
+        let tlsf: Tlsf<System, U4096, U4096> = Tlsf::new(System);
+        let mut seed = 0x2545_f491_u32;
+        let mut live = Vec::new();
+        for i in 0..2000 {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            if seed.is_multiple_of(3) && !live.is_empty() {
+                let (ptr, layout, value): (NonNull<u8>, Layout, u8) =
+                    live.swap_remove(seed as usize % live.len());
+                let bytes = unsafe { core::slice::from_raw_parts(ptr.as_ptr(), layout.size()) };
+                assert!(bytes.iter().all(|&byte| byte == value));
+                unsafe { tlsf.deallocate(ptr, layout) };
+            } else {
+                let size = 1 + seed as usize % 600;
+                let align = 1 << ((seed >> 24) % 7);
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let ptr = tlsf.allocate(layout).unwrap().cast::<u8>();
+                assert_eq!(ptr.as_ptr() as usize % align, 0);
+                unsafe { ptr.as_ptr().write_bytes(i as u8, size) };
+                live.push((ptr, layout, i as u8));
+            }
+        }
+
+        for (ptr, layout, _) in live.drain(..) {
+            unsafe { tlsf.deallocate(ptr, layout) };
+        }
+        let block_chunk = 4096 - BLOCK_HEADER - HEADER;
+        assert!(tlsf.blocks() > 1);
+        assert_eq!(tlsf.free(), tlsf.blocks() * block_chunk);
+        // every block was merged back into a single chunk
+        let layout = Layout::from_size_align(3800, 8).unwrap();
+        let before = tlsf.blocks();
+        let ptrs: Vec<_> = (0..before)
+            .map(|_| tlsf.allocate(layout).unwrap())
+            .collect();
+        assert_eq!(tlsf.blocks(), before);
+        for ptr in ptrs {
+            unsafe { tlsf.deallocate(ptr.cast(), layout) };
+        }
+    }
+
+    #[test]
+    /// Check large and over-aligned layouts are forwarded to the wrapped allocator.
+    fn tlsf_passthrough() {
+        let tlsf: Tlsf<System, U4096, U1024> = Tlsf::new(System);
+        for layout in [
+            Layout::from_size_align(1024, 8).unwrap(),
+            Layout::from_size_align(64, 4096).unwrap(),
+        ] {
+            let ptr = tlsf.allocate(layout).unwrap().cast::<u8>();
+            assert_eq!(ptr.as_ptr() as usize % layout.align(), 0);
+            assert_eq!(tlsf.blocks(), 0);
+            unsafe { tlsf.deallocate(ptr, layout) };
+        }
+
+        let layout = Layout::from_size_align(1000, 256).unwrap();
+        let ptr = tlsf.allocate(layout).unwrap().cast::<u8>();
+        assert_eq!(ptr.as_ptr() as usize % 256, 0);
+        assert_eq!(tlsf.blocks(), 1);
+        unsafe { tlsf.deallocate(ptr, layout) };
+    }
+}
@@ -0,0 +1,343 @@
+//! Index of the holes of a heap block by size.
+//!
+//! A [`SizeTree`] links the holes of a heap block in treaps ordered by size,
+//! then by address, so that the smallest hole large enough for a layout is
+//! found in `O(log n)` instead of by scanning the whole hole list. The tree
+//! is intrusive: each hole stores a [`TreeNode`] right after its `Hole`
+//! header, so holes must be at least [`MIN_INDEXED_SIZE`] bytes large. The
+//! priority of a node is a hash of its address, which keeps the treap
+//! balanced on average without storing anything more.
+//!
+//! Holes are split in several trees by the natural alignment of their start
+//! address, so that a layout aligned more than most holes only checks the
+//! holes aligned enough for it, and the unaligned ones large enough to be
+//! padded, instead of rejecting every hole of the right size one by one.
+//!
+//! The node stores a copy of the size of its hole, since merges grow the
+//! header of a hole before the node is moved in the tree.
+//!
+//! [`SizeTree`]: struct.SizeTree.html
+//! [`TreeNode`]: struct.TreeNode.html
+//! [`MIN_INDEXED_SIZE`]: constant.MIN_INDEXED_SIZE.html
+
+use core::alloc::Layout;
+use core::cmp::max;
+use core::cmp::min;
+use core::cmp::Ordering;
+use core::mem::size_of;
+
+use super::hole::Hole;
+use super::hole::HoleInfo;
+
+/// The node of a hole in a size tree, stored after its `Hole` header.
+#[repr(C)]
+pub struct TreeNode {
+    size: usize,
+    left: usize,
+    right: usize,
+}
+
+/// The number of alignment classes of holes, each with its own tree.
+///
+/// The hole starting at an address aligned to `2^k` words, but not more, is
+/// in the class `k`, and the last class also holds the more aligned holes.
+const ALIGN_CLASSES: usize = 8;
+
+/// The size of the smallest hole that can be linked in a size tree.
+pub const MIN_INDEXED_SIZE: usize = size_of::<Hole>() + size_of::<TreeNode>();
+
+/// Returns the node of the hole at `addr`.
+unsafe fn node<'a>(addr: usize) -> &'a mut TreeNode {
+    &mut *((addr + size_of::<Hole>()) as *mut TreeNode)
+}
+
+/// Returns the key of the hole at `addr`, ordering holes by size then address.
+unsafe fn key(addr: usize) -> (usize, usize) {
+    (node(addr).size, addr)
+}
+
+/// Returns the priority of the hole at `addr` in the treap.
+fn priority(addr: usize) -> u64 {
+    (addr as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+}
+
+/// Split the tree at `root` into the holes ordered before `key`, and the others.
+unsafe fn split(root: usize, key: (usize, usize)) -> (usize, usize) {
+    if root == 0 {
+        (0, 0)
+    } else if self::key(root) < key {
+        let (left, right) = split(node(root).right, key);
+        node(root).right = left;
+        (root, right)
+    } else {
+        let (left, right) = split(node(root).left, key);
+        node(root).left = right;
+        (left, root)
+    }
+}
+
+/// Merge two trees, the holes of `left` being ordered before the holes of `right`.
+unsafe fn merge(left: usize, right: usize) -> usize {
+    if left == 0 {
+        right
+    } else if right == 0 {
+        left
+    } else if priority(left) > priority(right) {
+        node(left).right = merge(node(left).right, right);
+        left
+    } else {
+        node(right).left = merge(left, node(right).left);
+        right
+    }
+}
+
+/// Insert the hole at `addr` in the tree at `root`, and return the new root.
+unsafe fn insert(root: usize, addr: usize) -> usize {
+    if root == 0 {
+        return addr;
+    }
+    if priority(addr) > priority(root) {
+        let (left, right) = split(root, key(addr));
+        node(addr).left = left;
+        node(addr).right = right;
+        return addr;
+    }
+    if key(addr) < key(root) {
+        node(root).left = insert(node(root).left, addr);
+    } else {
+        node(root).right = insert(node(root).right, addr);
+    }
+    root
+}
+
+/// Remove the hole with the given key from the tree at `root`, and return the new root.
+unsafe fn remove(root: usize, key: (usize, usize)) -> usize {
+    if root == 0 {
+        return 0;
+    }
+    match key.cmp(&self::key(root)) {
+        Ordering::Equal => merge(node(root).left, node(root).right),
+        Ordering::Less => {
+            node(root).left = remove(node(root).left, key);
+            root
+        }
+        Ordering::Greater => {
+            node(root).right = remove(node(root).right, key);
+            root
+        }
+    }
+}
+
+/// Returns the first hole ordered at or after `key` in the tree at `root`.
+fn lower_bound(root: usize, key: (usize, usize)) -> Option<HoleInfo> {
+    let mut found = None;
+    let mut current = root;
+    while current != 0 {
+        let current_key = unsafe { self::key(current) };
+        if current_key < key {
+            current = unsafe { node(current).right };
+        } else {
+            found = Some(HoleInfo {
+                addr: current,
+                size: current_key.0,
+            });
+            current = unsafe { node(current).left };
+        }
+    }
+    found
+}
+
+/// Returns the number of holes in the tree at `root`.
+unsafe fn len(root: usize) -> usize {
+    if root == 0 {
+        0
+    } else {
+        1 + len(node(root).left) + len(node(root).right)
+    }
+}
+
+/// Returns the alignment class of the hole at `addr`, from the natural
+/// alignment of its start in words.
+fn align_class(addr: usize) -> usize {
+    let words = (addr / size_of::<usize>()).trailing_zeros() as usize;
+    min(words, ALIGN_CLASSES - 1)
+}
+
+/// The holes of a heap block, ordered by size, in one tree per alignment class.
+pub struct SizeTree {
+    roots: [usize; ALIGN_CLASSES],
+}
+
+impl SizeTree {
+    /// Create an empty tree.
+    pub const fn new() -> Self {
+        SizeTree {
+            roots: [0; ALIGN_CLASSES],
+        }
+    }
+
+    /// Link the given hole in the tree of its alignment class.
+    ///
+    /// # Safety
+    ///
+    /// The hole must be in the hole list of the heap block, at least
+    /// `MIN_INDEXED_SIZE` bytes large, and not in the tree already.
+    pub unsafe fn insert(&mut self, hole: HoleInfo) {
+        let node = node(hole.addr);
+        node.size = hole.size;
+        node.left = 0;
+        node.right = 0;
+        let root = &mut self.roots[align_class(hole.addr)];
+        *root = insert(*root, hole.addr);
+    }
+
+    /// Unlink the given hole from the tree.
+    ///
+    /// # Safety
+    ///
+    /// The hole must be in the tree, with the size it was linked with, and
+    /// its node must not have been overwritten since.
+    pub unsafe fn remove(&mut self, hole: HoleInfo) {
+        let root = &mut self.roots[align_class(hole.addr)];
+        *root = remove(*root, (hole.size, hole.addr));
+    }
+
+    /// Forget every hole.
+    pub fn clear(&mut self) {
+        self.roots = [0; ALIGN_CLASSES];
+    }
+
+    /// Returns the smallest hole accepted by `fits` for `layout`, the lowest
+    /// one first among holes of the same size.
+    ///
+    /// Holes less aligned than the layout are only considered if they can
+    /// also hold a front padding of at least `min_split` bytes, so that
+    /// over-aligned layouts do not check the many small unaligned holes.
+    pub fn find(
+        &self,
+        layout: Layout,
+        min_split: usize,
+        mut fits: impl FnMut(HoleInfo) -> bool,
+    ) -> Option<HoleInfo> {
+        let mut best: Option<HoleInfo> = None;
+        for (class, &root) in self.roots.iter().enumerate() {
+            // the last class also holds holes aligned more than its alignment
+            let aligned =
+                class + 1 == ALIGN_CLASSES || size_of::<usize>() << class >= layout.align();
+            let size = if aligned {
+                layout.size()
+            } else {
+                layout.size().saturating_add(min_split)
+            };
+            let mut key = (size, 0);
+            while let Some(hole) = lower_bound(root, key) {
+                // only holes ordered before the best one so far can replace it
+                if best.is_some_and(|best| (best.size, best.addr) < (hole.size, hole.addr)) {
+                    break;
+                }
+                if fits(hole) {
+                    best = Some(hole);
+                    break;
+                }
+                key = (hole.size, hole.addr + 1);
+            }
+        }
+        best
+    }
+
+    /// Returns the size of the largest hole, or `0` if the tree is empty.
+    pub fn largest(&self) -> usize {
+        let mut largest = 0;
+        for &root in self.roots.iter() {
+            let mut current = root;
+            while current != 0 {
+                largest = max(largest, unsafe { node(current).size });
+                current = unsafe { node(current).right };
+            }
+        }
+        largest
+    }
+
+    /// Check the tree is ordered, and holds exactly the given holes.
+    pub fn check(&self, holes: impl Iterator<Item = HoleInfo>) -> bool {
+        let mut count = 0;
+        for hole in holes {
+            count += 1;
+            let linked = lower_bound(self.roots[align_class(hole.addr)], (hole.size, hole.addr));
+            if linked.is_none_or(|linked| linked.addr != hole.addr || linked.size != hole.size) {
+                return false;
+            }
+        }
+        let linked: usize = self.roots.iter().map(|&root| unsafe { len(root) }).sum();
+        linked == count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Check holes are found by size, then address, and removed by key.
+    fn size_tree_find() {
+        let mut memory = vec![[0usize; 8]; 64];
+        let base = memory.as_mut_ptr() as usize;
+        let holes: Vec<_> = (0..64)
+            .map(|index| HoleInfo {
+                addr: base + index * size_of::<[usize; 8]>(),
+                size: MIN_INDEXED_SIZE + (index * 7 % 16) * 8,
+            })
+            .collect();
+
+        let mut tree = SizeTree::new();
+        for hole in &holes {
+            unsafe { tree.insert(*hole) };
+        }
+        assert!(tree.check(holes.iter().copied()));
+        assert_eq!(tree.largest(), MIN_INDEXED_SIZE + 15 * 8);
+
+        // the smallest hole of at least the size, the lowest address first
+        let wanted = MIN_INDEXED_SIZE + 3 * 8;
+        let best = holes
+            .iter()
+            .filter(|hole| hole.size >= wanted)
+            .min_by_key(|hole| (hole.size, hole.addr))
+            .unwrap();
+        let layout = Layout::from_size_align(wanted, 8).unwrap();
+        assert_eq!(tree.find(layout, 0, |_| true).unwrap().addr, best.addr);
+        // rejected holes are skipped
+        let found = tree.find(layout, 0, |hole| hole.addr != best.addr).unwrap();
+        assert_eq!(found.size, wanted);
+        assert_ne!(found.addr, best.addr);
+
+        // over-aligned layouts find the same hole as a scan of every hole
+        for align in [16, 64, 256, 4096] {
+            for size in (8..MIN_INDEXED_SIZE + 16 * 8).step_by(24) {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let fits = |hole: HoleInfo| {
+                    let mut addr = (hole.addr + align - 1) & !(align - 1);
+                    if addr != hole.addr {
+                        addr = (hole.addr + MIN_INDEXED_SIZE + align - 1) & !(align - 1);
+                    }
+                    addr + size <= hole.addr + hole.size
+                };
+                let best = holes
+                    .iter()
+                    .copied()
+                    .filter(|hole| fits(*hole))
+                    .min_by_key(|hole| (hole.size, hole.addr));
+                let found = tree.find(layout, MIN_INDEXED_SIZE, fits);
+                assert_eq!(found.map(|hole| hole.addr), best.map(|hole| hole.addr));
+            }
+        }
+
+        for hole in holes.iter().step_by(2) {
+            unsafe { tree.remove(*hole) };
+        }
+        assert!(tree.check(holes.iter().skip(1).step_by(2).copied()));
+        let layout = Layout::from_size_align(MIN_INDEXED_SIZE + 16 * 8, 8).unwrap();
+        assert!(tree.find(layout, 0, |_| true).is_none());
+        tree.clear();
+        assert_eq!(tree.largest(), 0);
+    }
+}
@@ -21,3 +21,38 @@ pub fn align_down(addr: usize, align: usize) -> usize {
 pub fn align_up(addr: usize, align: usize) -> usize {
     align_down(addr + align - 1, align)
 }
+
+/// Align upwards, without overflowing.
+///
+/// Returns the smallest x with alignment `align` so that x >= addr, or `None`
+/// if there is no such address. The alignment must be a power of 2.
+pub fn checked_align_up(addr: usize, align: usize) -> Option<usize> {
+    addr.checked_add(align - 1)
+        .map(|addr| align_down(addr, align))
+}
+
+/// A 64-bit FNV-1a hasher.
+///
+/// Used to compute stable digests of the heap metadata, without depending
+/// on `std` or on the randomly-seeded default hasher.
+pub struct Fnv1a(u64);
+
+impl Fnv1a {
+    /// Create a new hasher with the FNV offset basis.
+    pub fn new() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    /// Feed a word to the hasher, byte by byte.
+    pub fn write_usize(&mut self, value: usize) {
+        for byte in value.to_le_bytes().iter() {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    /// Returns the current digest.
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
@@ -0,0 +1,131 @@
+//! Detection of allocator locks held for too long.
+//!
+//! A deadlock involving the global allocator, for instance an allocation
+//! made from an interrupt handler while the heap is locked, usually shows
+//! as a device spinning forever. With the `watchdog` feature, a `Deblockator`
+//! given a [`LockWatchdog`] records the call site holding its lock, and
+//! reports it when an acquisition has been waiting for longer than a bound.
+//!
+//! [`LockWatchdog`]: struct.LockWatchdog.html
+
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::Ordering;
+
+use super::clock::Clock;
+
+/// A diagnostic reporting allocator locks held for longer than a bound.
+///
+/// The callback is called with the call site that last acquired the lock,
+/// and the time waited so far, in clock units. It is called once per
+/// blocked acquisition, from the waiting thread, while the lock is still
+/// held: it must not use the allocator being watched.
+pub struct LockWatchdog {
+    clock: &'static dyn Clock,
+    bound: u64,
+    callback: fn(Option<&'static Location<'static>>, u64),
+    owner: AtomicPtr<Location<'static>>,
+}
+
+impl LockWatchdog {
+    /// Create a watchdog reporting waits longer than `bound` clock ticks.
+    pub const fn new(
+        clock: &'static dyn Clock,
+        bound: u64,
+        callback: fn(Option<&'static Location<'static>>, u64),
+    ) -> Self {
+        LockWatchdog {
+            clock,
+            bound,
+            callback,
+            owner: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Record the call site that acquired the lock.
+    pub fn acquired(&self, caller: &'static Location<'static>) {
+        self.owner
+            .store(caller as *const _ as *mut _, Ordering::Relaxed);
+    }
+
+    /// Returns the call site that last acquired the lock, if any.
+    pub fn owner(&self) -> Option<&'static Location<'static>> {
+        unsafe { self.owner.load(Ordering::Relaxed).as_ref() }
+    }
+
+    /// Returns the current time of the clock of the watchdog.
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// Report the owner of the lock if it was waited for since `start` for too long.
+    ///
+    /// Returns `true` if the callback was called.
+    pub fn check(&self, start: u64) -> bool {
+        let waited = self.clock.now().wrapping_sub(start);
+        if waited > self.bound {
+            (self.callback)(self.owner(), waited);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::alloc::GlobalAlloc;
+    use std::alloc::Layout;
+    use std::alloc::System;
+    use std::sync::atomic::AtomicU64;
+
+    use spin::Mutex;
+
+    use crate::Deblockator;
+
+    /// A clock advancing by one tick each time it is read.
+    struct Ticks(AtomicU64);
+
+    impl Clock for Ticks {
+        fn now(&self) -> u64 {
+            self.0.fetch_add(1, Ordering::Relaxed)
+        }
+    }
+
+    #[test]
+    #[cfg_attr(all(target_family = "wasm", not(target_feature = "atomics")), ignore)]
+    /// Check a blocked allocation reports the call site holding the lock.
+    fn watchdog_reports_owner() {
+        static CLOCK: Ticks = Ticks(AtomicU64::new(0));
+        static REPORTS: Mutex<Vec<(u32, u64)>> = Mutex::new(Vec::new());
+        fn report(owner: Option<&'static Location<'static>>, waited: u64) {
+            REPORTS.lock().push((owner.unwrap().line(), waited));
+        }
+
+        let va: Deblockator<System> =
+            Deblockator::new(System).with_lock_watchdog(LockWatchdog::new(&CLOCK, 100, report));
+        let layout = Layout::from_size_align(64, 8).unwrap();
+
+        std::thread::scope(|scope| {
+            let line = line!() + 1;
+            let blocks = va.blocks();
+            let waiter = scope.spawn(|| unsafe {
+                let ptr = va.alloc(layout);
+                assert!(!ptr.is_null());
+                va.dealloc(ptr, layout);
+            });
+            while REPORTS.lock().is_empty() {
+                std::thread::yield_now();
+            }
+            drop(blocks);
+
+            waiter.join().unwrap();
+            assert_eq!(REPORTS.lock().len(), 1);
+            assert_eq!(REPORTS.lock()[0].0, line);
+            assert!(REPORTS.lock()[0].1 > 100);
+        });
+    }
+}
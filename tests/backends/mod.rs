@@ -0,0 +1,62 @@
+use std::alloc::AllocError;
+use std::alloc::Allocator;
+use std::alloc::Layout;
+use std::alloc::System;
+use std::ptr::NonNull;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+/// A backing allocator forwarding to `System`, and recording its calls.
+pub struct Recording {
+    allocations: AtomicUsize,
+    deallocations: AtomicUsize,
+    bytes: AtomicUsize,
+    peak_bytes: AtomicUsize,
+}
+
+impl Recording {
+    pub const fn new() -> Self {
+        Recording {
+            allocations: AtomicUsize::new(0),
+            deallocations: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            peak_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the number of successful allocations.
+    pub fn allocations(&self) -> usize {
+        self.allocations.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of deallocations.
+    pub fn deallocations(&self) -> usize {
+        self.deallocations.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of bytes currently allocated.
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::SeqCst)
+    }
+
+    /// Returns the largest number of bytes allocated at once.
+    pub fn peak_bytes(&self) -> usize {
+        self.peak_bytes.load(Ordering::SeqCst)
+    }
+}
+
+unsafe impl Allocator for Recording {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = System.allocate(layout)?;
+        self.allocations.fetch_add(1, Ordering::SeqCst);
+        let bytes = self.bytes.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+        self.peak_bytes.fetch_max(bytes, Ordering::SeqCst);
+        Ok(ptr)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.deallocations.fetch_add(1, Ordering::SeqCst);
+        self.bytes.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.deallocate(ptr, layout)
+    }
+}
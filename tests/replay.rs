@@ -0,0 +1,116 @@
+//! Replays of allocation traces, checking the memory overhead of the
+//! allocator stays within bounds as its algorithms evolve.
+
+#![feature(allocator_api)]
+
+extern crate deblockator;
+
+use std::alloc::Allocator;
+use std::alloc::Layout;
+use std::collections::HashMap;
+use std::ptr::NonNull;
+
+use deblockator::Deblockator;
+use deblockator::Fit;
+
+mod backends;
+
+use backends::Recording;
+
+/// A trace of a parser building trees of small nodes and strings.
+const PARSER: &str = include_str!("traces/parser.trace");
+
+/// A trace of a cache of mixed-size entries with random lifetimes.
+const CHURN: &str = include_str!("traces/churn.trace");
+
+/// The memory used while replaying a trace.
+#[derive(Debug)]
+struct Replay {
+    /// The largest number of bytes allocated by the trace at once.
+    peak_live: usize,
+    /// The largest number of bytes obtained from the backing allocator at once.
+    peak_backing: usize,
+}
+
+impl Replay {
+    /// Returns the peak memory overhead of the allocator, in percents.
+    fn overhead(&self) -> usize {
+        (self.peak_backing - self.peak_live) * 100 / self.peak_live
+    }
+}
+
+/// Replay a trace on the given heap, checking all its memory is given back.
+fn replay(heap: &Deblockator<Recording>, trace: &str) -> Replay {
+    let mut live: HashMap<usize, (NonNull<u8>, Layout)> = HashMap::new();
+    let mut live_bytes = 0;
+    let mut peak_live = 0;
+
+    for line in trace.lines().filter(|line| !line.starts_with('#')) {
+        let mut fields = line.split_whitespace();
+        let op = fields.next().expect("missing operation");
+        let mut numbers = fields.map(|field| field.parse::<usize>().expect("bad number"));
+        let id = numbers.next().expect("missing id");
+        match op {
+            "+" => {
+                let size = numbers.next().expect("missing size");
+                let align = numbers.next().expect("missing alignment");
+                let layout = Layout::from_size_align(size, align).expect("bad layout");
+                let ptr = heap.allocate(layout).expect("allocation failed");
+                live.insert(id, (ptr.cast(), layout));
+                live_bytes += size;
+                peak_live = peak_live.max(live_bytes);
+            }
+            "-" => {
+                let (ptr, layout) = live.remove(&id).expect("unknown id");
+                unsafe { heap.deallocate(ptr, layout) };
+                live_bytes -= layout.size();
+            }
+            _ => panic!("unknown operation {:?}", op),
+        }
+    }
+    assert!(live.is_empty(), "allocations leaked by the trace");
+
+    heap.flush_caches();
+    let backend = heap.backing_allocator();
+    assert_eq!(backend.deallocations(), backend.allocations());
+    assert_eq!(backend.bytes(), 0);
+    Replay {
+        peak_live,
+        peak_backing: backend.peak_bytes(),
+    }
+}
+
+/// Replay a trace with each fit policy, checking their peak memory overhead.
+///
+/// The bounds are in percents of the peak bytes allocated by the trace, for
+/// first-fit, best-fit and deferred coalescing, and hold a small margin over
+/// the measured overheads.
+fn check_overheads(trace: &str, bounds: [usize; 3]) {
+    let heaps = [
+        Deblockator::new(Recording::new()),
+        Deblockator::new(Recording::new()).with_fit(Fit::Best),
+        Deblockator::new(Recording::new()).with_deferred_coalescing(),
+    ];
+    for (heap, bound) in heaps.iter().zip(bounds) {
+        let replay = replay(heap, trace);
+        assert!(
+            replay.overhead() <= bound,
+            "overhead of {}% over {}% ({:?})",
+            replay.overhead(),
+            bound,
+            replay
+        );
+    }
+}
+
+#[test]
+fn replay_parser() {
+    // a single block is enough, so the overhead is mostly unused block space
+    check_overheads(PARSER, [400, 400, 400]);
+}
+
+#[test]
+fn replay_churn() {
+    // the trace needs one or two blocks, each of them adding 130% overhead
+    check_overheads(CHURN, [200, 200, 200]);
+}
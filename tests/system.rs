@@ -0,0 +1,34 @@
+#![feature(allocator_api)]
+
+extern crate deblockator;
+
+use deblockator::Deblockator;
+
+mod backends;
+mod cases;
+
+use backends::Recording;
+
+#[global_allocator]
+static GLOBAL: Deblockator<Recording> = Deblockator::new(Recording::new());
+
+#[test]
+fn test_small_alloc() {
+    cases::small_alloc();
+    assert!(GLOBAL.backing_allocator().allocations() > 0);
+}
+
+#[test]
+fn test_local_heap() {
+    let heap: Deblockator<Recording> = Deblockator::new(Recording::new());
+    let mut v: Vec<usize, _> = Vec::new_in(&heap);
+    v.extend(0..500);
+    assert_eq!(heap.backing_allocator().allocations(), 1);
+    assert_eq!(heap.backing_allocator().peak_bytes(), heap.acquired_bytes());
+    drop(v);
+
+    heap.flush_caches();
+    let backend = heap.backing_allocator();
+    assert_eq!(backend.deallocations(), backend.allocations());
+    assert_eq!(backend.bytes(), 0);
+}